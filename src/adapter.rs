@@ -1,5 +1,6 @@
-use api::{ Error, User };
+use api::{ Error, InternalError, Principal };
 use services::*;
+use streams::{ Stream, StreamId };
 use values::*;
 
 use transformable_channels::mpsc::*;
@@ -46,12 +47,38 @@ pub trait AdapterManagerHandle: Send {
     /// Returns an error if any of:
     /// - `service` has channels;
     /// - a service with id `service.id` is already installed on the system;
-    /// - there is no adapter with id `service.lock`.
+    /// - there is no adapter with id `service.lock`;
+    /// - `service.parent` is not installed on the system or belongs to another adapter.
     fn add_service(& self, service: Service) -> Result<(), Error>;
 
+    /// Add a service to the system, along with all its channels. Called by the adapter
+    /// when a new service (typically a new device) has been detected/configured.
+    ///
+    /// Unlike calling `add_service` followed by `add_getter`/`add_setter`, the service
+    /// and all its channels become visible at once, and watches are registered only once
+    /// for the entire batch.
+    ///
+    /// # Requirements
+    ///
+    /// The adapter is in charge of making sure that identifiers persist across reboots.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of:
+    /// - a service with id `service.id` is already installed on the system;
+    /// - there is no adapter with id `service.adapter`;
+    /// - a channel with the same identifier as one of the channels is already registered;
+    /// - one of the channels has a `service` or an `adapter` that doesn't match `service`;
+    /// - `service.parent` is not installed on the system or belongs to another adapter.
+    ///
+    /// In either case, this method reverts all its changes.
+    fn add_service_with_channels(& self, service: Service) -> Result<(), Error>;
+
     /// Remove a service previously registered on the system. Typically, called by
     /// an adapter when a service (e.g. a device) is disconnected.
     ///
+    /// The children of the service (see `Service::parent`) are removed along with it.
+    ///
     /// # Errors
     ///
     /// Returns an error if any of:
@@ -60,6 +87,17 @@ pub trait AdapterManagerHandle: Send {
     /// cleanup before returning an error.
     fn remove_service(& self, service_id: &Id<ServiceId>) -> Result<(), Error>;
 
+    /// Update the tags and properties of a service previously registered on the system.
+    ///
+    /// Channels are left untouched, and any `getters` or `setters` in `service` are ignored.
+    /// Ongoing watches are preserved, unless the new tags cause channels to stop matching them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the service is not registered or if `service.adapter` doesn't
+    /// match the adapter of the service.
+    fn update_service(& self, service: Service) -> Result<(), Error>;
+
     /// Add a setter to the system. Typically, this is called by the adapter when a new
     /// service has been detected/configured. Some services may gain/lose getters at
     /// runtime depending on their configuration.
@@ -85,6 +123,17 @@ pub trait AdapterManagerHandle: Send {
     /// if the state is inconsistent.
     fn remove_getter(& self, id: &Id<Getter>) -> Result<(), Error>;
 
+    /// Update a getter previously registered on the system, e.g. to change its kind or its
+    /// tags. Unlike removing and re-adding the getter, this preserves ongoing watches that
+    /// still match the getter. Watches are registered anew with the adapter only if the kind
+    /// or the delivery of the getter has changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the getter is not registered or if its service or adapter
+    /// have changed.
+    fn update_getter(& self, getter: Channel<Getter>) -> Result<(), Error>;
+
     /// Add a setter to the system. Typically, this is called by the adapter when a new
     /// service has been detected/configured. Some services may gain/lose setters at
     /// runtime depending on their configuration.
@@ -109,6 +158,38 @@ pub trait AdapterManagerHandle: Send {
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
     fn remove_setter(& self, id: &Id<Setter>) -> Result<(), Error>;
+
+    /// Update a setter previously registered on the system, e.g. to change its kind or its
+    /// tags.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the setter is not registered or if its service or adapter
+    /// have changed.
+    fn update_setter(& self, setter: Channel<Setter>) -> Result<(), Error>;
+
+    /// Add an action to the system. Typically, this is called by the adapter when a new
+    /// service has been detected/configured.
+    ///
+    /// # Requirements
+    ///
+    /// The adapter is in charge of making sure that identifiers persist across reboots.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the adapter is not registered, the parent service is not
+    /// registered, or an action with the same identifier is already registered.
+    /// In either cases, this method reverts all its changes.
+    fn add_action(& self, action: Channel<Action>) -> Result<(), Error>;
+
+    /// Remove an action previously registered on the system.
+    ///
+    /// # Error
+    ///
+    /// This method returns an error if the action is not registered or if the service
+    /// is not registered. In either case, it attemps to clean as much as possible, even
+    /// if the state is inconsistent.
+    fn remove_action(& self, id: &Id<Action>) -> Result<(), Error>;
 }
 
 pub enum WatchEvent {
@@ -153,13 +234,36 @@ pub trait Adapter: Send + Sync {
     /// expects the adapter to attempt to minimize the connections with the actual devices.
     ///
     /// The AdapterManager is in charge of keeping track of the age of values.
-    fn fetch_values(&self, mut target: Vec<Id<Getter>>, _: User) -> ResultMap<Id<Getter>, Option<Value>, Error>;
+    fn fetch_values(&self, mut target: Vec<Id<Getter>>, _: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error>;
 
     /// Request that values be sent to channels.
     ///
     /// The AdapterManager always attempts to group calls to `send_values` by `Adapter`, and then
     /// expects the adapter to attempt to minimize the connections with the actual devices.
-    fn send_values(&self, values: HashMap<Id<Setter>, Value>, user: User) -> ResultMap<Id<Setter>, (), Error>;
+    fn send_values(&self, values: HashMap<Id<Setter>, Value>, user: Principal) -> ResultMap<Id<Setter>, (), Error>;
+
+    /// Invoke a group of actions, each with its argument.
+    ///
+    /// The AdapterManager always attempts to group calls to `invoke` by `Adapter`, and checks
+    /// that arguments and results have the types declared by each action.
+    ///
+    /// By default, reject all invocations. Adapters that register actions must override this
+    /// method.
+    fn invoke(&self, mut arguments: HashMap<Id<Action>, Value>, _: Principal) -> ResultMap<Id<Action>, Value, Error> {
+        arguments.drain().map(|(id, _)| {
+            let error = Err(Error::InternalError(InternalError::NoSuchAction(id.clone())));
+            (id, error)
+        }).collect()
+    }
+
+    /// Open the content of a stream, as referenced by a value `Value::Stream` previously
+    /// produced by `getter`.
+    ///
+    /// By default, reject all streams. Adapters that produce values of type `Stream` must
+    /// override this method.
+    fn open_stream(&self, _: &Id<Getter>, stream: &Id<StreamId>, _: Principal) -> Result<Stream, Error> {
+        Err(Error::InternalError(InternalError::NoSuchStream(stream.clone())))
+    }
 
     /// Watch a bunch of getters as they change.
     ///
@@ -1,8 +1,9 @@
 //! Utilities for writing adapters.
 
-use api::{ Error, User };
+use api::{ Error, Principal };
 use manager::*;
-use services::{ Getter, Setter };
+use services::{ Action, Getter, Setter };
+use streams::{ Stream, StreamId };
 use util::{ Id, AdapterId };
 use values::*;
 
@@ -50,14 +51,22 @@ impl<T> Adapter for MakeSyncAdapter<T> where T: Adapter {
         &self.version
     }
 
-    fn fetch_values(&self, set: Vec<Id<Getter>>, user: User) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+    fn fetch_values(&self, set: Vec<Id<Getter>>, user: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error> {
         self.lock.lock().unwrap().fetch_values(set, user)
     }
 
-    fn send_values(&self, values: HashMap<Id<Setter>, Value>, user: User) -> ResultMap<Id<Setter>, (), Error> {
+    fn send_values(&self, values: HashMap<Id<Setter>, Value>, user: Principal) -> ResultMap<Id<Setter>, (), Error> {
         self.lock.lock().unwrap().send_values(values, user)
     }
 
+    fn invoke(&self, arguments: HashMap<Id<Action>, Value>, user: Principal) -> ResultMap<Id<Action>, Value, Error> {
+        self.lock.lock().unwrap().invoke(arguments, user)
+    }
+
+    fn open_stream(&self, getter: &Id<Getter>, stream: &Id<StreamId>, user: Principal) -> Result<Stream, Error> {
+        self.lock.lock().unwrap().open_stream(getter, stream, user)
+    }
+
     fn register_watch(&self, watch: Vec<(Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)> ) ->
             Vec<(Id<Getter>, Result<Box<AdapterWatchGuard>, Error>)> {
         self.lock.lock().unwrap().register_watch(watch)
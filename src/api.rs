@@ -13,17 +13,28 @@
 //!
 //!
 
+use audit::{ AuditEntry, AuditQuery };
+use conditions::{ Conditional, PreconditionFailure };
+use leases::{ Lease, LeaseInfo };
+use locations::Location;
+use policy::Permission;
+use profiles::Profile;
 use services::*;
 use selector::*;
-pub use util::{ ResultMap, TargetMap, Targetted };
-use values::{ Value, Range, TypeError };
+use streams::{ Stream, StreamId };
+pub use util::{ JobId, LeaseId, ResultMap, RoleId, SceneId, TargetMap, Targetted };
+use values::{ Duration, HomeTimeZone, Value, Range, TimeStamp, TypeError };
 
 use transformable_channels::mpsc::*;
 
 use std::{ error, fmt };
+use std::collections::{ HashMap, HashSet };
 use std::error::Error as std_error;
 
-use serde::ser::Serialize;
+use chrono::UTC;
+
+use serde::de::{ Deserialize, Deserializer };
+use serde::ser::{ Serialize, Serializer as SerdeSerializer };
 use serde_json::value::Serializer;
 
 /// An error that arose during interaction with either a device, an adapter or the
@@ -51,9 +62,27 @@ pub enum Error {
     /// Attempting to send an invalid value. For instance, a time of day larger than 24h.
     InvalidValue(Value),
 
+    /// Attempting to set the home to a fixed timezone offset of 24h or more, in seconds.
+    InvalidTimeZone(i32),
+
     /// An error internal to the foxbox or an adapter. Normally, these errors should never
     /// arise from the high-level API.
     InternalError(InternalError),
+
+    /// Attempting to perform an operation that the user is not allowed to perform. See
+    /// module `policy`.
+    PermissionDenied(Permission),
+
+    /// Attempting to send a value to a setter leased by somebody else, or to lease it. See
+    /// module `leases`.
+    HeldBy(LeaseInfo),
+
+    /// A precondition of a conditional send does not hold. See module `conditions`.
+    PreconditionFailed(PreconditionFailure),
+
+    /// Attempting to fetch or watch a getter whose values may not be read back, because
+    /// its service has a write-only setter of the same kind.
+    WriteOnly(Id<Getter>),
 }
 
 impl ToJSON for Error {
@@ -74,11 +103,16 @@ impl fmt::Display for Error {
         match *self {
             Error::GetterDoesNotSupportPolling(ref getter) |
             Error::GetterDoesNotSupportWatching(ref getter) |
-            Error::GetterRequiresThresholdForWatching(ref getter) => write!(f, "{}: {}", self.description(), getter),
+            Error::GetterRequiresThresholdForWatching(ref getter) |
+            Error::WriteOnly(ref getter) => write!(f, "{}: {}", self.description(), getter),
             Error::TypeError(ref err) => write!(f, "{}: {}", self.description(), err),
             Error::RangeError(ref range) => write!(f, "{}: {:?}", self.description(), range),
             Error::InvalidValue(ref value) => write!(f, "{}: {:?}",self.description(), value),
+            Error::InvalidTimeZone(offset) => write!(f, "{}: {}s", self.description(), offset),
             Error::InternalError(ref err) => write!(f, "{}: {:?}", self.description(), err), // TODO implement Display for InternalError as well
+            Error::PermissionDenied(ref permission) => write!(f, "{}: {:?}", self.description(), permission),
+            Error::HeldBy(ref lease) => write!(f, "{}: priority {} until {:?}", self.description(), lease.priority, lease.expires),
+            Error::PreconditionFailed(ref failure) => write!(f, "{}: {:?}", self.description(), failure),
         }
     }
 }
@@ -92,7 +126,12 @@ impl error::Error for Error {
             Error::TypeError(_) => "Attempting to send a value with a wrong type",
             Error::RangeError(_) => "Attempting to use an inconsistent range",
             Error::InvalidValue(_) => "Attempting to send an invalid value",
-            Error::InternalError(_) => "Internal Error" // TODO implement Error for InternalError as well
+            Error::InvalidTimeZone(_) => "Attempting to use an invalid timezone offset",
+            Error::InternalError(_) => "Internal Error", // TODO implement Error for InternalError as well
+            Error::PermissionDenied(_) => "Permission denied",
+            Error::HeldBy(_) => "Setter held by another lease",
+            Error::PreconditionFailed(_) => "Precondition failed",
+            Error::WriteOnly(_) => "Attempting to read a value from a Channel<Getter> paired with a write-only Channel<Setter>",
         }
     }
 
@@ -110,23 +149,50 @@ pub enum InternalError {
     NoSuchGetter(Id<Getter>),
     /// Attempting to send values to a setter that isn't registered.
     NoSuchSetter(Id<Setter>),
+    /// Attempting to invoke an action that isn't registered.
+    NoSuchAction(Id<Action>),
     /// Attempting to access a service that isn't registered.
     NoSuchService(Id<ServiceId>),
     /// Attempting to access an adapter that isn't registered.
     NoSuchAdapter(Id<AdapterId>),
+    /// Attempting to open a stream that the adapter doesn't know about, or doesn't know anymore.
+    NoSuchStream(Id<StreamId>),
+    /// Attempting to retrieve the latest snapshot of a getter that doesn't have one yet.
+    NoSnapshot(Id<Getter>),
 
     /// Attempting to register a getter with an id that is already used.
     DuplicateGetter(Id<Getter>),
     /// Attempting to register a setter with an id that is already used.
     DuplicateSetter(Id<Setter>),
+    /// Attempting to register an action with an id that is already used.
+    DuplicateAction(Id<Action>),
     /// Attempting to register a service with an id that is already used.
     DuplicateService(Id<ServiceId>),
     /// Attempting to register an adapter with an id that is already used.
     DuplicateAdapter(Id<AdapterId>),
 
+    /// Attempting to access a location that doesn't exist.
+    NoSuchLocation(Id<LocationId>),
+    /// Attempting to add a location with an id that is already used.
+    DuplicateLocation(Id<LocationId>),
+    /// Attempting to build a location tree in which a location is its own ancestor.
+    LocationCycle(Id<LocationId>),
+
+    /// Attempting to add a profile with an id that is already used.
+    DuplicateProfile(Id<ProfileId>),
+    /// Attempting to remove a built-in profile.
+    BuiltinProfile(Id<ProfileId>),
+
+    /// Attempting to apply a scene that doesn't exist.
+    NoSuchScene(Id<SceneId>),
+
     /// Attempting to register a channel with an adapter that doesn't match that of its service.
     ConflictingAdapter(Id<AdapterId>, Id<AdapterId>),
 
+    /// Attempting to register a channel with a service that doesn't match the service
+    /// that is being registered.
+    ConflictingService(Id<ServiceId>, Id<ServiceId>),
+
     /// Open question: Individual adapters will have errors of many adapter-specific types.
     /// How do we make this best represent those?
     GenericError(String),
@@ -134,6 +200,9 @@ pub enum InternalError {
     /// Attempting to register a service in an invalid initial state. Typically, a service that
     /// pretends that it already has channels.
     InvalidInitialService,
+
+    /// Attempting to register a service that is related to itself.
+    RelatedToSelf(Id<ServiceId>),
 }
 
 /// An event during watching.
@@ -160,6 +229,20 @@ pub enum WatchEvent {
         value: Value
     },
 
+    /// Fired whenever a getter with `Delivery::Event` produces a value, if this value falls
+    /// within the range specified when we registered for watching, or if `Always` was specified.
+    /// Events have no state, so such getters never fire `EnterRange` or `ExitRange`.
+    Event {
+        /// The channel that sent the value.
+        from: Id<Getter>,
+
+        /// The payload of the event.
+        value: Value,
+
+        /// The date at which the event was received.
+        timestamp: TimeStamp,
+    },
+
     /// The set of devices being watched has changed, typically either
     /// because a tag was edited or because a device was
     /// removed. Payload is the id of the device that was removed.
@@ -179,18 +262,154 @@ pub enum WatchEvent {
     },
 }
 
-/// User identifier that will be passed from the REST API handlers to the
+/// User identifier that used to be passed from the REST API handlers to the
 /// adapters.
+///
+/// Replaced by `Principal`. Use `Principal::from(user)` to migrate.
+///
+/// # Migration
+///
+/// All the methods of `API` and `Adapter` that used to take a `User` now take a
+/// `Principal`. Callers convert with `Principal::from(user)`, while adapters replace
+/// `User` with `Principal` in their signatures and read `principal.user` where they
+/// used to match on `User::Id`.
+#[deprecated(note = "use Principal")]
 #[derive(Debug, Clone, PartialEq)]
 pub enum User {
     None,
     Id(i32)
 }
 
+#[allow(deprecated)]
+impl From<User> for Principal {
+    /// Convert a `User`, as produced by the REST API handlers, to a `Principal`.
+    fn from(user: User) -> Self {
+        let principal = match user {
+            User::None => Principal::anonymous(),
+            User::Id(id) => Principal::user(id),
+        };
+        principal.with_origin(Origin::Rest)
+    }
+}
+
+/// The channel through which a request entered the system.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Origin {
+    /// A request from the REST or `WebSocket` API.
+    Rest,
+
+    /// A request from a rule, acting on behalf of `Principal::user`. Payload is the name
+    /// of the rule.
+    Rule(String),
+
+    /// A request from a remote box, acting on behalf of one of its users. Payload is the
+    /// identifier of the remote box.
+    Remote(String),
+
+    /// A request from a scheduled job, acting on behalf of `Principal::user`. Payload is the
+    /// id of the job.
+    Job(Id<JobId>),
+
+    /// A request from within the system itself, e.g. an adapter or a test. This is the
+    /// default.
+    Internal,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Origin::Internal
+    }
+}
+
+/// The identity on behalf of which a request is performed. Passed unchanged from the API to
+/// adapters, and used by policy layers (see module `policy`) to decide what the request may do.
+///
+/// # JSON
+///
+/// An object with fields `user` (number or null), `roles` (array of strings), `origin` and
+/// `expires` (date string or null). All fields but `user` are optional.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Principal {
+    /// The user, or `None` for anonymous requests.
+    pub user: Option<i32>,
+
+    /// The roles of the principal, e.g. "admin" or "guest".
+    #[serde(default)]
+    pub roles: HashSet<Id<RoleId>>,
+
+    /// The channel through which the request entered the system.
+    #[serde(default)]
+    pub origin: Origin,
+
+    /// If specified, the principal may not perform any request after this date, e.g. for
+    /// a guest.
+    #[serde(default)]
+    pub expires: Option<TimeStamp>,
+}
+
+impl Principal {
+    /// An anonymous principal, without roles.
+    pub fn anonymous() -> Self {
+        Principal {
+            user: None,
+            roles: HashSet::new(),
+            origin: Origin::default(),
+            expires: None,
+        }
+    }
+
+    /// A principal acting on behalf of a user, without roles.
+    pub fn user(id: i32) -> Self {
+        Principal {
+            user: Some(id),
+            ..Principal::anonymous()
+        }
+    }
+
+    pub fn with_role(mut self, role: Id<RoleId>) -> Self {
+        self.roles.insert(role);
+        self
+    }
+
+    pub fn with_origin(self, origin: Origin) -> Self {
+        Principal {
+            origin: origin,
+            ..self
+        }
+    }
+
+    pub fn with_expiry(self, expires: TimeStamp) -> Self {
+        Principal {
+            expires: Some(expires),
+            ..self
+        }
+    }
+
+    pub fn has_role(&self, role: &Id<RoleId>) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Determine whether the principal has expired at a given date.
+    pub fn is_expired_at(&self, date: &TimeStamp) -> bool {
+        match self.expires {
+            None => false,
+            Some(ref expires) => expires <= date
+        }
+    }
+
+    /// Determine whether the principal has expired.
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(&TimeStamp::from_datetime(UTC::now()))
+    }
+}
+
 #[test]
-fn test_user_partialeq() {
-    assert_eq!(User::None, User::None);
-    assert_eq!(User::Id(1), User::Id(1));
+#[allow(deprecated)]
+fn test_principal_from_user() {
+    assert_eq!(Principal::from(User::None), Principal::anonymous().with_origin(Origin::Rest));
+    assert_eq!(Principal::from(User::Id(1)).user, Some(1));
+    assert!(Principal::user(1).with_expiry(TimeStamp::from_s(0)).is_expired());
+    assert!(!Principal::user(1).is_expired());
 }
 
 impl<K> Parser<Targetted<K, Value>> for Targetted<K, Value> where K: Parser<K> + Clone {
@@ -225,6 +444,36 @@ impl<K> Parser<Targetted<K, Value>> for Targetted<K, Value> where K: Parser<K> +
     }
 }
 
+/// The serialized form of `Targetted<K, Value>`, i.e. an object `{select, value}`, as
+/// accepted by the parser.
+#[derive(Serialize)]
+struct SerializedTargettedValue<'a, K> where K: 'a {
+    select: &'a Vec<K>,
+    value: &'a Value,
+}
+
+#[derive(Deserialize)]
+struct DeserializedTargettedValue<K> {
+    select: Vec<K>,
+    value: Value,
+}
+
+impl<K> Serialize for Targetted<K, Value> where K: Serialize + Clone {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: SerdeSerializer {
+        SerializedTargettedValue {
+            select: &self.select,
+            value: &self.payload,
+        }.serialize(serializer)
+    }
+}
+
+impl<K> Deserialize for Targetted<K, Value> where K: Deserialize + Clone {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let DeserializedTargettedValue { select, value } = try!(DeserializedTargettedValue::deserialize(deserializer));
+        Ok(Targetted::new(select, value))
+    }
+}
+
 impl<K> Parser<Targetted<K, Exactly<Range>>> for Targetted<K, Exactly<Range>> where K: Parser<K> + Clone {
     fn description() -> String {
         format!("Targetted<{}, Value>", K::description())
@@ -310,7 +559,8 @@ pub trait API: Send {
     ///     "service": "some-service-id",
     ///     "updated": "2014-11-28T12:00:09+00:00",
     ///     "mechanism": "setter",
-    ///     "kind": "OnOff"
+    ///     "kind": "OnOff",
+    ///     "write_only": false
     ///   ]
     /// }]"#;
     /// ```
@@ -330,6 +580,8 @@ pub trait API: Send {
     /// Note that this call is _not live_. In other words, if services
     /// are added after the call, they will not be affected.
     ///
+    /// The edit is recorded in the audit log, if any, on behalf of `user`.
+    ///
     /// # REST API
     ///
     /// `POST /api/v1/services/tag`
@@ -369,7 +621,7 @@ pub trait API: Send {
     /// ## Success
     ///
     /// A JSON string representing a number.
-    fn add_service_tags(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize;
+    fn add_service_tags(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
 
     /// Remove a set of tags from a set of services.
     ///
@@ -385,6 +637,8 @@ pub trait API: Send {
     /// Note that this call is _not live_. In other words, if services
     /// are added after the call, they will not be affected.
     ///
+    /// The edit is recorded in the audit log, if any, on behalf of `user`.
+    ///
     /// # REST API
     ///
     /// `DELETE /api/v1/services/tag`
@@ -424,7 +678,7 @@ pub trait API: Send {
     /// ## Success
     ///
     /// A JSON string representing a number.
-    fn remove_service_tags(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize;
+    fn remove_service_tags(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
 
     /// Get a list of getters matching some conditions
     ///
@@ -472,7 +726,8 @@ pub trait API: Send {
     ///   "service": "some-service-id",
     ///   "updated": "2014-11-28T12:00:09+00:00",
     ///   "mechanism": "getter",
-    ///   "kind": "OnOff"
+    ///   "kind": "OnOff",
+    ///   "delivery": "state"
     /// }]"#;
     /// ```
     fn get_getter_channels(& self, selectors: Vec<GetterSelector>) -> Vec<Channel<Getter>>;
@@ -484,6 +739,76 @@ pub trait API: Send {
     /// `GET /api/v1/channels`
     fn get_setter_channels(& self, selectors: Vec<SetterSelector>) -> Vec<Channel<Setter>>;
 
+    /// Get a list of actions matching some conditions
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/channels`
+    fn get_action_channels(& self, selectors: Vec<ActionSelector>) -> Vec<Channel<Action>>;
+
+    /// Explain which criteria of a selector accept or reject each service.
+    ///
+    /// The result contains one `Explanation` per service currently known, whether or not
+    /// it is accepted by the selector. This is designed for debugging selectors.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/services/explain`
+    ///
+    /// ## Requests
+    ///
+    /// A single `ServiceSelector` (see the documentation of `ServiceSelector`).
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Explanation` (see the documentation of `Explanation`).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// # let source =
+    /// r#"[{
+    ///   "id": "some-service-id",
+    ///   "matches": false,
+    ///   "matched": ["tags"],
+    ///   "failed": ["getters"]
+    /// }]"#;
+    /// ```
+    fn explain_services(& self, selector: ServiceSelector) -> Vec<Explanation<ServiceId>>;
+
+    /// Explain which criteria of a selector accept or reject each getter.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/channels/explain`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Explanation`, with one entry per getter currently known.
+    fn explain_getters(& self, selector: GetterSelector) -> Vec<Explanation<Getter>>;
+
+    /// Explain which criteria of a selector accept or reject each setter.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/channels/explain`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Explanation`, with one entry per setter currently known.
+    fn explain_setters(& self, selector: SetterSelector) -> Vec<Explanation<Setter>>;
+
+    /// Explain which criteria of a selector accept or reject each action.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/channels/explain`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Explanation`, with one entry per action currently known.
+    fn explain_actions(& self, selector: ActionSelector) -> Vec<Explanation<Action>>;
+
     /// Label a set of channels with a set of tags.
     ///
     /// A call to `API::put_{getter, setter}_tag(vec![req1, req2, ...], vec![tag1,
@@ -498,6 +823,8 @@ pub trait API: Send {
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
     ///
+    /// The edit is recorded in the audit log, if any, on behalf of `user`.
+    ///
     /// # REST API
     ///
     /// `POST /api/v1/channels/tag`
@@ -528,8 +855,8 @@ pub trait API: Send {
     /// ## Success
     ///
     /// A JSON representing a number.
-    fn add_getter_tags(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> usize;
-    fn add_setter_tags(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize;
+    fn add_getter_tags(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
+    fn add_setter_tags(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
 
     /// Remove a set of tags from a set of channels.
     ///
@@ -545,6 +872,8 @@ pub trait API: Send {
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
     ///
+    /// The edit is recorded in the audit log, if any, on behalf of `user`.
+    ///
     /// # REST API
     ///
     /// `DELETE /api/v1/channels/tag`
@@ -575,8 +904,403 @@ pub trait API: Send {
     /// ## Success
     ///
     /// A JSON representing a number.
-    fn remove_getter_tags(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> usize;
-    fn remove_setter_tags(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize;
+    fn remove_getter_tags(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
+    fn remove_setter_tags(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize;
+
+    /// As `add_service_tags`, `add_getter_tags` and `add_setter_tags`, but record the tags
+    /// as set by `origin` rather than by the user.
+    ///
+    /// Tags set by users or applications survive the service or channel being registered
+    /// again by its adapter. Tags set by the adapter are replaced whenever the adapter
+    /// registers the service or channel again.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/services/tag`, `POST /api/v1/channels/tag`
+    ///
+    /// As `add_service_tags`, `add_getter_tags` and `add_setter_tags`, with an
+    /// additional field `origin` (string, one of "adapter", "user", "application").
+    fn add_service_tags_with_origin(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize;
+    fn add_getter_tags_with_origin(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize;
+    fn add_setter_tags_with_origin(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize;
+
+    /// Remove from a set of services or channels all the tags set by `origin`, and return
+    /// the number of services or channels matching any of the selectors.
+    ///
+    /// Removing a tag set by the adapter is remembered: the adapter will not restore it
+    /// when it registers the service or channel again.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/services/tag`, `DELETE /api/v1/channels/tag`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<ServiceSelector>,
+    ///   origin: TagOrigin,
+    /// }
+    /// ```
+    fn remove_service_tags_by_origin(& self, selectors: Vec<ServiceSelector>, origin: TagOrigin, user: Principal) -> usize;
+    fn remove_getter_tags_by_origin(& self, selectors: Vec<GetterSelector>, origin: TagOrigin, user: Principal) -> usize;
+    fn remove_setter_tags_by_origin(& self, selectors: Vec<SetterSelector>, origin: TagOrigin, user: Principal) -> usize;
+
+    /// Dry-run variants of `add_service_tags`, `add_getter_tags`, `add_setter_tags`,
+    /// `remove_service_tags`, `remove_getter_tags` and `remove_setter_tags`: determine
+    /// the services or channels that the call would affect, without changing anything.
+    ///
+    /// # REST API
+    ///
+    /// As the corresponding calls, with query parameter `dry_run=true`.
+    ///
+    /// ## Success
+    ///
+    /// A JSON object mapping the id of each service or channel matching any of the
+    /// selectors to the array of tags that would actually be added or removed. This array
+    /// is empty if the service or channel would not change.
+    fn add_service_tags_dry_run(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>>;
+    fn add_getter_tags_dry_run(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>>;
+    fn add_setter_tags_dry_run(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>>;
+    fn remove_service_tags_dry_run(& self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>>;
+    fn remove_getter_tags_dry_run(& self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>>;
+    fn remove_setter_tags_dry_run(& self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>>;
+
+    /// List the tags currently used by services and channels, or with metadata attached.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/tags`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `TagInfo` (see the documentation of `TagInfo`).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// # let source =
+    /// r#"[{
+    ///   "id": "living-room",
+    ///   "services": 3,
+    ///   "getters": 1,
+    ///   "setters": 0,
+    ///   "metadata": {
+    ///     "name": "Living room",
+    ///     "icon": null
+    ///   }
+    /// }]"#;
+    /// ```
+    fn get_tags(& self) -> Vec<TagInfo>;
+
+    /// Rename a tag on all services and channels.
+    ///
+    /// If tag `to` is already in use, tags `from` and `to` are merged. The operation is
+    /// atomic, i.e. no client can observe a state in which only some of the services
+    /// and channels have been renamed. Returns the number of services and channels affected.
+    ///
+    /// The renaming is recorded in the audit log, if any, on behalf of `user`, along with
+    /// the services and channels that carried tag `from`.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/tags/rename`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   from: Id<TagId>,
+    ///   to: Id<TagId>,
+    /// }
+    /// ```
+    ///
+    /// ## Success
+    ///
+    /// A JSON representing a number.
+    fn rename_tag(& self, from: &Id<TagId>, to: &Id<TagId>, user: Principal) -> usize;
+
+    /// Attach metadata to a tag, replacing any previous metadata.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/tags/metadata`
+    fn set_tag_metadata(& self, tag: Id<TagId>, metadata: TagMetadata);
+
+    /// Remove the metadata attached to a tag. Returns `true` if the tag had metadata.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/tags/metadata`
+    fn remove_tag_metadata(& self, tag: &Id<TagId>) -> bool;
+
+    /// List all the profiles, i.e. named sets of channel kinds that services may satisfy.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/profiles`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Profile` (see the documentation of `Profile`).
+    fn get_profiles(& self) -> Vec<Profile>;
+
+    /// Add a profile. Services are matched against the new profile immediately.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/profiles`
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if a profile with the same id already exists.
+    fn add_profile(& self, profile: Profile) -> Result<(), Error>;
+
+    /// Remove a profile. Returns `Ok(false)` if there was no such profile.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/profiles`
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the profile is built-in, as services are always matched against
+    /// built-in profiles.
+    fn remove_profile(& self, id: &Id<ProfileId>) -> Result<bool, Error>;
+
+    /// Get the timezone of the home, used to interpret values of type `TimeOfDay`,
+    /// `DayOfWeek` and `Date`.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/timezone`
+    ///
+    /// ## Success
+    ///
+    /// A JSON string, either `"system"` or an offset such as `"+02:00"` (see the
+    /// documentation of `HomeTimeZone`).
+    fn get_home_timezone(& self) -> HomeTimeZone;
+
+    /// Set the timezone of the home.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/timezone`
+    ///
+    /// # Errors
+    ///
+    /// `Error::InvalidTimeZone` if the timezone is a fixed offset of 24h or more.
+    fn set_home_timezone(& self, timezone: HomeTimeZone) -> Result<(), Error>;
+
+    /// List all the locations.
+    ///
+    /// This also serves to export the location tree.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/locations`
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of `Location` (see the documentation of `Location`).
+    ///
+    /// ### Example
+    ///
+    /// ```
+    /// # let source =
+    /// r#"[{
+    ///   "id": "kitchen",
+    ///   "name": "Kitchen",
+    ///   "parent": "ground floor"
+    /// }, {
+    ///   "id": "ground floor",
+    ///   "name": null,
+    ///   "parent": null
+    /// }]"#;
+    /// ```
+    fn get_locations(& self) -> Vec<Location>;
+
+    /// Add a location.
+    ///
+    /// # REST API
+    ///
+    /// `POST /api/v1/locations`
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if a location with the same id already exists or if the parent
+    /// location does not exist.
+    fn add_location(& self, location: Location) -> Result<(), Error>;
+
+    /// Remove a location. Its children and the services placed in it are moved to its
+    /// parent, if any.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/locations`
+    fn remove_location(& self, id: &Id<LocationId>) -> Result<(), Error>;
+
+    /// Replace the entire location tree, e.g. to import a tree exported with `get_locations`.
+    /// Services placed in a location that is not part of the new tree are removed from
+    /// their location.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/locations`
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the tree is inconsistent, i.e. if two locations have the
+    /// same id, if a parent is missing or if there is a cycle. In this case, the current
+    /// tree is left unchanged.
+    fn import_locations(& self, locations: Vec<Location>) -> Result<(), Error>;
+
+    /// Place a set of services in a location, or remove them from their location if
+    /// `location` is `None`. Returns the number of services matching any of the selectors.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/services/location`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<ServiceSelector>,
+    ///   location: Option<Id<LocationId>>,
+    /// }
+    /// ```
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the location does not exist.
+    fn set_service_location(& self, selectors: Vec<ServiceSelector>, location: Option<Id<LocationId>>) -> Result<usize, Error>;
+
+    /// Set user properties on a set of services.
+    ///
+    /// A call to `API::set_service_user_properties(vec![req1, req2, ...], properties)` will
+    /// add or overwrite all the (key, value) pairs of `properties` in the user properties of
+    /// all the services matching _either_ `req1` or `req2` or ... and return the number of
+    /// services matching any of the selectors.
+    ///
+    /// User properties are distinct from the properties set by the adapter. They are
+    /// remembered by id, so they survive the service being removed and registered again.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/services/properties`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<ServiceSelector>,
+    ///   properties: HashMap<String, String>,
+    /// }
+    /// ```
+    ///
+    /// ## Errors
+    ///
+    /// In case of syntax error, Error 400, accompanied with a
+    /// somewhat human-readable JSON string detailing the error.
+    ///
+    /// ## Success
+    ///
+    /// A JSON representing a number.
+    fn set_service_user_properties(& self, selectors: Vec<ServiceSelector>, properties: HashMap<String, String>) -> usize;
+
+    /// Remove user properties from a set of services.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/services/properties`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<ServiceSelector>,
+    ///   properties: Vec<String>,
+    /// }
+    /// ```
+    ///
+    /// ## Success
+    ///
+    /// A JSON representing a number.
+    fn remove_service_user_properties(& self, selectors: Vec<ServiceSelector>, keys: Vec<String>) -> usize;
+
+    /// Set user properties on a set of channels.
+    ///
+    /// Note that this call is _not live_. In other words, if channels
+    /// are added after the call, they will not be affected.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/properties`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<GetterSelector>,
+    ///   properties: HashMap<String, String>,
+    /// }
+    /// ```
+    /// or
+    /// ```ignore
+    /// {
+    ///   set: Vec<SetterSelector>,
+    ///   properties: HashMap<String, String>,
+    /// }
+    /// ```
+    ///
+    /// ## Success
+    ///
+    /// A JSON representing a number.
+    fn set_getter_user_properties(& self, selectors: Vec<GetterSelector>, properties: HashMap<String, String>) -> usize;
+    fn set_setter_user_properties(& self, selectors: Vec<SetterSelector>, properties: HashMap<String, String>) -> usize;
+
+    /// Remove user properties from a set of channels.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/channels/properties`
+    ///
+    /// ## Requests
+    ///
+    /// Any JSON that can be deserialized to
+    ///
+    /// ```ignore
+    /// {
+    ///   set: Vec<GetterSelector>,
+    ///   properties: Vec<String>,
+    /// }
+    /// ```
+    /// or
+    /// ```ignore
+    /// {
+    ///   set: Vec<SetterSelector>,
+    ///   properties: Vec<String>,
+    /// }
+    /// ```
+    ///
+    /// ## Success
+    ///
+    /// A JSON representing a number.
+    fn remove_getter_user_properties(& self, selectors: Vec<GetterSelector>, keys: Vec<String>) -> usize;
+    fn remove_setter_user_properties(& self, selectors: Vec<SetterSelector>, keys: Vec<String>) -> usize;
 
     /// Read the latest value from a set of channels
     ///
@@ -613,7 +1337,21 @@ pub trait API: Send {
     /// ## Success
     ///
     /// The results, per getter.
-    fn fetch_values(&self, Vec<GetterSelector>, user: User) -> ResultMap<Id<Getter>, Option<Value>, Error>;
+    fn fetch_values(&self, Vec<GetterSelector>, user: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error>;
+
+    /// As `fetch_values`, but use the values fetched at most `max_age` ago, if any, rather
+    /// than contacting the adapters.
+    ///
+    /// The values fetched recently are shared by all principals: a value fetched on behalf
+    /// of one principal may be returned to another one without asking the adapter again.
+    /// Permissions must therefore be checked before calling this method, as `AccessControl`
+    /// does, and this method should not be used with getters whose value depends on the
+    /// principal.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/get?max_age=<duration>`
+    fn fetch_cached_values(&self, Vec<GetterSelector>, max_age: Duration, user: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error>;
 
     /// Send a bunch of values to a set of channels.
     ///
@@ -672,8 +1410,188 @@ pub trait API: Send {
     ///
     /// ## Success
     ///
-    /// The results, per setter.
-    fn send_values(&self, TargetMap<SetterSelector, Value>, user: User) -> ResultMap<Id<Setter>, (), Error>;
+    /// The results, per setter. Setters leased by somebody else than `user` reject the value
+    /// with `Error::HeldBy` (see `acquire_lease`).
+    fn send_values(&self, TargetMap<SetterSelector, Value>, user: Principal) -> ResultMap<Id<Setter>, (), Error>;
+
+    /// Send values to a set of setters, each only if its preconditions hold.
+    ///
+    /// Preconditions are evaluated just before dispatch, against values fetched from the
+    /// getters for the occasion or, if the precondition specifies a `max_age`, against
+    /// values fetched recently enough (see module `conditions`).
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/set_if`
+    ///
+    /// ## JSON
+    ///
+    /// As `send_values`, with an additional optional field `preconditions` on each object.
+    ///
+    /// ```
+    /// # extern crate foxbox_taxonomy;
+    /// # use foxbox_taxonomy::conditions::*;
+    /// # use foxbox_taxonomy::selector::*;
+    /// # use foxbox_taxonomy::api::*;
+    /// # use foxbox_taxonomy::parse::*;
+    ///
+    /// # fn main() {
+    ///
+    /// // Turn the heater off, only if it is currently on.
+    /// # let source =
+    /// r#"{
+    ///   "select": {"id": "heater-setter"},
+    ///   "value": {"OnOff": "Off"},
+    ///   "preconditions": [{
+    ///     "select": {"id": "heater-getter"},
+    ///     "range": {"Eq": {"OnOff": "On"}}
+    ///   }]
+    /// }"#;
+    ///
+    /// # TargetMap::<SetterSelector, Conditional>::from_str(&source).unwrap();
+    /// # }
+    /// ```
+    ///
+    /// ## Success
+    ///
+    /// The results, per setter. Setters whose preconditions do not hold are reported with
+    /// `Error::PreconditionFailed`, and no value is sent to them. They are recorded in the
+    /// audit log, if any, on behalf of `user`, along with the values sent.
+    fn send_values_if(&self, TargetMap<SetterSelector, Conditional>, user: Principal) -> ResultMap<Id<Setter>, (), Error>;
+
+    /// Dry-run variant of `send_values`: determine the setters to which values would be
+    /// sent and the errors that can be predicted without contacting the adapters, e.g.
+    /// type errors or leases held by somebody else, without sending anything.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/set?dry_run=true`
+    ///
+    /// ## Success
+    ///
+    /// The predicted results, per setter. `Ok` means that the value would be dispatched to
+    /// the adapter, which may still reject it.
+    fn send_values_dry_run(&self, TargetMap<SetterSelector, Value>, user: Principal) -> ResultMap<Id<Setter>, (), Error>;
+
+    /// Acquire a lease on all the setters matching any of the selectors, for a duration.
+    ///
+    /// Until the lease expires or is released, `send_values` rejects the values sent to these
+    /// setters by any principal other than `user`. Acquiring a lease replaces any lease
+    /// previously held by `user` on the same setters.
+    ///
+    /// Anybody who may see the setters may also see the priority and expiry date of the lease,
+    /// as field `lease` of their JSON. The holder and the id of the lease are only returned to
+    /// the caller. The id cannot be guessed, but only `user` may release the lease anyway.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/lease`
+    ///
+    /// ## Requests
+    ///
+    /// An object with fields `select` (SetterSelector | array of SetterSelector), `duration`
+    /// (Duration) and, optionally, `priority` (number, defaults to 0).
+    ///
+    /// ## Errors
+    ///
+    /// If any of the setters is already leased by somebody else with a priority greater than
+    /// or equal to `priority`, no lease is acquired and this returns `Error::HeldBy` with the
+    /// priority and expiry date of the conflicting lease. Leases with a lower priority are
+    /// preempted.
+    ///
+    /// Anonymous principals may not acquire leases, as they cannot be told apart. This
+    /// returns `Error::PermissionDenied(Permission::Write)`.
+    ///
+    /// ## Success
+    ///
+    /// The lease, as a JSON object with fields `id`, `holder`, `priority` and `expires`.
+    fn acquire_lease(&self, selectors: Vec<SetterSelector>, duration: Duration, priority: i32, user: Principal) -> Result<Lease, Error>;
+
+    /// Release a lease previously acquired by `user`.
+    ///
+    /// # REST API
+    ///
+    /// `DELETE /api/v1/channels/lease`
+    ///
+    /// ## Requests
+    ///
+    /// A JSON string, the id of the lease.
+    ///
+    /// ## Success
+    ///
+    /// The number of setters released. Leases held by somebody else are left untouched.
+    fn release_lease(&self, id: &Id<LeaseId>, user: Principal) -> usize;
+
+    /// Invoke a set of actions, each with an argument, and collect their results.
+    ///
+    /// Arguments that do not have the type declared by the action are rejected with a
+    /// `TypeError` without reaching the adapter. Similarly, results that do not have the type
+    /// declared by the action are replaced with a `TypeError`.
+    ///
+    /// # REST API
+    ///
+    /// `PUT /api/v1/channels/invoke`
+    ///
+    /// ## JSON
+    ///
+    /// As `send_values`, with `ActionSelector`s instead of `SetterSelector`s.
+    ///
+    /// ```
+    /// extern crate foxbox_taxonomy;
+    /// # use foxbox_taxonomy::selector::*;
+    /// # use foxbox_taxonomy::api::*;
+    /// # use foxbox_taxonomy::values::*;
+    ///
+    /// # fn main() {
+    /// # let source =
+    /// r#"{
+    ///   "select": {"id": "my-action"},
+    ///   "value": {"OnOff": "On"}
+    /// }"#;
+    ///
+    /// # TargetMap::<ActionSelector, Value>::from_str(&source).unwrap();
+    /// # }
+    /// ```
+    ///
+    /// ## Errors
+    ///
+    /// In case of syntax error, Error 400, accompanied with a
+    /// somewhat human-readable JSON string detailing the error.
+    ///
+    /// ## Success
+    ///
+    /// The results, per action.
+    fn invoke(&self, TargetMap<ActionSelector, Value>, user: Principal) -> ResultMap<Id<Action>, Value, Error>;
+
+    /// Open the content of a stream, as referenced by a value `Value::Stream` produced by
+    /// getter `getter`.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/channels/{getter}/streams/{stream}`
+    ///
+    /// ## Success
+    ///
+    /// The content, with the mime type of the stream as `Content-Type`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the getter doesn't exist, if it doesn't produce values of type
+    /// `Stream`, or if the adapter cannot open the stream.
+    fn open_stream(&self, getter: &Id<Getter>, stream: &Id<StreamId>, user: Principal) -> Result<Stream, Error>;
+
+    /// Fetch the latest value of a getter of type `Stream`, e.g. the latest snapshot of a
+    /// camera, and open its content.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/channels/{getter}/snapshot`
+    ///
+    /// ## Errors
+    ///
+    /// As `open_stream`. Additionally, returns `InternalError::NoSnapshot` if the getter has
+    /// no value yet.
+    fn fetch_snapshot(&self, getter: &Id<Getter>, user: Principal) -> Result<Stream, Error>;
 
     /// Watch for changes from channels.
     ///
@@ -702,6 +1620,30 @@ pub trait API: Send {
     fn watch_values(& self, watch: TargetMap<GetterSelector, Exactly<Range>>,
             on_event: Box<ExtSender<WatchEvent>>) -> Self::WatchGuard;
 
+    /// Get the entries of the audit log matching a query, from the oldest to the most recent.
+    ///
+    /// If the audit log is disabled, this returns an empty list.
+    ///
+    /// # REST API
+    ///
+    /// `GET /api/v1/audit`
+    ///
+    /// ## Requests
+    ///
+    /// A JSON object with any of the following optional fields:
+    ///
+    /// - `since`: a timestamp; only entries recorded at or after this date;
+    /// - `until`: a timestamp; only entries recorded strictly before this date;
+    /// - `user`: a number; only entries performed on behalf of this user;
+    /// - `services`, `getters`, `setters`: arrays of selectors; only entries targeting the
+    ///   matching services (or their channels), getters or setters.
+    ///
+    /// ## Success
+    ///
+    /// A JSON array of entries, each with fields `timestamp`, `principal`, `operation`
+    /// and `results`. Secrets are never recorded in the log.
+    fn get_audit_entries(& self, query: AuditQuery) -> Vec<AuditEntry>;
+
     /// A value that causes a disconnection once it is dropped.
     type WatchGuard;
 }
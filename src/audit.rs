@@ -0,0 +1,404 @@
+//! An audit log of the operations that change the state of the system, e.g. to find out
+//! who unlocked the door and when.
+//!
+//! The log records calls to `send_values` and `invoke`, edits of tags, and registration
+//! of adapters, services and channels, along with the principal who performed them, the
+//! targeted ids, the values sent and the result for each target. Secrets are never recorded.
+//!
+//! The most recent entries are kept in memory and, optionally, appended to a file, one JSON
+//! object per line. Once the file holds as many entries as are kept in memory, it is rotated
+//! to a file with the same name and suffix `.1`, replacing any previous rotated file, so the
+//! log never uses more than twice its capacity on disk. Entries cannot be modified or removed
+//! through this API.
+
+use api::{ Error, Principal };
+use selector::{ GetterSelector, ServiceSelector, SetterSelector };
+use services::*;
+use values::{ TimeStamp, Value };
+
+use chrono::UTC;
+
+use serde_json;
+
+use std::cmp;
+use std::collections::{ HashSet, VecDeque };
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufRead, BufReader, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+
+/// The number of entries kept in memory by default.
+pub const DEFAULT_AUDIT_CAPACITY: usize = 10000;
+
+/// An operation recorded in the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    AddAdapter,
+    RemoveAdapter,
+    AddService,
+    RemoveService,
+    UpdateService,
+    AddGetter,
+    RemoveGetter,
+    UpdateGetter,
+    AddSetter,
+    RemoveSetter,
+    UpdateSetter,
+    AddAction,
+    RemoveAction,
+
+    /// A call to `send_values`.
+    SendValues,
+
+    /// A call to `invoke`.
+    Invoke,
+
+    /// Tags added to services or channels.
+    AddTags(Vec<Id<TagId>>),
+
+    /// Tags removed from services or channels.
+    RemoveTags(Vec<Id<TagId>>),
+
+    /// All tags with a given origin removed from services or channels.
+    RemoveTagsByOrigin(TagOrigin),
+
+    /// A tag renamed on all services and channels.
+    RenameTag {
+        from: Id<TagId>,
+        to: Id<TagId>,
+    },
+}
+
+/// The target of an operation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuditTarget {
+    Adapter(Id<AdapterId>),
+    Service(Id<ServiceId>),
+    Getter(Id<Getter>),
+    Setter(Id<Setter>),
+    Action(Id<Action>),
+}
+
+/// The outcome of an operation on a single target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditResult {
+    pub target: AuditTarget,
+
+    /// The value sent to the target, if any. Secrets are never recorded, see `redacted`.
+    pub value: Option<Value>,
+
+    /// `true` if the value sent to the target was a secret, which was not recorded.
+    #[serde(default)]
+    pub redacted: bool,
+
+    /// The error, if the operation failed on this target.
+    pub error: Option<Error>,
+}
+
+impl AuditResult {
+    pub fn new(target: AuditTarget, value: Option<Value>, error: Option<Error>) -> Self {
+        let (value, redacted) = match value {
+            Some(Value::Secret(_)) => (None, true),
+            value => (value, false)
+        };
+        AuditResult {
+            target: target,
+            value: value,
+            redacted: redacted,
+            error: error,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// A single entry of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The date at which the operation was performed.
+    pub timestamp: TimeStamp,
+
+    /// The principal who performed the operation. Registrations by adapters are recorded
+    /// with an anonymous principal with origin `Origin::Internal`.
+    pub principal: Principal,
+
+    pub operation: Operation,
+
+    /// The outcome of the operation for each target.
+    pub results: Vec<AuditResult>,
+}
+
+/// Criteria for selecting entries of the audit log. Entries must match all the criteria
+/// that are specified.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries recorded at or after this date.
+    #[serde(default)]
+    pub since: Option<TimeStamp>,
+
+    /// Only entries recorded strictly before this date.
+    #[serde(default)]
+    pub until: Option<TimeStamp>,
+
+    /// Only entries performed on behalf of this user.
+    #[serde(default)]
+    pub user: Option<i32>,
+
+    /// Only entries targeting the services matching any of these selectors, or their
+    /// channels.
+    #[serde(default)]
+    pub services: Option<Vec<ServiceSelector>>,
+
+    /// Only entries targeting the getters matching any of these selectors.
+    #[serde(default)]
+    pub getters: Option<Vec<GetterSelector>>,
+
+    /// Only entries targeting the setters matching any of these selectors.
+    #[serde(default)]
+    pub setters: Option<Vec<SetterSelector>>,
+}
+
+impl AuditQuery {
+    pub fn new() -> Self {
+        AuditQuery::default()
+    }
+
+    pub fn with_since(self, since: TimeStamp) -> Self {
+        AuditQuery {
+            since: Some(since),
+            ..self
+        }
+    }
+
+    pub fn with_until(self, until: TimeStamp) -> Self {
+        AuditQuery {
+            until: Some(until),
+            ..self
+        }
+    }
+
+    pub fn with_user(self, user: i32) -> Self {
+        AuditQuery {
+            user: Some(user),
+            ..self
+        }
+    }
+
+    pub fn with_services(self, services: Vec<ServiceSelector>) -> Self {
+        AuditQuery {
+            services: Some(services),
+            ..self
+        }
+    }
+
+    pub fn with_getters(self, getters: Vec<GetterSelector>) -> Self {
+        AuditQuery {
+            getters: Some(getters),
+            ..self
+        }
+    }
+
+    pub fn with_setters(self, setters: Vec<SetterSelector>) -> Self {
+        AuditQuery {
+            setters: Some(setters),
+            ..self
+        }
+    }
+
+    /// Determine whether the query restricts the targets of entries.
+    pub fn has_selectors(&self) -> bool {
+        self.services.is_some() || self.getters.is_some() || self.setters.is_some()
+    }
+}
+
+/// The file backing an audit log.
+struct AuditFile {
+    path: PathBuf,
+    rotated: PathBuf,
+    file: File,
+
+    /// The number of entries in `file`.
+    len: usize,
+}
+
+impl AuditFile {
+    /// Move the current file to `rotated` and start a new one.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        try!(fs::rename(&self.path, &self.rotated));
+        self.file = try!(OpenOptions::new().append(true).create(true).open(&self.path));
+        self.len = 0;
+        Ok(())
+    }
+}
+
+/// An append-only log of operations.
+pub struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    capacity: usize,
+    file: Option<Mutex<AuditFile>>,
+}
+
+impl AuditLog {
+    /// Create an audit log kept only in memory, with the default capacity.
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_AUDIT_CAPACITY)
+    }
+
+    /// Create an audit log kept only in memory, keeping at most `capacity` entries (at
+    /// least one). Older entries are dropped.
+    pub fn with_capacity(capacity: usize) -> Self {
+        AuditLog {
+            entries: Mutex::new(VecDeque::new()),
+            capacity: cmp::max(capacity, 1),
+            file: None,
+        }
+    }
+
+    /// Open an audit log backed by a file, with the default capacity.
+    pub fn open<P>(path: P) -> Result<Self, io::Error> where P: AsRef<Path> {
+        Self::open_with_capacity(path, DEFAULT_AUDIT_CAPACITY)
+    }
+
+    /// Open an audit log backed by a file, keeping at most `capacity` entries (at least
+    /// one) in memory. The most recent entries of the file and of its rotated file are
+    /// loaded, one line at a time. New entries are appended to the file.
+    ///
+    /// Lines that cannot be parsed are skipped.
+    pub fn open_with_capacity<P>(path: P, capacity: usize) -> Result<Self, io::Error> where P: AsRef<Path> {
+        let capacity = cmp::max(capacity, 1);
+        let path = path.as_ref().to_path_buf();
+        let rotated = {
+            let mut name = path.clone().into_os_string();
+            name.push(".1");
+            PathBuf::from(name)
+        };
+        let mut entries = VecDeque::new();
+        try!(Self::load(&rotated, capacity, &mut entries));
+        let len = try!(Self::load(&path, capacity, &mut entries));
+        let file = try!(OpenOptions::new().append(true).create(true).open(&path));
+        let mut file = AuditFile {
+            path: path,
+            rotated: rotated,
+            file: file,
+            len: len,
+        };
+        if file.len >= capacity {
+            try!(file.rotate());
+        }
+        Ok(AuditLog {
+            entries: Mutex::new(entries),
+            capacity: capacity,
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Append the entries of a file to `entries`, keeping only the `capacity` most recent
+    /// ones. Return the number of entries in the file.
+    fn load(path: &Path, capacity: usize, entries: &mut VecDeque<AuditEntry>) -> Result<usize, io::Error> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let mut len = 0;
+        let reader = BufReader::new(try!(File::open(path)));
+        for line in reader.lines() {
+            let line = try!(line);
+            if line.is_empty() {
+                continue;
+            }
+            len += 1;
+            match serde_json::from_str(&line) {
+                Ok(entry) => {
+                    entries.push_back(entry);
+                    if entries.len() > capacity {
+                        entries.pop_front();
+                    }
+                }
+                Err(err) => warn!(target: "Taxonomy-audit", "Skipping invalid audit entry {:?}: {:?}", line, err)
+            }
+        }
+        Ok(len)
+    }
+
+    /// Record an operation, performed now.
+    pub fn record(&self, principal: Principal, operation: Operation, results: Vec<AuditResult>) {
+        let entry = AuditEntry {
+            timestamp: TimeStamp::from_datetime(UTC::now()),
+            principal: principal,
+            operation: operation,
+            results: results,
+        };
+        if let Some(ref file) = self.file {
+            match serde_json::to_string(&entry) {
+                Ok(line) => {
+                    let mut file = file.lock().unwrap();
+                    let written = writeln!(file.file, "{}", line).and_then(|_| file.file.flush());
+                    match written {
+                        Ok(()) => {
+                            file.len += 1;
+                            if file.len >= self.capacity {
+                                if let Err(err) = file.rotate() {
+                                    error!(target: "Taxonomy-audit", "Could not rotate audit log: {:?}", err);
+                                }
+                            }
+                        }
+                        Err(err) => error!(target: "Taxonomy-audit", "Could not write audit entry: {:?}", err)
+                    }
+                }
+                Err(err) => error!(target: "Taxonomy-audit", "Could not serialize audit entry: {:?}", err)
+            }
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry);
+        if entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// The maximal number of entries kept in memory.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The entries kept in memory, from the oldest to the most recent.
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The entries kept in memory matching a query, from the oldest to the most recent.
+    ///
+    /// If `targets` is specified, only return the entries that have at least one target in
+    /// `targets`. Selectors of `query` are ignored, as they need to be resolved by the caller.
+    pub fn query(&self, query: &AuditQuery, targets: Option<&HashSet<AuditTarget>>) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().iter().filter(|entry| {
+            if let Some(ref since) = query.since {
+                if entry.timestamp < *since {
+                    return false;
+                }
+            }
+            if let Some(ref until) = query.until {
+                if entry.timestamp >= *until {
+                    return false;
+                }
+            }
+            if let Some(user) = query.user {
+                if entry.principal.user != Some(user) {
+                    return false;
+                }
+            }
+            if let Some(targets) = targets {
+                if !entry.results.iter().any(|result| targets.contains(&result.target)) {
+                    return false;
+                }
+            }
+            true
+        }).cloned().collect()
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
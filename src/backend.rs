@@ -3,18 +3,25 @@
 use adapter::{ Adapter, AdapterWatchGuard, ResultMap, WatchEvent as AdapterWatchEvent };
 use transact::InsertInMap;
 
-use api::{ Error, InternalError, TargetMap, Targetted, WatchEvent };
+use api::{ Error, InternalError, Principal, TargetMap, Targetted, WatchEvent };
+use leases::{ Lease, LeaseInfo };
+use policy::Permission;
+use util::{ JsonFile, LeaseId };
+use locations::*;
+use profiles::*;
 use selector::*;
 use services::*;
 use values::*;
 
+use chrono::UTC;
 use sublock::atomlock::*;
 use transformable_channels::mpsc::*;
 
 use std::collections::{ HashMap, HashSet };
-use std::collections::hash_map::Entry;
-use std::hash::{ Hash, Hasher };
+use std::collections::hash_map::{ Entry, RandomState };
+use std::hash::{ BuildHasher, Hash, Hasher };
 use std::ops::{ Deref };
+use std::path::Path;
 use std::sync::{ Arc, Mutex, Weak };
 use std::sync::atomic::{ AtomicBool, Ordering };
 
@@ -36,14 +43,19 @@ macro_rules! log_debug_assert {
 /// been extracted from the maps while they were locked for use after unlocking.
 pub type AdapterRequest<T> = HashMap<Id<AdapterId>, (Arc<Adapter>, T)>;
 
-/// A request to an adapter, for performing a `fetch` operation.
-pub type FetchRequest = AdapterRequest<HashMap<Id<Getter>, Type>>;
+/// A request to an adapter, for performing a `fetch` operation. Getters that cannot be
+/// fetched are rejected immediately.
+pub type FetchRequest = AdapterRequest<(HashMap<Id<Getter>, Type>, ResultMap<Id<Getter>, Option<Value>, Error>)>;
 
 /// A request to an adapter, for performing a `send` operation.
 pub type SendRequest = AdapterRequest<(HashMap<Id<Setter>, Value>, ResultMap<Id<Setter>, (), Error>)>;
 
+/// A request to an adapter, for performing an `invoke` operation. For each action, we also
+/// keep the type of the expected result.
+pub type InvokeRequest = AdapterRequest<(HashMap<Id<Action>, Value>, HashMap<Id<Action>, Type>, ResultMap<Id<Action>, Value, Error>)>;
+
 /// A request to an adapter, for performing a `watch` operation.
-pub type WatchRequest = AdapterRequest<Vec<(Id<Getter>, Option<Range>, Weak<WatcherData>)>>;
+pub type WatchRequest = AdapterRequest<Vec<(Id<Getter>, Option<Range>, Delivery, Weak<WatcherData>)>>;
 
 pub type WatchGuardCommit = Vec<(Weak<WatcherData>, Vec<(Id<Getter>, Box<AdapterWatchGuard>)>)>;
 
@@ -54,18 +66,36 @@ struct ServiceData {
     /// The tags, as in a Service.
     tags: Arc<SubCell<HashSet<Id<TagId>>>>,
 
+    /// The origin of tags, as in a Service.
+    tag_origins: HashMap<Id<TagId>, TagOrigin>,
+
     /// The id, as in a `Service`.
     id: Id<ServiceId>,
 
     /// Creation time properties.
     properties: HashMap<String, String>,
 
+    /// Properties set by the user.
+    user_properties: HashMap<String, String>,
+
+    /// The location, as in `Service`.
+    location: Option<Id<LocationId>>,
+
+    /// The parent service, as in `Service`.
+    parent: Option<Id<ServiceId>>,
+
+    /// Related services, as in `Service`.
+    related: HashSet<Id<ServiceId>>,
+
     /// Information on the getters. Used to build field `getters` of service.
     getters: HashMap<Id<Getter>, Arc<SubCell<GetterData>>>,
 
-    /// Information on the setters. Used to build field `getters` of service.
+    /// Information on the setters. Used to build field `setters` of service.
     setters: HashMap<Id<Setter>, Arc<SubCell<SetterData>>>,
 
+    /// Information on the actions. Used to build field `actions` of service.
+    actions: HashMap<Id<Action>, Arc<SubCell<ActionData>>>,
+
     /// The adapter, as in `Service`.
     adapter: Id<AdapterId>,
 }
@@ -78,36 +108,61 @@ impl ServiceData {
     fn new(liveness: &Arc<Liveness>, service: Service) -> Self {
         ServiceData {
             tags: Arc::new(SubCell::new(liveness, service.tags)),
+            tag_origins: service.tag_origins,
             id: service.id,
             adapter: service.adapter,
             properties: service.properties,
+            user_properties: service.user_properties,
+            location: service.location,
+            parent: service.parent,
+            related: service.related,
             getters: HashMap::new(),
             setters: HashMap::new(),
+            actions: HashMap::new(),
         }
     }
     fn as_service(&self) -> Service {
         Service {
             tags: self.tags.borrow().clone(),
+            tag_origins: self.tag_origins.clone(),
             id: self.id.clone(),
             properties: self.properties.clone(),
+            user_properties: self.user_properties.clone(),
+            location: self.location.clone(),
+            location_ancestors: vec![],
+            parent: self.parent.clone(),
+            related: self.related.clone(),
             adapter: self.adapter.clone(),
             getters: self.getters.iter().map(|(key, value)| {
                 (key.clone(), (**value).borrow().channel.clone())
             }).collect(),
             setters: self.setters.iter().map(|(key, value)| {
                 (key.clone(), (**value).borrow().channel.clone())
-            }).collect()
+            }).collect(),
+            actions: self.actions.iter().map(|(key, value)| {
+                (key.clone(), (**value).borrow().channel.clone())
+            }).collect(),
+            profiles: HashSet::new(),
         }
     }
 }
+impl Tagged for ServiceData {
+    fn tags_mut(&mut self) -> (&mut HashSet<Id<TagId>>, &mut HashMap<Id<TagId>, TagOrigin>) {
+        (self.tags.borrow_mut(), &mut self.tag_origins)
+    }
+}
 
 struct ServiceView<'a> where 'a {
     data: &'a ServiceData,
+    locations: &'a LocationTree,
+    profiles: &'a ProfileRegistry,
 }
 impl<'a> ServiceView<'a> {
-    fn new(data: &'a ServiceData) -> Self {
+    fn new(data: &'a ServiceData, locations: &'a LocationTree, profiles: &'a ProfileRegistry) -> Self {
         ServiceView {
             data: data,
+            locations: locations,
+            profiles: profiles,
         }
     }
 }
@@ -121,6 +176,21 @@ impl<'a> ServiceLike for ServiceView<'a> {
     fn with_tags<F>(&self, f: F) -> bool where F: Fn(&HashSet<Id<TagId>>) -> bool {
         f(&*self.data.tags.borrow())
     }
+    fn user_properties(&self) -> &HashMap<String, String> {
+        &self.data.user_properties
+    }
+    fn is_in_location(&self, location: &Id<LocationId>) -> bool {
+        match self.data.location {
+            None => false,
+            Some(ref id) => self.locations.is_within(id, location)
+        }
+    }
+    fn parent(&self) -> Option<&Id<ServiceId>> {
+        self.data.parent.as_ref()
+    }
+    fn has_profile(&self, profile: &Id<ProfileId>) -> bool {
+        self.profiles.is_satisfied_by(profile, self)
+    }
     fn has_getters<F>(&self, f: F) -> bool where F: Fn(&Channel<Getter>) -> bool {
         for chan in self.data.getters.values() {
             if f(&*chan.borrow()) {
@@ -163,30 +233,111 @@ impl Deref for AdapterData {
     }
 }
 
-trait Tagged {
-    fn insert_tags(&mut self, tags: &[Id<TagId>]) -> bool;
-    fn remove_tags(&mut self, tags: &[Id<TagId>]) -> bool;
+/// The user properties of services and channels, as stored on disk.
+#[derive(Serialize, Deserialize, Default)]
+struct StoredUserProperties {
+    services: HashMap<Id<ServiceId>, HashMap<String, String>>,
+    getters: HashMap<Id<Getter>, HashMap<String, String>>,
+    setters: HashMap<Id<Setter>, HashMap<String, String>>,
 }
 
-impl<T> Tagged for Channel<T> where T: IOMechanism {
-    fn insert_tags(&mut self, tags: &[Id<TagId>]) -> bool {
+/// The tags set on a service or channel through the API, and the tags set by the adapter
+/// but removed by the user. Kept separately from the service or channel so that they
+/// survive re-registration.
+#[derive(Clone, Default)]
+struct TagMemory {
+    /// Tags set through the API, with their origin. This includes tags set by the adapter,
+    /// then renamed by the user.
+    added: HashMap<Id<TagId>, TagOrigin>,
+
+    /// Tags set by the adapter, then removed by the user.
+    removed: HashSet<Id<TagId>>,
+}
+
+impl TagMemory {
+    /// Merge the tags provided by the adapter with the tags we remember.
+    fn apply(&self, tags: &mut HashSet<Id<TagId>>, origins: &mut HashMap<Id<TagId>, TagOrigin>) {
+        for tag in &self.removed {
+            if origins.get(tag) == Some(&TagOrigin::Adapter) {
+                tags.remove(tag);
+                origins.remove(tag);
+            }
+        }
+        for (tag, origin) in &self.added {
+            tags.insert(tag.clone());
+            origins.insert(tag.clone(), *origin);
+        }
+    }
+}
+
+trait Tagged {
+    fn tags_mut(&mut self) -> (&mut HashSet<Id<TagId>>, &mut HashMap<Id<TagId>, TagOrigin>);
+
+    fn has_tag(&mut self, tag: &Id<TagId>) -> bool {
+        self.tags_mut().0.contains(tag)
+    }
+
+    fn insert_tags(&mut self, tags: &[Id<TagId>], origin: TagOrigin, memory: &mut TagMemory) -> bool {
+        let (tag_set, origins) = self.tags_mut();
         let mut has_changed = false;
         for tag in tags {
-            if self.tags.insert(tag.clone()) {
+            if tag_set.insert(tag.clone()) {
                 has_changed = true;
             }
+            origins.insert(tag.clone(), origin);
+            memory.removed.remove(tag);
+            memory.added.insert(tag.clone(), origin);
         }
         has_changed
     }
-    fn remove_tags(&mut self, tags: &[Id<TagId>]) -> bool {
+
+    fn remove_tags(&mut self, tags: &[Id<TagId>], memory: &mut TagMemory) -> bool {
+        let (tag_set, origins) = self.tags_mut();
         let mut has_changed = false;
         for tag in tags {
-            if self.tags.remove(&tag) {
+            if tag_set.remove(&tag) {
                 has_changed = true;
+                if let Some(TagOrigin::Adapter) = origins.remove(tag) {
+                    // Make sure that the adapter doesn't restore the tag.
+                    memory.removed.insert(tag.clone());
+                }
             }
+            memory.added.remove(tag);
         }
         has_changed
     }
+
+    fn remove_tags_by_origin(&mut self, origin: TagOrigin, memory: &mut TagMemory) -> bool {
+        let tags : Vec<_> = {
+            let (tag_set, origins) = self.tags_mut();
+            tag_set.iter()
+                .filter(|tag| *origins.get(tag).unwrap_or(&TagOrigin::Adapter) == origin)
+                .cloned()
+                .collect()
+        };
+        self.remove_tags(&tags, memory)
+    }
+
+    /// Rename a tag, keeping its origin. Renaming a tag set by the adapter is remembered,
+    /// so that the adapter doesn't restore the old tag.
+    fn rename_tag(&mut self, from: &Id<TagId>, to: &Id<TagId>, memory: &mut TagMemory) -> bool {
+        let origin = {
+            let (tag_set, origins) = self.tags_mut();
+            if !tag_set.contains(from) {
+                return false;
+            }
+            *origins.get(from).unwrap_or(&TagOrigin::Adapter)
+        };
+        self.remove_tags(&[from.clone()], memory);
+        self.insert_tags(&[to.clone()], origin, memory);
+        true
+    }
+}
+
+impl<T> Tagged for Channel<T> where T: IOMechanism {
+    fn tags_mut(&mut self) -> (&mut HashSet<Id<TagId>>, &mut HashMap<Id<TagId>, TagOrigin>) {
+        (&mut self.tags, &mut self.tag_origins)
+    }
 }
 
 /// A key used to uniquely represent a watcher.
@@ -227,11 +378,8 @@ impl Deref for GetterData {
     }
 }
 impl Tagged for GetterData {
-    fn insert_tags(&mut self, tags: &[Id<TagId>]) -> bool {
-        self.channel.insert_tags(tags)
-    }
-    fn remove_tags(&mut self, tags: &[Id<TagId>]) -> bool {
-        self.channel.remove_tags(tags)
+    fn tags_mut(&mut self) -> (&mut HashSet<Id<TagId>>, &mut HashMap<Id<TagId>, TagOrigin>) {
+        self.channel.tags_mut()
     }
 }
 
@@ -256,11 +404,8 @@ impl SetterData {
     }
 }
 impl Tagged for SetterData {
-    fn insert_tags(&mut self, tags: &[Id<TagId>]) -> bool {
-        self.channel.insert_tags(tags)
-    }
-    fn remove_tags(&mut self, tags: &[Id<TagId>]) -> bool {
-        self.channel.remove_tags(tags)
+    fn tags_mut(&mut self) -> (&mut HashSet<Id<TagId>>, &mut HashMap<Id<TagId>, TagOrigin>) {
+        self.channel.tags_mut()
     }
 }
 impl Deref for SetterData {
@@ -270,6 +415,33 @@ impl Deref for SetterData {
     }
 }
 
+/// Data and metadata on an action.
+struct ActionData {
+    channel: Channel<Action>,
+    service_tags: Arc<SubCell<HashSet<Id<TagId>>>>,
+}
+
+impl SelectedBy<ActionSelector> for ActionData {
+    fn matches(&self, selector: &ActionSelector) -> bool {
+        selector.matches(&*self.service_tags.borrow(), &self.channel)
+    }
+}
+
+impl ActionData {
+    fn new(channel: Channel<Action>, service_tags: Arc<SubCell<HashSet<Id<TagId>>>>) -> Self {
+        ActionData {
+            channel: channel,
+            service_tags: service_tags.clone(),
+        }
+    }
+}
+impl Deref for ActionData {
+    type Target = Channel<Action>;
+    fn deref(&self) -> &Self::Target {
+        &self.channel
+    }
+}
+
 /// All the information on a currently registered watch.
 ///
 /// A single watch may concern any number of getter channels, including channels not registered
@@ -372,6 +544,56 @@ pub struct State {
     /// Setters, indexed by their id
     setter_by_id: HashMap<Id<Setter>, Arc<SubCell<SetterData>>>,
 
+    /// Actions, indexed by their id
+    action_by_id: HashMap<Id<Action>, Arc<SubCell<ActionData>>>,
+
+    /// User properties of services, indexed by the id of the service. Kept separately
+    /// so that they survive a service being removed and registered again.
+    service_user_properties: HashMap<Id<ServiceId>, HashMap<String, String>>,
+
+    /// User properties of getters, indexed by the id of the getter.
+    getter_user_properties: HashMap<Id<Getter>, HashMap<String, String>>,
+
+    /// User properties of setters, indexed by the id of the setter.
+    setter_user_properties: HashMap<Id<Setter>, HashMap<String, String>>,
+
+    /// If specified, the file in which user properties are persisted.
+    user_properties_file: Option<JsonFile>,
+
+    /// Metadata on tags, indexed by tag.
+    tag_metadata: HashMap<Id<TagId>, TagMetadata>,
+
+    /// Tags set by users and applications on services, or set by adapters and removed
+    /// by users, indexed by the id of the service. Kept separately so that they survive
+    /// a service being removed and registered again.
+    service_tag_memory: HashMap<Id<ServiceId>, TagMemory>,
+
+    /// As `service_tag_memory`, for getters.
+    getter_tag_memory: HashMap<Id<Getter>, TagMemory>,
+
+    /// As `service_tag_memory`, for setters.
+    setter_tag_memory: HashMap<Id<Setter>, TagMemory>,
+
+    /// The tree of locations in which services may be placed.
+    locations: LocationTree,
+
+    /// The profiles used to classify services.
+    profiles: ProfileRegistry,
+
+    /// The location of services, indexed by the id of the service. Kept separately
+    /// so that they survive a service being removed and registered again.
+    service_locations: HashMap<Id<ServiceId>, Id<LocationId>>,
+
+    /// The timezone of the home.
+    home_timezone: HomeTimeZone,
+
+    /// Leases on setters, indexed by the id of the setter. Kept separately so that they
+    /// survive a setter being removed and registered again.
+    setter_leases: HashMap<Id<Setter>, Lease>,
+
+    /// A counter of all leases acquired. Used to generate unique ids.
+    lease_counter: usize,
+
     /// The set of watchers registered. Used both when we add/remove channels
     /// and a when a new value is available from a getter channel.
     watchers: Arc<Mutex<WatchMap>>,
@@ -398,9 +620,123 @@ impl State {
         for id in service.borrow().setters.keys() {
             let _ignored = self.setter_by_id.remove(id);
         }
+        for id in service.borrow().actions.keys() {
+            let _ignored = self.action_by_id.remove(id);
+        }
+        // Drop links to this service.
+        for other in self.service_by_id.values() {
+            other.borrow_mut().related.remove(id);
+        }
         Ok(adapter)
     }
 
+    /// Auxiliary function to restore the user properties previously set on a service or channel
+    /// with the same id, e.g. when an adapter registers it again after a reboot.
+    fn aux_restore_user_properties<K>(stash: &HashMap<Id<K>, HashMap<String, String>>, id: &Id<K>,
+        properties: &mut HashMap<String, String>)
+    {
+        if let Some(stored) = stash.get(id) {
+            *properties = stored.clone();
+        }
+    }
+
+    /// Auxiliary function to write the user properties to their file, if any. Failures are
+    /// logged, as the properties remain in memory.
+    fn aux_save_user_properties(&self) {
+        if let Some(ref file) = self.user_properties_file {
+            let stored = StoredUserProperties {
+                services: self.service_user_properties.clone(),
+                getters: self.getter_user_properties.clone(),
+                setters: self.setter_user_properties.clone(),
+            };
+            if let Err(err) = file.save(&stored) {
+                warn!(target: "Taxonomy-backend", "Could not save user properties: {}", err);
+            }
+        }
+    }
+
+    /// Auxiliary function to merge the tags provided by an adapter for a service or channel
+    /// with the tags previously set or removed by users and applications.
+    fn aux_restore_tags<K>(memory: &HashMap<Id<K>, TagMemory>, id: &Id<K>,
+        tags: &mut HashSet<Id<TagId>>, origins: &mut HashMap<Id<TagId>, TagOrigin>)
+    {
+        // Tags provided without an origin have been set by the adapter.
+        for tag in tags.iter() {
+            origins.entry(tag.clone()).or_insert(TagOrigin::Adapter);
+        }
+        if let Some(memory) = memory.get(id) {
+            memory.apply(tags, origins);
+        }
+    }
+
+    /// Auxiliary function to carry over to an update of a service or channel the tags that
+    /// have not been set by the adapter, so that an adapter can't overwrite them.
+    fn aux_keep_tags(old_tags: &HashSet<Id<TagId>>, old_origins: &HashMap<Id<TagId>, TagOrigin>,
+        tags: &mut HashSet<Id<TagId>>, origins: &mut HashMap<Id<TagId>, TagOrigin>)
+    {
+        for tag in old_tags {
+            match old_origins.get(tag) {
+                None | Some(&TagOrigin::Adapter) => continue,
+                Some(origin) => {
+                    tags.insert(tag.clone());
+                    origins.insert(tag.clone(), *origin);
+                }
+            }
+        }
+    }
+
+    /// Auxiliary function to find the lease, if any, that prevents `user` from sending
+    /// values to a setter at a given date.
+    fn aux_blocking_lease(leases: &HashMap<Id<Setter>, Lease>, id: &Id<Setter>, user: &Principal, now: &TimeStamp) -> Option<LeaseInfo> {
+        match leases.get(id) {
+            Some(lease) if lease.is_active_at(now) && !lease.is_held_by(user) => Some(lease.info()),
+            _ => None
+        }
+    }
+
+    /// Auxiliary function to hide the lease of a setter if it has expired, even though it
+    /// has not been forgotten yet.
+    fn aux_hide_expired_lease(setter: &mut Channel<Setter>, now: &TimeStamp) {
+        let is_expired = match setter.mechanism.lease {
+            Some(ref lease) => !lease.is_active_at(now),
+            None => false
+        };
+        if is_expired {
+            setter.mechanism.lease = None;
+        }
+    }
+
+    /// Auxiliary function to check that the parent of a service, if any, is registered
+    /// by the same adapter.
+    fn aux_check_parent(&self, service: &Service) -> Result<(), Error> {
+        if let Some(ref parent) = service.parent {
+            match self.service_by_id.get(parent) {
+                None => return Err(Error::InternalError(InternalError::NoSuchService(parent.clone()))),
+                Some(parent) => {
+                    let parent = parent.borrow();
+                    if parent.adapter != service.adapter {
+                        return Err(Error::InternalError(InternalError::ConflictingAdapter(parent.adapter.clone(), service.adapter.clone())));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Auxiliary function to check that the services related to a service are registered
+    /// and distinct from the service itself.
+    fn aux_check_related(&self, service: &Service) -> Result<(), Error> {
+        for related in &service.related {
+            if *related == service.id {
+                return Err(Error::InternalError(InternalError::RelatedToSelf(related.clone())));
+            }
+            if !self.service_by_id.contains_key(related) {
+                return Err(Error::InternalError(InternalError::NoSuchService(related.clone())));
+            }
+        }
+        Ok(())
+    }
+
     fn with_services<F>(&self, selectors: Vec<ServiceSelector>, mut cb: F) where F: FnMut(&Arc<SubCell<ServiceData>>) {
         for service in self.service_by_id.values() {
             // All services match when we have no selectors.
@@ -412,7 +748,7 @@ impl State {
             {
                 // Ensure that we release the borrow before calling `cb`.
                 let borrow = &*service.borrow();
-                let view = ServiceView::new(borrow);
+                let view = ServiceView::new(borrow, &self.locations, &self.profiles);
                 matches = selectors.iter().any(|selector| {
                     selector.matches(&view)
                 });
@@ -516,6 +852,7 @@ impl State {
     fn aux_getters_may_need_registration(&mut self, getters: Vec<Id<Getter>>) -> WatchRequest {
         debug!(target: "Taxonomy-backend", "checking if getters need to be watched {:?}", getters);
         let adapter_by_id = &self.adapter_by_id;
+        let service_by_id = &self.service_by_id;
         let mut per_adapter = HashMap::new();
         for id in getters {
             match self.getter_by_id.get_mut(&id) {
@@ -536,6 +873,11 @@ impl State {
                             // The guard has been dropped, we don't care anymore.
                             continue;
                         }
+                        if getter_data.watchers.contains_key(&watcher.key) {
+                            // The watcher is already watching this getter, e.g. the getter
+                            // has been updated without losing its watchers.
+                            continue;
+                        }
                         for targetted in &watcher.watch {
                             let matches = targetted.select.iter().any(|selector| {
                                 getter_data.matches(selector)
@@ -551,7 +893,7 @@ impl State {
 
                             // Register to be informed of future changes.
                             Self::aux_start_channel_watch(&mut watcher.clone(),
-                                &mut *getter_data, &targetted.payload, adapter_by_id, &mut per_adapter)
+                                &mut *getter_data, &targetted.payload, adapter_by_id, service_by_id, &mut per_adapter)
                         }
                     }
                 }
@@ -586,6 +928,21 @@ impl State {
             service_by_id: HashMap::new(),
             getter_by_id: HashMap::new(),
             setter_by_id: HashMap::new(),
+            action_by_id: HashMap::new(),
+            service_user_properties: HashMap::new(),
+            getter_user_properties: HashMap::new(),
+            setter_user_properties: HashMap::new(),
+            user_properties_file: None,
+            tag_metadata: HashMap::new(),
+            service_tag_memory: HashMap::new(),
+            getter_tag_memory: HashMap::new(),
+            setter_tag_memory: HashMap::new(),
+            locations: LocationTree::new(),
+            profiles: ProfileRegistry::new(),
+            service_locations: HashMap::new(),
+            home_timezone: HomeTimeZone::default(),
+            setter_leases: HashMap::new(),
+            lease_counter: 0,
             watchers: Arc::new(Mutex::new(WatchMap::new(liveness))),
        }
     }
@@ -640,12 +997,21 @@ impl State {
     /// Returns an error if any of:
     /// - `service` has channels;
     /// - a service with id `service.id` is already installed on the system;
-    /// - there is no adapter with id `service.adapter`.
-    pub fn add_service(&mut self, service: Service) -> Result<(), Error> {
+    /// - there is no adapter with id `service.adapter`;
+    /// - `service.parent` is not installed on the system or belongs to another adapter;
+    /// - a service of `service.related` is not installed on the system or is `service` itself.
+    pub fn add_service(&mut self, mut service: Service) -> Result<(), Error> {
         // Make sure that there are no channels.
-        if !service.getters.is_empty() || !service.setters.is_empty() {
+        if !service.getters.is_empty() || !service.setters.is_empty() || !service.actions.is_empty() {
             return Err(Error::InternalError(InternalError::InvalidInitialService));
         }
+        try!(self.aux_check_parent(&service));
+        try!(self.aux_check_related(&service));
+        Self::aux_restore_user_properties(&self.service_user_properties, &service.id, &mut service.user_properties);
+        Self::aux_restore_tags(&self.service_tag_memory, &service.id, &mut service.tags, &mut service.tag_origins);
+        if let Some(location) = self.service_locations.get(&service.id) {
+            service.location = Some(location.clone());
+        }
         let service = ServiceData::new(&self.liveness, service);
         let mut services_for_this_adapter =
             match self.adapter_by_id.get_mut(&service.adapter) {
@@ -674,9 +1040,189 @@ impl State {
         Ok(())
     }
 
+    /// Add a service to the system, along with all its channels, in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the adapter is not registered, if the service or any of its
+    /// channels is already registered, if any of the channels doesn't belong to
+    /// this service/adapter, or if the parent or related services are invalid, as in
+    /// `add_service`. In either case, this method reverts all its changes.
+    pub fn add_service_with_channels(&mut self, mut service: Service) -> Result<WatchRequest, Error> {
+        let getters : Vec<_> = service.getters.drain().map(|(_, channel)| channel).collect();
+        let setters : Vec<_> = service.setters.drain().map(|(_, channel)| channel).collect();
+        let actions : Vec<_> = service.actions.drain().map(|(_, channel)| channel).collect();
+
+        // Make sure that all channels belong to this service.
+        for getter in &getters {
+            if getter.service != service.id {
+                return Err(Error::InternalError(InternalError::ConflictingService(service.id.clone(), getter.service.clone())));
+            }
+            if getter.adapter != service.adapter {
+                return Err(Error::InternalError(InternalError::ConflictingAdapter(service.adapter.clone(), getter.adapter.clone())));
+            }
+        }
+        for setter in &setters {
+            if setter.service != service.id {
+                return Err(Error::InternalError(InternalError::ConflictingService(service.id.clone(), setter.service.clone())));
+            }
+            if setter.adapter != service.adapter {
+                return Err(Error::InternalError(InternalError::ConflictingAdapter(service.adapter.clone(), setter.adapter.clone())));
+            }
+        }
+        for action in &actions {
+            if action.service != service.id {
+                return Err(Error::InternalError(InternalError::ConflictingService(service.id.clone(), action.service.clone())));
+            }
+            if action.adapter != service.adapter {
+                return Err(Error::InternalError(InternalError::ConflictingAdapter(service.adapter.clone(), action.adapter.clone())));
+            }
+        }
+
+        try!(self.aux_check_parent(&service));
+        try!(self.aux_check_related(&service));
+
+        Self::aux_restore_user_properties(&self.service_user_properties, &service.id, &mut service.user_properties);
+        Self::aux_restore_tags(&self.service_tag_memory, &service.id, &mut service.tags, &mut service.tag_origins);
+        if let Some(location) = self.service_locations.get(&service.id) {
+            service.location = Some(location.clone());
+        }
+        let mut service = ServiceData::new(&self.liveness, service);
+        let getter_ids : Vec<_> = getters.iter().map(|getter| getter.id.clone()).collect();
+        let mut getters_data = Vec::with_capacity(getters.len());
+        for mut getter in getters {
+            let id = getter.id.clone();
+            Self::aux_restore_user_properties(&self.getter_user_properties, &id, &mut getter.user_properties);
+            Self::aux_restore_tags(&self.getter_tag_memory, &id, &mut getter.tags, &mut getter.tag_origins);
+            let getter_data = Arc::new(SubCell::new(&self.liveness, GetterData::new(getter, service.tags.clone())));
+            if service.getters.insert(id.clone(), getter_data.clone()).is_some() {
+                return Err(Error::InternalError(InternalError::DuplicateGetter(id)));
+            }
+            getters_data.push((id, getter_data));
+        }
+        let mut setters_data = Vec::with_capacity(setters.len());
+        for mut setter in setters {
+            let id = setter.id.clone();
+            Self::aux_restore_user_properties(&self.setter_user_properties, &id, &mut setter.user_properties);
+            setter.mechanism.lease = self.setter_leases.get(&id).map(Lease::info);
+            Self::aux_restore_tags(&self.setter_tag_memory, &id, &mut setter.tags, &mut setter.tag_origins);
+            let setter_data = Arc::new(SubCell::new(&self.liveness, SetterData::new(setter, service.tags.clone())));
+            if service.setters.insert(id.clone(), setter_data.clone()).is_some() {
+                return Err(Error::InternalError(InternalError::DuplicateSetter(id)));
+            }
+            setters_data.push((id, setter_data));
+        }
+        let mut actions_data = Vec::with_capacity(actions.len());
+        for action in actions {
+            let id = action.id.clone();
+            let action_data = Arc::new(SubCell::new(&self.liveness, ActionData::new(action, service.tags.clone())));
+            if service.actions.insert(id.clone(), action_data.clone()).is_some() {
+                return Err(Error::InternalError(InternalError::DuplicateAction(id)));
+            }
+            actions_data.push((id, action_data));
+        }
+
+        {
+            let services_for_this_adapter =
+                match self.adapter_by_id.get_mut(&service.adapter) {
+                    None => return Err(Error::InternalError(InternalError::NoSuchAdapter(service.adapter.clone()))),
+                    Some(&mut AdapterData {ref mut services, ..}) => {
+                        services
+                    }
+                };
+            let id = service.id.clone();
+            let service = Arc::new(SubCell::new(&self.liveness, service));
+            let insert_in_adapters =
+                match InsertInMap::start(services_for_this_adapter, vec![(id.clone(), service.clone())]) {
+                    Err(k) => return Err(Error::InternalError(InternalError::DuplicateService(k))),
+                    Ok(transaction) => transaction
+                };
+            let insert_in_services =
+                match InsertInMap::start(&mut self.service_by_id, vec![(id, service)]) {
+                    Err(k) => return Err(Error::InternalError(InternalError::DuplicateService(k))),
+                    Ok(transaction) => transaction
+                };
+            let insert_in_getters =
+                match InsertInMap::start(&mut self.getter_by_id, getters_data) {
+                    Err(k) => return Err(Error::InternalError(InternalError::DuplicateGetter(k))),
+                    Ok(transaction) => transaction
+                };
+            let insert_in_setters =
+                match InsertInMap::start(&mut self.setter_by_id, setters_data) {
+                    Err(k) => return Err(Error::InternalError(InternalError::DuplicateSetter(k))),
+                    Ok(transaction) => transaction
+                };
+            let insert_in_actions =
+                match InsertInMap::start(&mut self.action_by_id, actions_data) {
+                    Err(k) => return Err(Error::InternalError(InternalError::DuplicateAction(k))),
+                    Ok(transaction) => transaction
+                };
+
+            // If we haven't bailed out yet, leave all this stuff in the maps and sets.
+            insert_in_adapters.commit();
+            insert_in_services.commit();
+            insert_in_getters.commit();
+            insert_in_setters.commit();
+            insert_in_actions.commit();
+        }
+
+        Ok(self.aux_getters_may_need_registration(getter_ids))
+    }
+
+    /// Update the tags, properties and related services of a service previously registered
+    /// on the system.
+    ///
+    /// Channels, user properties, location and parent are left untouched, and any `getters`,
+    /// `setters`, `user_properties`, `location` or `parent` in `service` are ignored. Tags
+    /// set by users and applications are merged with the tags of `service`. Watchers
+    /// of the channels of this service are preserved, unless the new tags of the
+    /// service cause channels to stop matching them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the service is not registered, if `service.adapter` doesn't
+    /// match the adapter of the service, or if a service of `service.related` is not
+    /// registered or is the service itself.
+    pub fn update_service(&mut self, mut service: Service) -> Result<WatchRequest, Error> {
+        try!(self.aux_check_related(&service));
+        Self::aux_restore_tags(&self.service_tag_memory, &service.id, &mut service.tags, &mut service.tag_origins);
+        let getters = {
+            let service_data = match self.service_by_id.get(&service.id) {
+                None => return Err(Error::InternalError(InternalError::NoSuchService(service.id.clone()))),
+                Some(service_data) => service_data
+            };
+            let service_data = &mut *service_data.borrow_mut();
+            if service_data.adapter != service.adapter {
+                return Err(Error::InternalError(InternalError::ConflictingAdapter(service_data.adapter.clone(), service.adapter.clone())));
+            }
+            Self::aux_keep_tags(&*service_data.tags.borrow(), &service_data.tag_origins, &mut service.tags, &mut service.tag_origins);
+            service_data.properties = service.properties;
+            service_data.related = service.related;
+            service_data.tag_origins = service.tag_origins;
+            if *service_data.tags.borrow() == service.tags {
+                // Nothing else to do.
+                return Ok(HashMap::new());
+            }
+            *service_data.tags.borrow_mut() = service.tags;
+
+            // The tags of the service have changed, some of its getters may not match
+            // their watchers anymore.
+            for getter_data in service_data.getters.values() {
+                Self::aux_getter_may_need_unregistration(&mut *getter_data.borrow_mut(), false);
+            }
+            service_data.getters.keys().cloned().collect()
+        };
+
+        // ... and some of its getters may now match new watchers.
+        Ok(self.aux_getters_may_need_registration(getters))
+    }
+
     /// Remove a service previously registered on the system. Typically, called by
     /// an adapter when a service (e.g. a device) is disconnected.
     ///
+    /// The children of the service are removed along with it, and links from other
+    /// services to the removed services are dropped.
+    ///
     /// # Errors
     ///
     /// Returns an error if any of:
@@ -684,6 +1230,19 @@ impl State {
     /// - there is an internal inconsistency, in which case this method will still attempt to
     /// cleanup before returning an error.
     pub fn remove_service(&mut self, service_id: &Id<ServiceId>) -> Result<(), Error> {
+        // Children are removed along with their parent.
+        let children : Vec<_> = self.service_by_id.values().filter_map(|service| {
+            let service = service.borrow();
+            if service.parent.as_ref() == Some(service_id) {
+                Some(service.id.clone())
+            } else {
+                None
+            }
+        }).collect();
+        for child in children {
+            let _ignored = self.remove_service(&child);
+        }
+
         let adapter = try!(self.aux_remove_service(service_id));
         match self.adapter_by_id.get_mut(&adapter) {
             None => Err(Error::InternalError(InternalError::NoSuchAdapter(adapter.clone()))),
@@ -710,8 +1269,10 @@ impl State {
     /// Returns an error if the adapter is not registered, the parent service is not
     /// registered, or a channel with the same identifier is already registered.
     /// In either cases, this method reverts all its changes.
-    pub fn add_getter(&mut self, getter: Channel<Getter>) -> Result<WatchRequest, Error> {
+    pub fn add_getter(&mut self, mut getter: Channel<Getter>) -> Result<WatchRequest, Error> {
         let id = getter.id.clone();
+        Self::aux_restore_user_properties(&self.getter_user_properties, &id, &mut getter.user_properties);
+        Self::aux_restore_tags(&self.getter_tag_memory, &id, &mut getter.tags, &mut getter.tag_origins);
         {
             let getter_by_id = &mut self.getter_by_id;
             let service = match self.service_by_id.get_mut(&getter.service) {
@@ -744,6 +1305,49 @@ impl State {
 
 
 
+    /// Update a getter previously registered on the system, e.g. to change its kind or the
+    /// tags set by the adapter.
+    ///
+    /// Watchers that still match the getter are preserved. If the kind of the getter has
+    /// changed, its watchers are unregistered and registered anew, as the adapter may need
+    /// to watch the getter differently.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the getter is not registered or if `getter.service` or
+    /// `getter.adapter` don't match the current service or adapter of the getter.
+    pub fn update_getter(&mut self, mut getter: Channel<Getter>) -> Result<WatchRequest, Error> {
+        let id = getter.id.clone();
+        Self::aux_restore_tags(&self.getter_tag_memory, &id, &mut getter.tags, &mut getter.tag_origins);
+        {
+            let getter_data = match self.getter_by_id.get(&id) {
+                None => return Err(Error::InternalError(InternalError::NoSuchGetter(id))),
+                Some(getter_data) => getter_data
+            };
+            let getter_data = &mut *getter_data.borrow_mut();
+            if getter_data.channel.service != getter.service {
+                return Err(Error::InternalError(InternalError::ConflictingService(getter_data.channel.service.clone(), getter.service.clone())));
+            }
+            if getter_data.channel.adapter != getter.adapter {
+                return Err(Error::InternalError(InternalError::ConflictingAdapter(getter_data.channel.adapter.clone(), getter.adapter.clone())));
+            }
+            // Changing the delivery changes the way watches are registered, so we treat
+            // it as a change of kind.
+            let kind_changed = getter_data.channel.mechanism.kind != getter.mechanism.kind
+                || getter_data.channel.mechanism.delivery != getter.mechanism.delivery;
+            // User properties and tags belong to the user, not to the adapter.
+            getter.user_properties = getter_data.channel.user_properties.clone();
+            Self::aux_keep_tags(&getter_data.channel.tags, &getter_data.channel.tag_origins, &mut getter.tags, &mut getter.tag_origins);
+            getter_data.channel = getter;
+
+            // If the kind or delivery has changed, drop all watchers, they will be re-registered
+            // below if they still match. Otherwise, only drop watchers that don't
+            // match anymore.
+            Self::aux_getter_may_need_unregistration(getter_data, kind_changed);
+        }
+        Ok(self.aux_getters_may_need_registration(vec![id]))
+    }
+
     /// Remove a setter previously registered on the system. Typically, called by
     /// an adapter when a service is reconfigured to remove one of its setters.
     ///
@@ -776,6 +1380,9 @@ impl State {
     /// service has been detected/configured. Some services may gain/lose setters at
     /// runtime depending on their configuration.
     ///
+    /// If the setter is write-only, ongoing watches on the getters of the same kind of its
+    /// service stop receiving values, as in `aux_start_channel_watch`.
+    ///
     /// # Requirements
     ///
     /// The adapter is in charge of making sure that identifiers persist across reboots.
@@ -785,7 +1392,10 @@ impl State {
     /// Returns an error if the adapter is not registered, the parent service is not
     /// registered, or a channel with the same identifier is already registered.
     /// In either cases, this method reverts all its changes.
-    pub fn add_setter(&mut self, setter: Channel<Setter>) -> Result<(), Error> {
+    pub fn add_setter(&mut self, mut setter: Channel<Setter>) -> Result<(), Error> {
+        Self::aux_restore_user_properties(&self.setter_user_properties, &setter.id, &mut setter.user_properties);
+        setter.mechanism.lease = self.setter_leases.get(&setter.id).map(Lease::info);
+        Self::aux_restore_tags(&self.setter_tag_memory, &setter.id, &mut setter.tags, &mut setter.tag_origins);
         let service = match self.service_by_id.get_mut(&setter.service) {
             None => return Err(Error::InternalError(InternalError::NoSuchService(setter.service.clone()))),
             Some(service) => service
@@ -796,6 +1406,8 @@ impl State {
         }
 
         let id = setter.id.clone();
+        let is_write_only = setter.mechanism.write_only;
+        let kind = setter.mechanism.kind.clone();
         let setters = &mut service.setters;
         let setter_data = Arc::new(SubCell::new(&self.liveness, SetterData::new(setter, service.tags.clone())));
 
@@ -809,6 +1421,47 @@ impl State {
         };
         insert_in_service.commit();
         insert_in_setters.commit();
+        if is_write_only {
+            Self::aux_stop_write_only_watches(&service.getters, &kind);
+        }
+        Ok(())
+    }
+
+    /// Update a setter previously registered on the system, e.g. to change its kind or the
+    /// tags set by the adapter.
+    ///
+    /// If the setter becomes write-only, ongoing watches on the getters of the same kind
+    /// of its service stop receiving values, as in `aux_start_channel_watch`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the setter is not registered or if `setter.service` or
+    /// `setter.adapter` don't match the current service or adapter of the setter.
+    pub fn update_setter(&mut self, mut setter: Channel<Setter>) -> Result<(), Error> {
+        Self::aux_restore_tags(&self.setter_tag_memory, &setter.id, &mut setter.tags, &mut setter.tag_origins);
+        let setter_data = match self.setter_by_id.get(&setter.id) {
+            None => return Err(Error::InternalError(InternalError::NoSuchSetter(setter.id.clone()))),
+            Some(setter_data) => setter_data
+        };
+        let setter_data = &mut *setter_data.borrow_mut();
+        if setter_data.channel.service != setter.service {
+            return Err(Error::InternalError(InternalError::ConflictingService(setter_data.channel.service.clone(), setter.service.clone())));
+        }
+        if setter_data.channel.adapter != setter.adapter {
+            return Err(Error::InternalError(InternalError::ConflictingAdapter(setter_data.channel.adapter.clone(), setter.adapter.clone())));
+        }
+        // User properties, tags and leases belong to the user, not to the adapter.
+        setter.user_properties = setter_data.channel.user_properties.clone();
+        Self::aux_keep_tags(&setter_data.channel.tags, &setter_data.channel.tag_origins, &mut setter.tags, &mut setter.tag_origins);
+        setter.mechanism.lease = setter_data.channel.mechanism.lease.clone();
+        let is_write_only = setter.mechanism.write_only;
+        let kind = setter.mechanism.kind.clone();
+        setter_data.channel = setter;
+        if is_write_only {
+            if let Some(service) = self.service_by_id.get(&setter_data.channel.service) {
+                Self::aux_stop_write_only_watches(&service.borrow().getters, &kind);
+            }
+        }
         Ok(())
     }
 
@@ -839,40 +1492,135 @@ impl State {
         }
     }
 
+    /// Add an action to the system. Typically, this is called by the adapter when a new
+    /// service has been detected/configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parent service is not registered or belongs to another
+    /// adapter, or if an action with the same identifier is already registered.
+    /// In either cases, this method reverts all its changes.
+    pub fn add_action(&mut self, action: Channel<Action>) -> Result<(), Error> {
+        let service = match self.service_by_id.get_mut(&action.service) {
+            None => return Err(Error::InternalError(InternalError::NoSuchService(action.service.clone()))),
+            Some(service) => service
+        };
+        let mut service = &mut *service.borrow_mut();
+        if service.adapter != action.adapter {
+            return Err(Error::InternalError(InternalError::ConflictingAdapter(service.adapter.clone(), action.adapter)));
+        }
+
+        let id = action.id.clone();
+        let actions = &mut service.actions;
+        let action_data = Arc::new(SubCell::new(&self.liveness, ActionData::new(action, service.tags.clone())));
+
+        let insert_in_service = match InsertInMap::start(actions, vec![(id.clone(), action_data.clone())]) {
+            Ok(transaction) => transaction,
+            Err(id) => return Err(Error::InternalError(InternalError::DuplicateAction(id)))
+        };
+        let insert_in_actions = match InsertInMap::start(&mut self.action_by_id, vec![(id, action_data)]) {
+            Ok(transaction) => transaction,
+            Err(id) => return Err(Error::InternalError(InternalError::DuplicateAction(id)))
+        };
+        insert_in_service.commit();
+        insert_in_actions.commit();
+        Ok(())
+    }
+
+    /// Remove an action previously registered on the system.
+    ///
+    /// # Error
+    ///
+    /// This method returns an error if the action is not registered or if the service
+    /// is not registered. In either case, it attemps to clean as much as possible, even
+    /// if the state is inconsistent.
+    pub fn remove_action(&mut self, id: &Id<Action>) -> Result<(), Error> {
+        let action = match self.action_by_id.remove(id) {
+            None => return Err(Error::InternalError(InternalError::NoSuchAction(id.clone()))),
+            Some(action) => action
+        };
+
+        let service_id = &action.borrow().channel.service;
+        match self.service_by_id.get_mut(&service_id) {
+            None => Err(Error::InternalError(InternalError::NoSuchService(service_id.clone()))),
+            Some(service) => {
+                if service.borrow_mut().actions.remove(id).is_none() {
+                    Err(Error::InternalError(InternalError::NoSuchAction(id.clone())))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
     pub fn get_services(&self, selectors: Vec<ServiceSelector>) -> Vec<Service> {
         // This implementation is not nearly optimal, but it should be sufficient in a system
         // with relatively few services.
+        let now = TimeStamp::from_datetime(UTC::now());
         let mut result = Vec::new();
         self.with_services(selectors, |service| {
-            result.push(service.borrow().as_service())
+            let borrow = &*service.borrow();
+            let mut service = borrow.as_service();
+            // Profiles are not stored, as they depend on the channels of the service
+            // and on the profiles currently registered.
+            service.profiles = self.profiles.satisfied_by(&ServiceView::new(borrow, &self.locations, &self.profiles));
+            if let Some(ref location) = borrow.location {
+                service.location_ancestors = self.locations.ancestors(location);
+            }
+            for setter in service.setters.values_mut() {
+                Self::aux_hide_expired_lease(setter, &now);
+            }
+            result.push(service)
         });
         result
     }
 
-    pub fn add_service_tags(&mut self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        let mut result = 0;
+    /// Auxiliary function to collect the services matching some selectors, so that they
+    /// may be modified while other fields of `self` are borrowed.
+    fn aux_get_services(&self, selectors: Vec<ServiceSelector>) -> Vec<Arc<SubCell<ServiceData>>> {
+        let mut result = vec![];
         self.with_services(selectors, |service| {
-            let service = service.borrow_mut();
-            let mut tag_set = service.tags.borrow_mut();
-            for tag in &tags {
-                let _ = tag_set.insert(tag.clone());
-            }
-            result += 1;
+            result.push(service.clone());
         });
         result
     }
 
-    pub fn remove_service_tags(&mut self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        let mut result = 0;
-        self.with_services(selectors, |service| {
-            let service = service.borrow_mut();
-            let mut tag_set = service.tags.borrow_mut();
-            for tag in &tags {
-                let _ = tag_set.remove(&tag);
-            }
-            result += 1;
-        });
-        result
+    pub fn add_service_tags(&mut self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
+        self.add_service_tags_with_origin(selectors, tags, TagOrigin::User)
+    }
+
+    pub fn add_service_tags_with_origin(&mut self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin) -> usize {
+        let services = self.aux_get_services(selectors);
+        for service in &services {
+            let service = service.borrow_mut();
+            let memory = self.service_tag_memory.entry(service.id.clone()).or_insert_with(TagMemory::default);
+            service.insert_tags(&tags, origin, memory);
+        }
+        services.len()
+    }
+
+    pub fn remove_service_tags(&mut self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
+        let services = self.aux_get_services(selectors);
+        for service in &services {
+            let service = service.borrow_mut();
+            let memory = self.service_tag_memory.entry(service.id.clone()).or_insert_with(TagMemory::default);
+            service.remove_tags(&tags, memory);
+        }
+        services.len()
+    }
+
+    pub fn remove_service_tags_by_origin(&mut self, selectors: Vec<ServiceSelector>, origin: TagOrigin) -> usize {
+        let services = self.aux_get_services(selectors);
+        for service in &services {
+            let service = service.borrow_mut();
+            let memory = self.service_tag_memory.entry(service.id.clone()).or_insert_with(TagMemory::default);
+            service.remove_tags_by_origin(origin, memory);
+        }
+        services.len()
+    }
+
+    pub fn has_getter(&self, id: &Id<Getter>) -> bool {
+        self.getter_by_id.contains_key(id)
     }
 
     pub fn get_getter_channels(&self, selectors: Vec<GetterSelector>) -> Vec<Channel<Getter>>
@@ -881,19 +1629,63 @@ impl State {
     }
     pub fn get_setter_channels(&self, selectors: Vec<SetterSelector>) -> Vec<Channel<Setter>>
     {
-        Self::aux_get_channels(selectors, &self.setter_by_id)
+        let now = TimeStamp::from_datetime(UTC::now());
+        let mut result = Self::aux_get_channels(selectors, &self.setter_by_id);
+        for setter in &mut result {
+            Self::aux_hide_expired_lease(setter, &now);
+        }
+        result
+    }
+    pub fn get_action_channels(&self, selectors: Vec<ActionSelector>) -> Vec<Channel<Action>>
+    {
+        Self::aux_get_channels(selectors, &self.action_by_id)
+    }
+
+    pub fn explain_services(&self, selector: ServiceSelector) -> Vec<Explanation<ServiceId>> {
+        self.service_by_id.values().map(|service| {
+            let borrow = &*service.borrow();
+            let view = ServiceView::new(borrow, &self.locations, &self.profiles);
+            selector.explain(&view)
+        }).collect()
+    }
+
+    pub fn explain_getters(&self, selector: GetterSelector) -> Vec<Explanation<Getter>> {
+        self.getter_by_id.values().map(|data| {
+            let data = &*data.borrow();
+            selector.explain(&*data.service_tags.borrow(), &data.channel)
+        }).collect()
+    }
+
+    pub fn explain_setters(&self, selector: SetterSelector) -> Vec<Explanation<Setter>> {
+        self.setter_by_id.values().map(|data| {
+            let data = &*data.borrow();
+            selector.explain(&*data.service_tags.borrow(), &data.channel)
+        }).collect()
+    }
+
+    pub fn explain_actions(&self, selector: ActionSelector) -> Vec<Explanation<Action>> {
+        self.action_by_id.values().map(|data| {
+            let data = &*data.borrow();
+            selector.explain(&*data.service_tags.borrow(), &data.channel)
+        }).collect()
     }
 
     /// Add tags to a getter.
     /// As our in-memory representation stores the same getter both in the Service
     /// and in `self.getters`, we need to update both.
     pub fn add_getter_tags(&mut self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> (WatchRequest, usize) {
+        self.add_getter_tags_with_origin(selectors, tags, TagOrigin::User)
+    }
+
+    pub fn add_getter_tags_with_origin(&mut self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin) -> (WatchRequest, usize) {
         let mut size = 0;
         let mut channels = vec![];
         {
+            let memory = &mut self.getter_tag_memory;
             Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut data| {
+                let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
                 // This channel has changed, we may need to update watches.
-                if data.insert_tags(&tags) {
+                if data.insert_tags(&tags, origin, memory) {
                     channels.push(data.id.clone());
                 }
                 size += 1;
@@ -903,9 +1695,15 @@ impl State {
     }
 
     pub fn add_setter_tags(&mut self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize {
+        self.add_setter_tags_with_origin(selectors, tags, TagOrigin::User)
+    }
+
+    pub fn add_setter_tags_with_origin(&mut self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin) -> usize {
         let mut result = 0;
+        let memory = &mut self.setter_tag_memory;
         Self::with_channels_mut(selectors, &mut self.setter_by_id, |mut data| {
-            data.insert_tags(&tags);
+            let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
+            data.insert_tags(&tags, origin, memory);
             result += 1;
         });
         result
@@ -913,33 +1711,512 @@ impl State {
 
     pub fn remove_getter_tags(&mut self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> usize {
         let mut result = 0;
+        let memory = &mut self.getter_tag_memory;
         Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut data| {
-            data.remove_tags(&tags);
+            let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
+            data.remove_tags(&tags, memory);
             Self::aux_getter_may_need_unregistration(&mut data, false);
             result += 1;
         });
         result
     }
+
+    pub fn remove_getter_tags_by_origin(&mut self, selectors: Vec<GetterSelector>, origin: TagOrigin) -> usize {
+        let mut result = 0;
+        let memory = &mut self.getter_tag_memory;
+        Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut data| {
+            let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
+            data.remove_tags_by_origin(origin, memory);
+            Self::aux_getter_may_need_unregistration(&mut data, false);
+            result += 1;
+        });
+        result
+    }
+
     pub fn remove_setter_tags(&mut self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize {
         let mut result = 0;
+        let memory = &mut self.setter_tag_memory;
+        Self::with_channels_mut(selectors, &mut self.setter_by_id, |mut data| {
+            let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
+            data.remove_tags(&tags, memory);
+            result += 1;
+        });
+        result
+    }
+
+    pub fn remove_setter_tags_by_origin(&mut self, selectors: Vec<SetterSelector>, origin: TagOrigin) -> usize {
+        let mut result = 0;
+        let memory = &mut self.setter_tag_memory;
         Self::with_channels_mut(selectors, &mut self.setter_by_id, |mut data| {
-            data.remove_tags(&tags);
+            let memory = memory.entry(data.id.clone()).or_insert_with(TagMemory::default);
+            data.remove_tags_by_origin(origin, memory);
             result += 1;
         });
         result
     }
 
+    /// Auxiliary function to determine which of `tags` would actually be added to (if `add`)
+    /// or removed from (otherwise) a set of tags.
+    fn aux_preview_tags(current: &HashSet<Id<TagId>>, tags: &[Id<TagId>], add: bool) -> Vec<Id<TagId>> {
+        tags.iter()
+            .filter(|tag| current.contains(*tag) != add)
+            .cloned()
+            .collect()
+    }
+
+    /// Determine the services that `add_service_tags` (if `add`) or `remove_service_tags`
+    /// (otherwise) would affect, along with the tags that would actually change, without
+    /// changing anything.
+    pub fn preview_service_tags(&self, selectors: Vec<ServiceSelector>, tags: &[Id<TagId>], add: bool) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>> {
+        let mut result = HashMap::new();
+        self.with_services(selectors, |service| {
+            let service = service.borrow();
+            result.insert(service.id.clone(), Self::aux_preview_tags(&*service.tags.borrow(), tags, add));
+        });
+        result
+    }
+
+    /// As `preview_service_tags`, for getters.
+    pub fn preview_getter_tags(&self, selectors: Vec<GetterSelector>, tags: &[Id<TagId>], add: bool) -> HashMap<Id<Getter>, Vec<Id<TagId>>> {
+        let mut result = HashMap::new();
+        Self::with_channels(selectors, &self.getter_by_id, |data| {
+            result.insert(data.id.clone(), Self::aux_preview_tags(&data.channel.tags, tags, add));
+        });
+        result
+    }
+
+    /// As `preview_service_tags`, for setters.
+    pub fn preview_setter_tags(&self, selectors: Vec<SetterSelector>, tags: &[Id<TagId>], add: bool) -> HashMap<Id<Setter>, Vec<Id<TagId>>> {
+        let mut result = HashMap::new();
+        Self::with_channels(selectors, &self.setter_by_id, |data| {
+            result.insert(data.id.clone(), Self::aux_preview_tags(&data.channel.tags, tags, add));
+        });
+        result
+    }
+
+    /// Load the user properties stored in a file, then persist all changes to user
+    /// properties in this file. Stored properties are applied to services and channels
+    /// when they are registered, so this should be called before adding adapters.
+    pub fn open_user_properties<P>(&mut self, path: P) -> Result<(), Error> where P: AsRef<Path> {
+        let file = JsonFile::new(path);
+        if let Some(stored) = try!(file.load::<StoredUserProperties>()) {
+            self.service_user_properties.extend(stored.services);
+            self.getter_user_properties.extend(stored.getters);
+            self.setter_user_properties.extend(stored.setters);
+        }
+        self.user_properties_file = Some(file);
+        Ok(())
+    }
+
+    pub fn set_service_user_properties(&mut self, selectors: Vec<ServiceSelector>, properties: HashMap<String, String>) -> usize {
+        let mut updated = vec![];
+        self.with_services(selectors, |service| {
+            let service = service.borrow_mut();
+            for (key, value) in &properties {
+                service.user_properties.insert(key.clone(), value.clone());
+            }
+            updated.push((service.id.clone(), service.user_properties.clone()));
+        });
+        let result = updated.len();
+        self.service_user_properties.extend(updated);
+        self.aux_save_user_properties();
+        result
+    }
+
+    pub fn remove_service_user_properties(&mut self, selectors: Vec<ServiceSelector>, keys: Vec<String>) -> usize {
+        let mut updated = vec![];
+        self.with_services(selectors, |service| {
+            let service = service.borrow_mut();
+            for key in &keys {
+                service.user_properties.remove(key);
+            }
+            updated.push((service.id.clone(), service.user_properties.clone()));
+        });
+        let result = updated.len();
+        self.service_user_properties.extend(updated);
+        self.aux_save_user_properties();
+        result
+    }
+
+    /// Set user properties on getters.
+    /// As changing user properties may change the set of watchers that match a getter,
+    /// we may need to update watches.
+    pub fn set_getter_user_properties(&mut self, selectors: Vec<GetterSelector>, properties: HashMap<String, String>) -> (WatchRequest, usize) {
+        let mut size = 0;
+        let mut channels = vec![];
+        {
+            let stash = &mut self.getter_user_properties;
+            Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut data| {
+                for (key, value) in &properties {
+                    data.channel.user_properties.insert(key.clone(), value.clone());
+                }
+                stash.insert(data.id.clone(), data.channel.user_properties.clone());
+                // Overwriting a property may cause the getter to stop matching a watcher...
+                Self::aux_getter_may_need_unregistration(&mut data, false);
+                // ... or to start matching one.
+                channels.push(data.id.clone());
+                size += 1;
+            });
+        }
+        self.aux_save_user_properties();
+        (self.aux_getters_may_need_registration(channels), size)
+    }
+
+    pub fn remove_getter_user_properties(&mut self, selectors: Vec<GetterSelector>, keys: Vec<String>) -> usize {
+        let mut result = 0;
+        let stash = &mut self.getter_user_properties;
+        Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut data| {
+            for key in &keys {
+                data.channel.user_properties.remove(key);
+            }
+            stash.insert(data.id.clone(), data.channel.user_properties.clone());
+            Self::aux_getter_may_need_unregistration(&mut data, false);
+            result += 1;
+        });
+        self.aux_save_user_properties();
+        result
+    }
+
+    pub fn set_setter_user_properties(&mut self, selectors: Vec<SetterSelector>, properties: HashMap<String, String>) -> usize {
+        let mut result = 0;
+        let stash = &mut self.setter_user_properties;
+        Self::with_channels_mut(selectors, &mut self.setter_by_id, |data| {
+            for (key, value) in &properties {
+                data.channel.user_properties.insert(key.clone(), value.clone());
+            }
+            stash.insert(data.id.clone(), data.channel.user_properties.clone());
+            result += 1;
+        });
+        self.aux_save_user_properties();
+        result
+    }
+
+    pub fn remove_setter_user_properties(&mut self, selectors: Vec<SetterSelector>, keys: Vec<String>) -> usize {
+        let mut result = 0;
+        let stash = &mut self.setter_user_properties;
+        Self::with_channels_mut(selectors, &mut self.setter_by_id, |data| {
+            for key in &keys {
+                data.channel.user_properties.remove(key);
+            }
+            stash.insert(data.id.clone(), data.channel.user_properties.clone());
+            result += 1;
+        });
+        self.aux_save_user_properties();
+        result
+    }
+
+    /// List all the tags used by services and channels, or with metadata attached.
+    pub fn get_tags(&self) -> Vec<TagInfo> {
+        fn entry<'a>(tags: &'a mut HashMap<Id<TagId>, TagInfo>, tag: &Id<TagId>) -> &'a mut TagInfo {
+            tags.entry(tag.clone()).or_insert_with(|| TagInfo::new(tag.clone()))
+        }
+        let mut tags = HashMap::new();
+        for (tag, metadata) in &self.tag_metadata {
+            entry(&mut tags, tag).metadata = metadata.clone();
+        }
+        for service in self.service_by_id.values() {
+            for tag in &*service.borrow().tags.borrow() {
+                entry(&mut tags, tag).services += 1;
+            }
+        }
+        for getter in self.getter_by_id.values() {
+            for tag in &getter.borrow().channel.tags {
+                entry(&mut tags, tag).getters += 1;
+            }
+        }
+        for setter in self.setter_by_id.values() {
+            for tag in &setter.borrow().channel.tags {
+                entry(&mut tags, tag).setters += 1;
+            }
+        }
+        tags.drain().map(|(_, info)| info).collect()
+    }
+
+    /// Rename tag `from` into `to` on all services and channels. If `to` is already in use,
+    /// the two tags are merged. Metadata on `from` is moved to `to`, unless `to` already
+    /// has metadata.
+    ///
+    /// Returns the number of services and channels affected.
+    pub fn rename_tag(&mut self, from: &Id<TagId>, to: &Id<TagId>) -> (WatchRequest, usize) {
+        if from == to {
+            return (HashMap::new(), 0);
+        }
+        let mut size = 0;
+
+        // Getters whose tags or service tags have changed.
+        let mut getters = HashSet::new();
+        for service in self.service_by_id.values() {
+            let service = service.borrow_mut();
+            if !service.has_tag(from) {
+                continue;
+            }
+            let memory = self.service_tag_memory.entry(service.id.clone()).or_insert_with(TagMemory::default);
+            if service.rename_tag(from, to, memory) {
+                getters.extend(service.getters.keys().cloned());
+                size += 1;
+            }
+        }
+        for getter in self.getter_by_id.values() {
+            let getter = getter.borrow_mut();
+            if !getter.has_tag(from) {
+                continue;
+            }
+            let memory = self.getter_tag_memory.entry(getter.id.clone()).or_insert_with(TagMemory::default);
+            if getter.rename_tag(from, to, memory) {
+                getters.insert(getter.id.clone());
+                size += 1;
+            }
+        }
+        for setter in self.setter_by_id.values() {
+            let setter = setter.borrow_mut();
+            if !setter.has_tag(from) {
+                continue;
+            }
+            let memory = self.setter_tag_memory.entry(setter.id.clone()).or_insert_with(TagMemory::default);
+            if setter.rename_tag(from, to, memory) {
+                size += 1;
+            }
+        }
+
+        if let Some(metadata) = self.tag_metadata.remove(from) {
+            self.tag_metadata.entry(to.clone()).or_insert(metadata);
+        }
+
+        // Getters may have stopped matching watchers that expected `from`...
+        for id in &getters {
+            if let Some(getter) = self.getter_by_id.get(id) {
+                Self::aux_getter_may_need_unregistration(&mut *getter.borrow_mut(), false);
+            }
+        }
+        // ... or started matching watchers that expected `to`.
+        (self.aux_getters_may_need_registration(getters.drain().collect()), size)
+    }
+
+    pub fn get_home_timezone(&self) -> HomeTimeZone {
+        self.home_timezone
+    }
+
+    pub fn set_home_timezone(&mut self, timezone: HomeTimeZone) -> Result<(), Error> {
+        if let HomeTimeZone::Offset(offset) = timezone {
+            if !timezone.is_valid() {
+                return Err(Error::InvalidTimeZone(offset));
+            }
+        }
+        self.home_timezone = timezone;
+        Ok(())
+    }
+
+    /// Acquire a lease on all the setters matching any of the selectors.
+    ///
+    /// This is all or nothing: if any of the setters is leased by somebody else with
+    /// at least the same priority, no lease is acquired. Anonymous principals may not
+    /// acquire leases.
+    pub fn acquire_lease(&mut self, selectors: Vec<SetterSelector>, duration: Duration, priority: i32, holder: Principal) -> Result<Lease, Error> {
+        if holder.user.is_none() {
+            return Err(Error::PermissionDenied(Permission::Write));
+        }
+        let now = TimeStamp::from_datetime(UTC::now());
+        self.aux_forget_expired_leases(&now);
+
+        // Check for conflicts before changing anything.
+        let mut conflict = None;
+        {
+            let leases = &self.setter_leases;
+            Self::with_channels(selectors.clone(), &self.setter_by_id, |data| {
+                if let Some(lease) = leases.get(&data.id) {
+                    if conflict.is_none() && lease.blocks_at(&holder, priority, &now) {
+                        conflict = Some(lease.info());
+                    }
+                }
+            });
+        }
+        if let Some(lease) = conflict {
+            return Err(Error::HeldBy(lease));
+        }
+
+        let id = self.aux_new_lease_id();
+        let lease = Lease::new(id, holder, priority, duration);
+        let info = lease.info();
+        let stash = &mut self.setter_leases;
+        Self::with_channels_mut(selectors, &mut self.setter_by_id, |data| {
+            data.channel.mechanism.lease = Some(info.clone());
+            stash.insert(data.id.clone(), lease.clone());
+        });
+        Ok(lease)
+    }
+
+    /// Auxiliary function to generate the id of a new lease. Ids are unique and cannot be
+    /// guessed from the ids of previous leases.
+    fn aux_new_lease_id(&mut self) -> Id<LeaseId> {
+        self.lease_counter += 1;
+        let mut parts = vec![];
+        for salt in 0..2 {
+            // Each `RandomState` hashes with its own secret keys.
+            let mut hasher = RandomState::new().build_hasher();
+            (self.lease_counter, salt).hash(&mut hasher);
+            parts.push(hasher.finish());
+        }
+        Id::new(&format!("lease {:016x}{:016x}", parts[0], parts[1]))
+    }
+
+    /// Release a lease held by `holder`, returning the number of setters released.
+    pub fn release_lease(&mut self, id: &Id<LeaseId>, holder: &Principal) -> usize {
+        self.aux_forget_expired_leases(&TimeStamp::from_datetime(UTC::now()));
+        self.aux_remove_leases(|lease| lease.id == *id && lease.is_held_by(holder))
+    }
+
+    /// Auxiliary function to remove the leases that have expired at a given date.
+    fn aux_forget_expired_leases(&mut self, now: &TimeStamp) {
+        self.aux_remove_leases(|lease| !lease.is_active_at(now));
+    }
+
+    /// Auxiliary function to remove the leases matching a condition, both from the stash
+    /// and from the setters. Returns the number of setters affected.
+    fn aux_remove_leases<F>(&mut self, cond: F) -> usize where F: Fn(&Lease) -> bool {
+        let removed : Vec<_> = self.setter_leases.iter()
+            .filter(|&(_, lease)| cond(lease))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &removed {
+            self.setter_leases.remove(id);
+            if let Some(data) = self.setter_by_id.get(id) {
+                data.borrow_mut().channel.mechanism.lease = None;
+            }
+        }
+        removed.len()
+    }
+
+    pub fn get_profiles(&self) -> Vec<Profile> {
+        self.profiles.profiles()
+    }
+
+    pub fn add_profile(&mut self, profile: Profile) -> Result<(), Error> {
+        self.profiles.add(profile)
+    }
+
+    pub fn remove_profile(&mut self, id: &Id<ProfileId>) -> Result<bool, Error> {
+        self.profiles.remove(id)
+    }
+
+    pub fn get_locations(&self) -> Vec<Location> {
+        self.locations.locations()
+    }
+
+    pub fn add_location(&mut self, location: Location) -> Result<(), Error> {
+        self.locations.add(location)
+    }
+
+    /// Remove a location. Services placed in this location are moved to its parent, if any.
+    pub fn remove_location(&mut self, id: &Id<LocationId>) -> Result<(), Error> {
+        let removed = try!(self.locations.remove(id));
+        for service in self.service_by_id.values() {
+            let service = service.borrow_mut();
+            if service.location.as_ref() == Some(id) {
+                service.location = removed.parent.clone();
+            }
+        }
+        let mut moved = vec![];
+        for (service, location) in &self.service_locations {
+            if location == id {
+                moved.push(service.clone());
+            }
+        }
+        for service in moved {
+            match removed.parent {
+                None => self.service_locations.remove(&service),
+                Some(ref parent) => self.service_locations.insert(service, parent.clone())
+            };
+        }
+        Ok(())
+    }
+
+    /// Replace the entire location tree. Services placed in a location that is not part
+    /// of the new tree are removed from their location.
+    pub fn import_locations(&mut self, locations: Vec<Location>) -> Result<(), Error> {
+        self.locations = try!(LocationTree::from_locations(locations));
+        for service in self.service_by_id.values() {
+            let service = service.borrow_mut();
+            let is_dangling = match service.location {
+                Some(ref id) => !self.locations.contains(id),
+                None => false
+            };
+            if is_dangling {
+                service.location = None;
+            }
+        }
+        let dangling : Vec<_> = self.service_locations.iter()
+            .filter(|&(_, location)| !self.locations.contains(location))
+            .map(|(service, _)| service.clone())
+            .collect();
+        for service in dangling {
+            self.service_locations.remove(&service);
+        }
+        Ok(())
+    }
+
+    /// Place services in a location, or remove them from their location if `location`
+    /// is `None`.
+    pub fn set_service_location(&mut self, selectors: Vec<ServiceSelector>, location: Option<Id<LocationId>>) -> Result<usize, Error> {
+        if let Some(ref id) = location {
+            if !self.locations.contains(id) {
+                return Err(Error::InternalError(InternalError::NoSuchLocation(id.clone())));
+            }
+        }
+        let mut updated = vec![];
+        self.with_services(selectors, |service| {
+            let service = service.borrow_mut();
+            service.location = location.clone();
+            updated.push(service.id.clone());
+        });
+        let result = updated.len();
+        for id in updated {
+            match location {
+                None => self.service_locations.remove(&id),
+                Some(ref location) => self.service_locations.insert(id, location.clone())
+            };
+        }
+        Ok(result)
+    }
+
+    pub fn set_tag_metadata(&mut self, tag: Id<TagId>, metadata: TagMetadata) {
+        self.tag_metadata.insert(tag, metadata);
+    }
+
+    pub fn remove_tag_metadata(&mut self, tag: &Id<TagId>) -> bool {
+        self.tag_metadata.remove(tag).is_some()
+    }
+
+    /// Find the adapter in charge of the streams produced by a getter.
+    pub fn prepare_open_stream(&self, id: &Id<Getter>) -> Result<Arc<Adapter>, Error> {
+        let getter = match self.getter_by_id.get(id) {
+            None => return Err(Error::InternalError(InternalError::NoSuchGetter(id.clone()))),
+            Some(getter) => getter.borrow()
+        };
+        let typ = getter.channel.mechanism.kind.get_type();
+        if typ != Type::Stream {
+            return Err(Error::TypeError(TypeError {
+                expected: Type::Stream,
+                got: typ
+            }));
+        }
+        match self.adapter_by_id.get(&getter.channel.adapter) {
+            None => Err(Error::InternalError(InternalError::NoSuchAdapter(getter.channel.adapter.clone()))),
+            Some(adapter_data) => Ok(adapter_data.adapter.clone())
+        }
+    }
+
     /// Read the latest value from a set of channels
     pub fn prepare_fetch_values(&self, selectors: Vec<GetterSelector>) -> FetchRequest {
         // First, prepare the list of actual getters and group it by adapter.
         // Once we have done this, we can release the lock.
         let mut per_adapter : FetchRequest = HashMap::new();
         let adapter_by_id = &self.adapter_by_id;
+        let service_by_id = &self.service_by_id;
         Self::with_channels(selectors, &self.getter_by_id, |data| {
             use std::collections::hash_map::Entry::*;
             let id = data.channel.id.clone();
             let typ = data.channel.mechanism.kind.get_type();
-            match per_adapter.entry(data.adapter.clone()) {
+            let entry = match per_adapter.entry(data.adapter.clone()) {
                 Vacant(entry) => {
                     let adapter = match adapter_by_id.get(&data.channel.adapter) {
                         None => {
@@ -950,20 +2227,34 @@ impl State {
                             adapter_data.adapter.clone()
                         }
                     };
-                    let mut source = vec![(id, typ)];
-                    entry.insert((adapter, source.drain(..).collect()));
-                }
-                Occupied(mut entry) => {
-                    entry.get_mut().1.insert(id, typ);
+                    entry.insert((adapter, (HashMap::new(), HashMap::new())))
                 }
+                Occupied(entry) => entry.into_mut()
             };
+            let &mut (_, (ref mut request, ref mut failures)) = entry;
+
+            if Self::is_write_only(service_by_id, &data.channel) {
+                failures.insert(id.clone(), Err(Error::WriteOnly(id)));
+                return;
+            }
+            match data.channel.mechanism.delivery {
+                Delivery::State => {
+                    request.insert(id, typ);
+                }
+                Delivery::Event => {
+                    // Events have no current value, no need to even ask the Adapter.
+                    failures.insert(id.clone(), Err(Error::GetterDoesNotSupportPolling(id)));
+                }
+            }
         });
         per_adapter
     }
 
 
     /// Send values to a set of channels
-    pub fn prepare_send_values(&self, mut keyvalues: TargetMap<SetterSelector, Value>) -> SendRequest {
+    pub fn prepare_send_values(&self, mut keyvalues: TargetMap<SetterSelector, Value>, user: &Principal) -> SendRequest {
+        let now = TimeStamp::from_datetime(UTC::now());
+
         // First determine the channels and group them by adapter.
         let mut per_adapter = HashMap::new();
         for Targetted {select: selectors, payload: value} in keyvalues.drain(..) {
@@ -971,10 +2262,13 @@ impl State {
                 use std::collections::hash_map::Entry::*;
                 let id = data.channel.id.clone();
 
-                // Check that the values we are about to send have the correct type. If they
-                // don't, no need to even send them to the Adapter.
+                // Check that nobody else holds a lease on the setter, and that the values
+                // we are about to send have the correct type. If they don't, no need to even
+                // send them to the Adapter.
                 let typ = data.channel.mechanism.kind.get_type();
-                let checked = if value.get_type() == typ {
+                let checked = if let Some(lease) = Self::aux_blocking_lease(&self.setter_leases, &id, user, &now) {
+                    Err(Error::HeldBy(lease))
+                } else if value.get_type() == typ {
                     Ok(value.clone())
                 } else {
                     Err(Error::TypeError(TypeError {
@@ -1020,16 +2314,107 @@ impl State {
         per_adapter
     }
 
+    /// Invoke a set of actions
+    pub fn prepare_invoke(&self, mut arguments: TargetMap<ActionSelector, Value>) -> InvokeRequest {
+        // First determine the actions and group them by adapter.
+        let mut per_adapter : InvokeRequest = HashMap::new();
+        for Targetted {select: selectors, payload: value} in arguments.drain(..) {
+            Self::with_channels(selectors, &self.action_by_id, |data| {
+                use std::collections::hash_map::Entry::*;
+                let id = data.channel.id.clone();
+
+                // Check that the argument has the type declared by the action. If it
+                // doesn't, no need to even send it to the Adapter.
+                let typ = data.channel.mechanism.argument.clone();
+                let checked = if value.get_type() == typ {
+                    Ok(value.clone())
+                } else {
+                    Err(Error::TypeError(TypeError {
+                        got: value.get_type(),
+                        expected: typ
+                    }))
+                };
+                let entry = match per_adapter.entry(data.channel.adapter.clone()) {
+                    Vacant(entry) => {
+                        let adapter = match self.adapter_by_id.get(&data.channel.adapter) {
+                            None => {
+                                log_debug_assert!(false, "Internal inconsistency: could not find adapter {}", data.channel.adapter);
+                                return
+                            }
+                            Some(adapter) => adapter
+                        };
+                        entry.insert((adapter.adapter.clone(), (HashMap::new(), HashMap::new(), HashMap::new())))
+                    }
+                    Occupied(entry) => entry.into_mut()
+                };
+                let &mut (_, (ref mut request, ref mut result_types, ref mut failures)) = entry;
+                match checked {
+                    Ok(value) => {
+                        request.insert(id.clone(), value);
+                        result_types.insert(id, data.channel.mechanism.result.clone());
+                    }
+                    Err(error) => {
+                        failures.insert(id, Err(error));
+                    }
+                }
+            })
+        }
+        per_adapter
+    }
+
+    /// Stop delivering the values of the getters of `kind` among `getters`, once a write-only
+    /// setter of that kind has appeared on their service. Watchers keep receiving topology
+    /// changes, and are informed with `WatchEvent::InitializationError`.
+    fn aux_stop_write_only_watches(getters: &HashMap<Id<Getter>, Arc<SubCell<GetterData>>>, kind: &ChannelKind) {
+        for getter_data in getters.values() {
+            let getter_data = getter_data.borrow();
+            if getter_data.channel.mechanism.kind != *kind {
+                continue;
+            }
+            for watcher in getter_data.watchers.values() {
+                let watcher = match watcher.upgrade() {
+                    Some(watcher) => watcher,
+                    None => continue
+                };
+                if watcher.is_dropped.load(Ordering::Relaxed) {
+                    continue;
+                }
+                if watcher.guards.borrow_mut().remove(&getter_data.id).is_none() {
+                    // Already watching only the topology.
+                    continue;
+                }
+                let _ = watcher.on_event.lock().unwrap().send(WatchEvent::InitializationError {
+                    channel: getter_data.id.clone(),
+                    error: Error::WriteOnly(getter_data.id.clone())
+                });
+            }
+        }
+    }
+
+    /// Determine whether the values of a getter must never be read back, i.e. whether its
+    /// service has a write-only setter of the same kind.
+    fn is_write_only(service_by_id: &HashMap<Id<ServiceId>, Arc<SubCell<ServiceData>>>, getter: &Channel<Getter>) -> bool {
+        match service_by_id.get(&getter.service) {
+            None => false,
+            Some(service) => service.borrow().setters.values().any(|setter| {
+                let setter = setter.borrow();
+                setter.channel.mechanism.write_only && setter.channel.mechanism.kind == getter.mechanism.kind
+            })
+        }
+    }
+
     fn aux_start_channel_watch(watcher: &mut Arc<WatcherData>,
         getter_data: &mut GetterData,
         filter: &Exactly<Range>,
         adapter_by_id: &HashMap<Id<AdapterId>, AdapterData>,
+        service_by_id: &HashMap<Id<ServiceId>, Arc<SubCell<ServiceData>>>,
         per_adapter: &mut WatchRequest)
     {
         use std::collections::hash_map::Entry::*;
 
         let id = getter_data.id.clone();
         let adapter = getter_data.adapter.clone();
+        let delivery = getter_data.channel.mechanism.delivery;
 
         let insert_in_getter =
             match InsertInMap::start(&mut getter_data.watchers, vec![ ( watcher.key, Arc::downgrade(watcher) )] ) {
@@ -1049,6 +2434,17 @@ impl State {
             }
         };
 
+        if Self::is_write_only(service_by_id, &getter_data.channel) {
+            // Values sent to a write-only setter must never be read back, so only watch
+            // the topology.
+            let _ = watcher.on_event.lock().unwrap().send(WatchEvent::InitializationError {
+                channel: id.clone(),
+                error: Error::WriteOnly(id)
+            });
+            insert_in_getter.commit();
+            return;
+        }
+
         match per_adapter.entry(adapter) {
             Vacant(entry) => {
                 let adapter = match adapter_by_id.get(&getter_data.channel.adapter) {
@@ -1061,10 +2457,10 @@ impl State {
                         adapter_data.adapter.clone()
                     }
                 };
-                entry.insert((adapter, (vec![(id, range, Arc::downgrade(watcher) )])));
+                entry.insert((adapter, (vec![(id, range, delivery, Arc::downgrade(watcher) )])));
             },
             Occupied(mut entry) => {
-                (entry.get_mut().1).push((id, range, Arc::downgrade(watcher)));
+                (entry.get_mut().1).push((id, range, delivery, Arc::downgrade(watcher)));
             }
         }
 
@@ -1083,13 +2479,14 @@ impl State {
         // Regroup per adapter.
         let mut per_adapter = HashMap::new();
         let adapter_by_id = &self.adapter_by_id;
+        let service_by_id = &self.service_by_id;
         for Targetted { select: selectors, payload: filter } in watch.drain(..) {
             // Find out which channels already match the selectors and attach
             // the watcher immediately.
             let filter = &filter;
             Self::with_channels_mut(selectors, &mut self.getter_by_id, |mut getter_data| {
                 Self::aux_start_channel_watch(&mut watcher, &mut getter_data, filter,
-                    adapter_by_id, &mut per_adapter)
+                    adapter_by_id, service_by_id, &mut per_adapter)
             });
         }
 
@@ -1157,7 +2554,7 @@ impl State {
 
         let mut to_add = vec![];
         for (_, (adapter, mut adapter_request)) in per_adapter.drain() {
-            for (id, range, weak_watch_data) in adapter_request.drain(..) {
+            for (id, range, delivery, weak_watch_data) in adapter_request.drain(..) {
                 let watch_data = match weak_watch_data.upgrade() {
                     None => {
                         // The watch_data has already been dropped, nothing to do.
@@ -1166,6 +2563,13 @@ impl State {
                     }
                     Some(watch_data) => watch_data
                 };
+                // Events are stateless pulses, so we need to receive each of them, rather than
+                // just the transitions in and out of the range. For this reason, the adapter
+                // watches events without a range and we apply the range ourselves.
+                let (range, event_range) = match delivery {
+                    Delivery::State => (range, None),
+                    Delivery::Event => (None, Some(range))
+                };
                 let is_dropped = watch_data.is_dropped.clone();
                 if is_dropped.load(Ordering::Relaxed) {
                     // The WatchGuard has already been dropped.
@@ -1182,6 +2586,23 @@ impl State {
                         // the call to `stop_watch`.
                         return None;
                     }
+                    if let Some(ref range) = event_range {
+                        return match event {
+                            AdapterWatchEvent::Enter { id, value } => {
+                                if let Some(ref range) = *range {
+                                    if !range.contains(&value) {
+                                        return None;
+                                    }
+                                }
+                                Some(WatchEvent::Event {
+                                    from: id,
+                                    value: value,
+                                    timestamp: TimeStamp::from_datetime(UTC::now())
+                                })
+                            }
+                            AdapterWatchEvent::Exit { .. } => None
+                        };
+                    }
                     Some(match event {
                         AdapterWatchEvent::Enter { id, value } =>
                             WatchEvent::EnterRange {
@@ -0,0 +1,238 @@
+//! Conditional sends, i.e. sending a value to setters only if the values of some getters
+//! are in a given range, e.g. "turn the heater off only if it is currently on" or "lock
+//! the door only if it is closed".
+//!
+//! Preconditions are evaluated just before dispatching the values, either against fresh
+//! values fetched from the getters or against values fetched recently enough, kept in a
+//! `ValueCache`.
+
+use api::Error;
+use parse::*;
+use selector::GetterSelector;
+use services::Getter;
+use util::{ Id, Targetted };
+use values::{ Duration, Range, TimeStamp, Value };
+
+use chrono::{ Duration as ChronoDuration, UTC };
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A condition on the values of one or more getters.
+///
+/// # JSON
+///
+/// An object with fields `select` (a `GetterSelector` or an array of `GetterSelector`),
+/// `range` (a `Range`) and, optionally, `max_age` (a `Duration`).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Precondition {
+    /// The getters to check. The precondition holds if at least one getter matches these
+    /// selectors and the values of all the matching getters are in `range`.
+    pub select: Vec<GetterSelector>,
+
+    /// The range in which the values must be.
+    pub range: Range,
+
+    /// If specified, values fetched at most `max_age` ago may be used instead of fetching
+    /// fresh values. Otherwise, values are always fetched just before dispatch.
+    #[serde(default)]
+    pub max_age: Option<Duration>,
+}
+
+impl Precondition {
+    pub fn new(select: Vec<GetterSelector>, range: Range) -> Self {
+        Precondition {
+            select: select,
+            range: range,
+            max_age: None,
+        }
+    }
+
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Precondition {
+            max_age: Some(max_age),
+            ..self
+        }
+    }
+}
+
+impl Parser<Precondition> for Precondition {
+    fn description() -> String {
+        "Precondition".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let select = try!(path.push("select", |path| Vec::<GetterSelector>::take(path, source, "select")));
+        let range = try!(path.push("range", |path| Range::take(path, source, "range")));
+        let max_age = match path.push("max_age", |path| Duration::take_opt(path, source, "max_age")) {
+            Some(result) => Some(try!(result)),
+            None => None
+        };
+        Ok(Precondition {
+            select: select,
+            range: range,
+            max_age: max_age,
+        })
+    }
+}
+
+/// A value to send, only if all the preconditions hold.
+///
+/// # JSON
+///
+/// When targetted, an object `{select, value, preconditions}`, where `preconditions` is
+/// an optional array of `Precondition`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Conditional {
+    pub value: Value,
+
+    #[serde(default)]
+    pub preconditions: Vec<Precondition>,
+}
+
+impl Conditional {
+    pub fn new(value: Value) -> Self {
+        Conditional {
+            value: value,
+            preconditions: vec![],
+        }
+    }
+
+    pub fn with_precondition(mut self, precondition: Precondition) -> Self {
+        self.preconditions.push(precondition);
+        self
+    }
+}
+
+impl<K> Parser<Targetted<K, Conditional>> for Targetted<K, Conditional> where K: Parser<K> + Clone {
+    fn description() -> String {
+        format!("Targetted<{}, Conditional>", K::description())
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let select = try!(path.push("select", |path| Vec::<K>::take(path, source, "select")));
+        let value = try!(path.push("value", |path| Value::take(path, source, "value")));
+        let preconditions = match path.push("preconditions", |path| Precondition::take_vec_opt(path, source, "preconditions")) {
+            Some(result) => try!(result),
+            None => vec![]
+        };
+        Ok(Targetted {
+            select: select,
+            payload: Conditional {
+                value: value,
+                preconditions: preconditions,
+            }
+        })
+    }
+}
+
+/// The reason for which a precondition does not hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PreconditionFailure {
+    /// No getter matches the selectors of the precondition.
+    NoSuchGetter,
+
+    /// The value of a getter could not be determined, either because the getter has
+    /// no value yet, or because fetching it failed with an error.
+    NoValue(Id<Getter>, Option<Box<Error>>),
+
+    /// The value of a getter is not in the expected range.
+    OutOfRange(Id<Getter>, Value),
+}
+
+/// The number of values kept by a `ValueCache` created with `ValueCache::new`.
+pub const DEFAULT_CACHE_CAPACITY : usize = 1024;
+
+/// The latest values fetched from getters, along with the date at which they were fetched.
+///
+/// The cache holds at most a given number of values: once it is full, storing the value of
+/// another getter evicts the oldest value. Secrets are never cached.
+pub struct ValueCache {
+    values: Mutex<CachedValues>,
+    capacity: usize,
+}
+
+struct CachedValues {
+    /// For each getter, the date at which its value was fetched, the order in which it
+    /// was stored and the value itself.
+    values: HashMap<Id<Getter>, (TimeStamp, u64, Value)>,
+
+    /// The order of the next value stored.
+    counter: u64,
+}
+
+impl ValueCache {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Create a cache holding at most `capacity` values.
+    pub fn with_capacity(capacity: usize) -> Self {
+        ValueCache {
+            values: Mutex::new(CachedValues {
+                values: HashMap::new(),
+                counter: 0,
+            }),
+            capacity: capacity,
+        }
+    }
+
+    /// Store a value just fetched from a getter.
+    pub fn store(&self, id: Id<Getter>, value: Value) {
+        let mut cache = self.values.lock().unwrap();
+        if let Value::Secret(_) = value {
+            cache.values.remove(&id);
+            return;
+        }
+        if cache.values.len() >= self.capacity && !cache.values.contains_key(&id) {
+            let oldest = cache.values.iter()
+                .min_by_key(|&(_, &(_, order, _))| order)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                cache.values.remove(&oldest);
+            }
+        }
+        if self.capacity > 0 {
+            let order = cache.counter;
+            cache.counter += 1;
+            cache.values.insert(id, (TimeStamp::from_datetime(UTC::now()), order, value));
+        }
+    }
+
+    /// Forget the value of a getter.
+    pub fn remove(&self, id: &Id<Getter>) {
+        self.values.lock().unwrap().values.remove(id);
+    }
+
+    /// Forget the values of all the getters for which `keep` returns `false`.
+    pub fn retain<F>(&self, keep: F) where F: Fn(&Id<Getter>) -> bool {
+        let mut cache = self.values.lock().unwrap();
+        let removed : Vec<_> = cache.values.keys().filter(|id| !keep(id)).cloned().collect();
+        for id in removed {
+            cache.values.remove(&id);
+        }
+    }
+
+    /// The number of values currently cached.
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the value of a getter, if it was fetched at most `max_age` ago.
+    pub fn get(&self, id: &Id<Getter>, max_age: &Duration) -> Option<Value> {
+        let max_age : ChronoDuration = max_age.clone().into();
+        let now = UTC::now();
+        match self.values.lock().unwrap().values.get(id) {
+            Some(&(ref fetched, _, ref value)) if now - *fetched.as_datetime() <= max_age => Some(value.clone()),
+            _ => None
+        }
+    }
+}
+
+impl Default for ValueCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
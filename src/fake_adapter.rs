@@ -2,9 +2,10 @@
 //! Used for testing.
 use adapter::*;
 
-use api::{ Error, User };
+use api::{ Error, InternalError, Principal };
 use selector::*;
 use services::*;
+use streams::*;
 use values::*;
 
 use transformable_channels::mpsc::*;
@@ -12,6 +13,7 @@ use transformable_channels::mpsc::*;
 use std::cell::RefCell;
 use std::collections::HashMap ;
 use std::collections::hash_map::Entry::*;
+use std::io::Cursor;
 use std::sync::{ Arc, Mutex };
 use std::sync::atomic::{ AtomicBool, Ordering} ;
 use std::thread;
@@ -24,7 +26,16 @@ pub enum Tweak {
 
     /// Inject an error in a virtual setter. All operations on this setter will
     /// raise the error until `None` is injected instead.
-    InjectSetterError(Id<Setter>, Option<Error>)
+    InjectSetterError(Id<Setter>, Option<Error>),
+
+    /// Inject the result of a virtual action. All invocations of this action will
+    /// produce this result until `None` is injected instead, after which they
+    /// produce `Value::Unit`.
+    InjectActionResult(Id<Action>, Option<Result<Value, Error>>),
+
+    /// Inject the content of a virtual stream. Opening this stream will produce
+    /// this content until `None` is injected instead.
+    InjectStream(Id<StreamId>, Option<Binary>),
 }
 
 /// Something that happened to the virtual device, e.g. a value was sent.
@@ -61,6 +72,8 @@ pub struct FakeAdapter {
     rx_effect: Mutex<Option<Receiver<Effect>>>,
     values: SyncMap<Id<Getter>, Result<Value, Error>>,
     senders: SyncMap<Id<Setter>, Error>,
+    actions: SyncMap<Id<Action>, Result<Value, Error>>,
+    streams: SyncMap<Id<StreamId>, Binary>,
     watchers: SyncMap<Id<Getter>, Vec<WatcherState>>
 }
 
@@ -71,6 +84,8 @@ impl FakeAdapter {
 
         let (values_main, values_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (senders_main, senders_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
+        let (actions_main, actions_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
+        let (streams_main, streams_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
         let (watchers_main, watchers_thread) = dup(Arc::new(Mutex::new(HashMap::new())));
 
         let mutex = Arc::new(Mutex::new(tx));
@@ -84,6 +99,8 @@ impl FakeAdapter {
             name: id.as_atom().to_string().clone(),
             values: values_main,
             senders: senders_main,
+            actions: actions_main,
+            streams: streams_main,
             tweak: Arc::new(tweak),
             tx_effect: Mutex::new(Box::new(tx_effect)),
             rx_effect: Mutex::new(Some(rx_effect)),
@@ -142,6 +159,18 @@ impl FakeAdapter {
                     InjectSetterError(id, Some(err)) => {
                         senders_thread.lock().unwrap().insert(id, err);
                     }
+                    InjectActionResult(id, None) => {
+                        actions_thread.lock().unwrap().remove(&id);
+                    },
+                    InjectActionResult(id, Some(result)) => {
+                        actions_thread.lock().unwrap().insert(id, result);
+                    }
+                    InjectStream(id, None) => {
+                        streams_thread.lock().unwrap().remove(&id);
+                    },
+                    InjectStream(id, Some(binary)) => {
+                        streams_thread.lock().unwrap().insert(id, binary);
+                    }
                 }
                 tx.send(()).unwrap();
             }
@@ -182,7 +211,7 @@ impl Adapter for FakeAdapter {
 
     /// Request a value from a channel. The `FoxBox` (not the adapter)
     /// is in charge of keeping track of the age of values.
-    fn fetch_values(&self, mut channels: Vec<Id<Getter>>, _: User) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+    fn fetch_values(&self, mut channels: Vec<Id<Getter>>, _: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error> {
         let map = self.values.lock().unwrap();
         channels.drain(..).map(|id| {
             let result = match map.get(&id) {
@@ -195,7 +224,7 @@ impl Adapter for FakeAdapter {
     }
 
     /// Request that a value be sent to a channel.
-    fn send_values(&self, mut values: HashMap<Id<Setter>, Value>, _: User) -> ResultMap<Id<Setter>, (), Error> {
+    fn send_values(&self, mut values: HashMap<Id<Setter>, Value>, _: Principal) -> ResultMap<Id<Setter>, (), Error> {
         let map = self.senders.lock().unwrap();
         values.drain().map(|(id, value)| {
             let result = match map.get(&id) {
@@ -209,6 +238,29 @@ impl Adapter for FakeAdapter {
         }).collect()
     }
 
+    /// Invoke actions, producing the injected results.
+    fn invoke(&self, mut arguments: HashMap<Id<Action>, Value>, _: Principal) -> ResultMap<Id<Action>, Value, Error> {
+        let map = self.actions.lock().unwrap();
+        arguments.drain().map(|(id, _)| {
+            let result = match map.get(&id) {
+                None => Ok(Value::Unit),
+                Some(result) => result.clone()
+            };
+            (id, result)
+        }).collect()
+    }
+
+    /// Open streams, producing the injected content.
+    fn open_stream(&self, _: &Id<Getter>, stream: &Id<StreamId>, _: Principal) -> Result<Stream, Error> {
+        match self.streams.lock().unwrap().get(stream) {
+            None => Err(Error::InternalError(InternalError::NoSuchStream(stream.clone()))),
+            Some(binary) => {
+                let reader = Cursor::new((*binary.data).clone());
+                Ok(Stream::from_reader(binary.mimetype.clone(), Box::new(reader)))
+            }
+        }
+    }
+
     fn register_watch(&self, mut watch: Vec<(Id<Getter>, Option<Range>, Box<ExtSender<WatchEvent>>)>) ->
             Vec<(Id<Getter>, Result<Box<AdapterWatchGuard>, Error>)>
     {
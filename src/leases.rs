@@ -0,0 +1,125 @@
+//! Leases, i.e. time-limited exclusive holds on setters.
+//!
+//! When several callers drive the same setter, e.g. a rule and a human both controlling
+//! a thermostat, the latest call to `send_values` wins silently. To avoid this, a caller
+//! may acquire a lease on a set of setters. Until the lease expires or is released, values
+//! sent to these setters by anybody else are rejected with `Error::HeldBy`.
+//!
+//! Each lease has a priority. A caller may acquire a lease on setters already leased by
+//! somebody else only with a strictly higher priority, in which case the previous lease
+//! is preempted on these setters.
+//!
+//! Only authenticated principals may hold leases, as anonymous principals cannot be told
+//! apart. Principals other than the holder only ever see the `LeaseInfo` of a lease, i.e.
+//! its priority and expiry date.
+
+use api::Principal;
+use parse::*;
+use util::{ Id, LeaseId };
+use values::{ Duration, TimeStamp };
+
+use chrono::{ Duration as ChronoDuration, UTC };
+
+use serde::ser::Serialize;
+use serde_json::value::Serializer;
+
+/// A time-limited exclusive hold on one or more setters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    /// A unique identifier for this lease, used to release it.
+    pub id: Id<LeaseId>,
+
+    /// The principal on behalf of whom the lease was acquired.
+    pub holder: Principal,
+
+    /// The priority of the lease. A lease may only be preempted by a lease with a
+    /// strictly higher priority.
+    pub priority: i32,
+
+    /// The date at which the lease expires. Expired leases are ignored.
+    pub expires: TimeStamp,
+}
+
+impl Lease {
+    /// Create a lease, expiring after `duration`.
+    pub fn new(id: Id<LeaseId>, holder: Principal, priority: i32, duration: Duration) -> Self {
+        let duration : ChronoDuration = duration.into();
+        Lease {
+            id: id,
+            holder: holder,
+            priority: priority,
+            expires: TimeStamp::from_datetime(UTC::now() + duration),
+        }
+    }
+
+    /// Determine whether the lease is still in effect at a given date.
+    pub fn is_active_at(&self, date: &TimeStamp) -> bool {
+        *date < self.expires
+    }
+
+    /// Determine whether the lease is still in effect.
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(&TimeStamp::from_datetime(UTC::now()))
+    }
+
+    /// Determine whether a principal holds this lease, i.e. whether it represents the same
+    /// authenticated user, entering the system through the same origin, as the holder.
+    /// Anonymous principals never hold a lease.
+    pub fn is_held_by(&self, principal: &Principal) -> bool {
+        self.holder.user.is_some() && self.holder.user == principal.user && self.holder.origin == principal.origin
+    }
+
+    /// The part of the lease that may be shown to principals other than the holder.
+    pub fn info(&self) -> LeaseInfo {
+        LeaseInfo {
+            priority: self.priority,
+            expires: self.expires.clone(),
+        }
+    }
+
+    /// Determine whether this lease prevents `principal` from acquiring a lease with
+    /// priority `priority` at a given date.
+    pub fn blocks_at(&self, principal: &Principal, priority: i32, date: &TimeStamp) -> bool {
+        self.is_active_at(date) && !self.is_held_by(principal) && self.priority >= priority
+    }
+}
+
+impl ToJSON for Lease {
+    fn to_json(&self) -> JSON {
+        let mut serializer = Serializer::new();
+        match self.serialize(&mut serializer) {
+            Ok(()) => serializer.unwrap(),
+            Err(_) =>
+                vec![("Internal error while serializing", "")].to_json()
+        }
+    }
+}
+
+/// The public view of a lease, as shown in the JSON of setters and in `Error::HeldBy`.
+/// Neither the holder nor the id of the lease are exposed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseInfo {
+    /// The priority of the lease.
+    pub priority: i32,
+
+    /// The date at which the lease expires.
+    pub expires: TimeStamp,
+}
+
+impl LeaseInfo {
+    /// Determine whether the lease is still in effect at a given date.
+    pub fn is_active_at(&self, date: &TimeStamp) -> bool {
+        *date < self.expires
+    }
+}
+
+impl ToJSON for LeaseInfo {
+    fn to_json(&self) -> JSON {
+        let mut serializer = Serializer::new();
+        match self.serialize(&mut serializer) {
+            Ok(()) => serializer.unwrap(),
+            Err(_) =>
+                vec![("Internal error while serializing", "")].to_json()
+        }
+    }
+}
@@ -28,9 +28,18 @@ pub mod parse;
 /// Selecting one or more devices. Exposed through the API.
 pub mod selector;
 
+/// A hierarchy of locations (home, floor, room, ...) in which services may be placed.
+pub mod locations;
+
+/// Profiles, i.e. named bundles of channel kinds used to select families of devices.
+pub mod profiles;
+
 /// Values that may be sent to/received from devices
 pub mod values;
 
+/// Content provided by adapters by reference, such as camera snapshots and video streams.
+pub mod streams;
+
 /// Various utilities
 pub mod util;
 
@@ -41,6 +50,27 @@ mod backend;
 /// uses these to implements the taxonomy API.
 pub mod manager;
 
+/// Access control, i.e. which users may perform which operations on which services.
+pub mod policy;
+
+/// An audit log of the operations that change the state of the system.
+pub mod audit;
+
+/// Time-limited exclusive holds on setters, to prevent conflicting control.
+pub mod leases;
+
+/// Sending values to setters only if the values of some getters are in a given range.
+pub mod conditions;
+
+/// Scenes, i.e. saved values of setters that can be restored later.
+pub mod scenes;
+
+/// Sending values to setters at given dates or repeatedly, following cron expressions.
+pub mod scheduler;
+
+/// A rule engine, sending values to setters when the values of getters enter given ranges.
+pub mod rules;
+
 /// The API for defining Adapters.
 pub mod adapter;
 
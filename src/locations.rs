@@ -0,0 +1,267 @@
+//! A hierarchical model of locations, e.g. home / floor / room.
+//!
+//! Services may be assigned to a location. A selector for a location also accepts the
+//! services placed in any of its descendants, so a service placed in "kitchen" is accepted
+//! by a selector for "ground floor" if "kitchen" is on the "ground floor".
+
+use api::{ Error, InternalError };
+use parse::*;
+pub use util::{ Id, LocationId };
+
+use std::collections::HashMap;
+
+/// A location, e.g. a home, a floor or a room.
+///
+/// # JSON
+///
+/// A location is represented by an object with the following fields:
+///
+/// - id: string - an id unique to this location;
+/// - (optional) name: string - a human-readable name for the location;
+/// - (optional) parent: string - the id of the location containing this location.
+///
+/// ```
+/// use foxbox_taxonomy::locations::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let kitchen = Location::from_str("{\"id\": \"kitchen\", \"parent\": \"ground floor\"}").unwrap();
+/// assert_eq!(kitchen.parent, Some(Id::new("ground floor")));
+/// assert_eq!(kitchen.name, None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// An id unique to this location.
+    pub id: Id<LocationId>,
+
+    /// A human-readable name for the location.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The location containing this location, if any.
+    #[serde(default)]
+    pub parent: Option<Id<LocationId>>,
+}
+
+impl Location {
+    /// Create a location without a name.
+    pub fn new(id: Id<LocationId>, parent: Option<Id<LocationId>>) -> Self {
+        Location {
+            id: id,
+            name: None,
+            parent: parent,
+        }
+    }
+}
+
+impl Parser<Location> for Location {
+    fn description() -> String {
+        "Location".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let id = try!(path.push("id", |path| Id::take(path, source, "id")));
+        let name = match path.push("name", |path| String::take_opt(path, source, "name")) {
+            None => None,
+            Some(Ok(name)) => Some(name),
+            Some(Err(err)) => return Err(err)
+        };
+        let parent = match path.push("parent", |path| Id::take_opt(path, source, "parent")) {
+            None => None,
+            Some(Ok(parent)) => Some(parent),
+            Some(Err(err)) => return Err(err)
+        };
+        Ok(Location {
+            id: id,
+            name: name,
+            parent: parent,
+        })
+    }
+}
+
+impl ToJSON for Location {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("id", self.id.to_json()),
+            ("name", self.name.to_json()),
+            ("parent", self.parent.to_json()),
+        ].to_json()
+    }
+}
+
+/// A tree of locations.
+///
+/// The tree is guaranteed to be consistent, i.e. the parent of each location is part of
+/// the tree and there are no cycles.
+///
+/// # JSON
+///
+/// A tree is represented as an array of `Location`, in no specific order.
+#[derive(Debug, Clone, Default)]
+pub struct LocationTree {
+    locations: HashMap<Id<LocationId>, Location>,
+}
+
+impl LocationTree {
+    /// Create an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a tree from a set of locations, e.g. for importing a tree from JSON.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if two locations have the same id, if the parent of a location
+    /// is not part of `locations` or if there is a cycle.
+    pub fn from_locations(locations: Vec<Location>) -> Result<Self, Error> {
+        let mut tree = LocationTree::new();
+        for location in locations {
+            if tree.locations.contains_key(&location.id) {
+                return Err(Error::InternalError(InternalError::DuplicateLocation(location.id)));
+            }
+            tree.locations.insert(location.id.clone(), location);
+        }
+        for location in tree.locations.values() {
+            if let Some(ref parent) = location.parent {
+                if !tree.locations.contains_key(parent) {
+                    return Err(Error::InternalError(InternalError::NoSuchLocation(parent.clone())));
+                }
+            }
+            // Make sure that we reach the root in at most `len` steps.
+            let mut current = location.parent.as_ref();
+            let mut steps = 0;
+            while let Some(id) = current {
+                if steps >= tree.locations.len() {
+                    return Err(Error::InternalError(InternalError::LocationCycle(location.id.clone())));
+                }
+                steps += 1;
+                current = tree.locations.get(id).and_then(|location| location.parent.as_ref());
+            }
+        }
+        Ok(tree)
+    }
+
+    /// Get a location by id.
+    pub fn get(&self, id: &Id<LocationId>) -> Option<&Location> {
+        self.locations.get(id)
+    }
+
+    /// `true` if the tree contains a location with this id.
+    pub fn contains(&self, id: &Id<LocationId>) -> bool {
+        self.locations.contains_key(id)
+    }
+
+    /// All the locations of the tree, in no specific order.
+    pub fn locations(&self) -> Vec<Location> {
+        self.locations.values().cloned().collect()
+    }
+
+    /// Add a location to the tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a location with the same id already exists or if the parent
+    /// of `location` is not part of the tree.
+    pub fn add(&mut self, location: Location) -> Result<(), Error> {
+        if self.locations.contains_key(&location.id) {
+            return Err(Error::InternalError(InternalError::DuplicateLocation(location.id)));
+        }
+        if let Some(ref parent) = location.parent {
+            if !self.locations.contains_key(parent) {
+                return Err(Error::InternalError(InternalError::NoSuchLocation(parent.clone())));
+            }
+        }
+        self.locations.insert(location.id.clone(), location);
+        Ok(())
+    }
+
+    /// Remove a location from the tree. The children of this location are attached to
+    /// its parent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no such location.
+    pub fn remove(&mut self, id: &Id<LocationId>) -> Result<Location, Error> {
+        let removed = match self.locations.remove(id) {
+            None => return Err(Error::InternalError(InternalError::NoSuchLocation(id.clone()))),
+            Some(removed) => removed
+        };
+        for location in self.locations.values_mut() {
+            if location.parent.as_ref() == Some(id) {
+                location.parent = removed.parent.clone();
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The ancestors of location `id`, from its parent to the root.
+    pub fn ancestors(&self, id: &Id<LocationId>) -> Vec<Id<LocationId>> {
+        let mut result = vec![];
+        let mut current = self.locations.get(id).and_then(|location| location.parent.as_ref());
+        while let Some(id) = current {
+            result.push(id.clone());
+            current = self.locations.get(id).and_then(|location| location.parent.as_ref());
+        }
+        result
+    }
+
+    /// `true` if location `id` is `ancestor` or one of its descendants.
+    pub fn is_within(&self, id: &Id<LocationId>, ancestor: &Id<LocationId>) -> bool {
+        let mut current = Some(id);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self.locations.get(id).and_then(|location| location.parent.as_ref());
+        }
+        false
+    }
+}
+
+impl ToJSON for LocationTree {
+    fn to_json(&self) -> JSON {
+        JSON::Array(self.locations.values().map(|location| location.to_json()).collect())
+    }
+}
+
+#[test]
+fn test_location_tree() {
+    let home = Id::<LocationId>::new("home");
+    let ground_floor = Id::<LocationId>::new("ground floor");
+    let kitchen = Id::<LocationId>::new("kitchen");
+    let garden = Id::<LocationId>::new("garden");
+
+    let mut tree = LocationTree::from_locations(vec![
+        Location::new(kitchen.clone(), Some(ground_floor.clone())),
+        Location::new(ground_floor.clone(), Some(home.clone())),
+        Location::new(home.clone(), None),
+    ]).unwrap();
+    tree.add(Location::new(garden.clone(), None)).unwrap();
+
+    assert!(tree.is_within(&kitchen, &home));
+    assert!(tree.is_within(&kitchen, &kitchen));
+    assert!(!tree.is_within(&home, &kitchen));
+    assert!(!tree.is_within(&kitchen, &garden));
+
+    match tree.add(Location::new(garden.clone(), None)) {
+        Err(Error::InternalError(InternalError::DuplicateLocation(ref id))) if *id == garden => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match tree.add(Location::new(Id::new("shed"), Some(Id::new("backyard")))) {
+        Err(Error::InternalError(InternalError::NoSuchLocation(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    // Removing a location attaches its children to its parent.
+    tree.remove(&ground_floor).unwrap();
+    assert_eq!(tree.get(&kitchen).unwrap().parent, Some(home.clone()));
+    assert!(tree.is_within(&kitchen, &home));
+
+    // Cycles are rejected.
+    match LocationTree::from_locations(vec![
+        Location::new(home.clone(), Some(kitchen.clone())),
+        Location::new(kitchen.clone(), Some(home.clone())),
+    ]) {
+        Err(Error::InternalError(InternalError::LocationCycle(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+}
@@ -6,14 +6,21 @@
 
 pub use adapter::*;
 use api;
-use api::{ API, Error, TargetMap, User };
+use api::{ API, Error, LeaseId, Principal, TargetMap, Targetted };
+use audit::*;
 use backend::*;
+use conditions::*;
+use leases::Lease;
+use locations::Location;
+use profiles::Profile;
 use selector::*;
 use services::*;
+use streams::{ Stream, StreamId };
 use util::is_sync;
-use values::{ Range, TypeError, Value };
+use values::{ Duration, HomeTimeZone, Range, Type, TypeError, Value };
 
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
+use std::path::Path;
 use std::sync::{ Arc, Mutex, Weak };
 use std::sync::atomic::{ AtomicBool, Ordering };
 use std::thread;
@@ -35,6 +42,12 @@ pub struct AdapterManager {
     back_end: Arc<MainLock<State>>,
 
     tx_watch: Arc<Mutex<RawSender<WatchOp>>>,
+
+    /// If specified, the log in which state-changing operations are recorded.
+    audit: Option<Arc<AuditLog>>,
+
+    /// The latest values fetched from getters, used to evaluate preconditions.
+    cache: ValueCache,
 }
 
 impl AdapterManager {
@@ -49,6 +62,77 @@ impl AdapterManager {
         AdapterManager {
             back_end: state,
             tx_watch: tx_watch,
+            audit: None,
+            cache: ValueCache::new(),
+        }
+    }
+
+    /// Create an empty `AdapterManager` recording state-changing operations in an audit log.
+    pub fn with_audit_log(audit: Arc<AuditLog>) -> Self {
+        AdapterManager {
+            audit: Some(audit),
+            ..Self::new()
+        }
+    }
+
+    /// Load the user properties of services and channels from a file, and save them to this
+    /// file whenever they change. This should be called before adding adapters, as stored
+    /// properties are only applied to services and channels registered afterwards.
+    pub fn open_user_properties<P>(&self, path: P) -> Result<(), Error> where P: AsRef<Path> {
+        self.back_end.write().unwrap().open_user_properties(path)
+    }
+
+    /// Forget the cached values of the getters that are not registered anymore.
+    fn evict_removed_getters(&self) {
+        let back_end = self.back_end.read().unwrap();
+        self.cache.retain(|id| back_end.has_getter(id));
+    }
+
+    /// Record the registration or unregistration of an adapter, service or channel.
+    fn audit_registration(&self, operation: Operation, target: AuditTarget, error: Option<&Error>) {
+        if let Some(ref audit) = self.audit {
+            // Registrations are performed by adapters, i.e. from within the system.
+            audit.record(Principal::anonymous(), operation, vec![AuditResult::new(target, None, error.cloned())]);
+        }
+    }
+
+    /// If we keep an audit log, determine the targets of an operation before it takes place.
+    fn audit_targets<F>(&self, cb: F) -> Option<Vec<AuditTarget>> where F: FnOnce(&State) -> Vec<AuditTarget> {
+        match self.audit {
+            None => None,
+            Some(_) => Some(cb(&*self.back_end.read().unwrap()))
+        }
+    }
+
+    fn audit_service_targets(&self, selectors: &[ServiceSelector]) -> Option<Vec<AuditTarget>> {
+        self.audit_targets(|state| {
+            state.get_services(selectors.to_vec()).into_iter()
+                .map(|service| AuditTarget::Service(service.id))
+                .collect()
+        })
+    }
+
+    fn audit_getter_targets(&self, selectors: &[GetterSelector]) -> Option<Vec<AuditTarget>> {
+        self.audit_targets(|state| {
+            state.get_getter_channels(selectors.to_vec()).into_iter()
+                .map(|channel| AuditTarget::Getter(channel.id))
+                .collect()
+        })
+    }
+
+    fn audit_setter_targets(&self, selectors: &[SetterSelector]) -> Option<Vec<AuditTarget>> {
+        self.audit_targets(|state| {
+            state.get_setter_channels(selectors.to_vec()).into_iter()
+                .map(|channel| AuditTarget::Setter(channel.id))
+                .collect()
+        })
+    }
+
+    /// Record an edit of tags on targets previously determined with `audit_*_targets`.
+    fn audit_tags(&self, operation: Operation, targets: Option<Vec<AuditTarget>>, user: Principal) {
+        if let (Some(audit), Some(targets)) = (self.audit.as_ref(), targets) {
+            let results = targets.into_iter().map(|target| AuditResult::new(target, None, None)).collect();
+            audit.record(user, operation, results);
         }
     }
 }
@@ -66,7 +150,10 @@ impl AdapterManagerHandle for AdapterManager {
     ///
     /// Returns an error if an adapter with the same id is already present.
     fn add_adapter(&self, adapter: Arc<Adapter>) -> Result<(), Error> {
-        self.back_end.write().unwrap().add_adapter(adapter)
+        let id = adapter.id();
+        let result = self.back_end.write().unwrap().add_adapter(adapter);
+        self.audit_registration(Operation::AddAdapter, AuditTarget::Adapter(id), result.as_ref().err());
+        result
     }
 
     /// Remove an adapter from the system, including all its services and channels.
@@ -77,7 +164,12 @@ impl AdapterManagerHandle for AdapterManager {
     /// to cleanup as much as possible, even if for some reason the system is in an
     /// inconsistent state.
     fn remove_adapter(&self, id: &Id<AdapterId>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_adapter(id)
+        let result = self.back_end.write().unwrap().remove_adapter(id);
+        if result.is_ok() {
+            self.evict_removed_getters();
+        }
+        self.audit_registration(Operation::RemoveAdapter, AuditTarget::Adapter(id.clone()), result.as_ref().err());
+        result
     }
 
     /// Add a service to the system. Called by the adapter when a new
@@ -95,14 +187,46 @@ impl AdapterManagerHandle for AdapterManager {
     /// Returns an error if any of:
     /// - `service` has channels;
     /// - a service with id `service.id` is already installed on the system;
-    /// - there is no adapter with id `service.adapter`.
+    /// - there is no adapter with id `service.adapter`;
+    /// - `service.parent` is not installed on the system or belongs to another adapter.
     fn add_service(&self, service: Service) -> Result<(), Error> {
-        self.back_end.write().unwrap().add_service(service)
+        let id = service.id.clone();
+        let result = self.back_end.write().unwrap().add_service(service);
+        self.audit_registration(Operation::AddService, AuditTarget::Service(id), result.as_ref().err());
+        result
+    }
+
+    /// Add a service to the system, along with all its channels. Called by the adapter
+    /// when a new service (typically a new device) has been detected/configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of:
+    /// - a service with id `service.id` is already installed on the system;
+    /// - there is no adapter with id `service.adapter`;
+    /// - a channel with the same identifier as one of the channels is already registered;
+    /// - one of the channels has a `service` or an `adapter` that doesn't match `service`;
+    /// - `service.parent` is not installed on the system or belongs to another adapter.
+    fn add_service_with_channels(&self, service: Service) -> Result<(), Error> {
+        let id = service.id.clone();
+        let result = {
+            // Acquire and release lock asap.
+            self.back_end.write().unwrap().add_service_with_channels(service)
+        };
+        self.audit_registration(Operation::AddService, AuditTarget::Service(id), result.as_ref().err());
+        let request = try!(result);
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.add_service_with_channels => need to register watches");
+        }
+        self.register_watches(request);
+        Ok(())
     }
 
     /// Remove a service previously registered on the system. Typically, called by
     /// an adapter when a service (e.g. a device) is disconnected.
     ///
+    /// The children of the service (see `Service::parent`) are removed along with it.
+    ///
     /// # Error
     ///
     /// Returns an error if any of:
@@ -110,7 +234,33 @@ impl AdapterManagerHandle for AdapterManager {
     /// - there is an internal inconsistency, in which case this method will still attempt to
     /// cleanup before returning an error.
     fn remove_service(&self, id: &Id<ServiceId>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_service(id)
+        let result = self.back_end.write().unwrap().remove_service(id);
+        if result.is_ok() {
+            self.evict_removed_getters();
+        }
+        self.audit_registration(Operation::RemoveService, AuditTarget::Service(id.clone()), result.as_ref().err());
+        result
+    }
+
+    /// Update the tags and properties of a service previously registered on the system.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the service is not registered or if `service.adapter` doesn't
+    /// match the adapter of the service.
+    fn update_service(&self, service: Service) -> Result<(), Error> {
+        let id = service.id.clone();
+        let result = {
+            // Acquire and release lock asap.
+            self.back_end.write().unwrap().update_service(service)
+        };
+        self.audit_registration(Operation::UpdateService, AuditTarget::Service(id), result.as_ref().err());
+        let request = try!(result);
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.update_service => need to register watches");
+        }
+        self.register_watches(request);
+        Ok(())
     }
 
     /// Add a setter to the system. Typically, this is called by the adapter when a new
@@ -127,10 +277,13 @@ impl AdapterManagerHandle for AdapterManager {
     /// registered, or a channel with the same identifier is already registered.
     /// In either cases, this method reverts all its changes.
     fn add_getter(&self, getter: Channel<Getter>) -> Result<(), Error> {
-        let request = {
+        let id = getter.id.clone();
+        let result = {
             // Acquire and release lock asap.
-            try!(self.back_end.write().unwrap().add_getter(getter))
+            self.back_end.write().unwrap().add_getter(getter)
         };
+        self.audit_registration(Operation::AddGetter, AuditTarget::Getter(id), result.as_ref().err());
+        let request = try!(result);
         if !request.is_empty() {
             debug!(target: "Taxonomy-manager", "manager.add_getter => need to register watches");
         }
@@ -147,7 +300,33 @@ impl AdapterManagerHandle for AdapterManager {
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
     fn remove_getter(&self, id: &Id<Getter>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_getter(id)
+        let result = self.back_end.write().unwrap().remove_getter(id);
+        if result.is_ok() {
+            self.cache.remove(id);
+        }
+        self.audit_registration(Operation::RemoveGetter, AuditTarget::Getter(id.clone()), result.as_ref().err());
+        result
+    }
+
+    /// Update a getter previously registered on the system, preserving ongoing watches.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the getter is not registered or if its service or adapter
+    /// have changed.
+    fn update_getter(&self, getter: Channel<Getter>) -> Result<(), Error> {
+        let id = getter.id.clone();
+        let result = {
+            // Acquire and release lock asap.
+            self.back_end.write().unwrap().update_getter(getter)
+        };
+        self.audit_registration(Operation::UpdateGetter, AuditTarget::Getter(id), result.as_ref().err());
+        let request = try!(result);
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.update_getter => need to register watches");
+        }
+        self.register_watches(request);
+        Ok(())
     }
 
     /// Add a setter to the system. Typically, this is called by the adapter when a new
@@ -164,7 +343,10 @@ impl AdapterManagerHandle for AdapterManager {
     /// registered, or a channel with the same identifier is already registered.
     /// In either cases, this method reverts all its changes.
     fn add_setter(&self, setter: Channel<Setter>) -> Result<(), Error> {
-        self.back_end.write().unwrap().add_setter(setter)
+        let id = setter.id.clone();
+        let result = self.back_end.write().unwrap().add_setter(setter);
+        self.audit_registration(Operation::AddSetter, AuditTarget::Setter(id), result.as_ref().err());
+        result
     }
 
     /// Remove a setter previously registered on the system. Typically, called by
@@ -176,7 +358,49 @@ impl AdapterManagerHandle for AdapterManager {
     /// is not registered. In either case, it attemps to clean as much as possible, even
     /// if the state is inconsistent.
     fn remove_setter(&self, id: &Id<Setter>) -> Result<(), Error> {
-        self.back_end.write().unwrap().remove_setter(id)
+        let result = self.back_end.write().unwrap().remove_setter(id);
+        self.audit_registration(Operation::RemoveSetter, AuditTarget::Setter(id.clone()), result.as_ref().err());
+        result
+    }
+
+    /// Update a setter previously registered on the system.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the setter is not registered or if its service or adapter
+    /// have changed.
+    fn update_setter(&self, setter: Channel<Setter>) -> Result<(), Error> {
+        let id = setter.id.clone();
+        let result = self.back_end.write().unwrap().update_setter(setter);
+        self.audit_registration(Operation::UpdateSetter, AuditTarget::Setter(id), result.as_ref().err());
+        result
+    }
+
+    /// Add an action to the system.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the adapter is not registered, the parent service is not
+    /// registered, or an action with the same identifier is already registered.
+    /// In either cases, this method reverts all its changes.
+    fn add_action(&self, action: Channel<Action>) -> Result<(), Error> {
+        let id = action.id.clone();
+        let result = self.back_end.write().unwrap().add_action(action);
+        self.audit_registration(Operation::AddAction, AuditTarget::Action(id), result.as_ref().err());
+        result
+    }
+
+    /// Remove an action previously registered on the system.
+    ///
+    /// # Error
+    ///
+    /// This method returns an error if the action is not registered or if the service
+    /// is not registered. In either case, it attemps to clean as much as possible, even
+    /// if the state is inconsistent.
+    fn remove_action(&self, id: &Id<Action>) -> Result<(), Error> {
+        let result = self.back_end.write().unwrap().remove_action(id);
+        self.audit_registration(Operation::RemoveAction, AuditTarget::Action(id.clone()), result.as_ref().err());
+        result
     }
 }
 
@@ -204,9 +428,13 @@ impl API for AdapterManager {
     ///
     /// Note that this call is _not live_. In other words, if services
     /// are added after the call, they will not be affected.
-    fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().add_service_tags(selectors, tags)
+    fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_service_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
+        let result = self.back_end.write().unwrap().add_service_tags(selectors, tags);
         // FIXME: This can cause watcher registrations
+        self.audit_tags(operation, targets, user);
+        result
     }
 
     /// Remove a set of tags from a set of services.
@@ -222,8 +450,12 @@ impl API for AdapterManager {
     ///
     /// Note that this call is _not live_. In okther words, if services
     /// are added after the call, they will not be affected.
-    fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_service_tags(selectors, tags)
+    fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_service_targets(&selectors);
+        let operation = Operation::RemoveTags(tags.clone());
+        let result = self.back_end.write().unwrap().remove_service_tags(selectors, tags);
+        self.audit_tags(operation, targets, user);
+        result
     }
 
     /// Get a list of channels matching some conditions
@@ -233,6 +465,23 @@ impl API for AdapterManager {
     fn get_setter_channels(&self, selectors: Vec<SetterSelector>) -> Vec<Channel<Setter>> {
         self.back_end.read().unwrap().get_setter_channels(selectors)
     }
+    fn get_action_channels(&self, selectors: Vec<ActionSelector>) -> Vec<Channel<Action>> {
+        self.back_end.read().unwrap().get_action_channels(selectors)
+    }
+
+    /// Explain which criteria of a selector accept or reject each service or channel.
+    fn explain_services(&self, selector: ServiceSelector) -> Vec<Explanation<ServiceId>> {
+        self.back_end.read().unwrap().explain_services(selector)
+    }
+    fn explain_getters(&self, selector: GetterSelector) -> Vec<Explanation<Getter>> {
+        self.back_end.read().unwrap().explain_getters(selector)
+    }
+    fn explain_setters(&self, selector: SetterSelector) -> Vec<Explanation<Setter>> {
+        self.back_end.read().unwrap().explain_setters(selector)
+    }
+    fn explain_actions(&self, selector: ActionSelector) -> Vec<Explanation<Action>> {
+        self.back_end.read().unwrap().explain_actions(selector)
+    }
 
     /// Label a set of channels with a set of tags.
     ///
@@ -247,7 +496,9 @@ impl API for AdapterManager {
     ///
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
-    fn add_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> usize {
+    fn add_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_getter_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
         let (request, result) = {
             // Acquire and release the write lock.
             self.back_end.write().unwrap().add_getter_tags(selectors, tags)
@@ -256,10 +507,15 @@ impl API for AdapterManager {
             debug!(target: "Taxonomy-manager", "manager.add_getter_tags => need to register watches");
         }
         self.register_watches(request);
+        self.audit_tags(operation, targets, user);
         result
     }
-    fn add_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().add_setter_tags(selectors, tags)
+    fn add_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_setter_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
+        let result = self.back_end.write().unwrap().add_setter_tags(selectors, tags);
+        self.audit_tags(operation, targets, user);
+        result
     }
 
     /// Remove a set of tags from a set of channels.
@@ -275,15 +531,200 @@ impl API for AdapterManager {
     ///
     /// Note that this call is _not live_. In other words, if channels
     /// are added after the call, they will not be affected.
-    fn remove_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_getter_tags(selectors, tags)
+    fn remove_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_getter_targets(&selectors);
+        let operation = Operation::RemoveTags(tags.clone());
+        let result = self.back_end.write().unwrap().remove_getter_tags(selectors, tags);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+    fn remove_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, user: Principal) -> usize {
+        let targets = self.audit_setter_targets(&selectors);
+        let operation = Operation::RemoveTags(tags.clone());
+        let result = self.back_end.write().unwrap().remove_setter_tags(selectors, tags);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+
+    fn add_service_tags_with_origin(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_service_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
+        let result = self.back_end.write().unwrap().add_service_tags_with_origin(selectors, tags, origin);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+    fn add_getter_tags_with_origin(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_getter_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().add_getter_tags_with_origin(selectors, tags, origin)
+        };
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.add_getter_tags_with_origin => need to register watches");
+        }
+        self.register_watches(request);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+    fn add_setter_tags_with_origin(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_setter_targets(&selectors);
+        let operation = Operation::AddTags(tags.clone());
+        let result = self.back_end.write().unwrap().add_setter_tags_with_origin(selectors, tags, origin);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+
+    fn remove_service_tags_by_origin(&self, selectors: Vec<ServiceSelector>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_service_targets(&selectors);
+        let operation = Operation::RemoveTagsByOrigin(origin.clone());
+        let result = self.back_end.write().unwrap().remove_service_tags_by_origin(selectors, origin);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+    fn remove_getter_tags_by_origin(&self, selectors: Vec<GetterSelector>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_getter_targets(&selectors);
+        let operation = Operation::RemoveTagsByOrigin(origin.clone());
+        let result = self.back_end.write().unwrap().remove_getter_tags_by_origin(selectors, origin);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+    fn add_service_tags_dry_run(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_service_tags(selectors, &tags, true)
+    }
+    fn add_getter_tags_dry_run(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_getter_tags(selectors, &tags, true)
+    }
+    fn add_setter_tags_dry_run(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_setter_tags(selectors, &tags, true)
+    }
+    fn remove_service_tags_dry_run(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_service_tags(selectors, &tags, false)
+    }
+    fn remove_getter_tags_dry_run(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_getter_tags(selectors, &tags, false)
+    }
+    fn remove_setter_tags_dry_run(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>> {
+        self.back_end.read().unwrap().preview_setter_tags(selectors, &tags, false)
+    }
+
+    fn remove_setter_tags_by_origin(&self, selectors: Vec<SetterSelector>, origin: TagOrigin, user: Principal) -> usize {
+        let targets = self.audit_setter_targets(&selectors);
+        let operation = Operation::RemoveTagsByOrigin(origin.clone());
+        let result = self.back_end.write().unwrap().remove_setter_tags_by_origin(selectors, origin);
+        self.audit_tags(operation, targets, user);
+        result
+    }
+
+    fn get_profiles(&self) -> Vec<Profile> {
+        self.back_end.read().unwrap().get_profiles()
+    }
+
+    fn add_profile(&self, profile: Profile) -> Result<(), Error> {
+        self.back_end.write().unwrap().add_profile(profile)
+    }
+
+    fn remove_profile(&self, id: &Id<ProfileId>) -> Result<bool, Error> {
+        self.back_end.write().unwrap().remove_profile(id)
+    }
+
+    fn get_home_timezone(&self) -> HomeTimeZone {
+        self.back_end.read().unwrap().get_home_timezone()
+    }
+
+    fn set_home_timezone(&self, timezone: HomeTimeZone) -> Result<(), Error> {
+        self.back_end.write().unwrap().set_home_timezone(timezone)
+    }
+
+    fn get_locations(&self) -> Vec<Location> {
+        self.back_end.read().unwrap().get_locations()
+    }
+
+    fn add_location(&self, location: Location) -> Result<(), Error> {
+        self.back_end.write().unwrap().add_location(location)
+    }
+
+    fn remove_location(&self, id: &Id<LocationId>) -> Result<(), Error> {
+        self.back_end.write().unwrap().remove_location(id)
+    }
+
+    fn import_locations(&self, locations: Vec<Location>) -> Result<(), Error> {
+        self.back_end.write().unwrap().import_locations(locations)
+    }
+
+    fn set_service_location(&self, selectors: Vec<ServiceSelector>, location: Option<Id<LocationId>>) -> Result<usize, Error> {
+        self.back_end.write().unwrap().set_service_location(selectors, location)
+    }
+
+    /// List the tags currently used by services and channels, or with metadata attached.
+    fn get_tags(&self) -> Vec<TagInfo> {
+        self.back_end.read().unwrap().get_tags()
+    }
+
+    /// Rename a tag on all services and channels, atomically.
+    fn rename_tag(&self, from: &Id<TagId>, to: &Id<TagId>, user: Principal) -> usize {
+        let targets = self.audit_targets(|state| {
+            if from == to {
+                return vec![];
+            }
+            let services = state.get_services(vec![ServiceSelector::new().with_tags(vec![from.clone()])]).into_iter()
+                .map(|service| AuditTarget::Service(service.id));
+            let getters = state.get_getter_channels(vec![GetterSelector::new().with_tags(vec![from.clone()])]).into_iter()
+                .map(|channel| AuditTarget::Getter(channel.id));
+            let setters = state.get_setter_channels(vec![SetterSelector::new().with_tags(vec![from.clone()])]).into_iter()
+                .map(|channel| AuditTarget::Setter(channel.id));
+            services.chain(getters).chain(setters).collect()
+        });
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().rename_tag(from, to)
+        };
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.rename_tag => need to register watches");
+        }
+        self.register_watches(request);
+        self.audit_tags(Operation::RenameTag { from: from.clone(), to: to.clone() }, targets, user);
+        result
+    }
+
+    fn set_tag_metadata(&self, tag: Id<TagId>, metadata: TagMetadata) {
+        self.back_end.write().unwrap().set_tag_metadata(tag, metadata)
+    }
+
+    fn remove_tag_metadata(&self, tag: &Id<TagId>) -> bool {
+        self.back_end.write().unwrap().remove_tag_metadata(tag)
+    }
+
+    /// Set or remove user properties on a set of services or channels.
+    fn set_service_user_properties(&self, selectors: Vec<ServiceSelector>, properties: HashMap<String, String>) -> usize {
+        self.back_end.write().unwrap().set_service_user_properties(selectors, properties)
+    }
+    fn remove_service_user_properties(&self, selectors: Vec<ServiceSelector>, keys: Vec<String>) -> usize {
+        self.back_end.write().unwrap().remove_service_user_properties(selectors, keys)
+    }
+    fn set_getter_user_properties(&self, selectors: Vec<GetterSelector>, properties: HashMap<String, String>) -> usize {
+        let (request, result) = {
+            // Acquire and release the write lock.
+            self.back_end.write().unwrap().set_getter_user_properties(selectors, properties)
+        };
+        if !request.is_empty() {
+            debug!(target: "Taxonomy-manager", "manager.set_getter_user_properties => need to register watches");
+        }
+        self.register_watches(request);
+        result
+    }
+    fn set_setter_user_properties(&self, selectors: Vec<SetterSelector>, properties: HashMap<String, String>) -> usize {
+        self.back_end.write().unwrap().set_setter_user_properties(selectors, properties)
+    }
+    fn remove_getter_user_properties(&self, selectors: Vec<GetterSelector>, keys: Vec<String>) -> usize {
+        self.back_end.write().unwrap().remove_getter_user_properties(selectors, keys)
     }
-    fn remove_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> usize {
-        self.back_end.write().unwrap().remove_setter_tags(selectors, tags)
+    fn remove_setter_user_properties(&self, selectors: Vec<SetterSelector>, keys: Vec<String>) -> usize {
+        self.back_end.write().unwrap().remove_setter_user_properties(selectors, keys)
     }
 
     /// Read the latest value from a set of channels
-    fn fetch_values(&self, selectors: Vec<GetterSelector>, user: User) ->
+    fn fetch_values(&self, selectors: Vec<GetterSelector>, user: Principal) ->
         ResultMap<Id<Getter>, Option<Value>, Error>
     {
         // First, prepare the request.
@@ -294,7 +735,11 @@ impl API for AdapterManager {
         }
         // Now fetch the values
         let mut results = HashMap::new();
-        for (_, (adapter, mut getters)) in request.drain() {
+        for (_, (adapter, (mut getters, failures))) in request.drain() {
+            results.extend(failures);
+            if getters.is_empty() {
+                continue;
+            }
             let (getters, mut types) : (Vec<_>, Vec<_>) = getters.drain().unzip();
             let mut got = adapter
                 .fetch_values(getters, user.clone());
@@ -318,31 +763,276 @@ impl API for AdapterManager {
 
             results.extend(checked);
         }
+
+        // Remember the values, for the sake of preconditions.
+        for (id, result) in &results {
+            if let Ok(Some(ref value)) = *result {
+                self.cache.store(id.clone(), value.clone());
+            }
+        }
+        results
+    }
+
+    /// Fetch the values of a set of getters, using the values fetched recently enough, if any.
+    /// Note that the cache is shared by all principals, see `API::fetch_cached_values`.
+    fn fetch_cached_values(&self, selectors: Vec<GetterSelector>, max_age: Duration, user: Principal) ->
+        ResultMap<Id<Getter>, Option<Value>, Error>
+    {
+        let channels = self.back_end.read().unwrap().get_getter_channels(selectors);
+        let mut results = HashMap::new();
+        let mut to_fetch = vec![];
+        for channel in channels {
+            match self.cache.get(&channel.id, &max_age) {
+                Some(value) => {
+                    results.insert(channel.id, Ok(Some(value)));
+                }
+                None => to_fetch.push(GetterSelector::new().with_id(channel.id))
+            }
+        }
+        if !to_fetch.is_empty() {
+            results.extend(self.fetch_values(to_fetch, user));
+        }
         results
     }
 
     /// Send a bunch of values to a set of channels
-    fn send_values(&self, keyvalues: TargetMap<SetterSelector, Value>, user: User) ->
+    fn send_values(&self, keyvalues: TargetMap<SetterSelector, Value>, user: Principal) ->
         ResultMap<Id<Setter>, (), Error>
     {
         // First, prepare the request.
         let mut prepared;
         {
             // Make sure that the lock is released asap.
-            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues);
+            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues, &user);
         }
 
         // Dispatch to adapter
         let mut results = HashMap::new();
+        let mut sent = HashMap::new();
         for (_, (adapter, (request, failures))) in prepared.drain() {
+            if self.audit.is_some() {
+                sent.extend(request.iter().map(|(id, value)| (id.clone(), value.clone())));
+            }
             let got = adapter.send_values(request, user.clone());
             results.extend(got);
             results.extend(failures);
         }
 
+        if let Some(ref audit) = self.audit {
+            let audited = results.iter().map(|(id, result)| {
+                AuditResult::new(AuditTarget::Setter(id.clone()), sent.remove(id), result.as_ref().err().cloned())
+            }).collect();
+            audit.record(user, Operation::SendValues, audited);
+        }
+
+        results
+    }
+
+    /// Determine what `send_values` would do, without contacting the adapters.
+    fn send_values_dry_run(&self, keyvalues: TargetMap<SetterSelector, Value>, user: Principal) ->
+        ResultMap<Id<Setter>, (), Error>
+    {
+        let mut prepared;
+        {
+            // Make sure that the lock is released asap.
+            prepared = self.back_end.read().unwrap().prepare_send_values(keyvalues, &user);
+        }
+
+        let mut results = HashMap::new();
+        for (_, (_, (request, failures))) in prepared.drain() {
+            results.extend(request.into_iter().map(|(id, _)| (id, Ok(()))));
+            results.extend(failures);
+        }
         results
     }
 
+    /// Send a bunch of values to a set of channels, if their preconditions hold.
+    fn send_values_if(&self, mut keyvalues: TargetMap<SetterSelector, Conditional>, user: Principal) ->
+        ResultMap<Id<Setter>, (), Error>
+    {
+        // First, determine the getters involved in each precondition.
+        let resolved : Vec<_> = {
+            // Acquire and release the read lock.
+            let state = self.back_end.read().unwrap();
+            keyvalues.drain(..).map(|Targetted { select, payload }| {
+                let getters : Vec<Vec<Id<Getter>>> = payload.preconditions.iter().map(|precondition| {
+                    state.get_getter_channels(precondition.select.clone()).into_iter()
+                        .map(|channel| channel.id)
+                        .collect()
+                }).collect();
+                (select, payload, getters)
+            }).collect()
+        };
+
+        // Look up the values cached recently enough, once, so that they cannot expire
+        // between the lookup and the evaluation, and fetch the others.
+        let mut to_fetch = HashSet::new();
+        let mut checks = Vec::with_capacity(resolved.len());
+        for (select, payload, getters) in resolved {
+            let mut cached = Vec::with_capacity(getters.len());
+            for (precondition, ids) in payload.preconditions.iter().zip(getters) {
+                let values : Vec<_> = ids.into_iter().map(|id| {
+                    let value = precondition.max_age.as_ref().and_then(|max_age| self.cache.get(&id, max_age));
+                    if value.is_none() {
+                        to_fetch.insert(id.clone());
+                    }
+                    (id, value)
+                }).collect();
+                cached.push(values);
+            }
+            checks.push((select, payload, cached));
+        }
+        let fresh = if to_fetch.is_empty() {
+            HashMap::new()
+        } else {
+            self.fetch_values(to_fetch.into_iter().map(|id| GetterSelector::new().with_id(id)).collect(), user.clone())
+        };
+
+        // Evaluate the preconditions.
+        let mut accepted = vec![];
+        let mut rejected = vec![];
+        for (select, payload, getters) in checks {
+            let mut failure = None;
+            for (precondition, ids) in payload.preconditions.iter().zip(getters) {
+                if ids.is_empty() {
+                    failure = Some(PreconditionFailure::NoSuchGetter);
+                }
+                for (id, cached) in ids {
+                    if failure.is_some() {
+                        break;
+                    }
+                    let value = match fresh.get(&id) {
+                        Some(&Ok(Some(ref value))) => Ok(value.clone()),
+                        Some(&Ok(None)) => Err(None),
+                        Some(&Err(ref err)) => Err(Some(Box::new(err.clone()))),
+                        None => cached.ok_or(None)
+                    };
+                    failure = match value {
+                        Err(err) => Some(PreconditionFailure::NoValue(id, err)),
+                        Ok(ref value) if !precondition.range.contains(value) =>
+                            Some(PreconditionFailure::OutOfRange(id, value.clone())),
+                        Ok(_) => None
+                    };
+                }
+                if failure.is_some() {
+                    break;
+                }
+            }
+            match failure {
+                None => accepted.push(Targetted::new(select, payload.value)),
+                Some(failure) => rejected.push((select, failure))
+            }
+        }
+
+        // Dispatch the values whose preconditions hold.
+        let mut results = if accepted.is_empty() {
+            HashMap::new()
+        } else {
+            self.send_values(accepted, user.clone())
+        };
+        if !rejected.is_empty() {
+            let mut audited = vec![];
+            {
+                // Acquire and release the read lock.
+                let state = self.back_end.read().unwrap();
+                for (select, failure) in rejected {
+                    for channel in state.get_setter_channels(select) {
+                        if results.contains_key(&channel.id) {
+                            continue;
+                        }
+                        let error = Error::PreconditionFailed(failure.clone());
+                        audited.push(AuditResult::new(AuditTarget::Setter(channel.id.clone()), None, Some(error.clone())));
+                        results.insert(channel.id, Err(error));
+                    }
+                }
+            }
+            if let Some(ref audit) = self.audit {
+                if !audited.is_empty() {
+                    audit.record(user, Operation::SendValues, audited);
+                }
+            }
+        }
+        results
+    }
+
+    fn acquire_lease(&self, selectors: Vec<SetterSelector>, duration: Duration, priority: i32, user: Principal) -> Result<Lease, Error> {
+        self.back_end.write().unwrap().acquire_lease(selectors, duration, priority, user)
+    }
+
+    fn release_lease(&self, id: &Id<LeaseId>, user: Principal) -> usize {
+        self.back_end.write().unwrap().release_lease(id, &user)
+    }
+
+    /// Invoke a set of actions
+    fn invoke(&self, arguments: TargetMap<ActionSelector, Value>, user: Principal) ->
+        ResultMap<Id<Action>, Value, Error>
+    {
+        // First, prepare the request.
+        let mut prepared;
+        {
+            // Make sure that the lock is released asap.
+            prepared = self.back_end.read().unwrap().prepare_invoke(arguments);
+        }
+
+        // Dispatch to adapter
+        let mut results = HashMap::new();
+        let mut sent = HashMap::new();
+        for (_, (adapter, (request, result_types, failures))) in prepared.drain() {
+            if self.audit.is_some() {
+                sent.extend(request.iter().map(|(id, value)| (id.clone(), value.clone())));
+            }
+            let mut got = adapter.invoke(request, user.clone());
+            let checked = got.drain()
+                .map(|(id, result)| {
+                    let result = match (result, result_types.get(&id)) {
+                        (Ok(value), Some(typ)) if value.get_type() != *typ => {
+                            Err(Error::TypeError(TypeError {
+                                expected: typ.clone(),
+                                got: value.get_type()
+                            }))
+                        }
+                        (result, _) => result
+                    };
+                    (id, result)
+                });
+            results.extend(checked);
+            results.extend(failures);
+        }
+
+        if let Some(ref audit) = self.audit {
+            let audited = results.iter().map(|(id, result)| {
+                AuditResult::new(AuditTarget::Action(id.clone()), sent.remove(id), result.as_ref().err().cloned())
+            }).collect();
+            audit.record(user, Operation::Invoke, audited);
+        }
+
+        results
+    }
+
+    /// Open the content of a stream produced by a getter.
+    fn open_stream(&self, getter: &Id<Getter>, stream: &Id<StreamId>, user: Principal) -> Result<Stream, Error> {
+        let adapter = {
+            // Acquire and release lock asap.
+            try!(self.back_end.read().unwrap().prepare_open_stream(getter))
+        };
+        adapter.open_stream(getter, stream, user)
+    }
+
+    /// Fetch the latest snapshot of a getter and open its content.
+    fn fetch_snapshot(&self, getter: &Id<Getter>, user: Principal) -> Result<Stream, Error> {
+        let mut values = self.fetch_values(vec![GetterSelector::new().with_id(getter.clone())], user.clone());
+        match values.remove(getter) {
+            None => Err(Error::InternalError(api::InternalError::NoSuchGetter(getter.clone()))),
+            Some(Err(err)) => Err(err),
+            Some(Ok(None)) => Err(Error::InternalError(api::InternalError::NoSnapshot(getter.clone()))),
+            Some(Ok(Some(Value::Stream(stream)))) => self.open_stream(getter, &stream.id, user),
+            Some(Ok(Some(value))) => Err(Error::TypeError(TypeError {
+                expected: Type::Stream,
+                got: value.get_type()
+            }))
+        }
+    }
+
     /// Watch for any change
     fn watch_values(&self, watch: TargetMap<GetterSelector, Exactly<Range>>,
         on_event: Box<ExtSender<api::WatchEvent>>) -> Self::WatchGuard
@@ -362,6 +1052,40 @@ impl API for AdapterManager {
         WatchGuard::new(self.tx_watch.lock().unwrap().internal_clone(), watch_key, is_dropped)
     }
 
+    fn get_audit_entries(&self, query: AuditQuery) -> Vec<AuditEntry> {
+        let audit = match self.audit {
+            None => return vec![],
+            Some(ref audit) => audit
+        };
+        if !query.has_selectors() {
+            return audit.query(&query, None);
+        }
+
+        // Resolve the selectors into the set of ids they currently match.
+        let mut targets = HashSet::new();
+        {
+            // Acquire and release the read lock.
+            let state = self.back_end.read().unwrap();
+            if let Some(ref selectors) = query.services {
+                for service in state.get_services(selectors.clone()) {
+                    targets.extend(service.getters.keys().cloned().map(AuditTarget::Getter));
+                    targets.extend(service.setters.keys().cloned().map(AuditTarget::Setter));
+                    targets.extend(service.actions.keys().cloned().map(AuditTarget::Action));
+                    targets.insert(AuditTarget::Service(service.id));
+                }
+            }
+            if let Some(ref selectors) = query.getters {
+                targets.extend(state.get_getter_channels(selectors.clone()).into_iter()
+                    .map(|channel| AuditTarget::Getter(channel.id)));
+            }
+            if let Some(ref selectors) = query.setters {
+                targets.extend(state.get_setter_channels(selectors.clone()).into_iter()
+                    .map(|channel| AuditTarget::Setter(channel.id)));
+            }
+        }
+        audit.query(&query, Some(&targets))
+    }
+
     /// A value that causes a disconnection once it is dropped.
     type WatchGuard = WatchGuard;
 }
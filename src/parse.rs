@@ -1,6 +1,7 @@
 //! Utilities for defining a JSON parser.
 
 use util::Id;
+use values::DEFAULT_BINARY_MAX_SIZE;
 
 use std::cell::RefCell;
 use std::collections::{ HashMap, HashSet };
@@ -30,20 +31,37 @@ pub fn check_fields(path: Path, json: &JSON) -> Result<(), ParseError> {
 }
 
 /// A path in the JSON tree. Used for displaying error messages.
+///
+/// The path also carries the limits enforced while parsing, which apply to the entire tree.
 #[derive(Clone, Debug)]
 pub struct Path {
     buf: Rc<RefCell<String>>,
     len: usize,
+    max_binary_size: usize,
 }
 impl Path {
-    /// Create an empty Path.
+    /// Create an empty Path, with the default limits.
     pub fn new() -> Self {
         Path {
             buf: Rc::new(RefCell::new(String::new())),
             len: 0,
+            max_binary_size: DEFAULT_BINARY_MAX_SIZE,
+        }
+    }
+
+    /// Change the maximal size of binary data accepted while parsing from this path, in bytes.
+    pub fn with_max_binary_size(self, size: usize) -> Self {
+        Path {
+            max_binary_size: size,
+            ..self
         }
     }
 
+    /// The maximal size of binary data accepted while parsing from this path, in bytes.
+    pub fn max_binary_size(&self) -> usize {
+        self.max_binary_size
+    }
+
     /// Push a suffix after a path.
     pub fn push_str<F, T>(&self, suffix: &str, cb: F) -> T
         where F: FnOnce(Path) -> T
@@ -58,6 +76,7 @@ impl Path {
         let path = Path {
             buf: buf,
             len: len,
+            max_binary_size: self.max_binary_size,
         };
         let result = cb(path);
         {
@@ -109,6 +128,11 @@ pub enum ParseError {
     UnknownConstant {
         at: String,
         constant: String,
+    },
+    TooLarge {
+        at: String,
+        size: usize,
+        max: usize,
     }
 }
 
@@ -154,6 +178,13 @@ impl ParseError {
             at: at.to_string(),
         }
     }
+    pub fn too_large(size: usize, max: usize, at: &Path) -> Self {
+        ParseError::TooLarge {
+            at: at.to_string(),
+            size: size,
+            max: max,
+        }
+    }
     pub fn json(error: error::Error) -> Self {
         ParseError::JSON(JSONError(error))
     }
@@ -347,6 +378,26 @@ impl Parser<String> for String {
 }
 
 
+impl<T> Parser<HashMap<String, T>> for HashMap<String, T> where T: Parser<T> {
+    fn description() -> String {
+        format!("Map<{}>", T::description())
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match *source {
+            JSON::Object(ref mut obj) => {
+                let mut result = HashMap::with_capacity(obj.len());
+                for (key, source) in obj.iter_mut() {
+                    let value = try!(path.push(key, |path| T::parse(path, source)));
+                    result.insert(key.clone(), value);
+                }
+                Ok(result)
+            }
+            _ => Err(ParseError::type_error("map", &path, "object"))
+        }
+    }
+}
+
+
 impl<T> Parser<Arc<T>> for Arc<T> where T: Parser<T> {
     fn description() -> String {
         T::description()
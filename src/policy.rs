@@ -0,0 +1,868 @@
+//! Access control around an implementation of `API`.
+//!
+//! A `Policy` is a list of `Rule`s, each of which allows or denies some `Permission`s to a
+//! user or to a role, on the services matching some `ServiceSelector`s. For instance, "kids
+//! may read and watch everything" and "kids may not write to services that have a
+//! `DoorLocked` setter".
+//!
+//! An `AccessControl` wraps any implementation of `API` on behalf of a single `Principal`, and
+//! only lets through the operations that the policy grants to this principal. A permission is
+//! granted on a service if at least one rule allows it and no rule denies it. Expired principals
+//! are granted nothing.
+
+pub use util::{ Id, RoleId };
+
+use api::{ API, Error, LeaseId, Principal, ResultMap, TargetMap, Targetted, WatchEvent };
+use audit::{ AuditEntry, AuditQuery };
+use conditions::Conditional;
+use leases::Lease;
+use locations::Location;
+use profiles::Profile;
+use selector::*;
+use services::*;
+use streams::{ Stream, StreamId };
+use values::{ Duration, HomeTimeZone, Range, Value };
+
+use transformable_channels::mpsc::*;
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex, RwLock };
+
+/// An operation that a user may be allowed to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    /// Fetch values from getters and open their streams.
+    Read,
+
+    /// Send values to setters and invoke actions.
+    Write,
+
+    /// Watch values from getters.
+    Watch,
+
+    /// Edit the tags, the user properties and the location of services and channels.
+    Tag,
+
+    /// Change the configuration of the home: tag metadata, profiles, locations and timezone.
+    /// This permission is not attached to services, so the `services` of rules that grant or
+    /// deny it are ignored.
+    Configure,
+}
+
+/// The users to whom a rule applies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subject {
+    /// All users, including anonymous users.
+    Anyone,
+
+    /// A single user.
+    User(i32),
+
+    /// All the principals that have a role, either in `Principal::roles` or granted to
+    /// their user with `Policy::grant_role`.
+    Role(Id<RoleId>),
+}
+
+/// Whether a rule allows or denies permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+/// A rule allowing or denying permissions to some users, on some services.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// The users to whom this rule applies.
+    pub subject: Subject,
+
+    /// Whether this rule allows or denies `permissions`.
+    pub access: Access,
+
+    /// The permissions allowed or denied by this rule.
+    pub permissions: HashSet<Permission>,
+
+    /// The services to which this rule applies, i.e. the services matching _any_ of these
+    /// selectors. Use `vec![ServiceSelector::new()]` for all services.
+    pub services: Vec<ServiceSelector>,
+}
+
+impl Rule {
+    /// A rule allowing `permissions` to `subject` on `services`.
+    pub fn allow(subject: Subject, permissions: Vec<Permission>, services: Vec<ServiceSelector>) -> Self {
+        Rule {
+            subject: subject,
+            access: Access::Allow,
+            permissions: permissions.into_iter().collect(),
+            services: services,
+        }
+    }
+
+    /// A rule denying `permissions` to `subject` on `services`, regardless of the rules
+    /// that allow them.
+    pub fn deny(subject: Subject, permissions: Vec<Permission>, services: Vec<ServiceSelector>) -> Self {
+        Rule {
+            access: Access::Deny,
+            ..Rule::allow(subject, permissions, services)
+        }
+    }
+}
+
+/// A set of rules, along with the roles of users.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    roles: HashMap<i32, HashSet<Id<RoleId>>>,
+}
+
+impl Policy {
+    /// An empty policy, which grants nothing to anyone.
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Grant a role to a user.
+    pub fn grant_role(&mut self, user: i32, role: Id<RoleId>) {
+        self.roles.entry(user).or_insert_with(HashSet::new).insert(role);
+    }
+
+    /// Revoke a role from a user. Returns `false` if the user didn't have this role.
+    pub fn revoke_role(&mut self, user: i32, role: &Id<RoleId>) -> bool {
+        match self.roles.get_mut(&user) {
+            None => false,
+            Some(roles) => roles.remove(role)
+        }
+    }
+
+    fn applies_to(&self, subject: &Subject, principal: &Principal) -> bool {
+        match *subject {
+            Subject::Anyone => true,
+            Subject::User(ref id) => principal.user.as_ref() == Some(id),
+            Subject::Role(ref role) => {
+                if principal.has_role(role) {
+                    return true;
+                }
+                match principal.user {
+                    None => false,
+                    Some(ref user) => self.roles.get(user).map_or(false, |roles| roles.contains(role))
+                }
+            }
+        }
+    }
+
+    /// The selectors of services on which `permission` is allowed and denied to `principal`.
+    fn selectors(&self, principal: &Principal, permission: Permission) -> (Vec<ServiceSelector>, Vec<ServiceSelector>) {
+        let mut allow = vec![];
+        let mut deny = vec![];
+        if principal.is_expired() {
+            return (allow, deny);
+        }
+        for rule in &self.rules {
+            if !rule.permissions.contains(&permission) || !self.applies_to(&rule.subject, principal) {
+                continue;
+            }
+            match rule.access {
+                Access::Allow => allow.extend(rule.services.iter().cloned()),
+                Access::Deny => deny.extend(rule.services.iter().cloned()),
+            }
+        }
+        (allow, deny)
+    }
+
+    /// Determine whether `permission` is granted to `principal`, regardless of services.
+    fn is_granted(&self, principal: &Principal, permission: Permission) -> bool {
+        if principal.is_expired() {
+            return false;
+        }
+        let mut allowed = false;
+        for rule in &self.rules {
+            if !rule.permissions.contains(&permission) || !self.applies_to(&rule.subject, principal) {
+                continue;
+            }
+            match rule.access {
+                Access::Allow => allowed = true,
+                Access::Deny => return false,
+            }
+        }
+        allowed
+    }
+}
+
+/// Separate channels on services in `granted` from the others.
+fn split_channels<IO>(channels: Vec<Channel<IO>>, granted: &HashSet<Id<ServiceId>>) -> (Vec<Id<IO>>, Vec<Id<IO>>)
+    where IO: IOMechanism
+{
+    let mut allowed = vec![];
+    let mut denied = vec![];
+    for channel in channels {
+        if granted.contains(&channel.service) {
+            allowed.push(channel.id);
+        } else {
+            denied.push(channel.id);
+        }
+    }
+    (allowed, denied)
+}
+
+/// An implementation of `API` that lets through only the operations granted to a principal by
+/// a `Policy`.
+///
+/// Services, channels and explanations are visible if the principal has any of permissions
+/// `Read`, `Write`, `Watch` or `Tag` on the service. Operations on channels that the principal
+/// may not access produce `Error::PermissionDenied` in the `ResultMap`, while operations on tags and
+/// properties silently ignore them.
+///
+/// The `Principal` arguments of methods are replaced by the principal of the `AccessControl`,
+/// so that callers cannot impersonate someone else.
+///
+/// Watches are restricted to the getters that the principal may watch at the time
+/// `watch_values` is called.
+pub struct AccessControl<A> where A: API + Sync {
+    api: Arc<A>,
+    policy: Arc<RwLock<Policy>>,
+    principal: Principal,
+}
+
+impl<A> AccessControl<A> where A: API + Sync {
+    pub fn new(api: Arc<A>, policy: Arc<RwLock<Policy>>, principal: Principal) -> Self {
+        AccessControl {
+            api: api,
+            policy: policy,
+            principal: principal,
+        }
+    }
+
+    /// The principal on behalf of whom this `AccessControl` acts.
+    pub fn principal(&self) -> &Principal {
+        &self.principal
+    }
+
+    /// Check that `permission` is granted on all the getters matching any of `selectors`.
+    pub fn check_getters(&self, selectors: Vec<GetterSelector>, permission: Permission) -> Result<(), Error> {
+        let (_, denied) = self.restrict_getters(selectors, permission);
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied(permission))
+        }
+    }
+
+    /// Check that `permission` is granted on all the setters matching any of `selectors`.
+    pub fn check_setters(&self, selectors: Vec<SetterSelector>, permission: Permission) -> Result<(), Error> {
+        let (_, denied) = self.restrict_setters(selectors, permission);
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied(permission))
+        }
+    }
+
+    /// The services on which `permission` is granted.
+    fn granted_services(&self, permission: Permission) -> HashSet<Id<ServiceId>> {
+        // Release the policy before calling the API.
+        let (allow, deny) = self.policy.read().unwrap().selectors(&self.principal, permission);
+        if allow.is_empty() {
+            return HashSet::new();
+        }
+        let mut granted : HashSet<_> = self.api.get_services(allow).into_iter().map(|service| service.id).collect();
+        if !deny.is_empty() && !granted.is_empty() {
+            for service in self.api.get_services(deny) {
+                granted.remove(&service.id);
+            }
+        }
+        granted
+    }
+
+    /// The services that the principal may see.
+    fn visible_services(&self) -> HashSet<Id<ServiceId>> {
+        let mut visible = HashSet::new();
+        for permission in &[Permission::Read, Permission::Write, Permission::Watch, Permission::Tag] {
+            visible.extend(self.granted_services(*permission));
+        }
+        visible
+    }
+
+    fn is_configuration_granted(&self) -> bool {
+        self.policy.read().unwrap().is_granted(&self.principal, Permission::Configure)
+    }
+
+    fn restrict_services(&self, selectors: Vec<ServiceSelector>, permission: Permission) -> Vec<ServiceSelector> {
+        let granted = self.granted_services(permission);
+        self.api.get_services(selectors).into_iter()
+            .filter(|service| granted.contains(&service.id))
+            .map(|service| ServiceSelector::new().with_id(service.id))
+            .collect()
+    }
+
+    fn restrict_getters(&self, selectors: Vec<GetterSelector>, permission: Permission) -> (Vec<GetterSelector>, Vec<Id<Getter>>) {
+        let granted = self.granted_services(permission);
+        let (allowed, denied) = split_channels(self.api.get_getter_channels(selectors), &granted);
+        (allowed.into_iter().map(|id| GetterSelector::new().with_id(id)).collect(), denied)
+    }
+
+    fn restrict_setters(&self, selectors: Vec<SetterSelector>, permission: Permission) -> (Vec<SetterSelector>, Vec<Id<Setter>>) {
+        let granted = self.granted_services(permission);
+        let (allowed, denied) = split_channels(self.api.get_setter_channels(selectors), &granted);
+        (allowed.into_iter().map(|id| SetterSelector::new().with_id(id)).collect(), denied)
+    }
+
+    fn restrict_actions(&self, selectors: Vec<ActionSelector>, permission: Permission) -> (Vec<ActionSelector>, Vec<Id<Action>>) {
+        let granted = self.granted_services(permission);
+        let (allowed, denied) = split_channels(self.api.get_action_channels(selectors), &granted);
+        (allowed.into_iter().map(|id| ActionSelector::new().with_id(id)).collect(), denied)
+    }
+
+    /// Check that the principal may read from a getter. Unknown getters are let through, so that
+    /// the underlying API reports them.
+    fn check_readable(&self, getter: &Id<Getter>) -> Result<(), Error> {
+        let channels = self.api.get_getter_channels(vec![GetterSelector::new().with_id(getter.clone())]);
+        match channels.first() {
+            Some(channel) if !self.granted_services(Permission::Read).contains(&channel.service) =>
+                Err(Error::PermissionDenied(Permission::Read)),
+            _ => Ok(())
+        }
+    }
+
+    /// Determine whether the principal may watch a getter. Unknown getters may not be watched.
+    fn is_watch_granted(&self, getter: &Id<Getter>) -> bool {
+        let channels = self.api.get_getter_channels(vec![GetterSelector::new().with_id(getter.clone())]);
+        match channels.first() {
+            Some(channel) => self.granted_services(Permission::Watch).contains(&channel.service),
+            None => false
+        }
+    }
+}
+
+impl<A> API for AccessControl<A> where A: API + Sync + 'static {
+    fn get_services(&self, selectors: Vec<ServiceSelector>) -> Vec<Service> {
+        let visible = self.visible_services();
+        self.api.get_services(selectors).into_iter()
+            .filter(|service| visible.contains(&service.id))
+            .collect()
+    }
+
+    fn add_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.add_service_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn remove_service_tags(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.remove_service_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn get_getter_channels(&self, selectors: Vec<GetterSelector>) -> Vec<Channel<Getter>> {
+        let visible = self.visible_services();
+        self.api.get_getter_channels(selectors).into_iter()
+            .filter(|channel| visible.contains(&channel.service))
+            .collect()
+    }
+
+    fn get_setter_channels(&self, selectors: Vec<SetterSelector>) -> Vec<Channel<Setter>> {
+        let visible = self.visible_services();
+        self.api.get_setter_channels(selectors).into_iter()
+            .filter(|channel| visible.contains(&channel.service))
+            .collect()
+    }
+
+    fn get_action_channels(&self, selectors: Vec<ActionSelector>) -> Vec<Channel<Action>> {
+        let visible = self.visible_services();
+        self.api.get_action_channels(selectors).into_iter()
+            .filter(|channel| visible.contains(&channel.service))
+            .collect()
+    }
+
+    fn explain_services(&self, selector: ServiceSelector) -> Vec<Explanation<ServiceId>> {
+        let visible = self.visible_services();
+        self.api.explain_services(selector).into_iter()
+            .filter(|explanation| visible.contains(&explanation.id))
+            .collect()
+    }
+
+    fn explain_getters(&self, selector: GetterSelector) -> Vec<Explanation<Getter>> {
+        let visible : HashSet<_> = self.get_getter_channels(vec![GetterSelector::new()]).into_iter()
+            .map(|channel| channel.id)
+            .collect();
+        self.api.explain_getters(selector).into_iter()
+            .filter(|explanation| visible.contains(&explanation.id))
+            .collect()
+    }
+
+    fn explain_setters(&self, selector: SetterSelector) -> Vec<Explanation<Setter>> {
+        let visible : HashSet<_> = self.get_setter_channels(vec![SetterSelector::new()]).into_iter()
+            .map(|channel| channel.id)
+            .collect();
+        self.api.explain_setters(selector).into_iter()
+            .filter(|explanation| visible.contains(&explanation.id))
+            .collect()
+    }
+
+    fn explain_actions(&self, selector: ActionSelector) -> Vec<Explanation<Action>> {
+        let visible : HashSet<_> = self.get_action_channels(vec![ActionSelector::new()]).into_iter()
+            .map(|channel| channel.id)
+            .collect();
+        self.api.explain_actions(selector).into_iter()
+            .filter(|explanation| visible.contains(&explanation.id))
+            .collect()
+    }
+
+    fn add_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.add_getter_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn add_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.add_setter_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn remove_getter_tags(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.remove_getter_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn remove_setter_tags(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.remove_setter_tags(selectors, tags, self.principal.clone())
+    }
+
+    fn add_service_tags_with_origin(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, _: Principal) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.add_service_tags_with_origin(selectors, tags, origin, self.principal.clone())
+    }
+
+    fn add_getter_tags_with_origin(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.add_getter_tags_with_origin(selectors, tags, origin, self.principal.clone())
+    }
+
+    fn add_setter_tags_with_origin(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>, origin: TagOrigin, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.add_setter_tags_with_origin(selectors, tags, origin, self.principal.clone())
+    }
+
+    fn remove_service_tags_by_origin(&self, selectors: Vec<ServiceSelector>, origin: TagOrigin, _: Principal) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.remove_service_tags_by_origin(selectors, origin, self.principal.clone())
+    }
+
+    fn remove_getter_tags_by_origin(&self, selectors: Vec<GetterSelector>, origin: TagOrigin, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.remove_getter_tags_by_origin(selectors, origin, self.principal.clone())
+    }
+
+    fn remove_setter_tags_by_origin(&self, selectors: Vec<SetterSelector>, origin: TagOrigin, _: Principal) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.remove_setter_tags_by_origin(selectors, origin, self.principal.clone())
+    }
+
+    fn add_service_tags_dry_run(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>> {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.add_service_tags_dry_run(selectors, tags)
+    }
+
+    fn add_getter_tags_dry_run(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>> {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.add_getter_tags_dry_run(selectors, tags)
+    }
+
+    fn add_setter_tags_dry_run(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>> {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.add_setter_tags_dry_run(selectors, tags)
+    }
+
+    fn remove_service_tags_dry_run(&self, selectors: Vec<ServiceSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<ServiceId>, Vec<Id<TagId>>> {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.remove_service_tags_dry_run(selectors, tags)
+    }
+
+    fn remove_getter_tags_dry_run(&self, selectors: Vec<GetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Getter>, Vec<Id<TagId>>> {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.remove_getter_tags_dry_run(selectors, tags)
+    }
+
+    fn remove_setter_tags_dry_run(&self, selectors: Vec<SetterSelector>, tags: Vec<Id<TagId>>) -> HashMap<Id<Setter>, Vec<Id<TagId>>> {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.remove_setter_tags_dry_run(selectors, tags)
+    }
+
+    /// List the tags, counting only the services and channels that the principal may see.
+    /// Tags that are not used on any of these are only listed to principals who may configure
+    /// the home.
+    fn get_tags(&self) -> Vec<TagInfo> {
+        fn entry<'a>(tags: &'a mut HashMap<Id<TagId>, TagInfo>, tag: &Id<TagId>) -> &'a mut TagInfo {
+            tags.entry(tag.clone()).or_insert_with(|| TagInfo::new(tag.clone()))
+        }
+        let mut tags = HashMap::new();
+        for service in self.get_services(vec![ServiceSelector::new()]) {
+            for tag in &service.tags {
+                entry(&mut tags, tag).services += 1;
+            }
+        }
+        for getter in self.get_getter_channels(vec![GetterSelector::new()]) {
+            for tag in &getter.tags {
+                entry(&mut tags, tag).getters += 1;
+            }
+        }
+        for setter in self.get_setter_channels(vec![SetterSelector::new()]) {
+            for tag in &setter.tags {
+                entry(&mut tags, tag).setters += 1;
+            }
+        }
+        let is_configuration_granted = self.is_configuration_granted();
+        for info in self.api.get_tags() {
+            if let Some(visible) = tags.get_mut(&info.id) {
+                visible.metadata = info.metadata;
+                continue;
+            }
+            if is_configuration_granted {
+                tags.insert(info.id.clone(), TagInfo {
+                    services: 0,
+                    getters: 0,
+                    setters: 0,
+                    ..info
+                });
+            }
+        }
+        tags.drain().map(|(_, info)| info).collect()
+    }
+
+    fn rename_tag(&self, from: &Id<TagId>, to: &Id<TagId>, _: Principal) -> usize {
+        if !self.is_configuration_granted() {
+            return 0;
+        }
+        self.api.rename_tag(from, to, self.principal.clone())
+    }
+
+    fn set_tag_metadata(&self, tag: Id<TagId>, metadata: TagMetadata) {
+        if self.is_configuration_granted() {
+            self.api.set_tag_metadata(tag, metadata)
+        }
+    }
+
+    fn remove_tag_metadata(&self, tag: &Id<TagId>) -> bool {
+        self.is_configuration_granted() && self.api.remove_tag_metadata(tag)
+    }
+
+    fn get_profiles(&self) -> Vec<Profile> {
+        self.api.get_profiles()
+    }
+
+    fn add_profile(&self, profile: Profile) -> Result<(), Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.add_profile(profile)
+    }
+
+    fn remove_profile(&self, id: &Id<ProfileId>) -> Result<bool, Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.remove_profile(id)
+    }
+
+    fn get_home_timezone(&self) -> HomeTimeZone {
+        self.api.get_home_timezone()
+    }
+
+    fn set_home_timezone(&self, timezone: HomeTimeZone) -> Result<(), Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.set_home_timezone(timezone)
+    }
+
+    fn get_locations(&self) -> Vec<Location> {
+        self.api.get_locations()
+    }
+
+    fn add_location(&self, location: Location) -> Result<(), Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.add_location(location)
+    }
+
+    fn remove_location(&self, id: &Id<LocationId>) -> Result<(), Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.remove_location(id)
+    }
+
+    fn import_locations(&self, locations: Vec<Location>) -> Result<(), Error> {
+        if !self.is_configuration_granted() {
+            return Err(Error::PermissionDenied(Permission::Configure));
+        }
+        self.api.import_locations(locations)
+    }
+
+    fn set_service_location(&self, selectors: Vec<ServiceSelector>, location: Option<Id<LocationId>>) -> Result<usize, Error> {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.set_service_location(selectors, location)
+    }
+
+    fn set_service_user_properties(&self, selectors: Vec<ServiceSelector>, properties: HashMap<String, String>) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.set_service_user_properties(selectors, properties)
+    }
+
+    fn remove_service_user_properties(&self, selectors: Vec<ServiceSelector>, keys: Vec<String>) -> usize {
+        let selectors = self.restrict_services(selectors, Permission::Tag);
+        self.api.remove_service_user_properties(selectors, keys)
+    }
+
+    fn set_getter_user_properties(&self, selectors: Vec<GetterSelector>, properties: HashMap<String, String>) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.set_getter_user_properties(selectors, properties)
+    }
+
+    fn set_setter_user_properties(&self, selectors: Vec<SetterSelector>, properties: HashMap<String, String>) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.set_setter_user_properties(selectors, properties)
+    }
+
+    fn remove_getter_user_properties(&self, selectors: Vec<GetterSelector>, keys: Vec<String>) -> usize {
+        let (selectors, _) = self.restrict_getters(selectors, Permission::Tag);
+        self.api.remove_getter_user_properties(selectors, keys)
+    }
+
+    fn remove_setter_user_properties(&self, selectors: Vec<SetterSelector>, keys: Vec<String>) -> usize {
+        let (selectors, _) = self.restrict_setters(selectors, Permission::Tag);
+        self.api.remove_setter_user_properties(selectors, keys)
+    }
+
+    fn fetch_values(&self, selectors: Vec<GetterSelector>, _: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+        let (selectors, denied) = self.restrict_getters(selectors, Permission::Read);
+        let mut result = if selectors.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.fetch_values(selectors, self.principal.clone())
+        };
+        for id in denied {
+            result.insert(id, Err(Error::PermissionDenied(Permission::Read)));
+        }
+        result
+    }
+
+    fn fetch_cached_values(&self, selectors: Vec<GetterSelector>, max_age: Duration, _: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+        let (selectors, denied) = self.restrict_getters(selectors, Permission::Read);
+        let mut result = if selectors.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.fetch_cached_values(selectors, max_age, self.principal.clone())
+        };
+        for id in denied {
+            result.insert(id, Err(Error::PermissionDenied(Permission::Read)));
+        }
+        result
+    }
+
+    fn send_values(&self, mut targets: TargetMap<SetterSelector, Value>, _: Principal) -> ResultMap<Id<Setter>, (), Error> {
+        let mut denied = vec![];
+        let targets : TargetMap<_, _> = targets.drain(..).filter_map(|Targetted { select, payload }| {
+            let (select, rejected) = self.restrict_setters(select, Permission::Write);
+            denied.extend(rejected);
+            if select.is_empty() {
+                None
+            } else {
+                Some(Targetted::new(select, payload))
+            }
+        }).collect();
+        let mut result = if targets.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.send_values(targets, self.principal.clone())
+        };
+        for id in denied {
+            result.insert(id, Err(Error::PermissionDenied(Permission::Write)));
+        }
+        result
+    }
+
+    fn send_values_dry_run(&self, mut targets: TargetMap<SetterSelector, Value>, _: Principal) -> ResultMap<Id<Setter>, (), Error> {
+        let mut denied = vec![];
+        let targets : TargetMap<_, _> = targets.drain(..).filter_map(|Targetted { select, payload }| {
+            let (select, rejected) = self.restrict_setters(select, Permission::Write);
+            denied.extend(rejected);
+            if select.is_empty() {
+                None
+            } else {
+                Some(Targetted::new(select, payload))
+            }
+        }).collect();
+        let mut result = if targets.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.send_values_dry_run(targets, self.principal.clone())
+        };
+        for id in denied {
+            result.insert(id, Err(Error::PermissionDenied(Permission::Write)));
+        }
+        result
+    }
+
+    fn send_values_if(&self, mut targets: TargetMap<SetterSelector, Conditional>, _: Principal) -> ResultMap<Id<Setter>, (), Error> {
+        let mut denied = vec![];
+        let targets : TargetMap<_, _> = targets.drain(..).filter_map(|Targetted { select, mut payload }| {
+            let (select, rejected) = self.restrict_setters(select, Permission::Write);
+            denied.extend(rejected.into_iter().map(|id| (id, Permission::Write)));
+            if select.is_empty() {
+                return None;
+            }
+            // Preconditions may only check getters that the principal may read.
+            let mut is_readable = true;
+            for precondition in &mut payload.preconditions {
+                let (allowed, rejected) = self.restrict_getters(precondition.select.clone(), Permission::Read);
+                is_readable = is_readable && rejected.is_empty();
+                precondition.select = allowed;
+            }
+            if !is_readable {
+                denied.extend(self.api.get_setter_channels(select).into_iter().map(|channel| (channel.id, Permission::Read)));
+                return None;
+            }
+            Some(Targetted::new(select, payload))
+        }).collect();
+        let mut result = if targets.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.send_values_if(targets, self.principal.clone())
+        };
+        for (id, permission) in denied {
+            result.insert(id, Err(Error::PermissionDenied(permission)));
+        }
+        result
+    }
+
+    fn acquire_lease(&self, selectors: Vec<SetterSelector>, duration: Duration, priority: i32, _: Principal) -> Result<Lease, Error> {
+        let (selectors, denied) = self.restrict_setters(selectors, Permission::Write);
+        if !denied.is_empty() {
+            return Err(Error::PermissionDenied(Permission::Write));
+        }
+        self.api.acquire_lease(selectors, duration, priority, self.principal.clone())
+    }
+
+    fn release_lease(&self, id: &Id<LeaseId>, _: Principal) -> usize {
+        self.api.release_lease(id, self.principal.clone())
+    }
+
+    fn invoke(&self, mut targets: TargetMap<ActionSelector, Value>, _: Principal) -> ResultMap<Id<Action>, Value, Error> {
+        let mut denied = vec![];
+        let targets : TargetMap<_, _> = targets.drain(..).filter_map(|Targetted { select, payload }| {
+            let (select, rejected) = self.restrict_actions(select, Permission::Write);
+            denied.extend(rejected);
+            if select.is_empty() {
+                None
+            } else {
+                Some(Targetted::new(select, payload))
+            }
+        }).collect();
+        let mut result = if targets.is_empty() {
+            HashMap::new()
+        } else {
+            self.api.invoke(targets, self.principal.clone())
+        };
+        for id in denied {
+            result.insert(id, Err(Error::PermissionDenied(Permission::Write)));
+        }
+        result
+    }
+
+    fn open_stream(&self, getter: &Id<Getter>, stream: &Id<StreamId>, _: Principal) -> Result<Stream, Error> {
+        try!(self.check_readable(getter));
+        self.api.open_stream(getter, stream, self.principal.clone())
+    }
+
+    fn fetch_snapshot(&self, getter: &Id<Getter>, _: Principal) -> Result<Stream, Error> {
+        try!(self.check_readable(getter));
+        self.api.fetch_snapshot(getter, self.principal.clone())
+    }
+
+    /// Watch the getters matching the selectors, as the underlying API, but only deliver the
+    /// values of getters that the principal may watch.
+    ///
+    /// Getters that currently match but may not be watched are reported immediately with
+    /// `WatchEvent::InitializationError` and `Error::PermissionDenied(Permission::Watch)`.
+    /// The permission is checked again each time a getter sends a value, so that changes to
+    /// the policy apply to ongoing watches. Getters that may not be watched are reported in
+    /// the same way, once until they are added or removed, or granted again.
+    fn watch_values(&self, watch: TargetMap<GetterSelector, Exactly<Range>>,
+            on_event: Box<ExtSender<WatchEvent>>) -> Self::WatchGuard {
+        let granted = self.granted_services(Permission::Watch);
+        let mut reported = HashSet::new();
+        for targetted in &watch {
+            for channel in self.api.get_getter_channels(targetted.select.clone()) {
+                if !granted.contains(&channel.service) {
+                    reported.insert(channel.id);
+                }
+            }
+        }
+        for id in &reported {
+            let _ = on_event.send(WatchEvent::InitializationError {
+                channel: id.clone(),
+                error: Error::PermissionDenied(Permission::Watch)
+            });
+        }
+
+        let reported = Arc::new(Mutex::new(reported));
+        let access = AccessControl::new(self.api.clone(), self.policy.clone(), self.principal.clone());
+        let on_event = on_event.filter_map(move |event| {
+            let target = match event {
+                WatchEvent::EnterRange { ref from, .. } |
+                WatchEvent::ExitRange { ref from, .. } |
+                WatchEvent::Event { ref from, .. } => Some((from.clone(), true)),
+                WatchEvent::GetterAdded(ref id) |
+                WatchEvent::GetterRemoved(ref id) => Some((id.clone(), false)),
+                WatchEvent::InitializationError { .. } => None
+            };
+            let (id, is_value) = match target {
+                None => return Some(event),
+                Some(target) => target
+            };
+            let mut reported = reported.lock().unwrap();
+            if !is_value {
+                // Topology changes are sent while the underlying API is locked, so we cannot
+                // check the getter now. Check it when it next sends a value.
+                reported.remove(&id);
+                return Some(event);
+            }
+            if access.principal.is_expired() {
+                return None;
+            }
+            if access.is_watch_granted(&id) {
+                reported.remove(&id);
+                Some(event)
+            } else if reported.insert(id.clone()) {
+                Some(WatchEvent::InitializationError {
+                    channel: id,
+                    error: Error::PermissionDenied(Permission::Watch)
+                })
+            } else {
+                None
+            }
+        });
+        self.api.watch_values(watch, Box::new(on_event))
+    }
+
+    fn get_audit_entries(&self, query: AuditQuery) -> Vec<AuditEntry> {
+        if !self.is_configuration_granted() {
+            return vec![];
+        }
+        self.api.get_audit_entries(query)
+    }
+
+    type WatchGuard = A::WatchGuard;
+}
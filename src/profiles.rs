@@ -0,0 +1,299 @@
+//! Profiles: named bundles of channel kinds.
+//!
+//! A profile describes a family of devices by the channels they offer, independently of
+//! the adapter, e.g. profile `Light` is satisfied by any service offering a getter and
+//! a setter of kind `LightOn`. Applications may select services by profile rather than
+//! by inspecting the channels of each service.
+
+use api::{ Error, InternalError };
+use parse::*;
+use selector::ServiceLike;
+use services::*;
+pub use util::{ Id, ProfileId };
+
+use std::collections::{ HashMap, HashSet };
+
+/// A named set of requirements on the channels of a service.
+///
+/// # JSON
+///
+/// A profile is represented by an object with the following fields:
+///
+/// - id: string - an id unique to this profile;
+/// - (optional) getters: array of `ChannelKind` - the service must offer a getter of each kind;
+/// - (optional) setters: array of `ChannelKind` - the service must offer a setter of each kind.
+///
+/// ```
+/// use foxbox_taxonomy::profiles::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let source = "{
+///   \"id\": \"Light\",
+///   \"getters\": [\"LightOn\"],
+///   \"setters\": [\"LightOn\"]
+/// }";
+/// let profile = Profile::from_str(source).unwrap();
+/// assert_eq!(profile.getters.len(), 1);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// An id unique to this profile.
+    pub id: Id<ProfileId>,
+
+    /// The service must offer a getter of each of these kinds.
+    #[serde(default)]
+    pub getters: Vec<ChannelKind>,
+
+    /// The service must offer a setter of each of these kinds.
+    #[serde(default)]
+    pub setters: Vec<ChannelKind>,
+}
+
+impl Profile {
+    /// Create a profile.
+    pub fn new(id: Id<ProfileId>, getters: Vec<ChannelKind>, setters: Vec<ChannelKind>) -> Self {
+        Profile {
+            id: id,
+            getters: getters,
+            setters: setters,
+        }
+    }
+
+    /// The built-in profile with a given id, if any. See `ProfileRegistry`.
+    pub fn builtin(id: &Id<ProfileId>) -> Option<Profile> {
+        builtin_profiles().into_iter().find(|profile| profile.id == *id)
+    }
+
+    /// `true` if `service` offers all the channels required by this profile.
+    pub fn is_satisfied_by<T>(&self, service: &T) -> bool where T: ServiceLike {
+        let getters_ok = self.getters.iter().all(|kind| {
+            service.has_getters(|channel| channel.mechanism.kind == *kind)
+        });
+        if !getters_ok {
+            return false;
+        }
+        self.setters.iter().all(|kind| {
+            service.has_setters(|channel| channel.mechanism.kind == *kind)
+        })
+    }
+}
+
+impl Parser<Profile> for Profile {
+    fn description() -> String {
+        "Profile".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let id = try!(path.push("id", |path| Id::take(path, source, "id")));
+        let getters = match path.push("getters", |path| ChannelKind::take_vec_opt(path, source, "getters")) {
+            None => vec![],
+            Some(Ok(getters)) => getters,
+            Some(Err(err)) => return Err(err)
+        };
+        let setters = match path.push("setters", |path| ChannelKind::take_vec_opt(path, source, "setters")) {
+            None => vec![],
+            Some(Ok(setters)) => setters,
+            Some(Err(err)) => return Err(err)
+        };
+        Ok(Profile {
+            id: id,
+            getters: getters,
+            setters: setters,
+        })
+    }
+}
+
+impl ToJSON for Profile {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("id", self.id.to_json()),
+            ("getters", self.getters.to_json()),
+            ("setters", self.setters.to_json()),
+        ].to_json()
+    }
+}
+
+fn builtin_profiles() -> Vec<Profile> {
+    vec![
+        Profile::new(Id::new("Light"), vec![ChannelKind::LightOn], vec![ChannelKind::LightOn]),
+        Profile::new(Id::new("DoorLock"), vec![ChannelKind::DoorLocked], vec![ChannelKind::DoorLocked]),
+        Profile::new(Id::new("Camera"), vec![], vec![ChannelKind::TakeSnapshot]),
+    ]
+}
+
+/// The set of profiles known to the system.
+///
+/// A registry created with `new` contains the following built-in profiles, which cannot be
+/// removed:
+///
+/// - `Light`: getter and setter `LightOn`;
+/// - `DoorLock`: getter and setter `DoorLocked`;
+/// - `Camera`: setter `TakeSnapshot`.
+#[derive(Debug, Clone)]
+pub struct ProfileRegistry {
+    profiles: HashMap<Id<ProfileId>, Profile>,
+}
+
+impl ProfileRegistry {
+    /// Create a registry containing the built-in profiles.
+    pub fn new() -> Self {
+        let mut registry = ProfileRegistry {
+            profiles: HashMap::new(),
+        };
+        for profile in builtin_profiles() {
+            registry.profiles.insert(profile.id.clone(), profile);
+        }
+        registry
+    }
+
+    /// Get a profile by id.
+    pub fn get(&self, id: &Id<ProfileId>) -> Option<&Profile> {
+        self.profiles.get(id)
+    }
+
+    /// All the profiles, in no specific order.
+    pub fn profiles(&self) -> Vec<Profile> {
+        self.profiles.values().cloned().collect()
+    }
+
+    /// Add a profile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a profile with the same id already exists.
+    pub fn add(&mut self, profile: Profile) -> Result<(), Error> {
+        if self.profiles.contains_key(&profile.id) {
+            return Err(Error::InternalError(InternalError::DuplicateProfile(profile.id)));
+        }
+        self.profiles.insert(profile.id.clone(), profile);
+        Ok(())
+    }
+
+    /// Remove a profile. Returns `Ok(false)` if there was no such profile.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the profile is built-in.
+    pub fn remove(&mut self, id: &Id<ProfileId>) -> Result<bool, Error> {
+        if Profile::builtin(id).is_some() {
+            return Err(Error::InternalError(InternalError::BuiltinProfile(id.clone())));
+        }
+        Ok(self.profiles.remove(id).is_some())
+    }
+
+    /// `true` if profile `id` exists and `service` satisfies it.
+    pub fn is_satisfied_by<T>(&self, id: &Id<ProfileId>, service: &T) -> bool where T: ServiceLike {
+        match self.profiles.get(id) {
+            None => false,
+            Some(profile) => profile.is_satisfied_by(service)
+        }
+    }
+
+    /// The ids of all the profiles satisfied by `service`.
+    pub fn satisfied_by<T>(&self, service: &T) -> HashSet<Id<ProfileId>> where T: ServiceLike {
+        self.profiles.values()
+            .filter(|profile| profile.is_satisfied_by(service))
+            .map(|profile| profile.id.clone())
+            .collect()
+    }
+}
+
+impl Default for ProfileRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ToJSON for ProfileRegistry {
+    fn to_json(&self) -> JSON {
+        JSON::Array(self.profiles.values().map(|profile| profile.to_json()).collect())
+    }
+}
+
+#[test]
+fn test_profiles() {
+    let adapter = Id::<AdapterId>::new("adapter");
+    let mut service = Service::empty(Id::new("service"), adapter.clone());
+    let mut registry = ProfileRegistry::new();
+    assert!(registry.satisfied_by(&service).is_empty());
+
+    // A service with a getter `LightOn` is not a light yet...
+    let getter = Channel {
+        id: Id::<Getter>::new("getter"),
+        service: service.id.clone(),
+        adapter: adapter.clone(),
+        last_seen: None,
+        tags: HashSet::new(),
+        tag_origins: HashMap::new(),
+        user_properties: HashMap::new(),
+        mechanism: Getter {
+            kind: ChannelKind::LightOn,
+            updated: None,
+            delivery: Delivery::State,
+        },
+    };
+    service.getters.insert(getter.id.clone(), getter);
+    assert!(!registry.is_satisfied_by(&Id::new("Light"), &service));
+
+    // ... until it also has a setter `LightOn`.
+    let setter = Channel {
+        id: Id::<Setter>::new("setter"),
+        service: service.id.clone(),
+        adapter: adapter.clone(),
+        last_seen: None,
+        tags: HashSet::new(),
+        tag_origins: HashMap::new(),
+        user_properties: HashMap::new(),
+        mechanism: Setter {
+            kind: ChannelKind::LightOn,
+            updated: None,
+            write_only: false,
+            lease: None,
+        },
+    };
+    service.setters.insert(setter.id.clone(), setter);
+    assert!(registry.is_satisfied_by(&Id::new("Light"), &service));
+
+    // A service matches selectors on built-in profiles from its channels, even if its
+    // `profiles` have not been computed by the manager.
+    assert!(service.profiles.is_empty());
+    assert!(::selector::ServiceSelector::new().with_profile(Id::new("Light")).matches(&service));
+    assert!(!::selector::ServiceSelector::new().with_profile(Id::new("DoorLock")).matches(&service));
+    service.profiles.insert(Id::new("Light"));
+    service.setters.clear();
+    assert!(!::selector::ServiceSelector::new().with_profile(Id::new("Light")).matches(&service));
+    service.setters.insert(Id::<Setter>::new("setter"), Channel {
+        id: Id::<Setter>::new("setter"),
+        service: service.id.clone(),
+        adapter: adapter.clone(),
+        last_seen: None,
+        tags: HashSet::new(),
+        tag_origins: HashMap::new(),
+        user_properties: HashMap::new(),
+        mechanism: Setter {
+            kind: ChannelKind::LightOn,
+            updated: None,
+            write_only: false,
+            lease: None,
+        },
+    });
+
+    // Custom profiles.
+    let switch = Profile::new(Id::new("Switch"), vec![], vec![ChannelKind::LightOn]);
+    registry.add(switch.clone()).unwrap();
+    match registry.add(switch) {
+        Err(Error::InternalError(InternalError::DuplicateProfile(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    let satisfied = registry.satisfied_by(&service);
+    assert_eq!(satisfied.len(), 2);
+    assert!(satisfied.contains(&Id::new("Switch")));
+    assert!(registry.remove(&Id::new("Switch")).unwrap());
+    assert!(!registry.remove(&Id::new("Switch")).unwrap());
+
+    // Built-in profiles.
+    match registry.remove(&Id::new("Light")) {
+        Err(Error::InternalError(InternalError::BuiltinProfile(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert!(registry.get(&Id::new("Light")).is_some());
+}
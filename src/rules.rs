@@ -0,0 +1,577 @@
+//! A rule engine (`ThinkerBell`), sending values to setters whenever the values of some
+//! getters enter given ranges, e.g. "when the door opens at night, turn on the lights".
+//!
+//! Rules are exposed as services of a dedicated adapter, so that they can be managed
+//! through the usual API:
+//!
+//! - a root service with a setter of kind `AddThinkerbellRule`, accepting a
+//!   `ThinkerbellRule`, whose `source` is a `Script` in JSON;
+//! - for each rule, a service with getters of kinds `ThinkerbellRuleSource` and
+//!   `ThinkerbellRuleOn`, and setters of kinds `ThinkerbellRuleOn`, to enable or disable
+//!   the rule, and `RemoveThinkerbellRule`, to remove it.
+//!
+//! A rule is triggered whenever all its conditions become met, i.e. for each condition,
+//! the value of at least one of the matching getters is in the range. The values are then
+//! sent on behalf of the principal who added the rule, with origin `Origin::Rule(name)`.
+//!
+//! Rules are subject to the `Policy` of the engine: the principal who adds a rule must be
+//! allowed to watch the getters of its conditions and to write to the setters of its
+//! actions. Since permissions may be revoked and principals may expire, the actions are
+//! authorized again each time the rule is triggered.
+
+use adapter::{ Adapter, AdapterManagerHandle, AdapterWatchGuard, WatchResult, WatchTarget };
+use api::{ API, Error, InternalError, Origin, Principal, ResultMap, TargetMap, Targetted, WatchEvent };
+use parse::*;
+use policy::{ AccessControl, Permission, Policy };
+use selector::{ GetterSelector, SetterSelector };
+use services::{ Channel, ChannelKind, Delivery, Getter, Service, Setter };
+use util::{ AdapterId, Exactly, Id, ServiceId };
+use values::{ OnOff, Range, ThinkerbellRule, Value };
+
+use transformable_channels::mpsc::*;
+
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex, RwLock, Weak };
+use std::thread;
+
+/// A condition of a rule.
+///
+/// # JSON
+///
+/// An object with fields `select` (a `GetterSelector` or an array of `GetterSelector`) and
+/// `range` (a `Range`).
+#[derive(Debug, Clone)]
+pub struct Condition {
+    /// The getters to watch. The condition is met if the value of at least one of them is
+    /// in `range`.
+    pub select: Vec<GetterSelector>,
+
+    pub range: Range,
+}
+
+impl Condition {
+    pub fn new(select: Vec<GetterSelector>, range: Range) -> Self {
+        Condition {
+            select: select,
+            range: range,
+        }
+    }
+}
+
+impl Parser<Condition> for Condition {
+    fn description() -> String {
+        "Condition".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let select = try!(path.push("select", |path| Vec::<GetterSelector>::take(path, source, "select")));
+        let range = try!(path.push("range", |path| Range::take(path, source, "range")));
+        Ok(Condition {
+            select: select,
+            range: range,
+        })
+    }
+}
+
+/// The body of a rule, i.e. the `source` of a `ThinkerbellRule`.
+///
+/// # JSON
+///
+/// An object with fields `conditions` (an array of `Condition`) and `actions` (an array
+/// of `{select, value}`, as for `send_values`).
+///
+/// ```
+/// use foxbox_taxonomy::rules::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let script = Script::from_str(r#"{
+///   "conditions": [{
+///     "select": {"kind": "OpenClosed"},
+///     "range": {"Eq": {"OpenClosed": "Open"}}
+///   }],
+///   "actions": [{
+///     "select": {"kind": "LightOn"},
+///     "value": {"OnOff": "On"}
+///   }]
+/// }"#).unwrap();
+/// assert_eq!(script.conditions.len(), 1);
+/// assert_eq!(script.actions.len(), 1);
+///
+/// assert!(Script::from_str(r#"{"conditions": [], "actions": []}"#).is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Script {
+    /// The conditions that must all be met to trigger the rule. Never empty.
+    pub conditions: Vec<Condition>,
+
+    /// The values to send when the rule is triggered.
+    pub actions: TargetMap<SetterSelector, Value>,
+}
+
+impl Parser<Script> for Script {
+    fn description() -> String {
+        "Script".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let conditions = try!(path.push("conditions", |path| Condition::take_vec(path, source, "conditions")));
+        if conditions.is_empty() {
+            return Err(ParseError::type_error("conditions", &path, "non-empty array"));
+        }
+        let actions = try!(path.push("actions", |path| Targetted::<SetterSelector, Value>::take_vec(path, source, "actions")));
+        Ok(Script {
+            conditions: conditions,
+            actions: actions,
+        })
+    }
+}
+
+/// An event received from a watch, labelled with the rule and condition it concerns.
+struct RuleEvent {
+    rule: String,
+    generation: usize,
+    condition: usize,
+    event: WatchEvent,
+}
+
+/// A message processed by the thread of the engine.
+enum EngineMsg {
+    Event(RuleEvent),
+
+    /// Acknowledge once all the previous messages have been processed.
+    Sync(RawSender<()>),
+}
+
+struct RuleState<G> {
+    rule: ThinkerbellRule,
+    script: Script,
+
+    /// The principal on behalf of whom the actions are executed.
+    principal: Principal,
+
+    service: Id<ServiceId>,
+    getter_source: Id<Getter>,
+    getter_on: Id<Getter>,
+    setter_on: Id<Setter>,
+    setter_remove: Id<Setter>,
+
+    is_on: bool,
+
+    /// Incremented whenever the rule is enabled or disabled, to ignore events from
+    /// previous watches.
+    generation: usize,
+
+    /// For each condition, the getters whose value is currently in range.
+    in_range: Vec<HashSet<Id<Getter>>>,
+
+    /// The watches on the conditions. Dropping them stops watching.
+    guards: Vec<G>,
+}
+
+impl<G> RuleState<G> {
+    fn is_met_except(&self, condition: Option<usize>) -> bool {
+        self.in_range.iter().enumerate().all(|(index, getters)| {
+            Some(index) == condition || !getters.is_empty()
+        })
+    }
+
+    /// Update the state of the rule, returning `true` if the rule must be triggered.
+    fn handle(&mut self, condition: usize, event: WatchEvent) -> bool {
+        if condition >= self.in_range.len() {
+            return false;
+        }
+        match event {
+            WatchEvent::EnterRange { from, .. } => {
+                let was_met = self.is_met_except(None);
+                self.in_range[condition].insert(from);
+                !was_met && self.is_met_except(None)
+            }
+            WatchEvent::ExitRange { from, .. } | WatchEvent::GetterRemoved(from) => {
+                self.in_range[condition].remove(&from);
+                false
+            }
+            // Events have no state: they trigger the rule if all the other conditions are met.
+            WatchEvent::Event { .. } => self.is_met_except(Some(condition)),
+            _ => false
+        }
+    }
+}
+
+struct EngineState<G> {
+    rules: HashMap<String, RuleState<G>>,
+    generation: usize,
+}
+
+/// The rule engine, registered as an adapter.
+pub struct ThinkerBell<A> where A: API + AdapterManagerHandle + Sync + 'static, A::WatchGuard: Send + 'static {
+    /// A weak reference, as the API holds a reference to this adapter.
+    api: Weak<A>,
+    id: Id<AdapterId>,
+    root: Id<ServiceId>,
+    setter_add: Id<Setter>,
+    policy: Arc<RwLock<Policy>>,
+    state: Arc<Mutex<EngineState<A::WatchGuard>>>,
+    tx: Mutex<RawSender<EngineMsg>>,
+    version: [u32; 4],
+}
+
+impl<A> ThinkerBell<A> where A: API + AdapterManagerHandle + Sync + 'static, A::WatchGuard: Send + 'static {
+    /// Create the rule engine, register it with `api` as an adapter and register its root
+    /// service. Rules are authorized against `policy`.
+    pub fn init(api: &Arc<A>, policy: Arc<RwLock<Policy>>) -> Result<Arc<Self>, Error> {
+        let (tx, rx) = channel();
+        let engine = Arc::new(ThinkerBell {
+            api: Arc::downgrade(api),
+            id: Id::new("thinkerbell@link.mozilla.org"),
+            root: Id::new("thinkerbell-root-service"),
+            setter_add: Id::new("thinkerbell-add-rule"),
+            policy: policy,
+            state: Arc::new(Mutex::new(EngineState {
+                rules: HashMap::new(),
+                generation: 0,
+            })),
+            tx: Mutex::new(tx),
+            version: [0, 1, 0, 0],
+        });
+
+        try!(api.add_adapter(engine.clone() as Arc<Adapter>));
+        let mut root = Service::empty(engine.root.clone(), engine.id.clone());
+        root.setters.insert(engine.setter_add.clone(), engine.setter(&engine.root, &engine.setter_add, ChannelKind::AddThinkerbellRule));
+        if let Err(err) = api.add_service_with_channels(root) {
+            let _ = api.remove_adapter(&engine.id);
+            return Err(err);
+        }
+
+        // Execute actions on a dedicated thread, to avoid blocking the adapters that send
+        // the events.
+        let api = engine.api.clone();
+        let policy = engine.policy.clone();
+        let state = engine.state.clone();
+        thread::spawn(move || {
+            for msg in rx {
+                let msg = match msg {
+                    EngineMsg::Event(msg) => msg,
+                    EngineMsg::Sync(tx) => {
+                        let _ = tx.send(());
+                        continue;
+                    }
+                };
+                let api = match api.upgrade() {
+                    None => return, // The API has been dropped.
+                    Some(api) => api
+                };
+                let trigger = {
+                    let mut state = state.lock().unwrap();
+                    match state.rules.get_mut(&msg.rule) {
+                        Some(rule) => {
+                            if rule.is_on && rule.generation == msg.generation && rule.handle(msg.condition, msg.event) {
+                                Some((rule.script.actions.clone(), rule.principal.clone()))
+                            } else {
+                                None
+                            }
+                        }
+                        None => None
+                    }
+                };
+                if let Some((actions, principal)) = trigger {
+                    if principal.is_expired() {
+                        warn!(target: "Taxonomy-rules", "Rule {} triggered, but its principal has expired", msg.rule);
+                        continue;
+                    }
+                    debug!(target: "Taxonomy-rules", "Rule {} triggered", msg.rule);
+                    let access = AccessControl::new(api, policy.clone(), principal.clone());
+                    for (id, result) in access.send_values(actions, principal) {
+                        if let Err(err) = result {
+                            warn!(target: "Taxonomy-rules", "Rule {} could not send value to {}: {:?}", msg.rule, id, err);
+                        }
+                    }
+                }
+            }
+        });
+        Ok(engine)
+    }
+
+    /// The setter used to add rules.
+    pub fn get_add_rule_setter(&self) -> Id<Setter> {
+        self.setter_add.clone()
+    }
+
+    /// The service representing a rule, if it exists.
+    pub fn get_rule_service(&self, name: &str) -> Option<Id<ServiceId>> {
+        self.state.lock().unwrap().rules.get(name).map(|rule| rule.service.clone())
+    }
+
+    /// Block until all the events received so far have been processed, including the
+    /// values sent by the rules they triggered.
+    pub fn sync(&self) {
+        let (tx, rx) = channel();
+        if self.tx.lock().unwrap().send(EngineMsg::Sync(tx)).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+
+    fn getter(&self, service: &Id<ServiceId>, id: &Id<Getter>, kind: ChannelKind) -> Channel<Getter> {
+        Channel {
+            id: id.clone(),
+            service: service.clone(),
+            adapter: self.id.clone(),
+            last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            tags: HashSet::new(),
+            mechanism: Getter {
+                updated: None,
+                delivery: Delivery::State,
+                kind: kind,
+            },
+        }
+    }
+
+    fn setter(&self, service: &Id<ServiceId>, id: &Id<Setter>, kind: ChannelKind) -> Channel<Setter> {
+        Channel {
+            id: id.clone(),
+            service: service.clone(),
+            adapter: self.id.clone(),
+            last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            tags: HashSet::new(),
+            mechanism: Setter {
+                updated: None,
+                write_only: false,
+                lease: None,
+                kind: kind,
+            },
+        }
+    }
+
+    fn api(&self) -> Result<Arc<A>, Error> {
+        match self.api.upgrade() {
+            Some(api) => Ok(api),
+            None => Err(Error::InternalError(InternalError::GenericError("The API has been dropped".to_owned())))
+        }
+    }
+
+    /// Add a rule, register its service and start watching its conditions.
+    fn add_rule(&self, rule: &ThinkerbellRule, principal: Principal) -> Result<(), Error> {
+        let script = match Script::from_str(&rule.source) {
+            Ok(script) => script,
+            Err(err) => {
+                debug!(target: "Taxonomy-rules", "Invalid rule {}: {:?}", rule.name, err);
+                return Err(Error::InvalidValue(Value::ThinkerbellRule(rule.clone())));
+            }
+        };
+        let api = try!(self.api());
+
+        // The principal must be allowed to watch the conditions and to send the actions.
+        if principal.is_expired() {
+            return Err(Error::PermissionDenied(Permission::Write));
+        }
+        let access = AccessControl::new(api.clone(), self.policy.clone(), principal.clone());
+        for condition in &script.conditions {
+            try!(access.check_getters(condition.select.clone(), Permission::Watch));
+        }
+        for action in &script.actions {
+            try!(access.check_setters(action.select.clone(), Permission::Write));
+        }
+
+        let service_id = Id::<ServiceId>::new(&format!("thinkerbell-rule-{}", rule.name));
+        let getter_source = Id::<Getter>::new(&format!("thinkerbell-rule-{}-source", rule.name));
+        let getter_on = Id::<Getter>::new(&format!("thinkerbell-rule-{}-on", rule.name));
+        let setter_on = Id::<Setter>::new(&format!("thinkerbell-rule-{}-on", rule.name));
+        let setter_remove = Id::<Setter>::new(&format!("thinkerbell-rule-{}-remove", rule.name));
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.rules.contains_key(&rule.name) {
+                return Err(Error::InternalError(InternalError::DuplicateService(service_id)));
+            }
+            let conditions = script.conditions.len();
+            state.rules.insert(rule.name.clone(), RuleState {
+                rule: rule.clone(),
+                script: script,
+                principal: principal.with_origin(Origin::Rule(rule.name.clone())),
+                service: service_id.clone(),
+                getter_source: getter_source.clone(),
+                getter_on: getter_on.clone(),
+                setter_on: setter_on.clone(),
+                setter_remove: setter_remove.clone(),
+                is_on: false,
+                generation: 0,
+                in_range: vec![HashSet::new(); conditions],
+                guards: vec![],
+            });
+        }
+
+        let mut service = Service::empty(service_id.clone(), self.id.clone());
+        service.parent = Some(self.root.clone());
+        service.getters.insert(getter_source.clone(), self.getter(&service_id, &getter_source, ChannelKind::ThinkerbellRuleSource));
+        service.getters.insert(getter_on.clone(), self.getter(&service_id, &getter_on, ChannelKind::ThinkerbellRuleOn));
+        service.setters.insert(setter_on.clone(), self.setter(&service_id, &setter_on, ChannelKind::ThinkerbellRuleOn));
+        service.setters.insert(setter_remove.clone(), self.setter(&service_id, &setter_remove, ChannelKind::RemoveThinkerbellRule));
+        if let Err(err) = api.add_service_with_channels(service) {
+            self.state.lock().unwrap().rules.remove(&rule.name);
+            return Err(err);
+        }
+        self.set_rule_on(&rule.name, true);
+        Ok(())
+    }
+
+    /// Remove a rule and its service, stopping its watches.
+    fn remove_rule(&self, name: &str) -> Result<(), Error> {
+        let api = try!(self.api());
+        let removed = self.state.lock().unwrap().rules.remove(name);
+        match removed {
+            None => Err(Error::InternalError(InternalError::NoSuchService(Id::new(&format!("thinkerbell-rule-{}", name))))),
+            Some(rule) => api.remove_service(&rule.service)
+        }
+    }
+
+    /// Enable or disable a rule. Enabling a rule that is already enabled restarts its watches.
+    fn set_rule_on(&self, name: &str, is_on: bool) {
+        let (generation, conditions, principal, previous) = {
+            let mut state = self.state.lock().unwrap();
+            state.generation += 1;
+            let generation = state.generation;
+            match state.rules.get_mut(name) {
+                None => return,
+                Some(rule) => {
+                    rule.is_on = is_on;
+                    rule.generation = generation;
+                    for getters in &mut rule.in_range {
+                        getters.clear();
+                    }
+                    let previous : Vec<_> = rule.guards.drain(..).collect();
+                    (generation, rule.script.conditions.clone(), rule.principal.clone(), previous)
+                }
+            }
+        };
+        // Stop the previous watches outside of the lock.
+        drop(previous);
+        if !is_on {
+            return;
+        }
+        let api = match self.api.upgrade() {
+            None => return,
+            Some(api) => api
+        };
+        // Watch only the getters that the principal may watch.
+        let access = AccessControl::new(api, self.policy.clone(), principal);
+        let guards : Vec<_> = conditions.into_iter().enumerate().map(|(index, condition)| {
+            let rule = name.to_owned();
+            let tx = self.tx.lock().unwrap().map(move |event| {
+                EngineMsg::Event(RuleEvent {
+                    rule: rule.clone(),
+                    generation: generation,
+                    condition: index,
+                    event: event,
+                })
+            });
+            access.watch_values(vec![Targetted::new(condition.select, Exactly::Exactly(condition.range))], Box::new(tx))
+        }).collect();
+        let stale = {
+            let mut state = self.state.lock().unwrap();
+            match state.rules.get_mut(name) {
+                Some(rule) => {
+                    if rule.generation == generation {
+                        rule.guards = guards;
+                        vec![]
+                    } else {
+                        // The rule was disabled or enabled again in the meantime.
+                        guards
+                    }
+                }
+                // The rule was removed in the meantime.
+                None => guards
+            }
+        };
+        drop(stale);
+    }
+}
+
+impl<A> Adapter for ThinkerBell<A> where A: API + AdapterManagerHandle + Sync + 'static, A::WatchGuard: Send + 'static {
+    fn id(&self) -> Id<AdapterId> {
+        self.id.clone()
+    }
+
+    fn name(&self) -> &str {
+        "ThinkerBell"
+    }
+
+    fn vendor(&self) -> &str {
+        "team@link.mozilla.org"
+    }
+
+    fn version(&self) -> &[u32;4] {
+        &self.version
+    }
+
+    fn fetch_values(&self, mut target: Vec<Id<Getter>>, _: Principal) -> ResultMap<Id<Getter>, Option<Value>, Error> {
+        let state = self.state.lock().unwrap();
+        target.drain(..).map(|id| {
+            let mut result = Err(Error::InternalError(InternalError::NoSuchGetter(id.clone())));
+            for rule in state.rules.values() {
+                if rule.getter_source == id {
+                    result = Ok(Some(Value::String(Arc::new(rule.rule.source.clone()))));
+                    break;
+                }
+                if rule.getter_on == id {
+                    result = Ok(Some(Value::OnOff(if rule.is_on { OnOff::On } else { OnOff::Off })));
+                    break;
+                }
+            }
+            (id, result)
+        }).collect()
+    }
+
+    fn send_values(&self, mut values: HashMap<Id<Setter>, Value>, user: Principal) -> ResultMap<Id<Setter>, (), Error> {
+        values.drain().map(|(id, value)| {
+            if id == self.setter_add {
+                let result = match value {
+                    Value::ThinkerbellRule(ref rule) => self.add_rule(rule, user.clone()),
+                    _ => Err(Error::InvalidValue(value.clone()))
+                };
+                return (id, result);
+            }
+            let (name, is_remove) = {
+                let state = self.state.lock().unwrap();
+                let found = state.rules.iter().filter_map(|(name, rule)| {
+                    if rule.setter_on == id {
+                        Some((name.clone(), false))
+                    } else if rule.setter_remove == id {
+                        Some((name.clone(), true))
+                    } else {
+                        None
+                    }
+                }).next();
+                match found {
+                    None => return (id.clone(), Err(Error::InternalError(InternalError::NoSuchSetter(id.clone())))),
+                    Some(found) => found
+                }
+            };
+            let result = match (is_remove, value) {
+                (true, _) => self.remove_rule(&name),
+                (false, Value::OnOff(OnOff::On)) => {
+                    self.set_rule_on(&name, true);
+                    Ok(())
+                }
+                (false, Value::OnOff(OnOff::Off)) => {
+                    self.set_rule_on(&name, false);
+                    Ok(())
+                }
+                (false, value) => Err(Error::InvalidValue(value))
+            };
+            (id, result)
+        }).collect()
+    }
+
+    fn register_watch(&self, mut watch: Vec<WatchTarget>) -> WatchResult {
+        watch.drain(..).map(|(id, _, _)| {
+            let result : Result<Box<AdapterWatchGuard>, Error> = Err(Error::GetterDoesNotSupportWatching(id.clone()));
+            (id, result)
+        }).collect()
+    }
+
+    fn stop(&self) {
+        // Drop all watches.
+        let rules : Vec<_> = self.state.lock().unwrap().rules.drain().collect();
+        drop(rules);
+    }
+}
@@ -0,0 +1,377 @@
+//! Scenes, i.e. named sets of values for setters, captured from the current state of the
+//! devices and restored later, e.g. "movie night".
+//!
+//! A scene is captured by determining the current value of each setter from the getter
+//! of the same kind on the same service. A scene is applied by sending the values with
+//! `send_values`, one step after another, optionally with delays between steps, e.g. to
+//! close the blinds before dimming the lights.
+//!
+//! Scenes may not contain secrets, as secrets are never serialized.
+
+use api::{ API, Error, InternalError, Principal, ResultMap, SceneId, Targetted };
+use selector::{ GetterSelector, SetterSelector };
+use services::Setter;
+use util::{ Id, JsonFile };
+use values::{ Duration, Value };
+
+use chrono::Duration as ChronoDuration;
+
+use transformable_channels::mpsc::*;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{ Arc, Condvar, Mutex };
+use std::thread;
+use std::time::{ Duration as StdDuration, Instant };
+
+/// A group of values sent together.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneStep {
+    /// The delay to wait for before sending the values, after the previous step.
+    #[serde(default)]
+    pub delay: Option<Duration>,
+
+    /// The values to send, per setter.
+    pub values: HashMap<Id<Setter>, Value>,
+}
+
+/// A named set of values for setters, sent in one or more steps.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub id: Id<SceneId>,
+    pub steps: Vec<SceneStep>,
+}
+
+impl Scene {
+    /// The first value of the scene that is a secret, if any.
+    fn find_secret(&self) -> Option<&Value> {
+        self.steps.iter()
+            .flat_map(|step| step.values.values())
+            .find(|value| match **value { Value::Secret(_) => true, _ => false })
+    }
+
+    /// Create a scene without any step.
+    pub fn new(id: Id<SceneId>) -> Self {
+        Scene {
+            id: id,
+            steps: vec![],
+        }
+    }
+
+    /// Add a step to the scene, sent after waiting for `delay`, if specified.
+    pub fn with_step(mut self, values: HashMap<Id<Setter>, Value>, delay: Option<Duration>) -> Self {
+        self.steps.push(SceneStep {
+            delay: delay,
+            values: values,
+        });
+        self
+    }
+}
+
+/// A set of scenes, indexed by id, optionally persisted to a JSON file.
+pub struct SceneStore {
+    scenes: Mutex<HashMap<Id<SceneId>, Scene>>,
+    file: Option<JsonFile>,
+}
+
+impl SceneStore {
+    /// Create a store kept only in memory.
+    pub fn new() -> Self {
+        SceneStore {
+            scenes: Mutex::new(HashMap::new()),
+            file: None,
+        }
+    }
+
+    /// Open a store backed by a file, loading the scenes it already contains. The file is
+    /// rewritten whenever a scene is added or removed.
+    pub fn open<P>(path: P) -> Result<Self, Error> where P: AsRef<Path> {
+        let file = JsonFile::new(path);
+        let mut scenes = HashMap::new();
+        if let Some(list) = try!(file.load::<Vec<Scene>>()) {
+            for scene in list {
+                scenes.insert(scene.id.clone(), scene);
+            }
+        }
+        Ok(SceneStore {
+            scenes: Mutex::new(scenes),
+            file: Some(file),
+        })
+    }
+
+    pub fn get(&self, id: &Id<SceneId>) -> Option<Scene> {
+        self.scenes.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn get_scenes(&self) -> Vec<Scene> {
+        self.scenes.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Add a scene, replacing any scene with the same id.
+    ///
+    /// Scenes containing secrets are rejected with `Error::InvalidValue`.
+    pub fn put(&self, scene: Scene) -> Result<(), Error> {
+        if let Some(secret) = scene.find_secret() {
+            return Err(Error::InvalidValue(secret.clone()));
+        }
+        let mut scenes = self.scenes.lock().unwrap();
+        scenes.insert(scene.id.clone(), scene);
+        self.save(&*scenes)
+    }
+
+    /// Remove a scene, returning `true` if it existed.
+    pub fn remove(&self, id: &Id<SceneId>) -> Result<bool, Error> {
+        let mut scenes = self.scenes.lock().unwrap();
+        if scenes.remove(id).is_none() {
+            return Ok(false);
+        }
+        try!(self.save(&*scenes));
+        Ok(true)
+    }
+
+    /// Write the scenes to the file, if any.
+    fn save(&self, scenes: &HashMap<Id<SceneId>, Scene>) -> Result<(), Error> {
+        match self.file {
+            None => Ok(()),
+            Some(ref file) => file.save(&scenes.values().collect::<Vec<_>>())
+        }
+    }
+}
+
+impl Default for SceneStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The results of sending one step of a scene.
+#[derive(Debug)]
+pub struct StepResult {
+    pub scene: Id<SceneId>,
+
+    /// The index of the step in `Scene::steps`.
+    pub step: usize,
+
+    /// The results, per setter. Setters that are not registered anymore are reported with
+    /// `InternalError::NoSuchSetter`.
+    pub results: ResultMap<Id<Setter>, (), Error>,
+}
+
+/// A way to cancel the steps of a scene that are still waiting for their delay.
+struct Cancellation {
+    is_cancelled: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Cancellation {
+    fn new() -> Self {
+        Cancellation {
+            is_cancelled: Mutex::new(false),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn cancel(&self) {
+        *self.is_cancelled.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    /// Wait for `duration`, unless cancelled in the meantime. Returns `true` if cancelled.
+    fn wait(&self, duration: StdDuration) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut is_cancelled = self.is_cancelled.lock().unwrap();
+        while !*is_cancelled {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            is_cancelled = self.condvar.wait_timeout(is_cancelled, deadline - now).unwrap().0;
+        }
+        *is_cancelled
+    }
+}
+
+/// Send the values of a step, reporting the setters that have disappeared.
+fn send_step<A>(api: &A, step: &SceneStep, user: Principal) -> ResultMap<Id<Setter>, (), Error> where A: API {
+    let targets = step.values.iter().map(|(setter, value)| {
+        Targetted::new(vec![SetterSelector::new().with_id(setter.clone())], value.clone())
+    }).collect();
+    let mut results = api.send_values(targets, user);
+    for setter in step.values.keys() {
+        if !results.contains_key(setter) {
+            results.insert(setter.clone(), Err(Error::InternalError(InternalError::NoSuchSetter(setter.clone()))));
+        }
+    }
+    results
+}
+
+/// Capturing and applying scenes on top of an implementation of the API.
+pub struct Scenes<A> where A: API {
+    api: Arc<A>,
+    store: SceneStore,
+
+    /// The scenes whose steps are waiting for their delay.
+    pending: Arc<Mutex<HashMap<Id<SceneId>, Arc<Cancellation>>>>,
+}
+
+impl<A> Scenes<A> where A: API {
+    pub fn new(api: Arc<A>, store: SceneStore) -> Self {
+        Scenes {
+            api: api,
+            store: store,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Capture the current values of all the setters matching any of the selectors as a
+    /// scene with a single step, and store it, replacing any scene with the same id.
+    ///
+    /// The value of a setter is the value of a getter with the same kind on the same
+    /// service. If `max_age` is specified, values fetched at most `max_age` ago are used
+    /// rather than fetching fresh values. Setters whose value cannot be determined, or is
+    /// a secret, are left out of the scene.
+    pub fn capture(&self, id: Id<SceneId>, selectors: Vec<SetterSelector>, max_age: Option<Duration>, user: Principal) -> Result<Scene, Error> {
+        let setters = self.api.get_setter_channels(selectors);
+
+        // Pair each setter with the getters of the same kind on the same service.
+        let pairs : Vec<_> = setters.into_iter().map(|setter| {
+            let selector = GetterSelector::new()
+                .with_parent(setter.service.clone())
+                .with_kind(setter.mechanism.kind.clone());
+            (setter.id, selector)
+        }).collect();
+        let selectors = pairs.iter().map(|&(_, ref selector)| selector.clone()).collect();
+        let values = match max_age {
+            Some(max_age) => self.api.fetch_cached_values(selectors, max_age, user),
+            None => self.api.fetch_values(selectors, user)
+        };
+
+        let mut captured = HashMap::new();
+        for (setter, selector) in pairs {
+            let getters = self.api.get_getter_channels(vec![selector]);
+            let value = getters.iter().filter_map(|getter| {
+                match values.get(&getter.id) {
+                    Some(&Ok(Some(Value::Secret(_)))) => None,
+                    Some(&Ok(Some(ref value))) => Some(value.clone()),
+                    _ => None
+                }
+            }).next();
+            match value {
+                Some(value) => {
+                    captured.insert(setter, value);
+                }
+                None => debug!(target: "Taxonomy-scenes", "Could not capture the value of setter {}", setter)
+            }
+        }
+
+        let scene = Scene::new(id).with_step(captured, None);
+        try!(self.store.put(scene.clone()));
+        Ok(scene)
+    }
+
+    /// Cancel the steps of a scene that are still waiting for their delay, if any. Returns
+    /// `true` if there were such steps.
+    pub fn cancel(&self, id: &Id<SceneId>) -> bool {
+        match self.pending.lock().unwrap().remove(id) {
+            None => false,
+            Some(cancellation) => {
+                cancellation.cancel();
+                true
+            }
+        }
+    }
+
+    /// Store a scene, e.g. one built with several steps, replacing any scene with the same id.
+    pub fn put(&self, scene: Scene) -> Result<(), Error> {
+        self.store.put(scene)
+    }
+
+    pub fn get(&self, id: &Id<SceneId>) -> Option<Scene> {
+        self.store.get(id)
+    }
+
+    pub fn get_scenes(&self) -> Vec<Scene> {
+        self.store.get_scenes()
+    }
+
+    /// Remove a scene, returning `true` if it existed. Steps of the scene that are still
+    /// waiting for their delay are cancelled.
+    pub fn remove(&self, id: &Id<SceneId>) -> Result<bool, Error> {
+        self.cancel(id);
+        self.store.remove(id)
+    }
+}
+
+impl<A> Scenes<A> where A: API + Sync + 'static {
+    /// Apply a scene, sending its steps one after another, each after its delay, if any.
+    ///
+    /// The steps that have no delay and precede any delayed step are sent before this method
+    /// returns. The other steps are sent from a background thread, so that the caller is
+    /// not blocked while they wait. The results of each step are sent to `on_step`, which
+    /// is dropped once the last step has been sent or the scene has been cancelled.
+    ///
+    /// Applying a scene cancels the steps of any previous application of the same scene
+    /// that are still waiting for their delay.
+    pub fn apply(&self, id: &Id<SceneId>, user: Principal, on_step: Box<ExtSender<StepResult>>) -> Result<(), Error> {
+        let scene = match self.store.get(id) {
+            None => return Err(Error::InternalError(InternalError::NoSuchScene(id.clone()))),
+            Some(scene) => scene
+        };
+        self.cancel(id);
+
+        let delay_of = |step: &SceneStep| -> StdDuration {
+            let delay : ChronoDuration = match step.delay {
+                None => return StdDuration::from_millis(0),
+                Some(ref delay) => delay.clone().into()
+            };
+            let millis = delay.num_milliseconds();
+            StdDuration::from_millis(if millis > 0 { millis as u64 } else { 0 })
+        };
+        let mut steps = scene.steps.into_iter().enumerate().peekable();
+        loop {
+            let is_immediate = match steps.peek() {
+                None => return Ok(()),
+                Some(&(_, ref step)) => delay_of(step) == StdDuration::from_millis(0)
+            };
+            if !is_immediate {
+                break;
+            }
+            let (index, step) = steps.next().unwrap();
+            let _ = on_step.send(StepResult {
+                scene: id.clone(),
+                step: index,
+                results: send_step(&*self.api, &step, user.clone()),
+            });
+        }
+
+        let steps : Vec<_> = steps.map(|(index, step)| (index, delay_of(&step), step)).collect();
+        let cancellation = Arc::new(Cancellation::new());
+        self.pending.lock().unwrap().insert(id.clone(), cancellation.clone());
+        let api = self.api.clone();
+        let pending = self.pending.clone();
+        let id = id.clone();
+        thread::spawn(move || {
+            for (index, delay, step) in steps {
+                if cancellation.wait(delay) {
+                    debug!(target: "Taxonomy-scenes", "Scene {} cancelled before step {}", id, index);
+                    return;
+                }
+                let _ = on_step.send(StepResult {
+                    scene: id.clone(),
+                    step: index,
+                    results: send_step(&*api, &step, user.clone()),
+                });
+            }
+            // Forget about this application of the scene, unless it has been replaced.
+            let mut pending = pending.lock().unwrap();
+            let is_current = match pending.get(&id) {
+                Some(current) => &**current as *const Cancellation == &*cancellation as *const Cancellation,
+                None => false
+            };
+            if is_current {
+                pending.remove(&id);
+            }
+        });
+        Ok(())
+    }
+}
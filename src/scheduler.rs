@@ -0,0 +1,491 @@
+//! Scheduled operations, e.g. "turn on the porch lights at 19:00 every day", without
+//! requiring a full rules engine.
+//!
+//! A job sends values to setters on behalf of a principal, either at a given date or
+//! repeatedly, following a cron expression evaluated in the timezone of the home. Values are
+//! sent with origin `Origin::Job(id)`, only to the setters that the policy lets the principal
+//! write to, and jobs whose principal has expired are skipped. Jobs
+//! are executed by calling `Scheduler::tick`, either periodically from a thread started
+//! with `Scheduler::start` or manually. The current date is read from a `Clock`, so that
+//! the scheduler can be tested without waiting.
+
+use api::{ API, Error, JobId, Origin, Principal, ResultMap, TargetMap };
+use policy::{ AccessControl, Policy };
+use selector::SetterSelector;
+use services::Setter;
+use util::{ Id, JsonFile, TrivialEnumVisitor };
+use values::{ Date, Duration, HomeTimeZone, TimeOfDay, TimeStamp, Value };
+
+use chrono::{ Datelike, Duration as ChronoDuration, UTC };
+
+use serde::ser::{ Serialize, Serializer };
+use serde::de::{ Deserialize, Deserializer };
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration as StdDuration;
+
+/// A source for the current date.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> TimeStamp;
+}
+
+/// The clock of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> TimeStamp {
+        TimeStamp::from_datetime(UTC::now())
+    }
+}
+
+/// A clock that only moves when told to, for testing purposes.
+pub struct FakeClock {
+    now: Mutex<TimeStamp>,
+}
+
+impl FakeClock {
+    pub fn new(now: TimeStamp) -> Self {
+        FakeClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: TimeStamp) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let duration : ChronoDuration = duration.into();
+        let mut now = self.now.lock().unwrap();
+        *now = TimeStamp::from_datetime(*now.as_datetime() + duration);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> TimeStamp {
+        self.now.lock().unwrap().clone()
+    }
+}
+
+impl<C> Clock for Arc<C> where C: Clock {
+    fn now(&self) -> TimeStamp {
+        (**self).now()
+    }
+}
+
+/// A cron expression, i.e. five fields `minute hour day-of-month month day-of-week`.
+///
+/// Each field is either `*`, a value, a range `a-b` or a comma-separated list of these,
+/// each optionally followed by a step `/n`. Days of the week are numbered from 0 (Sunday)
+/// to 6 (Saturday), 7 also meaning Sunday. As in cron, if both the day of the month and
+/// the day of the week are restricted, i.e. do not start with `*`, a day matches if either
+/// of them matches.
+///
+/// # (De)serialization
+///
+/// Serialized values of this type are represented by the expression, as a string.
+///
+/// ```
+/// use foxbox_taxonomy::scheduler::*;
+///
+/// assert!(CronSchedule::new("0 19 * * *").is_some());
+/// assert!(CronSchedule::new("*/15 9-17 * * 1-5").is_some());
+/// assert!(CronSchedule::new("0 24 * * *").is_none());
+/// assert!(CronSchedule::new("0 19 * *").is_none());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    restricts_day_of_month: bool,
+    restricts_day_of_week: bool,
+}
+
+impl CronSchedule {
+    /// Parse a cron expression. Return `None` if the expression is invalid.
+    pub fn new(source: &str) -> Option<Self> {
+        let fields : Vec<_> = source.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None;
+        }
+        let minutes = match Self::parse_field(fields[0], 0, 59) {
+            Some(mask) => mask,
+            None => return None
+        };
+        let hours = match Self::parse_field(fields[1], 0, 23) {
+            Some(mask) => mask,
+            None => return None
+        };
+        let days_of_month = match Self::parse_field(fields[2], 1, 31) {
+            Some(mask) => mask,
+            None => return None
+        };
+        let months = match Self::parse_field(fields[3], 1, 12) {
+            Some(mask) => mask,
+            None => return None
+        };
+        let mut days_of_week = match Self::parse_field(fields[4], 0, 7) {
+            Some(mask) => mask,
+            None => return None
+        };
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Some(CronSchedule {
+            source: fields.join(" "),
+            minutes: minutes,
+            hours: hours,
+            days_of_month: days_of_month,
+            months: months,
+            days_of_week: days_of_week,
+            // As in cron, a field starting with `*`, e.g. `*/2`, doesn't restrict days.
+            restricts_day_of_month: !fields[2].starts_with('*'),
+            restricts_day_of_week: !fields[4].starts_with('*'),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Parse a field into a bit mask of the values it accepts.
+    fn parse_field(source: &str, min: u32, max: u32) -> Option<u64> {
+        let mut mask = 0;
+        for part in source.split(',') {
+            let mut split = part.splitn(2, '/');
+            let range = split.next().unwrap_or("");
+            let step = match split.next() {
+                None => 1,
+                Some(step) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => step,
+                    _ => return None
+                }
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else {
+                let mut bounds = range.splitn(2, '-');
+                let start = match bounds.next().map(|start| start.parse::<u32>()) {
+                    Some(Ok(start)) => start,
+                    _ => return None
+                };
+                let end = match bounds.next() {
+                    // With a step, `a/n` means `a-max/n`.
+                    None if part.contains('/') => max,
+                    None => start,
+                    Some(end) => match end.parse::<u32>() {
+                        Ok(end) => end,
+                        Err(_) => return None
+                    }
+                };
+                (start, end)
+            };
+            if start < min || end > max || start > end {
+                return None;
+            }
+            let mut value = start;
+            while value <= end {
+                mask |= 1 << value;
+                value += step;
+            }
+        }
+        Some(mask)
+    }
+
+    fn matches_date(&self, date: &Date) -> bool {
+        let date = date.as_naive_date();
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day_of_month = self.days_of_month & (1 << date.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+        if self.restricts_day_of_month && self.restricts_day_of_week {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    /// The first instant strictly after `date` matching this expression, in a given
+    /// timezone, or `None` if no such instant exists, e.g. for "0 0 31 2 *".
+    pub fn next_after(&self, date: &TimeStamp, timezone: &HomeTimeZone) -> Option<TimeStamp> {
+        // Leap days may only happen on a given day of the week once every 28 years.
+        const MAX_DAYS : u32 = 366 * 28;
+
+        let mut day = timezone.date(date);
+        for _ in 0..MAX_DAYS {
+            if self.matches_date(&day) {
+                for hour in 0..24 {
+                    if self.hours & (1 << hour) == 0 {
+                        continue;
+                    }
+                    for minute in 0..60 {
+                        if self.minutes & (1 << minute) == 0 {
+                            continue;
+                        }
+                        let time = match TimeOfDay::from_hms(hour, minute, 0) {
+                            Some(time) => time,
+                            None => continue
+                        };
+                        // Skip local times that don't exist because of daylight-saving.
+                        if let Some(candidate) = timezone.timestamp(&day, &time) {
+                            if candidate > *date {
+                                return Some(candidate);
+                            }
+                        }
+                    }
+                }
+            }
+            let next = day.as_naive_date().succ();
+            day = match Date::from_ymd(next.year(), next.month(), next.day()) {
+                Some(next) => next,
+                None => return None
+            };
+        }
+        None
+    }
+}
+
+impl Serialize for CronSchedule {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.source.serialize(serializer)
+    }
+}
+
+impl Deserialize for CronSchedule {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            CronSchedule::new(source).ok_or(())
+        }))
+    }
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Run once, at a given date. If the date is already past when the job is added, run
+    /// at the next tick.
+    Once(TimeStamp),
+
+    /// Run at each instant matching a cron expression, in the timezone of the home.
+    Cron(CronSchedule),
+}
+
+/// The outcome of an execution of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRun {
+    /// The date at which the job was executed.
+    pub date: TimeStamp,
+
+    /// The result of `send_values`, per setter.
+    pub results: ResultMap<Id<Setter>, (), Error>,
+}
+
+/// Values to send to setters on a schedule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Id<JobId>,
+
+    pub schedule: Schedule,
+
+    /// The values to send. Secrets are not accepted, as they are never serialized.
+    pub values: TargetMap<SetterSelector, Value>,
+
+    /// The principal on behalf of whom the values are sent.
+    pub principal: Principal,
+
+    /// The next date at which the job will run, or `None` if it will not run anymore.
+    /// Maintained by the scheduler.
+    #[serde(default)]
+    pub next_run: Option<TimeStamp>,
+
+    /// The outcome of the latest execution, if any. Maintained by the scheduler.
+    #[serde(default)]
+    pub last_run: Option<JobRun>,
+}
+
+impl Job {
+    pub fn new(id: Id<JobId>, schedule: Schedule, values: TargetMap<SetterSelector, Value>, principal: Principal) -> Self {
+        Job {
+            id: id,
+            schedule: schedule,
+            values: values,
+            principal: principal,
+            next_run: None,
+            last_run: None,
+        }
+    }
+}
+
+/// Executing jobs on top of an implementation of the API.
+pub struct Scheduler<A, C> where A: API, C: Clock {
+    api: Arc<A>,
+    policy: Arc<RwLock<Policy>>,
+    clock: C,
+    jobs: Mutex<HashMap<Id<JobId>, Job>>,
+    file: Option<JsonFile>,
+    stopped: AtomicBool,
+}
+
+impl<A, C> Scheduler<A, C> where A: API + Sync + 'static, C: Clock {
+    /// Create a scheduler whose jobs are kept only in memory. Jobs are authorized against
+    /// `policy`.
+    pub fn new(api: Arc<A>, policy: Arc<RwLock<Policy>>, clock: C) -> Self {
+        Scheduler {
+            api: api,
+            policy: policy,
+            clock: clock,
+            jobs: Mutex::new(HashMap::new()),
+            file: None,
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Create a scheduler whose jobs are stored in a file, loading the jobs it already
+    /// contains. The file is rewritten whenever a job is added, removed or executed.
+    pub fn open<P>(api: Arc<A>, policy: Arc<RwLock<Policy>>, clock: C, path: P) -> Result<Self, Error> where P: AsRef<Path> {
+        let file = JsonFile::new(path);
+        let mut jobs = HashMap::new();
+        if let Some(list) = try!(file.load::<Vec<Job>>()) {
+            for job in list {
+                jobs.insert(job.id.clone(), job);
+            }
+        }
+        Ok(Scheduler {
+            file: Some(file),
+            jobs: Mutex::new(jobs),
+            ..Self::new(api, policy, clock)
+        })
+    }
+
+    /// Add a job, replacing any job with the same id. Return the job, with its next run.
+    ///
+    /// Jobs sending secrets are rejected with `Error::InvalidValue`.
+    pub fn add_job(&self, mut job: Job) -> Result<Job, Error> {
+        for target in &job.values {
+            if let Value::Secret(_) = target.payload {
+                return Err(Error::InvalidValue(target.payload.clone()));
+            }
+        }
+        job.next_run = match job.schedule {
+            Schedule::Once(ref date) => Some(date.clone()),
+            Schedule::Cron(ref cron) => cron.next_after(&self.clock.now(), &self.api.get_home_timezone())
+        };
+        job.last_run = None;
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.insert(job.id.clone(), job.clone());
+        try!(self.save(&*jobs));
+        Ok(job)
+    }
+
+    /// Remove a job, returning `true` if it existed.
+    pub fn remove_job(&self, id: &Id<JobId>) -> Result<bool, Error> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.remove(id).is_none() {
+            return Ok(false);
+        }
+        try!(self.save(&*jobs));
+        Ok(true)
+    }
+
+    pub fn get_job(&self, id: &Id<JobId>) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn get_jobs(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Execute all the jobs whose next run is due, then schedule their next run. Return
+    /// the ids of the jobs executed.
+    ///
+    /// A job that missed several runs, e.g. because the scheduler was not ticked for a
+    /// while, is executed only once. A job whose principal has expired is not executed, but
+    /// its next run is scheduled nevertheless.
+    pub fn tick(&self) -> Result<Vec<Id<JobId>>, Error> {
+        let now = self.clock.now();
+        let due : Vec<Job> = {
+            // Acquire and release lock asap, as sending values may take time.
+            let jobs = self.jobs.lock().unwrap();
+            jobs.values().filter(|job| {
+                match job.next_run {
+                    Some(ref date) => *date <= now,
+                    None => false
+                }
+            }).cloned().collect()
+        };
+        if due.is_empty() {
+            return Ok(vec![]);
+        }
+        let timezone = self.api.get_home_timezone();
+        let mut executed = Vec::with_capacity(due.len());
+        for job in due {
+            let results = if job.principal.is_expired() {
+                warn!(target: "Taxonomy-scheduler", "Job {} is due, but its principal has expired", job.id);
+                None
+            } else {
+                let principal = job.principal.clone().with_origin(Origin::Job(job.id.clone()));
+                let access = AccessControl::new(self.api.clone(), self.policy.clone(), principal.clone());
+                Some(access.send_values(job.values.clone(), principal))
+            };
+            let is_executed = results.is_some();
+            let mut jobs = self.jobs.lock().unwrap();
+            if let Some(stored) = jobs.get_mut(&job.id) {
+                // Ignore the run if the job was replaced in the meantime.
+                if stored.next_run == job.next_run && stored.schedule == job.schedule {
+                    if let Some(results) = results {
+                        stored.last_run = Some(JobRun {
+                            date: now.clone(),
+                            results: results,
+                        });
+                    }
+                    stored.next_run = match stored.schedule {
+                        Schedule::Once(_) => None,
+                        Schedule::Cron(ref cron) => cron.next_after(&now, &timezone)
+                    };
+                }
+            }
+            if is_executed {
+                executed.push(job.id);
+            }
+        }
+        let jobs = self.jobs.lock().unwrap();
+        try!(self.save(&*jobs));
+        Ok(executed)
+    }
+
+    /// Stop the thread started by `start`, if any, after its current tick.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Write the jobs to the file, if any.
+    fn save(&self, jobs: &HashMap<Id<JobId>, Job>) -> Result<(), Error> {
+        match self.file {
+            None => Ok(()),
+            Some(ref file) => file.save(&jobs.values().collect::<Vec<_>>())
+        }
+    }
+}
+
+impl<A, C> Scheduler<A, C> where A: API + Sync + 'static, C: Clock + 'static {
+    /// Start a thread calling `tick` every `period`, until `stop` is called.
+    pub fn start(scheduler: Arc<Self>, period: StdDuration) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while !scheduler.stopped.load(Ordering::SeqCst) {
+                if let Err(err) = scheduler.tick() {
+                    warn!(target: "Taxonomy-scheduler", "Could not execute jobs: {:?}", err);
+                }
+                thread::sleep(period);
+            }
+        })
+    }
+}
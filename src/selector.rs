@@ -5,13 +5,14 @@
 //! living room (that's a selector), rather than needing to access every single heater one by one.
 
 pub use parse::*;
-use services::{ Service, ChannelKind, Channel, Getter, Setter };
+use profiles::Profile;
+use services::{ Service, ChannelKind, Channel, Action, Delivery, Getter, Setter };
 use util::*;
 use values::Duration;
 
 use std::cmp;
 use std::hash::Hash;
-use std::collections::HashSet;
+use std::collections::{ HashMap, HashSet };
 
 fn merge<T>(mut a: HashSet<T>, b: Vec<T>) -> HashSet<T> where T: Hash + Eq {
     for x in b {
@@ -20,16 +21,153 @@ fn merge<T>(mut a: HashSet<T>, b: Vec<T>) -> HashSet<T> where T: Hash + Eq {
     a
 }
 
+fn merge_properties(mut a: HashMap<String, String>, b: Vec<(String, String)>) -> HashMap<String, String> {
+    for (k, v) in b {
+        a.insert(k, v);
+    }
+    a
+}
+
 pub trait SelectedBy<T> {
     fn matches(&self, &T) -> bool;
 }
 
+/// A criterion used by a selector to accept or reject a service or a channel.
+///
+/// # JSON
+///
+/// Criteria are represented by the name of the corresponding field of the selector, i.e.
+/// `"id"`, `"service"`, `"kind"`, `"delivery"`, `"tags"`, `"service_tags"`, `"user_properties"`,
+/// `"location"`, `"parent"`, `"profile"`, `"getters"` or `"setters"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Criterion {
+    /// Field `id` of the selector.
+    Id,
+
+    /// Field `service` of a channel selector.
+    Parent,
+
+    /// Field `kind` of a channel selector.
+    Kind,
+
+    /// Field `delivery` of a getter selector.
+    Delivery,
+
+    /// Field `tags` of the selector.
+    Tags,
+
+    /// Field `service_tags` of a channel selector.
+    ServiceTags,
+
+    /// Field `user_properties` of the selector.
+    UserProperties,
+
+    /// Field `location` of a service selector.
+    Location,
+
+    /// Field `parent` of a service selector.
+    ParentService,
+
+    /// Field `profile` of a service selector.
+    Profile,
+
+    /// Field `getters` of a service selector.
+    Getters,
+
+    /// Field `setters` of a service selector.
+    Setters,
+}
+
+impl ToJSON for Criterion {
+    fn to_json(&self) -> JSON {
+        use self::Criterion::*;
+        let name = match *self {
+            Id => "id",
+            Parent => "service",
+            Kind => "kind",
+            Delivery => "delivery",
+            Tags => "tags",
+            ServiceTags => "service_tags",
+            UserProperties => "user_properties",
+            Location => "location",
+            ParentService => "parent",
+            Profile => "profile",
+            Getters => "getters",
+            Setters => "setters",
+        };
+        JSON::String(name.to_owned())
+    }
+}
+
+/// A report on whether a selector accepts a service or a channel, and why.
+///
+/// Only the criteria actually specified by the selector are reported. This is designed
+/// for debugging, e.g. to find out why a watch never fires.
+///
+/// # JSON
+///
+/// An explanation is represented by an object with the following fields:
+///
+/// - id: string - the id of the service or channel;
+/// - matches: bool - `true` if the selector accepts the service or channel;
+/// - matched: array of string - the criteria that accepted the service or channel (see `Criterion`);
+/// - failed: array of string - the criteria that rejected the service or channel (see `Criterion`).
+#[derive(Clone, Debug)]
+pub struct Explanation<T> {
+    /// The service or channel being examined.
+    pub id: Id<T>,
+
+    /// The criteria that accepted the service or channel.
+    pub matched: Vec<Criterion>,
+
+    /// The criteria that rejected the service or channel.
+    pub failed: Vec<Criterion>,
+}
+
+impl<T> Explanation<T> {
+    fn new(id: Id<T>) -> Self {
+        Explanation {
+            id: id,
+            matched: vec![],
+            failed: vec![],
+        }
+    }
+
+    fn check(&mut self, criterion: Criterion, accepted: bool) {
+        if accepted {
+            self.matched.push(criterion)
+        } else {
+            self.failed.push(criterion)
+        }
+    }
+
+    /// `true` if the selector accepts the service or channel, i.e. if no criterion failed.
+    pub fn matches(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl<T> ToJSON for Explanation<T> {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("id", self.id.to_json()),
+            ("matches", self.matches().to_json()),
+            ("matched", self.matched.to_json()),
+            ("failed", self.failed.to_json()),
+        ].to_json()
+    }
+}
+
 /// A trait used to let `ServiceSelector` work on complex data structures
 /// that are not necessarily exactly Selector.
 pub trait ServiceLike {
     fn id(&self) -> &Id<ServiceId>;
     fn adapter(&self) -> &Id<AdapterId>;
     fn with_tags<F>(&self, f: F) -> bool where F: Fn(&HashSet<Id<TagId>>) -> bool;
+    fn user_properties(&self) -> &HashMap<String, String>;
+    fn is_in_location(&self, location: &Id<LocationId>) -> bool;
+    fn parent(&self) -> Option<&Id<ServiceId>>;
+    fn has_profile(&self, profile: &Id<ProfileId>) -> bool;
     fn has_getters<F>(&self, f: F) -> bool where F: Fn(&Channel<Getter>) -> bool;
     fn has_setters<F>(&self, f: F) -> bool where F: Fn(&Channel<Setter>) -> bool;
 }
@@ -44,6 +182,23 @@ impl ServiceLike for Service {
     fn with_tags<F>(&self, f: F) -> bool where F: Fn(&HashSet<Id<TagId>>) -> bool {
         f(&self.tags)
     }
+    fn user_properties(&self) -> &HashMap<String, String> {
+        &self.user_properties
+    }
+    fn is_in_location(&self, location: &Id<LocationId>) -> bool {
+        self.location.as_ref() == Some(location) || self.location_ancestors.contains(location)
+    }
+    fn parent(&self) -> Option<&Id<ServiceId>> {
+        self.parent.as_ref()
+    }
+    fn has_profile(&self, profile: &Id<ProfileId>) -> bool {
+        // Built-in profiles may be checked against the channels. Other profiles are only
+        // known to the manager, which computes `profiles`.
+        match Profile::builtin(profile) {
+            Some(builtin) => builtin.is_satisfied_by(self),
+            None => self.profiles.contains(profile)
+        }
+    }
     fn has_getters<F>(&self, f: F) -> bool where F: Fn(&Channel<Getter>) -> bool {
         for chan in self.getters.values() {
             if f(chan) {
@@ -83,6 +238,12 @@ impl ServiceLike for Service {
 ///
 /// - (optional) string `id`: accept only a service with a given id;
 /// - (optional) array of string `tags`:  accept only services with all the tags in the array;
+/// - (optional) object `user_properties`: accept only services whose user properties contain
+///    all the (key, value) pairs of this object;
+/// - (optional) string `location`: accept only services placed in this location or in any
+///    of its descendants;
+/// - (optional) string `parent`: accept only the children of the service with this id;
+/// - (optional) string `profile`: accept only services that satisfy the profile with this id;
 /// - (optional) array of objects `getters` (see `GetterSelector`): accept only services with
 ///    channels matching all the selectors in this array;
 /// - (optional) array of objects `setters` (see `SetterSelector`): accept only services with
@@ -97,6 +258,10 @@ impl ServiceLike for Service {
 /// let json_selector = "{
 ///   \"id\": \"setter 1\",
 ///   \"tags\": [\"tag 1\", \"tag 2\"],
+///   \"user_properties\": {\"name\": \"Reading lamp\"},
+///   \"location\": \"ground floor\",
+///   \"parent\": \"hub 1\",
+///   \"profile\": \"Light\",
 ///   \"getters\": [{
 ///     \"kind\": \"Ready\"
 ///   }],
@@ -122,6 +287,20 @@ pub struct ServiceSelector {
     ///  Restrict results to services that have all the tags in `tags`.
     pub tags: HashSet<Id<TagId>>,
 
+    /// Restrict results to services whose user properties contain all the
+    /// (key, value) pairs in `user_properties`.
+    pub user_properties: HashMap<String, String>,
+
+    /// If `Exactly(location)`, restrict results to services placed in `location`
+    /// or in any of its descendants.
+    pub location: Exactly<Id<LocationId>>,
+
+    /// If `Exactly(parent)`, restrict results to the children of service `parent`.
+    pub parent: Exactly<Id<ServiceId>>,
+
+    /// If `Exactly(profile)`, restrict results to services that satisfy `profile`.
+    pub profile: Exactly<Id<ProfileId>>,
+
     /// Restrict results to services that have all the getters in `getters`.
     pub getters: Vec<GetterSelector>,
 
@@ -153,6 +332,35 @@ impl Parser<ServiceSelector> for ServiceSelector {
             }
             Some(Err(err)) => return Err(err),
         };
+        let user_properties = match path.push("user_properties", |path| HashMap::take_opt(path, source, "user_properties")) {
+            None => HashMap::new(),
+            Some(Ok(map)) => {
+                is_empty = false;
+                map
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let location = try!(match path.push("location", |path| Exactly::take_opt(path, source, "location")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        let parent = try!(match path.push("parent", |path| Exactly::take_opt(path, source, "parent")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        let profile = try!(match path.push("profile", |path| Exactly::take_opt(path, source, "profile")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
         let getters = match path.push("getters", |path| GetterSelector::take_vec_opt(path, source, "getters")) {
             None => vec![],
             Some(Ok(vec)) => {
@@ -176,6 +384,10 @@ impl Parser<ServiceSelector> for ServiceSelector {
             Ok(ServiceSelector {
                 id: id,
                 tags: tags,
+                user_properties: user_properties,
+                location: location,
+                parent: parent,
+                profile: profile,
                 getters: getters,
                 setters: setters,
                 private: ()
@@ -206,6 +418,39 @@ impl ServiceSelector {
         }
     }
 
+    /// Restrict results to services whose user properties contain all the
+    /// (key, value) pairs in `properties`.
+    pub fn with_user_properties(self, properties: Vec<(String, String)>) -> Self {
+        ServiceSelector {
+            user_properties: merge_properties(self.user_properties, properties),
+            .. self
+        }
+    }
+
+    /// Restrict results to services placed in `location` or in any of its descendants.
+    pub fn with_location(self, location: Id<LocationId>) -> Self {
+        ServiceSelector {
+            location: self.location.and(Exactly::Exactly(location)),
+            .. self
+        }
+    }
+
+    /// Restrict results to the children of service `parent`.
+    pub fn with_parent(self, parent: Id<ServiceId>) -> Self {
+        ServiceSelector {
+            parent: self.parent.and(Exactly::Exactly(parent)),
+            .. self
+        }
+    }
+
+    /// Restrict results to services that satisfy `profile`.
+    pub fn with_profile(self, profile: Id<ProfileId>) -> Self {
+        ServiceSelector {
+            profile: self.profile.and(Exactly::Exactly(profile)),
+            .. self
+        }
+    }
+
     /// Restrict results to services that have all the getters in `getters`.
     pub fn with_getters(mut self, mut getters: Vec<GetterSelector>) -> Self {
         ServiceSelector {
@@ -227,6 +472,10 @@ impl ServiceSelector {
         ServiceSelector {
             id: self.id.and(other.id),
             tags: self.tags.union(&other.tags).cloned().collect(),
+            user_properties: merge_properties(self.user_properties, other.user_properties.drain().collect()),
+            location: self.location.and(other.location),
+            parent: self.parent.and(other.parent),
+            profile: self.profile.and(other.profile),
             getters: {self.getters.append(&mut other.getters); self.getters},
             setters: {self.setters.append(&mut other.setters); self.setters},
             private: (),
@@ -236,32 +485,69 @@ impl ServiceSelector {
     pub fn matches<T>(&self, service: &T) -> bool
         where T: ServiceLike
     {
-        if !self.id.matches(service.id()) {
+        self.evaluate(service, |_, accepted| accepted)
+    }
+
+    /// Determine which criteria of this selector accept or reject a service.
+    pub fn explain<T>(&self, service: &T) -> Explanation<ServiceId>
+        where T: ServiceLike
+    {
+        let mut explanation = Explanation::new(service.id().clone());
+        self.evaluate(service, |criterion, accepted| {
+            explanation.check(criterion, accepted);
+            true
+        });
+        explanation
+    }
+
+    /// Evaluate each criterion specified by this selector against a service, and pass the
+    /// outcome to `on_criterion`. Stop as soon as `on_criterion` returns `false`, in which
+    /// case return `false`.
+    fn evaluate<T, F>(&self, service: &T, mut on_criterion: F) -> bool
+        where T: ServiceLike, F: FnMut(Criterion, bool) -> bool
+    {
+        if !self.id.is_empty() && !on_criterion(Criterion::Id, self.id.matches(service.id())) {
             return false;
         }
-        if !service.with_tags(|tags| has_selected_tags(&self.tags, tags)) {
+        if !self.tags.is_empty()
+            && !on_criterion(Criterion::Tags, service.with_tags(|tags| has_selected_tags(&self.tags, tags))) {
             return false;
         }
-        // If any of the getter selectors doesn't find a getter,
-        // we don't match.
-        let getters_fail = self.getters.iter().any(|selector| {
-            !service.has_getters(|channel| {
-                selector.matches(&self.tags, channel)
-            })
-        });
-        if getters_fail {
+        if !self.user_properties.is_empty()
+            && !on_criterion(Criterion::UserProperties, has_selected_properties(&self.user_properties, service.user_properties())) {
             return false;
         }
-        // If any of the setter selectors doesn't find a setter,
-        // we don't match.
-        let setters_fail = self.setters.iter().any(|selector| {
-            !service.has_setters(|channel| {
-                selector.matches(&self.tags, channel)
-            })
-        });
-        if setters_fail {
+        if !self.location.is_empty() && !on_criterion(Criterion::Location, has_selected_location(&self.location, service)) {
+            return false;
+        }
+        if !self.parent.is_empty() && !on_criterion(Criterion::ParentService, has_selected_parent(&self.parent, service)) {
+            return false;
+        }
+        if !self.profile.is_empty() && !on_criterion(Criterion::Profile, has_selected_profile(&self.profile, service)) {
             return false;
         }
+        // Each of the getter selectors must find a getter.
+        if !self.getters.is_empty() {
+            let getters_ok = self.getters.iter().all(|selector| {
+                service.has_getters(|channel| {
+                    selector.matches(&self.tags, channel)
+                })
+            });
+            if !on_criterion(Criterion::Getters, getters_ok) {
+                return false;
+            }
+        }
+        // Each of the setter selectors must find a setter.
+        if !self.setters.is_empty() {
+            let setters_ok = self.setters.iter().all(|selector| {
+                service.has_setters(|channel| {
+                    selector.matches(&self.tags, channel)
+                })
+            });
+            if !on_criterion(Criterion::Setters, setters_ok) {
+                return false;
+            }
+        }
         true
     }
 }
@@ -296,7 +582,10 @@ impl SelectedBy<ServiceSelector> for Service {
 /// - (optional) array of string `tags`:  accept only channels with all the tags in the array;
 /// - (optional) array of string `service_tags`:  accept only channels of a service with all the
 ///        tags in the array;
-/// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind.
+/// - (optional) object `user_properties`: accept only channels whose user properties contain
+///        all the (key, value) pairs of this object;
+/// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind;
+/// - (optional) string `delivery` (see `Delivery`): accept only channels with a given delivery.
 ///
 /// While each field is optional, at least one field must be provided.
 ///
@@ -309,7 +598,9 @@ impl SelectedBy<ServiceSelector> for Service {
 ///   \"service\": \"service 1\",                  \
 ///   \"tags\": [\"tag 1\", \"tag 2\"],            \
 ///   \"service_tags\": [\"tag 3\", \"tag 4\"],    \
-///   \"kind\": \"Ready\"                          \
+///   \"user_properties\": {\"room\": \"hall\"},  \
+///   \"kind\": \"Ready\",                         \
+///   \"delivery\": \"event\"                      \
 /// }";
 ///
 /// GetterSelector::from_str(json_selector).unwrap();
@@ -336,10 +627,18 @@ pub struct GetterSelector {
     ///  Restrict results to channels offered by a service that has all the tags in `tags`.
     pub service_tags: HashSet<Id<TagId>>,
 
+    /// Restrict results to channels whose user properties contain all the
+    /// (key, value) pairs in `user_properties`.
+    pub user_properties: HashMap<String, String>,
+
     /// If `Exatly(k)`, restrict results to channels that produce values
     /// of kind `k`.
     pub kind: Exactly<ChannelKind>,
 
+    /// If `Exactly(d)`, restrict results to channels that deliver values
+    /// as `d`, i.e. either states or events.
+    pub delivery: Exactly<Delivery>,
+
     /// Make sure that we can't instantiate from another crate.
     private: (),
 }
@@ -380,6 +679,14 @@ impl Parser<GetterSelector> for GetterSelector {
             }
             Some(Err(err)) => return Err(err),
         };
+        let user_properties = match path.push("user_properties", |path| HashMap::take_opt(path, source, "user_properties")) {
+            None => HashMap::new(),
+            Some(Ok(map)) => {
+                is_empty = false;
+                map
+            }
+            Some(Err(err)) => return Err(err),
+        };
         let kind = try!(match path.push("kind", |path| Exactly::take_opt(path, source, "kind")) {
             None => Ok(Exactly::Always),
             Some(result) => {
@@ -387,6 +694,13 @@ impl Parser<GetterSelector> for GetterSelector {
                 result
             }
         });
+        let delivery = try!(match path.push("delivery", |path| Exactly::take_opt(path, source, "delivery")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
         if is_empty {
             Err(ParseError::empty_object(&path))
         } else {
@@ -395,7 +709,9 @@ impl Parser<GetterSelector> for GetterSelector {
                 parent: service_id,
                 tags: tags,
                 service_tags: service_tags,
+                user_properties: user_properties,
                 kind: kind,
+                delivery: delivery,
                 private: ()
             })
         }
@@ -431,6 +747,14 @@ impl GetterSelector {
         }
     }
 
+    /// Restrict to channels with a specific delivery.
+    pub fn with_delivery(self, delivery: Delivery) -> Self {
+        GetterSelector {
+            delivery: self.delivery.and(Exactly::Exactly(delivery)),
+            .. self
+        }
+    }
+
     ///  Restrict to channels that have all the tags in `tags`.
     pub fn with_tags(self, tags: Vec<Id<TagId>>) -> Self {
         GetterSelector {
@@ -447,33 +771,71 @@ impl GetterSelector {
         }
     }
 
+    /// Restrict to channels whose user properties contain all the (key, value) pairs
+    /// in `properties`.
+    pub fn with_user_properties(self, properties: Vec<(String, String)>) -> Self {
+        GetterSelector {
+            user_properties: merge_properties(self.user_properties, properties),
+            .. self
+        }
+    }
+
     /// Restrict to channels that are accepted by two selector.
-    pub fn and(self, other: Self) -> Self {
+    pub fn and(self, mut other: Self) -> Self {
         GetterSelector {
             id: self.id.and(other.id),
             parent: self.parent.and(other.parent),
             tags: self.tags.union(&other.tags).cloned().collect(),
             service_tags: self.service_tags.union(&other.service_tags).cloned().collect(),
+            user_properties: merge_properties(self.user_properties, other.user_properties.drain().collect()),
             kind: self.kind.and(other.kind),
+            delivery: self.delivery.and(other.delivery),
             private: (),
         }
     }
 
     /// Determine if a channel is matched by this selector.
     pub fn matches(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Getter>) -> bool {
-        if !self.id.matches(&channel.id) {
+        self.evaluate(service_tags, channel, |_, accepted| accepted)
+    }
+
+    /// Determine which criteria of this selector accept or reject a channel.
+    pub fn explain(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Getter>) -> Explanation<Getter> {
+        let mut explanation = Explanation::new(channel.id.clone());
+        self.evaluate(service_tags, channel, |criterion, accepted| {
+            explanation.check(criterion, accepted);
+            true
+        });
+        explanation
+    }
+
+    /// Evaluate each criterion specified by this selector against a channel, and pass the
+    /// outcome to `on_criterion`. Stop as soon as `on_criterion` returns `false`, in which
+    /// case return `false`.
+    fn evaluate<F>(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Getter>, mut on_criterion: F) -> bool
+        where F: FnMut(Criterion, bool) -> bool
+    {
+        if !self.id.is_empty() && !on_criterion(Criterion::Id, self.id.matches(&channel.id)) {
+            return false;
+        }
+        if !self.parent.is_empty() && !on_criterion(Criterion::Parent, self.parent.matches(&channel.service)) {
+            return false;
+        }
+        if !self.kind.is_empty() && !on_criterion(Criterion::Kind, self.kind.matches(&channel.mechanism.kind)) {
             return false;
         }
-        if !self.parent.matches(&channel.service) {
+        if !self.delivery.is_empty() && !on_criterion(Criterion::Delivery, self.delivery.matches(&channel.mechanism.delivery)) {
             return false;
         }
-        if !self.kind.matches(&channel.mechanism.kind) {
+        if !self.tags.is_empty() && !on_criterion(Criterion::Tags, has_selected_tags(&self.tags, &channel.tags)) {
             return false;
         }
-        if !has_selected_tags(&self.tags, &channel.tags) {
+        if !self.service_tags.is_empty()
+            && !on_criterion(Criterion::ServiceTags, has_selected_tags(&self.service_tags, service_tags)) {
             return false;
         }
-        if !has_selected_tags(&self.service_tags, service_tags) {
+        if !self.user_properties.is_empty()
+            && !on_criterion(Criterion::UserProperties, has_selected_properties(&self.user_properties, &channel.user_properties)) {
             return false;
         }
         true
@@ -491,6 +853,8 @@ impl GetterSelector {
 /// - (optional) array of string `tags`:  accept only channels with all the tags in the array;
 /// - (optional) array of string `service_tags`:  accept only channels of a service with all the
 ///        tags in the array;
+/// - (optional) object `user_properties`: accept only channels whose user properties contain
+///        all the (key, value) pairs of this object;
 /// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind.
 ///
 /// While each field is optional, at least one field must be provided.
@@ -504,6 +868,7 @@ impl GetterSelector {
 ///   \"service\": \"service 1\",                  \
 ///   \"tags\": [\"tag 1\", \"tag 2\"],            \
 ///   \"service_tags\": [\"tag 3\", \"tag 4\"],    \
+///   \"user_properties\": {\"room\": \"hall\"},  \
 ///   \"kind\": \"Ready\"                          \
 /// }";
 ///
@@ -516,7 +881,7 @@ impl GetterSelector {
 ///   other => panic!("Unexpected result {:?}", other)
 /// }
 /// ```
-#[derive(Clone, Debug, Deserialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct SetterSelector {
     /// If `Exactly(id)`, return only the channel with the corresponding id.
     pub id: Exactly<Id<Setter>>,
@@ -531,6 +896,10 @@ pub struct SetterSelector {
     ///  Restrict results to channels offered by a service that has all the tags in `tags`.
     pub service_tags: HashSet<Id<TagId>>,
 
+    /// Restrict results to channels whose user properties contain all the
+    /// (key, value) pairs in `user_properties`.
+    pub user_properties: HashMap<String, String>,
+
     /// If `Exactly(k)`, restrict results to channels that accept values
     /// of kind `k`.
     pub kind: Exactly<ChannelKind>,
@@ -575,6 +944,14 @@ impl Parser<SetterSelector> for SetterSelector {
             }
             Some(Err(err)) => return Err(err),
         };
+        let user_properties = match path.push("user_properties", |path| HashMap::take_opt(path, source, "user_properties")) {
+            None => HashMap::new(),
+            Some(Ok(map)) => {
+                is_empty = false;
+                map
+            }
+            Some(Err(err)) => return Err(err),
+        };
         let kind = try!(match path.push("kind", |path| Exactly::take_opt(path, source, "kind")) {
             None => Ok(Exactly::Always),
             Some(result) => {
@@ -590,6 +967,7 @@ impl Parser<SetterSelector> for SetterSelector {
                 parent: service_id,
                 tags: tags,
                 service_tags: service_tags,
+                user_properties: user_properties,
                 kind: kind,
                 private: ()
             })
@@ -643,13 +1021,23 @@ impl SetterSelector {
         }
     }
 
+    /// Restrict to channels whose user properties contain all the (key, value) pairs
+    /// in `properties`.
+    pub fn with_user_properties(self, properties: Vec<(String, String)>) -> Self {
+        SetterSelector {
+            user_properties: merge_properties(self.user_properties, properties),
+            .. self
+        }
+    }
+
     /// Restrict results to channels that are accepted by two selector.
-    pub fn and(self, other: Self) -> Self {
+    pub fn and(self, mut other: Self) -> Self {
         SetterSelector {
             id: self.id.and(other.id),
             parent: self.parent.and(other.parent),
             tags: self.tags.union(&other.tags).cloned().collect(),
             service_tags: self.service_tags.union(&other.service_tags).cloned().collect(),
+            user_properties: merge_properties(self.user_properties, other.user_properties.drain().collect()),
             kind: self.kind.and(other.kind),
             private: (),
         }
@@ -657,19 +1045,288 @@ impl SetterSelector {
 
     /// Determine if a channel is matched by this selector.
     pub fn matches(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Setter>) -> bool {
-        if !self.id.matches(&channel.id) {
+        self.evaluate(service_tags, channel, |_, accepted| accepted)
+    }
+
+    /// Determine which criteria of this selector accept or reject a channel.
+    pub fn explain(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Setter>) -> Explanation<Setter> {
+        let mut explanation = Explanation::new(channel.id.clone());
+        self.evaluate(service_tags, channel, |criterion, accepted| {
+            explanation.check(criterion, accepted);
+            true
+        });
+        explanation
+    }
+
+    /// Evaluate each criterion specified by this selector against a channel, and pass the
+    /// outcome to `on_criterion`. Stop as soon as `on_criterion` returns `false`, in which
+    /// case return `false`.
+    fn evaluate<F>(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Setter>, mut on_criterion: F) -> bool
+        where F: FnMut(Criterion, bool) -> bool
+    {
+        if !self.id.is_empty() && !on_criterion(Criterion::Id, self.id.matches(&channel.id)) {
+            return false;
+        }
+        if !self.parent.is_empty() && !on_criterion(Criterion::Parent, self.parent.matches(&channel.service)) {
+            return false;
+        }
+        if !self.kind.is_empty() && !on_criterion(Criterion::Kind, self.kind.matches(&channel.mechanism.kind)) {
+            return false;
+        }
+        if !self.tags.is_empty() && !on_criterion(Criterion::Tags, has_selected_tags(&self.tags, &channel.tags)) {
+            return false;
+        }
+        if !self.service_tags.is_empty()
+            && !on_criterion(Criterion::ServiceTags, has_selected_tags(&self.service_tags, service_tags)) {
+            return false;
+        }
+        if !self.user_properties.is_empty()
+            && !on_criterion(Criterion::UserProperties, has_selected_properties(&self.user_properties, &channel.user_properties)) {
+            return false;
+        }
+        true
+    }
+}
+
+/// A selector for one or more action channels.
+///
+/// # JSON
+///
+/// A selector is an object with the following fields:
+///
+/// - (optional) string `id`: accept only a channel with a given id;
+/// - (optional) string `service`: accept only channels of a service with a given id;
+/// - (optional) array of string `tags`:  accept only channels with all the tags in the array;
+/// - (optional) array of string `service_tags`:  accept only channels of a service with all the
+///        tags in the array;
+/// - (optional) object `user_properties`: accept only channels whose user properties contain
+///        all the (key, value) pairs of this object;
+/// - (optional) string|object `kind` (see `ChannelKind`): accept only channels of a given kind.
+///
+/// While each field is optional, at least one field must be provided.
+///
+/// ```
+/// use foxbox_taxonomy::selector::*;
+///
+/// // A selector with all fields defined.
+/// let json_selector = "{                         \
+///   \"id\": \"action 1\",                        \
+///   \"service\": \"service 1\",                  \
+///   \"tags\": [\"tag 1\", \"tag 2\"],            \
+///   \"service_tags\": [\"tag 3\", \"tag 4\"],    \
+///   \"user_properties\": {\"room\": \"hall\"},  \
+///   \"kind\": \"Ready\"                          \
+/// }";
+///
+/// ActionSelector::from_str(json_selector).unwrap();
+///
+/// // The following will be rejected because no field is provided:
+/// let json_empty = "{}";
+/// match ActionSelector::from_str(json_empty) {
+///   Err(ParseError::EmptyObject {..}) => { /* as expected */ },
+///   other => panic!("Unexpected result {:?}", other)
+/// }
+/// ```
+#[derive(Clone, Debug, Deserialize, Default)]
+pub struct ActionSelector {
+    /// If `Exactly(id)`, return only the channel with the corresponding id.
+    pub id: Exactly<Id<Action>>,
+
+    /// If `Exactly(id)`, return only channels that are immediate children
+    /// of service `id`.
+    pub parent: Exactly<Id<ServiceId>>,
+
+    ///  Restrict results to channels that have all the tags in `tags`.
+    pub tags: HashSet<Id<TagId>>,
+
+    ///  Restrict results to channels offered by a service that has all the tags in `tags`.
+    pub service_tags: HashSet<Id<TagId>>,
+
+    /// Restrict results to channels whose user properties contain all the
+    /// (key, value) pairs in `user_properties`.
+    pub user_properties: HashMap<String, String>,
+
+    /// If `Exactly(k)`, restrict results to actions of kind `k`.
+    pub kind: Exactly<ChannelKind>,
+
+    /// Make sure that we can't instantiate from another crate.
+    private: (),
+}
+
+impl Parser<ActionSelector> for ActionSelector {
+    fn description() -> String {
+        "ActionSelector".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let mut is_empty = true;
+        let id = try!(match path.push("id", |path| Exactly::take_opt(path, source, "id")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        let service_id = try!(match path.push("service", |path| Exactly::take_opt(path, source, "service")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        let tags : HashSet<_> = match path.push("tags", |path| Id::take_vec_opt(path, source, "tags")) {
+            None => HashSet::new(),
+            Some(Ok(mut vec)) => {
+                is_empty = false;
+                vec.drain(..).collect()
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let service_tags : HashSet<_> = match path.push("service_tags", |path| Id::take_vec_opt(path, source, "service_tags")) {
+            None => HashSet::new(),
+            Some(Ok(mut vec)) => {
+                is_empty = false;
+                vec.drain(..).collect()
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let user_properties = match path.push("user_properties", |path| HashMap::take_opt(path, source, "user_properties")) {
+            None => HashMap::new(),
+            Some(Ok(map)) => {
+                is_empty = false;
+                map
+            }
+            Some(Err(err)) => return Err(err),
+        };
+        let kind = try!(match path.push("kind", |path| Exactly::take_opt(path, source, "kind")) {
+            None => Ok(Exactly::Always),
+            Some(result) => {
+                is_empty = false;
+                result
+            }
+        });
+        if is_empty {
+            Err(ParseError::empty_object(&path))
+        } else {
+            Ok(ActionSelector {
+                id: id,
+                parent: service_id,
+                tags: tags,
+                service_tags: service_tags,
+                user_properties: user_properties,
+                kind: kind,
+                private: ()
+            })
+        }
+    }
+}
+
+impl ActionSelector {
+    /// Create a new selector that accepts all action channels.
+    pub fn new() -> Self {
+        ActionSelector::default()
+    }
+
+    /// Selector to a channel with a specific id.
+    pub fn with_id(self, id: Id<Action>) -> Self {
+        ActionSelector {
+            id: self.id.and(Exactly::Exactly(id)),
+            .. self
+        }
+    }
+
+    /// Selector to channels with a specific parent.
+    pub fn with_parent(self, id: Id<ServiceId>) -> Self {
+        ActionSelector {
+            parent: self.parent.and(Exactly::Exactly(id)),
+            .. self
+        }
+    }
+
+    /// Selector to channels with a specific kind.
+    pub fn with_kind(self, kind: ChannelKind) -> Self {
+        ActionSelector {
+            kind: self.kind.and(Exactly::Exactly(kind)),
+            .. self
+        }
+    }
+
+    ///  Restrict to channels that have all the tags in `tags`.
+    pub fn with_tags(self, tags: Vec<Id<TagId>>) -> Self {
+        ActionSelector {
+            tags: merge(self.tags, tags),
+            .. self
+        }
+    }
+
+    ///  Restrict to channels offered by a service that has all the tags in `tags`.
+    pub fn with_service_tags(self, tags: Vec<Id<TagId>>) -> Self {
+        ActionSelector {
+            service_tags: merge(self.service_tags, tags),
+            .. self
+        }
+    }
+
+    /// Restrict to channels whose user properties contain all the (key, value) pairs
+    /// in `properties`.
+    pub fn with_user_properties(self, properties: Vec<(String, String)>) -> Self {
+        ActionSelector {
+            user_properties: merge_properties(self.user_properties, properties),
+            .. self
+        }
+    }
+
+    /// Restrict results to channels that are accepted by two selector.
+    pub fn and(self, mut other: Self) -> Self {
+        ActionSelector {
+            id: self.id.and(other.id),
+            parent: self.parent.and(other.parent),
+            tags: self.tags.union(&other.tags).cloned().collect(),
+            service_tags: self.service_tags.union(&other.service_tags).cloned().collect(),
+            user_properties: merge_properties(self.user_properties, other.user_properties.drain().collect()),
+            kind: self.kind.and(other.kind),
+            private: (),
+        }
+    }
+
+    /// Determine if a channel is matched by this selector.
+    pub fn matches(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Action>) -> bool {
+        self.evaluate(service_tags, channel, |_, accepted| accepted)
+    }
+
+    /// Determine which criteria of this selector accept or reject a channel.
+    pub fn explain(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Action>) -> Explanation<Action> {
+        let mut explanation = Explanation::new(channel.id.clone());
+        self.evaluate(service_tags, channel, |criterion, accepted| {
+            explanation.check(criterion, accepted);
+            true
+        });
+        explanation
+    }
+
+    /// Evaluate each criterion specified by this selector against a channel, and pass the
+    /// outcome to `on_criterion`. Stop as soon as `on_criterion` returns `false`, in which
+    /// case return `false`.
+    fn evaluate<F>(&self, service_tags: &HashSet<Id<TagId>>, channel: &Channel<Action>, mut on_criterion: F) -> bool
+        where F: FnMut(Criterion, bool) -> bool
+    {
+        if !self.id.is_empty() && !on_criterion(Criterion::Id, self.id.matches(&channel.id)) {
             return false;
         }
-        if !self.parent.matches(&channel.service) {
+        if !self.parent.is_empty() && !on_criterion(Criterion::Parent, self.parent.matches(&channel.service)) {
             return false;
         }
-        if !self.kind.matches(&channel.mechanism.kind) {
+        if !self.kind.is_empty() && !on_criterion(Criterion::Kind, self.kind.matches(&channel.mechanism.kind)) {
             return false;
         }
-        if !has_selected_tags(&self.tags, &channel.tags) {
+        if !self.tags.is_empty() && !on_criterion(Criterion::Tags, has_selected_tags(&self.tags, &channel.tags)) {
             return false;
         }
-        if !has_selected_tags(&self.service_tags, service_tags) {
+        if !self.service_tags.is_empty()
+            && !on_criterion(Criterion::ServiceTags, has_selected_tags(&self.service_tags, service_tags)) {
+            return false;
+        }
+        if !self.user_properties.is_empty()
+            && !on_criterion(Criterion::UserProperties, has_selected_properties(&self.user_properties, &channel.user_properties)) {
             return false;
         }
         true
@@ -743,3 +1400,36 @@ fn has_selected_tags(actual: &HashSet<Id<TagId>>, requested: &HashSet<Id<TagId>>
     }
     true
 }
+
+fn has_selected_properties(requested: &HashMap<String, String>, actual: &HashMap<String, String>) -> bool {
+    for (key, value) in requested {
+        if actual.get(key) != Some(value) {
+            return false;
+        }
+    }
+    true
+}
+
+fn has_selected_location<T>(requested: &Exactly<Id<LocationId>>, service: &T) -> bool where T: ServiceLike {
+    match *requested {
+        Exactly::Always => true,
+        Exactly::Exactly(ref location) => service.is_in_location(location),
+        Exactly::Never => false,
+    }
+}
+
+fn has_selected_parent<T>(requested: &Exactly<Id<ServiceId>>, service: &T) -> bool where T: ServiceLike {
+    match *requested {
+        Exactly::Always => true,
+        Exactly::Exactly(ref parent) => service.parent() == Some(parent),
+        Exactly::Never => false,
+    }
+}
+
+fn has_selected_profile<T>(requested: &Exactly<Id<ProfileId>>, service: &T) -> bool where T: ServiceLike {
+    match *requested {
+        Exactly::Always => true,
+        Exactly::Exactly(ref profile) => service.has_profile(profile),
+        Exactly::Never => false,
+    }
+}
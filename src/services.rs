@@ -6,12 +6,14 @@
 //! devices may have been added or removed from the `FoxBox` by the time
 //! these data structures are read.
 
+use leases::LeaseInfo;
 use parse::*;
 use values::*;
-pub use util::{ Exactly, Id, AdapterId, ServiceId, KindId, TagId, VendorId };
+pub use util::{ Exactly, Id, AdapterId, ServiceId, KindId, LocationId, ProfileId, TagId, VendorId };
 
 use serde::ser::{ Serialize, Serializer };
 use serde::de::{ Deserialize, Deserializer, Error };
+use util::TrivialEnumVisitor;
 
 use std::hash::{ Hash, Hasher };
 use std::collections::{ HashSet, HashMap };
@@ -46,9 +48,17 @@ macro_rules! tag_id {
 /// - id: string - an id unique to this service;
 /// - adapter: string;
 /// - tags: array of strings;
+/// - tag_origins: object (keys are tags, values are strings, see `TagOrigin`);
 /// - properties: object;
+/// - user_properties: object;
+/// - location: string|null - the location in which the user has placed the service, if any;
+/// - location_ancestors: array of strings - the ancestors of `location`, from its parent to the root;
+/// - parent: string|null - the id of the parent service (e.g. a hub), if any;
+/// - related: array of strings - the ids of services related to this service;
+/// - profiles: array of strings - the ids of the profiles satisfied by this service;
 /// - getters: object (keys are string identifiers, for more details on values see Channel<Getter>);
 /// - setters: object (keys are string identifiers, for more details on values see Channel<Setter>);
+/// - actions: object (keys are string identifiers, for more details on values see Channel<Action>);
 ///
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
@@ -65,6 +75,11 @@ pub struct Service {
     /// power source or on a battery.
     pub tags: HashSet<Id<TagId>>,
 
+    /// The origin of each tag in `tags`. Tags that do not appear in this map
+    /// are considered as set by the adapter.
+    #[serde(default)]
+    pub tag_origins: HashMap<Id<TagId>, TagOrigin>,
+
     /// An id unique to this service.
     pub id: Id<ServiceId>,
 
@@ -72,12 +87,57 @@ pub struct Service {
     /// For instance, these can be device manufacturer, model, etc.
     pub properties: HashMap<String, String>,
 
+    /// Service properties that are set by the user, e.g. a display name or a
+    /// serial number. These are kept distinct from the properties set by the
+    /// adapter, and survive the service being removed and registered again.
+    #[serde(default)]
+    pub user_properties: HashMap<String, String>,
+
+    /// The location in which the user has placed this service, if any.
+    /// See module `locations`.
+    #[serde(default)]
+    pub location: Option<Id<LocationId>>,
+
+    /// The ancestors of `location` in the location tree, from its parent to the root.
+    ///
+    /// Computed by the manager from the location tree. Any value provided when
+    /// registering the service is ignored.
+    #[serde(default)]
+    pub location_ancestors: Vec<Id<LocationId>>,
+
+    /// The service through which this service is exposed, if any. For instance,
+    /// a light bulb may be exposed through a hub, or one gang of a multi-gang switch
+    /// through the switch itself.
+    ///
+    /// The parent is registered by the same adapter. Removing the parent removes
+    /// all its children.
+    #[serde(default)]
+    pub parent: Option<Id<ServiceId>>,
+
+    /// Services related to this service, e.g. the other gangs of a multi-gang switch.
+    /// Related services must be registered before this service, and may not include this
+    /// service itself. Links to services that are removed are dropped automatically.
+    #[serde(default)]
+    pub related: HashSet<Id<ServiceId>>,
+
+    /// The profiles satisfied by this service (see module `profiles`).
+    ///
+    /// Computed by the manager from the channels of the service. Any value provided
+    /// when registering the service is ignored. When matching a selector against the
+    /// service itself, built-in profiles are checked against the channels instead.
+    #[serde(default)]
+    pub profiles: HashSet<Id<ProfileId>>,
+
     /// Getter channels connected directly to this service.
     pub getters: HashMap<Id<Getter>, Channel<Getter>>,
 
     /// Setter channels connected directly to this service.
     pub setters: HashMap<Id<Setter>, Channel<Setter>>,
 
+    /// Action channels connected directly to this service.
+    #[serde(default)]
+    pub actions: HashMap<Id<Action>, Channel<Action>>,
+
     /// Identifier of the adapter for this service.
     pub adapter: Id<AdapterId>,
 }
@@ -87,24 +147,47 @@ impl Service {
     pub fn empty(id: Id<ServiceId>, adapter: Id<AdapterId>) -> Self {
         Service {
             tags: HashSet::new(),
+            tag_origins: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
+            actions: HashMap::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
             id: id,
             adapter: adapter,
         }
     }
 }
 
+impl Service {
+    /// The tags of this service that have a given origin.
+    pub fn tags_with_origin(&self, origin: TagOrigin) -> HashSet<Id<TagId>> {
+        tags_with_origin(&self.tags, &self.tag_origins, origin)
+    }
+}
+
 impl ToJSON for Service {
     fn to_json(&self) -> JSON {
         let mut source = vec![
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
+            ("tag_origins", self.tag_origins.to_json()),
             ("properties", self.properties.to_json()),
+            ("user_properties", self.user_properties.to_json()),
+            ("location", self.location.to_json()),
+            ("location_ancestors", self.location_ancestors.to_json()),
+            ("parent", self.parent.to_json()),
+            ("related", self.related.to_json()),
+            ("profiles", self.profiles.to_json()),
             ("getters", self.getters.to_json()),
             ("setters", self.setters.to_json()),
+            ("actions", self.actions.to_json()),
         ];
 
         let map = source.drain(..)
@@ -115,6 +198,204 @@ impl ToJSON for Service {
 }
 
 
+/// The origin of a tag.
+///
+/// Tags set by the adapter are replaced whenever the adapter registers the service or
+/// channel again, while tags set by the user or by applications survive re-registration.
+/// Similarly, if the user removes a tag set by the adapter, the tag remains removed even
+/// if the adapter registers it again.
+///
+/// # JSON
+///
+/// Represented by one of the strings `"adapter"`, `"user"` or `"application"`.
+///
+/// ```
+/// use foxbox_taxonomy::services::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = TagOrigin::from_str("\"user\"").unwrap();
+/// assert_eq!(parsed, TagOrigin::User);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TagOrigin {
+    /// The tag was set by the adapter.
+    Adapter,
+
+    /// The tag was set by the user.
+    User,
+
+    /// The tag was set by an application, e.g. a rule.
+    Application,
+}
+
+impl Parser<TagOrigin> for TagOrigin {
+    fn description() -> String {
+        "TagOrigin".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some("adapter") => Ok(TagOrigin::Adapter),
+            Some("user") => Ok(TagOrigin::User),
+            Some("application") => Ok(TagOrigin::Application),
+            Some(str) => Err(ParseError::unknown_constant(str, &path)),
+            None => Err(ParseError::type_error("TagOrigin", &path, "string"))
+        }
+    }
+}
+
+impl ToJSON for TagOrigin {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.as_str().to_owned())
+    }
+}
+
+impl TagOrigin {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            TagOrigin::Adapter => "adapter",
+            TagOrigin::User => "user",
+            TagOrigin::Application => "application",
+        }
+    }
+}
+
+impl Serialize for TagOrigin {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.as_str().serialize(serializer)
+    }
+}
+
+impl Deserialize for TagOrigin {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            match source {
+                "adapter" => Ok(TagOrigin::Adapter),
+                "user" => Ok(TagOrigin::User),
+                "application" => Ok(TagOrigin::Application),
+                _ => Err(())
+            }
+        }))
+    }
+}
+
+fn tags_with_origin(tags: &HashSet<Id<TagId>>, origins: &HashMap<Id<TagId>, TagOrigin>, origin: TagOrigin) -> HashSet<Id<TagId>> {
+    tags.iter()
+        .filter(|tag| *origins.get(tag).unwrap_or(&TagOrigin::Adapter) == origin)
+        .cloned()
+        .collect()
+}
+
+/// Optional metadata attached to a tag, to help present it to the user.
+///
+/// # JSON
+///
+/// Tag metadata is represented by an object with the following fields:
+///
+/// - (optional) name: string - a human-readable name for the tag;
+/// - (optional) icon: string - an identifier or url for an icon.
+///
+/// ```
+/// use foxbox_taxonomy::services::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let metadata = TagMetadata::from_str("{\"name\": \"Living room\"}").unwrap();
+/// assert_eq!(metadata.name, Some("Living room".to_owned()));
+/// assert_eq!(metadata.icon, None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TagMetadata {
+    /// A human-readable name for the tag.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// An identifier or url for an icon.
+    #[serde(default)]
+    pub icon: Option<String>,
+}
+
+impl Parser<TagMetadata> for TagMetadata {
+    fn description() -> String {
+        "TagMetadata".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let name = match path.push("name", |path| String::take_opt(path, source, "name")) {
+            None => None,
+            Some(Ok(name)) => Some(name),
+            Some(Err(err)) => return Err(err)
+        };
+        let icon = match path.push("icon", |path| String::take_opt(path, source, "icon")) {
+            None => None,
+            Some(Ok(icon)) => Some(icon),
+            Some(Err(err)) => return Err(err)
+        };
+        Ok(TagMetadata { name: name, icon: icon })
+    }
+}
+
+impl ToJSON for TagMetadata {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("name", self.name.to_json()),
+            ("icon", self.icon.to_json()),
+        ].to_json()
+    }
+}
+
+/// Information on a tag currently known to the system.
+///
+/// # JSON
+///
+/// Tag information is represented by an object with the following fields:
+///
+/// - id: string - the tag itself;
+/// - services: number - the number of services labelled with this tag;
+/// - getters: number - the number of getters labelled with this tag;
+/// - setters: number - the number of setters labelled with this tag;
+/// - metadata: object (see `TagMetadata`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagInfo {
+    /// The tag.
+    pub id: Id<TagId>,
+
+    /// The number of services labelled with this tag.
+    pub services: usize,
+
+    /// The number of getters labelled with this tag.
+    pub getters: usize,
+
+    /// The number of setters labelled with this tag.
+    pub setters: usize,
+
+    /// Metadata attached to the tag, if any.
+    pub metadata: TagMetadata,
+}
+
+impl TagInfo {
+    /// Information on a tag that is not used anywhere yet.
+    pub fn new(id: Id<TagId>) -> Self {
+        TagInfo {
+            id: id,
+            services: 0,
+            getters: 0,
+            setters: 0,
+            metadata: TagMetadata::default(),
+        }
+    }
+}
+
+impl ToJSON for TagInfo {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("id", self.id.to_json()),
+            ("services", self.services.to_json()),
+            ("getters", self.getters.to_json()),
+            ("setters", self.setters.to_json()),
+            ("metadata", self.metadata.to_json()),
+        ].to_json()
+    }
+}
+
+
 /// The kind of the channel, i.e. a strongly-typed description of
 /// _what_ the channel can do. Used both for locating channels
 /// (e.g. "I need a clock" or "I need something that can provide
@@ -233,14 +514,18 @@ pub enum ChannelKind {
     /// service. This is typically used for devices which have additional
     /// authentication (like an IP Camera).
     ///
+    /// Values have type `Secret`, so that they do not leak through logs or JSON.
+    ///
     /// # JSON
     ///
     /// This kind is represented by the string "Password".
     /// ```
     /// use foxbox_taxonomy::services::*;
     /// use foxbox_taxonomy::parse::*;
+    /// use foxbox_taxonomy::values::Type;
     ///
     /// let parsed = ChannelKind::from_str("\"Password\"").unwrap();
+    /// assert_eq!(parsed.get_type(), Type::Secret);
     /// assert_eq!(parsed, ChannelKind::Password);
     /// ```
     Password,
@@ -301,6 +586,9 @@ pub enum ChannelKind {
     /// Used for instance to trigger an action at a specific hour
     /// every day.
     ///
+    /// Values are modelled as a `Duration` since midnight UTC. New code
+    /// should prefer `CurrentLocalTimeOfDay`.
+    ///
     /// # JSON
     ///
     /// This kind is represented by string "CurrentTimeOfDay".
@@ -314,6 +602,54 @@ pub enum ChannelKind {
     /// ```
     CurrentTimeOfDay,
 
+    /// The service is used to read the current time of day, in the
+    /// timezone of the home.
+    ///
+    /// # JSON
+    ///
+    /// This kind is represented by string "CurrentLocalTimeOfDay".
+    ///
+    /// ```
+    /// use foxbox_taxonomy::services::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = ChannelKind::from_str("\"CurrentLocalTimeOfDay\"").unwrap();
+    /// assert_eq!(parsed, ChannelKind::CurrentLocalTimeOfDay);
+    /// ```
+    CurrentLocalTimeOfDay,
+
+    /// The service is used to read the current day of the week, in the
+    /// timezone of the home.
+    ///
+    /// # JSON
+    ///
+    /// This kind is represented by string "CurrentDayOfWeek".
+    ///
+    /// ```
+    /// use foxbox_taxonomy::services::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = ChannelKind::from_str("\"CurrentDayOfWeek\"").unwrap();
+    /// assert_eq!(parsed, ChannelKind::CurrentDayOfWeek);
+    /// ```
+    CurrentDayOfWeek,
+
+    /// The service is used to read the current date, in the timezone
+    /// of the home.
+    ///
+    /// # JSON
+    ///
+    /// This kind is represented by string "CurrentDate".
+    ///
+    /// ```
+    /// use foxbox_taxonomy::services::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = ChannelKind::from_str("\"CurrentDate\"").unwrap();
+    /// assert_eq!(parsed, ChannelKind::CurrentDate);
+    /// ```
+    CurrentDate,
+
     /// The service is part of a countdown. This is the time
     /// remaining until the countdown is elapsed.
     ///
@@ -357,7 +693,8 @@ pub enum ChannelKind {
     ThinkerbellRuleSource,
     ThinkerbellRuleOn,
 
-    /// Capture a new snapshot.
+    /// Capture a new snapshot. The snapshot may then be retrieved from a getter of kind
+    /// `Snapshot`.
     ///
     /// # JSON
     ///
@@ -377,6 +714,36 @@ pub enum ChannelKind {
     /// ```
     TakeSnapshot,
 
+    /// The latest snapshot captured by a camera, as a reference to its content.
+    ///
+    /// # JSON
+    ///
+    /// This kind is represented by string "Snapshot".
+    ///
+    /// ```
+    /// use foxbox_taxonomy::services::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = ChannelKind::from_str("\"Snapshot\"").unwrap();
+    /// assert_eq!(parsed, ChannelKind::Snapshot);
+    /// ```
+    Snapshot,
+
+    /// A live video stream, as a reference to its content.
+    ///
+    /// # JSON
+    ///
+    /// This kind is represented by string "VideoStream".
+    ///
+    /// ```
+    /// use foxbox_taxonomy::services::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = ChannelKind::from_str("\"VideoStream\"").unwrap();
+    /// assert_eq!(parsed, ChannelKind::VideoStream);
+    /// ```
+    VideoStream,
+
     /// Write to a log file
     ///
     /// # JSON
@@ -479,6 +846,9 @@ impl Parser<ChannelKind> for ChannelKind {
                 "CountEveryInterval" => Ok(ChannelKind::CountEveryInterval),
                 "CurrentTime" => Ok(ChannelKind::CurrentTime),
                 "CurrentTimeOfDay" => Ok(ChannelKind::CurrentTimeOfDay),
+                "CurrentLocalTimeOfDay" => Ok(ChannelKind::CurrentLocalTimeOfDay),
+                "CurrentDayOfWeek" => Ok(ChannelKind::CurrentDayOfWeek),
+                "CurrentDate" => Ok(ChannelKind::CurrentDate),
                 "AddThinkerbellRule" => Ok(ChannelKind::AddThinkerbellRule),
                 "RemoveThinkerbellRule" => Ok(ChannelKind::RemoveThinkerbellRule),
                 "ThinkerbellRuleSource" => Ok(ChannelKind::ThinkerbellRuleSource),
@@ -486,6 +856,8 @@ impl Parser<ChannelKind> for ChannelKind {
                 "RemainingTime" => Ok(ChannelKind::RemainingTime),
                 "OvenTemperature" => Ok(ChannelKind::OvenTemperature),
                 "TakeSnapshot" => Ok(ChannelKind::TakeSnapshot),
+                "Snapshot" => Ok(ChannelKind::Snapshot),
+                "VideoStream" => Ok(ChannelKind::VideoStream),
                 "Log" => Ok(ChannelKind::Log),
                 "WebPushNotify" => Ok(ChannelKind::WebPushNotify),
                 _ => Err(ParseError::unknown_constant(str, &path))
@@ -525,6 +897,9 @@ impl ToJSON for ChannelKind {
             Password => JSON::String("Password".to_owned()),
             CurrentTime => JSON::String("CurrentTime".to_owned()),
             CurrentTimeOfDay => JSON::String("CurrentTimeOfDay".to_owned()),
+            CurrentLocalTimeOfDay => JSON::String("CurrentLocalTimeOfDay".to_owned()),
+            CurrentDayOfWeek => JSON::String("CurrentDayOfWeek".to_owned()),
+            CurrentDate => JSON::String("CurrentDate".to_owned()),
             CountEveryInterval => JSON::String("CountEveryInterval".to_owned()),
             Countdown => JSON::String("Countdown".to_owned()),
             RemainingTime => JSON::String("RemainingTime".to_owned()),
@@ -534,6 +909,8 @@ impl ToJSON for ChannelKind {
             ThinkerbellRuleSource => JSON::String("ThinkerbellRuleSource".to_owned()),
             ThinkerbellRuleOn => JSON::String("ThinkerbellRuleOn".to_owned()),
             TakeSnapshot => JSON::String("TakeSnapshot".to_owned()),
+            Snapshot => JSON::String("Snapshot".to_owned()),
+            VideoStream => JSON::String("VideoStream".to_owned()),
             Log => JSON::String("Log".to_owned()),
             WebPushNotify => JSON::String("WebPushNotify".to_owned()),
             Extension { ref vendor, ref adapter, ref kind, ref typ } => {
@@ -560,6 +937,9 @@ impl ChannelKind {
             DoorLocked => Type::DoorLocked,
             CurrentTime => Type::TimeStamp,
             CurrentTimeOfDay | RemainingTime | Countdown | CountEveryInterval => Type::Duration,
+            CurrentLocalTimeOfDay => Type::TimeOfDay,
+            CurrentDayOfWeek => Type::DayOfWeek,
+            CurrentDate => Type::Date,
             OvenTemperature => Type::Temperature,
             AddThinkerbellRule => Type::ThinkerbellRule,
             RemoveThinkerbellRule => Type::Unit,
@@ -567,7 +947,9 @@ impl ChannelKind {
             ThinkerbellRuleOn => Type::OnOff,
             Log => Type::String,
             TakeSnapshot => Type::Unit,
-			Username | Password => Type::String,
+            Snapshot | VideoStream => Type::Stream,
+            Username => Type::String,
+            Password => Type::Secret,
             WebPushNotify => Type::WebPushNotify,
             Extension { ref typ, ..} => typ.clone(),
         }
@@ -584,11 +966,94 @@ pub struct Getter {
     /// polling or through a trigger.
     #[serde(default)]
     pub updated: Option<TimeStamp>,
+
+    /// Whether the getter exposes a current state or a stream of events.
+    #[serde(default)]
+    pub delivery: Delivery,
 }
 
 impl IOMechanism for Getter {
 }
 
+/// The manner in which a getter delivers its values.
+///
+/// # JSON
+///
+/// Represented by one of the strings `"state"` or `"event"`.
+///
+/// ```
+/// use foxbox_taxonomy::services::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = Delivery::from_str("\"event\"").unwrap();
+/// assert_eq!(parsed, Delivery::Event);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    /// The getter has a current value, which may be fetched with `fetch_values` or watched,
+    /// e.g. the state of a light or the temperature of an oven. This is the default.
+    State,
+
+    /// The getter has no current value. Its values are stateless pulses, e.g. a doorbell
+    /// ringing or a motion being detected, and can only be received through watches, as
+    /// `WatchEvent::Event`. Attempting to fetch the value of such a getter fails with
+    /// `Error::GetterDoesNotSupportPolling`.
+    Event,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery::State
+    }
+}
+
+impl Parser<Delivery> for Delivery {
+    fn description() -> String {
+        "Delivery".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some("state") => Ok(Delivery::State),
+            Some("event") => Ok(Delivery::Event),
+            Some(str) => Err(ParseError::unknown_constant(str, &path)),
+            None => Err(ParseError::type_error("Delivery", &path, "string"))
+        }
+    }
+}
+
+impl ToJSON for Delivery {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.as_str().to_owned())
+    }
+}
+
+impl Delivery {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Delivery::State => "state",
+            Delivery::Event => "event",
+        }
+    }
+}
+
+impl Serialize for Delivery {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.as_str().serialize(serializer)
+    }
+}
+
+impl Deserialize for Delivery {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            match source {
+                "state" => Ok(Delivery::State),
+                "event" => Ok(Delivery::Event),
+                _ => Err(())
+            }
+        }))
+    }
+}
+
 /// An setter operation available on an channel.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Setter {
@@ -598,11 +1063,49 @@ pub struct Setter {
     /// Date at which the latest value was sent to the channel.
     #[serde(default)]
     pub updated: Option<TimeStamp>,
+
+    /// If `true`, values sent to this setter can never be read back: `fetch_values`
+    /// rejects the getters of the same service that have the same kind.
+    #[serde(default)]
+    pub write_only: bool,
+
+    /// The lease currently held on this setter, if any (see module `leases`).
+    ///
+    /// Maintained by the manager. Any value provided when registering the setter is ignored.
+    #[serde(default)]
+    pub lease: Option<LeaseInfo>,
 }
 
 impl IOMechanism for Setter {
 }
 
+/// An action available on a channel, i.e. a command that accepts an argument and
+/// produces a result, e.g. "take a snapshot and return the image" or "unlock with
+/// this PIN".
+///
+/// Unlike a setter, whose type is determined by its kind, an action declares the type
+/// of its argument and the type of its result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Action {
+    /// The kind of action, used to select actions.
+    pub kind: ChannelKind,
+
+    /// The type of the argument expected by this action. Use `Type::Unit` for
+    /// actions that expect no argument.
+    pub argument: Type,
+
+    /// The type of the result produced by this action. Use `Type::Unit` for
+    /// actions that produce no result.
+    pub result: Type,
+
+    /// Date at which the action was last invoked.
+    #[serde(default)]
+    pub updated: Option<TimeStamp>,
+}
+
+impl IOMechanism for Action {
+}
+
 /// An channel represents a single place where data can enter or
 /// leave a device. Note that channels support either a single kind
 /// of getter or a single kind of setter. Devices that support both
@@ -619,6 +1122,11 @@ pub struct Channel<IO> where IO: IOMechanism {
     #[serde(default)]
     pub tags: HashSet<Id<TagId>>,
 
+    /// The origin of each tag in `tags`. Tags that do not appear in this map
+    /// are considered as set by the adapter.
+    #[serde(default)]
+    pub tag_origins: HashMap<Id<TagId>, TagOrigin>,
+
     /// An id unique to this channel.
     pub id: Id<IO>,
 
@@ -634,6 +1142,11 @@ pub struct Channel<IO> where IO: IOMechanism {
     /// The last time the device was seen.
     #[serde(default)]
     pub last_seen: Option<TimeStamp>,
+
+    /// Channel properties that are set by the user, e.g. a display name. These
+    /// survive the channel being removed and registered again.
+    #[serde(default)]
+    pub user_properties: HashMap<String, String>,
 }
 
 impl ToJSON for Channel<Getter> {
@@ -642,9 +1155,12 @@ impl ToJSON for Channel<Getter> {
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
+            ("tag_origins", self.tag_origins.to_json()),
             ("service", self.service.to_json()),
             ("mechanism", JSON::String("getter".to_owned())),
             ("kind", self.mechanism.kind.to_json()),
+            ("delivery", self.mechanism.delivery.to_json()),
+            ("user_properties", self.user_properties.to_json()),
         ];
         if let Some(ref ts) = self.last_seen {
             source.push(("last_seen", ts.to_json()))
@@ -667,9 +1183,43 @@ impl ToJSON for Channel<Setter> {
             ("id", self.id.to_json()),
             ("adapter", self.adapter.to_json()),
             ("tags", self.tags.to_json()),
+            ("tag_origins", self.tag_origins.to_json()),
             ("service", self.service.to_json()),
             ("mechanism", JSON::String("setter".to_owned())),
             ("kind", self.mechanism.kind.to_json()),
+            ("write_only", JSON::Bool(self.mechanism.write_only)),
+            ("user_properties", self.user_properties.to_json()),
+        ];
+        if let Some(ref ts) = self.last_seen {
+            source.push(("last_seen", ts.to_json()))
+        }
+        if let Some(ref ts) = self.mechanism.updated {
+            source.push(("updated", ts.to_json()));
+        }
+        if let Some(ref lease) = self.mechanism.lease {
+            source.push(("lease", lease.to_json()));
+        }
+
+        let map = source.drain(..)
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect();
+        JSON::Object(map)
+    }
+}
+
+impl ToJSON for Channel<Action> {
+    fn to_json(&self) -> JSON {
+        let mut source = vec![
+            ("id", self.id.to_json()),
+            ("adapter", self.adapter.to_json()),
+            ("tags", self.tags.to_json()),
+            ("tag_origins", self.tag_origins.to_json()),
+            ("service", self.service.to_json()),
+            ("mechanism", JSON::String("action".to_owned())),
+            ("kind", self.mechanism.kind.to_json()),
+            ("argument", self.mechanism.argument.to_json()),
+            ("result", self.mechanism.result.to_json()),
+            ("user_properties", self.user_properties.to_json()),
         ];
         if let Some(ref ts) = self.last_seen {
             source.push(("last_seen", ts.to_json()))
@@ -685,6 +1235,13 @@ impl ToJSON for Channel<Setter> {
     }
 }
 
+impl<IO> Channel<IO> where IO: IOMechanism {
+    /// The tags of this channel that have a given origin.
+    pub fn tags_with_origin(&self, origin: TagOrigin) -> HashSet<Id<TagId>> {
+        tags_with_origin(&self.tags, &self.tag_origins, origin)
+    }
+}
+
 impl<IO> Eq for Channel<IO> where IO: IOMechanism {
 }
 
@@ -0,0 +1,79 @@
+//! Content provided by adapters by reference, e.g. camera snapshots or video streams.
+//!
+//! Values of type `Stream` do not embed the content, only a `StreamRef`. The content is
+//! opened on demand with `API::open_stream` or `API::fetch_snapshot`, and is provided by the
+//! adapter either as a local file or as a reader.
+
+pub use util::{ Id, MimeTypeId, StreamId };
+
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::path::PathBuf;
+
+/// The actual source of the content of a stream.
+pub enum StreamContent {
+    /// The content is stored in a local file.
+    File(PathBuf),
+
+    /// The content is produced by a reader, possibly chunk by chunk, e.g. for a live stream.
+    Reader(Box<Read + Send>),
+}
+
+impl fmt::Debug for StreamContent {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            StreamContent::File(ref path) => write!(formatter, "StreamContent::File({:?})", path),
+            StreamContent::Reader(_) => write!(formatter, "StreamContent::Reader(..)"),
+        }
+    }
+}
+
+/// An open stream, as returned by an adapter.
+#[derive(Debug)]
+pub struct Stream {
+    /// The mime type of the content.
+    pub mimetype: Id<MimeTypeId>,
+
+    /// The source of the content.
+    pub content: StreamContent,
+}
+
+impl Stream {
+    /// Create a stream whose content is stored in a local file.
+    pub fn from_file(mimetype: Id<MimeTypeId>, path: PathBuf) -> Self {
+        Stream {
+            mimetype: mimetype,
+            content: StreamContent::File(path),
+        }
+    }
+
+    /// Create a stream whose content is produced by a reader.
+    pub fn from_reader(mimetype: Id<MimeTypeId>, reader: Box<Read + Send>) -> Self {
+        Stream {
+            mimetype: mimetype,
+            content: StreamContent::Reader(reader),
+        }
+    }
+
+    /// Get a reader for the content, opening the file if necessary.
+    pub fn into_reader(self) -> Result<Box<Read + Send>, io::Error> {
+        match self.content {
+            StreamContent::File(path) => {
+                let file = try!(File::open(path));
+                Ok(Box::new(file))
+            }
+            StreamContent::Reader(reader) => Ok(reader)
+        }
+    }
+
+    /// Read the entire content in memory.
+    ///
+    /// Do not use this method with unbounded content, e.g. live video streams.
+    pub fn read_to_end(self) -> Result<Vec<u8>, io::Error> {
+        let mut reader = try!(self.into_reader());
+        let mut buf = vec![];
+        try!(reader.read_to_end(&mut buf));
+        Ok(buf)
+    }
+}
@@ -1,15 +1,20 @@
+use api::{ Error as APIError, InternalError };
 use parse::*;
 
 use std::cmp::PartialEq;
 use std::collections::HashMap;
+use std::fs::{ self, File };
 use std::hash::{ Hash, Hasher };
+use std::io::{ Read, Write };
 use std::marker::PhantomData;
+use std::path::{ Path, PathBuf };
 use std::fmt;
 
 use string_cache::Atom;
 
 use serde::ser::{ Serialize, Serializer };
 use serde::de::{ Deserialize, Deserializer, Error, Type };
+use serde_json;
 
 /// A marker for a request that a expects a specific value.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -119,7 +124,7 @@ pub type ResultMap<K, T, E> = HashMap<K, Result<T, E>>;
 /// A bunch of instructions, going to different targets.
 pub type TargetMap<K, T> = Vec<Targetted<K, T>>;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Targetted<K, T> where K: Clone, T: Clone {
     pub select: Vec<K>,
     pub payload: T
@@ -369,6 +374,16 @@ pub struct AdapterId;
 pub struct TagId;
 
 
+/// A marker for Id.
+/// Only useful for writing `Id<LocationId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct LocationId;
+
+/// A marker for Id.
+/// Only useful for writing `Id<ProfileId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct ProfileId;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
 pub struct KindId;
 
@@ -378,5 +393,80 @@ pub struct VendorId;
 #[derive(Clone, Debug)]
 pub struct MimeTypeId;
 
+/// A marker for Id.
+/// Only useful for writing `Id<StreamId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct StreamId;
+
+/// A marker for Id.
+/// Only useful for writing `Id<RoleId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct RoleId;
+
+/// A marker for Id.
+/// Only useful for writing `Id<LeaseId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct LeaseId;
+
+/// A marker for Id.
+/// Only useful for writing `Id<SceneId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct SceneId;
+
+/// A marker for Id.
+/// Only useful for writing `Id<JobId>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash, Eq)]
+pub struct JobId;
+
 /// Helper function, to check that a type implements Sync.
 pub fn is_sync<T: Sync>() {}
+
+/// A file storing a value as JSON, e.g. the scenes or the jobs of the scheduler.
+#[derive(Debug, Clone)]
+pub struct JsonFile {
+    path: PathBuf,
+}
+
+impl JsonFile {
+    pub fn new<P>(path: P) -> Self where P: AsRef<Path> {
+        JsonFile {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the value stored in the file, or `None` if the file doesn't exist.
+    pub fn load<T>(&self) -> Result<Option<T>, APIError> where T: Deserialize {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let mut source = String::new();
+        if let Err(err) = File::open(&self.path).and_then(|mut file| file.read_to_string(&mut source)) {
+            return Err(APIError::InternalError(InternalError::GenericError(format!("Could not read {}: {}", self.path.display(), err))));
+        }
+        match serde_json::from_str(&source) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(APIError::InternalError(InternalError::GenericError(format!("Could not parse {}: {}", self.path.display(), err))))
+        }
+    }
+
+    /// Replace the value stored in the file. To avoid corrupting the file if we are
+    /// interrupted, write to a temporary file first, then move it.
+    pub fn save<T>(&self, value: &T) -> Result<(), APIError> where T: Serialize {
+        let source = match serde_json::to_string(value) {
+            Ok(source) => source,
+            Err(err) => return Err(APIError::InternalError(InternalError::GenericError(format!("Could not serialize {}: {}", self.path.display(), err))))
+        };
+        let tmp = self.path.with_extension("tmp");
+        let result = File::create(&tmp)
+            .and_then(|mut file| file.write_all(source.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, &self.path));
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => Err(APIError::InternalError(InternalError::GenericError(format!("Could not write {}: {}", self.path.display(), err))))
+        }
+    }
+}
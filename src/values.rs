@@ -11,7 +11,8 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::{ error, fmt };
 
-use chrono::{ Duration as ChronoDuration, DateTime, Local, TimeZone, UTC };
+use chrono::{ Duration as ChronoDuration, Datelike, DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime,
+    NaiveTime, Timelike, TimeZone, UTC, Weekday };
 
 use serde_json;
 use serde::ser::{ Serialize, Serializer };
@@ -81,12 +82,25 @@ pub enum Type {
     /// event has taken place.
     TimeStamp,
 
+    /// A time of day in the timezone of the home, e.g. 07:00. Used for
+    /// instance for alarms.
+    TimeOfDay,
+
+    /// A day of the week, in the timezone of the home.
+    DayOfWeek,
+
+    /// A calendar date, in the timezone of the home.
+    Date,
+
     ThinkerbellRule,
 
     WebPushNotify,
 
     Temperature,
     String,
+
+    /// A secret, e.g. a password. See `Secret`.
+    Secret,
     ///
     /// ...
     ///
@@ -94,6 +108,10 @@ pub enum Type {
     Json,
     Binary,
 
+    /// A reference to content that is too large to be transmitted as a value, e.g.
+    /// a camera snapshot or a video stream. See `StreamRef`.
+    Stream,
+
     ExtBool,
     ExtNumeric,
 }
@@ -111,13 +129,18 @@ impl Parser<Type> for Type {
                 "DoorLocked" => Ok(DoorLocked),
                 "Duration" => Ok(Duration),
                 "TimeStamp" => Ok(TimeStamp),
+                "TimeOfDay" => Ok(TimeOfDay),
+                "DayOfWeek" => Ok(DayOfWeek),
+                "Date" => Ok(Date),
                 "Temperature" => Ok(Temperature),
                 "ThinkerbellRule" => Ok(ThinkerbellRule),
                 "WebPushNotify" => Ok(WebPushNotify),
                 "String" => Ok(String),
+                "Secret" => Ok(Secret),
                 "Color" => Ok(Color),
                 "Json" => Ok(Json),
                 "Binary" => Ok(Binary),
+                "Stream" => Ok(Stream),
                 "ExtBool" => Ok(ExtBool),
                 "ExtNumeric" => Ok(ExtNumeric),
                 _ => Err(ParseError::unknown_constant(string, &path))
@@ -136,13 +159,18 @@ impl ToJSON for Type {
             DoorLocked => "DoorLocked",
             Duration => "Duration",
             TimeStamp => "TimeStamp",
+            TimeOfDay => "TimeOfDay",
+            DayOfWeek => "DayOfWeek",
+            Date => "Date",
             Temperature => "Temperature",
             ThinkerbellRule => "ThinkerbellRule",
             WebPushNotify => "WebPushNotify",
             String => "String",
+            Secret => "Secret",
             Color => "Color",
             Json => "Json",
             Binary => "Binary",
+            Stream => "Stream",
             ExtBool => "ExtBool",
             ExtNumeric => "ExtNumeric",
         };
@@ -157,9 +185,9 @@ impl Type {
     pub fn supports_eq(&self) -> bool {
         use self::Type::*;
         match *self {
-            Duration | TimeStamp | Temperature | ExtNumeric | Color | ThinkerbellRule => false,
-            WebPushNotify | Unit | String | Json | Binary | OnOff | OpenClosed |
-            DoorLocked | ExtBool => true,
+            Duration | TimeStamp | Temperature | ExtNumeric | ThinkerbellRule | Secret => false,
+            WebPushNotify | Unit | String | Json | Binary | Stream | OnOff | OpenClosed |
+            DoorLocked | ExtBool | Color | TimeOfDay | DayOfWeek | Date => true,
         }
     }
 
@@ -675,14 +703,57 @@ impl PartialOrd for Temperature {
 
 /// A color. Internal representation may vary. The `FoxBox` adapters are
 /// expected to perform conversions to the format requested by their
-/// device.
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+/// device, e.g. with `to_rgba`, `to_hsva` or `to_xyya`.
+///
+/// Conversions between `RGBA`, `HSVA` and `XYYA` are lossless, up to floating-point precision,
+/// for colors that fit in the sRGB gamut. Colors outside of this gamut are clamped when
+/// converted to `RGBA` or `HSVA`. Conversions from `Kelvin` are approximations of the color
+/// of a black body, and there is no conversion back to `Kelvin`.
+///
+/// # Alpha
+///
+/// Field `a` is the opacity, between 0 (fully transparent) and 1 (fully opaque). When it is
+/// omitted from JSON, it is taken to be 1.
+///
+/// # Comparison
+///
+/// Two colors are equal if they have the same representation and the same components. Use
+/// `approx_eq` to determine whether two colors represent the same color, up to floating-point
+/// precision, even if they use distinct representations. `Range::Eq` uses `approx_eq`.
+/// Colors are not ordered: two distinct colors are incomparable.
+///
+/// # JSON
+///
+/// In addition to the representations detailed for each variant, a color may be represented
+/// by a string `"#rrggbb"` or `"#rrggbbaa"`, where each component is a two-digit hexadecimal
+/// number. Such strings are parsed as `RGBA`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = Color::from_str("\"#ff000080\"").unwrap();
+/// if let Color::RGBA(r, g, b, a) = parsed {
+///   assert_eq!((r, g, b), (1., 0., 0.));
+///   assert!((a - 128. / 255.).abs() < 1e-6);
+/// } else {
+///   panic!("Unexpected color {:?}", parsed);
+/// }
+///
+/// let red = Color::from_str("\"#ff0000\"").unwrap();
+/// assert!(red != Color::HSVA(0., 1., 1., 1.));
+/// assert!(red.approx_eq(&Color::HSVA(0., 1., 1., 1.)));
+/// assert_eq!(red.to_hex(), "#ff0000ff");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Color {
+    /// Red, green, blue and alpha, in the sRGB color space.
+    ///
     /// # JSON
     ///
-    /// Values are represented as an object {r: float, f: float, b: float, a: float},
-    /// where each color is between 0 and 1. Field `a` may be omitted, in which case
-    /// it is taken to be 0.
+    /// Values are represented as an object {r: float, g: float, b: float, a: float},
+    /// where each component is between 0 and 1. Field `a` may be omitted, in which case
+    /// it is taken to be 1.
     ///
     /// ```
     /// use foxbox_taxonomy::values::*;
@@ -697,11 +768,14 @@ pub enum Color {
     /// }";
     ///
     /// let parsed = Color::from_str(source).unwrap();
-    /// let Color::RGBA(r, g, b, a) = parsed;
-    /// assert_eq!(r, 0.1);
-    /// assert_eq!(g, 0.2);
-    /// assert_eq!(b, 0.4);
-    /// assert_eq!(a, 0.8);
+    /// if let Color::RGBA(r, g, b, a) = parsed {
+    ///   assert_eq!(r, 0.1);
+    ///   assert_eq!(g, 0.2);
+    ///   assert_eq!(b, 0.4);
+    ///   assert_eq!(a, 0.8);
+    /// } else {
+    ///   panic!("Unexpected color {:?}", parsed);
+    /// }
     ///
     /// println!("Testing serialization");
     /// let serialized : JSON = parsed.to_json();
@@ -731,7 +805,7 @@ pub enum Color {
     ///
     ///
     /// println!("Testing auto-added alpha");
-    /// // This source does not specify alpha, so alpha is 0.
+    /// // This source does not specify alpha, so the color is opaque.
     /// let source_3 = "{
     ///   \"r\": 0.1,
     ///   \"g\": 0.2,
@@ -739,11 +813,14 @@ pub enum Color {
     /// }";
     ///
     /// let parsed = Color::from_str(source_3).unwrap();
-    /// let Color::RGBA(r, g, b, a) = parsed;
-    /// assert_eq!(r, 0.1);
-    /// assert_eq!(g, 0.2);
-    /// assert_eq!(b, 0.4);
-    /// assert_eq!(a, 0.);
+    /// if let Color::RGBA(r, g, b, a) = parsed {
+    ///   assert_eq!(r, 0.1);
+    ///   assert_eq!(g, 0.2);
+    ///   assert_eq!(b, 0.4);
+    ///   assert_eq!(a, 1.);
+    /// } else {
+    ///   panic!("Unexpected color {:?}", parsed);
+    /// }
     ///
     ///
     /// println!("Testing parsing error (missing field)");
@@ -758,35 +835,360 @@ pub enum Color {
     ///   other => panic!("Unexpected result {:?}", other)
     /// }
     /// ```
-    RGBA(f64, f64, f64, f64)
+    RGBA(f64, f64, f64, f64),
+
+    /// Hue, saturation, value and alpha.
+    ///
+    /// # JSON
+    ///
+    /// Values are represented as an object {h: float, s: float, v: float, a: float},
+    /// where `h` is an angle in degrees, in [0, 360[, and the other components are between
+    /// 0 and 1. Field `a` may be omitted, in which case it is taken to be 1.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = Color::from_str("{\"h\": 120, \"s\": 1, \"v\": 1}").unwrap();
+    /// assert!(parsed.approx_eq(&Color::RGBA(0., 1., 0., 1.)));
+    ///
+    /// let serialized : JSON = parsed.to_json();
+    /// assert_eq!(serialized.find("h").unwrap().as_f64().unwrap(), 120.);
+    /// ```
+    HSVA(f64, f64, f64, f64),
+
+    /// Chromaticity `x`, `y` and luminance `Y` in the CIE 1931 color space, and alpha.
+    /// Conversions assume the D65 white point used by sRGB.
+    ///
+    /// # JSON
+    ///
+    /// Values are represented as an object {x: float, y: float, Y: float, a: float}, where
+    /// each component is between 0 and 1. Fields `Y` and `a` may be omitted, in which case
+    /// they are taken to be 1.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// // The white point of sRGB.
+    /// let parsed = Color::from_str("{\"x\": 0.3127, \"y\": 0.3290}").unwrap();
+    /// if let Color::RGBA(r, g, b, _) = parsed.to_rgba() {
+    ///   assert!((r - 1.).abs() < 1e-3 && (g - 1.).abs() < 1e-3 && (b - 1.).abs() < 1e-3);
+    /// }
+    /// ```
+    XYYA(f64, f64, f64, f64),
+
+    /// The color of a black body at a given temperature, in Kelvin, between 1667 and 25000.
+    /// Typically used for white light bulbs.
+    ///
+    /// # JSON
+    ///
+    /// Values are represented as an object {kelvin: float}. Such colors are always opaque,
+    /// so field `a` is rejected.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = Color::from_str("{\"kelvin\": 2700}").unwrap();
+    /// assert_eq!(parsed, Color::Kelvin(2700.));
+    ///
+    /// // Warm white has more red than blue.
+    /// if let Color::RGBA(r, _, b, _) = parsed.to_rgba() {
+    ///   assert!(r > b);
+    /// }
+    ///
+    /// // Conversions to `XYYA` and `RGBA` agree, including on luminance.
+    /// let xyya = parsed.to_xyya();
+    /// assert!(xyya.approx_eq(&parsed));
+    /// match (xyya, parsed.to_rgba().to_xyya()) {
+    ///   (Color::XYYA(x1, y1, big_y1, a1), Color::XYYA(x2, y2, big_y2, a2)) => {
+    ///     assert!((x1 - x2).abs() < 1e-6 && (y1 - y2).abs() < 1e-6);
+    ///     assert!((big_y1 - big_y2).abs() < 1e-6 && (a1 - a2).abs() < 1e-6);
+    ///   }
+    ///   other => panic!("Unexpected colors {:?}", other)
+    /// }
+    ///
+    /// match Color::from_str("{\"kelvin\": 2700, \"a\": 0.5}") {
+    ///   Err(ParseError::UnknownFields{..}) => {},
+    ///   other => panic!("Unexpected result {:?}", other)
+    /// }
+    /// ```
+    Kelvin(f64),
+}
+
+/// The tolerance used when comparing colors.
+const COLOR_EPSILON: f64 = 1e-6;
+
+/// The chromaticity of the white point of sRGB.
+const D65_X: f64 = 0.3127;
+const D65_Y: f64 = 0.3290;
+
+impl Color {
+    /// Convert to red, green, blue and alpha.
+    pub fn to_rgba(&self) -> Color {
+        let (r, g, b, a) = self.rgba_components();
+        Color::RGBA(r, g, b, a)
+    }
+
+    /// Convert to hue, saturation, value and alpha.
+    pub fn to_hsva(&self) -> Color {
+        if let Color::HSVA(..) = *self {
+            return self.clone();
+        }
+        let (r, g, b, a) = self.rgba_components();
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let h = if delta == 0. {
+            0.
+        } else if max == r {
+            60. * (((g - b) / delta) % 6.)
+        } else if max == g {
+            60. * ((b - r) / delta + 2.)
+        } else {
+            60. * ((r - g) / delta + 4.)
+        };
+        let h = if h < 0. { h + 360. } else { h };
+        let s = if max == 0. { 0. } else { delta / max };
+        Color::HSVA(h, s, max, a)
+    }
+
+    /// Convert to chromaticity, luminance and alpha in the CIE 1931 color space.
+    pub fn to_xyya(&self) -> Color {
+        match *self {
+            Color::XYYA(..) => return self.clone(),
+            Color::Kelvin(kelvin) => {
+                let (x, y, big_y) = Self::kelvin_to_xyy(kelvin);
+                return Color::XYYA(x, y, big_y, 1.)
+            }
+            _ => {}
+        }
+        let (r, g, b, a) = self.rgba_components();
+        let (r, g, b) = (Self::linearize(r), Self::linearize(g), Self::linearize(b));
+        let big_x = 0.4124564 * r + 0.3575761 * g + 0.1804375 * b;
+        let big_y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let big_z = 0.0193339 * r + 0.1191920 * g + 0.9503041 * b;
+        let sum = big_x + big_y + big_z;
+        if sum == 0. {
+            // Black has no chromaticity, use that of the white point.
+            Color::XYYA(D65_X, D65_Y, 0., a)
+        } else {
+            Color::XYYA(big_x / sum, big_y / sum, big_y, a)
+        }
+    }
+
+    /// `true` if both colors represent the same color, up to floating-point precision, even
+    /// if they use distinct representations.
+    pub fn approx_eq(&self, other: &Color) -> bool {
+        let (r1, g1, b1, a1) = self.rgba_components();
+        let (r2, g2, b2, a2) = other.rgba_components();
+        (r1 - r2).abs() < COLOR_EPSILON
+            && (g1 - g2).abs() < COLOR_EPSILON
+            && (b1 - b2).abs() < COLOR_EPSILON
+            && (a1 - a2).abs() < COLOR_EPSILON
+    }
+
+    /// Represent as a string `"#rrggbbaa"`.
+    pub fn to_hex(&self) -> String {
+        let (r, g, b, a) = self.rgba_components();
+        let byte = |c: f64| (c * 255.).round() as u8;
+        format!("#{:02x}{:02x}{:02x}{:02x}", byte(r), byte(g), byte(b), byte(a))
+    }
+
+    /// Parse a string `"#rrggbb"` or `"#rrggbbaa"`.
+    pub fn from_hex(source: &str) -> Option<Color> {
+        if !source.starts_with('#') || !(source.len() == 7 || source.len() == 9) {
+            return None;
+        }
+        // Make sure that the string is ASCII before slicing it.
+        if !source[1..].chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+        let mut components = vec![];
+        for i in 0 .. (source.len() - 1) / 2 {
+            let digits = &source[1 + 2 * i .. 3 + 2 * i];
+            match u8::from_str_radix(digits, 16) {
+                Ok(byte) => components.push(byte as f64 / 255.),
+                Err(_) => return None
+            }
+        }
+        let a = if components.len() == 4 { components[3] } else { 1. };
+        Some(Color::RGBA(components[0], components[1], components[2], a))
+    }
+
+    fn rgba_components(&self) -> (f64, f64, f64, f64) {
+        match *self {
+            Color::RGBA(r, g, b, a) => (r, g, b, a),
+            Color::HSVA(h, s, v, a) => {
+                let c = v * s;
+                let h = (h % 360.) / 60.;
+                let x = c * (1. - (h % 2. - 1.).abs());
+                let (r, g, b) = match h as u32 {
+                    0 => (c, x, 0.),
+                    1 => (x, c, 0.),
+                    2 => (0., c, x),
+                    3 => (0., x, c),
+                    4 => (x, 0., c),
+                    _ => (c, 0., x),
+                };
+                let m = v - c;
+                (r + m, g + m, b + m, a)
+            }
+            Color::XYYA(x, y, big_y, a) => {
+                let (r, g, b) = Self::xyy_to_linear(x, y, big_y);
+                (Self::delinearize(r), Self::delinearize(g), Self::delinearize(b), a)
+            }
+            Color::Kelvin(kelvin) => {
+                let (x, y, big_y) = Self::kelvin_to_xyy(kelvin);
+                let (r, g, b) = Self::xyy_to_linear(x, y, big_y);
+                (Self::delinearize(r), Self::delinearize(g), Self::delinearize(b), 1.)
+            }
+        }
+    }
+
+    /// Convert from the CIE 1931 color space to linear sRGB, without clamping.
+    fn xyy_to_linear(x: f64, y: f64, big_y: f64) -> (f64, f64, f64) {
+        if y == 0. {
+            return (0., 0., 0.);
+        }
+        let big_x = x * big_y / y;
+        let big_z = (1. - x - y) * big_y / y;
+        let r = 3.2404542 * big_x - 1.5371385 * big_y - 0.4985314 * big_z;
+        let g = -0.9692660 * big_x + 1.8760108 * big_y + 0.0415560 * big_z;
+        let b = 0.0556434 * big_x - 0.2040259 * big_y + 1.0572252 * big_z;
+        (r, g, b)
+    }
+
+    /// The chromaticity of a black body, with the luminance of the brightest color of this
+    /// chromaticity that fits in sRGB.
+    fn kelvin_to_xyy(kelvin: f64) -> (f64, f64, f64) {
+        let (x, y) = Self::kelvin_to_xy(kelvin);
+        let (r, g, b) = Self::xyy_to_linear(x, y, 1.);
+        (x, y, 1. / r.max(g).max(b))
+    }
+
+    /// Approximate the chromaticity of a black body, using the cubic spline of
+    /// Kim et al. (2002).
+    fn kelvin_to_xy(kelvin: f64) -> (f64, f64) {
+        let t = kelvin.max(1667.).min(25000.);
+        let x = if t <= 4000. {
+            -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+        } else {
+            -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+        };
+        let y = if t <= 2222. {
+            -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+        } else if t <= 4000. {
+            -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+        } else {
+            3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+        };
+        (x, y)
+    }
+
+    /// Convert a gamma-encoded sRGB component to linear light.
+    fn linearize(c: f64) -> f64 {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    /// Convert a linear light component to gamma-encoded sRGB, clamping out-of-gamut values.
+    fn delinearize(c: f64) -> f64 {
+        let c = if c <= 0.0031308 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1. / 2.4) - 0.055
+        };
+        c.max(0.).min(1.)
+    }
+}
+
+impl PartialOrd for Color {
+    /// Colors are not ordered. Two colors are either equal or incomparable.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            Some(Ordering::Equal)
+        } else {
+            None
+        }
+    }
 }
+
 impl Parser<Color> for Color {
     fn description() -> String {
         "Color".to_owned()
     }
     fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
-        let r = try!(path.push("r", |path| f64::take(path, source, "r")));
-        let g = try!(path.push("g", |path| f64::take(path, source, "g")));
-        let b = try!(path.push("b", |path| f64::take(path, source, "b")));
+        if let JSON::String(ref string) = *source {
+            return match Color::from_hex(string) {
+                Some(color) => Ok(color),
+                None => Err(ParseError::type_error("Color", &path, "string \"#rrggbb\" or \"#rrggbbaa\""))
+            }
+        }
+        let check = |values: Vec<(f64, &str)>| {
+            for (val, name) in values {
+                if val < 0. || val > 1. {
+                    return Err(ParseError::type_error(name, &path, "a number in [0, 1]"));
+                }
+            }
+            Ok(())
+        };
+        let has_alpha = source.find("a").is_some();
         let a = try!(match path.push("a", |path| f64::take_opt(path, source, "a")) {
-            None => Ok(0.),
+            None => Ok(1.),
             Some(a) => a
         });
-        for &(val, ref name) in &vec![(&r, "r"), (&g, "g"), (&b, "b"), (&a, "a")] {
-            if *val < 0. || *val > 1. {
-                return Err(ParseError::type_error(name, &path, "a number in [0, 1]"));
+        if source.find("kelvin").is_some() {
+            if has_alpha {
+                return Err(ParseError::unknown_fields(vec!["a".to_owned()], &path));
+            }
+            let kelvin = try!(path.push("kelvin", |path| f64::take(path, source, "kelvin")));
+            if kelvin < 1667. || kelvin > 25000. {
+                return Err(ParseError::type_error("kelvin", &path, "a number in [1667, 25000]"));
             }
+            Ok(Color::Kelvin(kelvin))
+        } else if source.find("h").is_some() {
+            let h = try!(path.push("h", |path| f64::take(path, source, "h")));
+            let s = try!(path.push("s", |path| f64::take(path, source, "s")));
+            let v = try!(path.push("v", |path| f64::take(path, source, "v")));
+            if h < 0. || h >= 360. {
+                return Err(ParseError::type_error("h", &path, "a number in [0, 360["));
+            }
+            try!(check(vec![(s, "s"), (v, "v"), (a, "a")]));
+            Ok(Color::HSVA(h, s, v, a))
+        } else if source.find("x").is_some() {
+            let x = try!(path.push("x", |path| f64::take(path, source, "x")));
+            let y = try!(path.push("y", |path| f64::take(path, source, "y")));
+            let big_y = try!(match path.push("Y", |path| f64::take_opt(path, source, "Y")) {
+                None => Ok(1.),
+                Some(big_y) => big_y
+            });
+            try!(check(vec![(x, "x"), (y, "y"), (big_y, "Y"), (a, "a")]));
+            Ok(Color::XYYA(x, y, big_y, a))
+        } else {
+            let r = try!(path.push("r", |path| f64::take(path, source, "r")));
+            let g = try!(path.push("g", |path| f64::take(path, source, "g")));
+            let b = try!(path.push("b", |path| f64::take(path, source, "b")));
+            try!(check(vec![(r, "r"), (g, "g"), (b, "b"), (a, "a")]));
+            Ok(Color::RGBA(r, g, b, a))
         }
-        Ok(Color::RGBA(r, g, b, a))
     }
 }
 
 impl ToJSON for Color {
     fn to_json(&self) -> JSON {
-        let Color::RGBA(ref r, ref g, ref b, ref a) = *self;
-        let mut vec = vec![("r", r), ("g", g), ("b", b), ("a", a)];
+        let mut vec = match *self {
+            Color::RGBA(r, g, b, a) => vec![("r", r), ("g", g), ("b", b), ("a", a)],
+            Color::HSVA(h, s, v, a) => vec![("h", h), ("s", s), ("v", v), ("a", a)],
+            Color::XYYA(x, y, big_y, a) => vec![("x", x), ("y", y), ("Y", big_y), ("a", a)],
+            Color::Kelvin(kelvin) => vec![("kelvin", kelvin)],
+        };
         let map = vec.drain(..)
-            .map(|(name, value)| (name.to_owned(), JSON::F64(*value)))
+            .map(|(name, value)| (name.to_owned(), JSON::F64(value)))
             .collect();
         JSON::Object(map)
     }
@@ -956,7 +1358,54 @@ impl<T> PartialOrd<ExtValue<T>> for ExtValue<T>
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Binary data, e.g. a small image.
+///
+/// # JSON
+///
+/// Represented by an object `{data: string, encoding: "base64", mimetype: string}`, where `data`
+/// is encoded in base64. For compatibility, the parser also accepts `data` as an array of bytes,
+/// in which case `encoding` must be omitted.
+///
+/// The mimetype must have the form `type/subtype`. To protect against memory exhaustion, the
+/// parser rejects data larger than `Path::max_binary_size()` bytes, by default
+/// `DEFAULT_BINARY_MAX_SIZE`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = Binary::from_str("{
+///   \"data\": \"AAEC\",
+///   \"encoding\": \"base64\",
+///   \"mimetype\": \"binary/raw\"
+/// }").unwrap();
+/// assert_eq!(*parsed.data, vec![0, 1, 2]);
+///
+/// let legacy = Binary::from_str("{
+///   \"data\": [0, 1, 2],
+///   \"mimetype\": \"binary/raw\"
+/// }").unwrap();
+/// assert_eq!(legacy, parsed);
+///
+/// let serialized = parsed.to_json();
+/// assert_eq!(serialized.find("data").unwrap().as_string().unwrap(), "AAEC");
+///
+/// // Invalid mimetype.
+/// assert!(Binary::from_str("{\"data\": \"AAEC\", \"encoding\": \"base64\", \"mimetype\": \"raw\"}").is_err());
+///
+/// // Data too large.
+/// let path = Path::new().with_max_binary_size(2);
+/// match Binary::from_str_at(path, "{\"data\": \"AAEC\", \"encoding\": \"base64\", \"mimetype\": \"binary/raw\"}") {
+///   Err(ParseError::TooLarge { size: 3, max: 2, .. }) => {},
+///   other => panic!("Unexpected result {:?}", other)
+/// }
+/// let path = Path::new().with_max_binary_size(3);
+/// match Binary::from_str_at(path, "{\"data\": \"AAECAw==\", \"encoding\": \"base64\", \"mimetype\": \"binary/raw\"}") {
+///   Err(ParseError::TooLarge { size: 4, max: 3, .. }) => {},
+///   other => panic!("Unexpected result {:?}", other)
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
 pub struct Binary {
    /// The actual data. We put it behind an `Arc` to make sure
    /// that cloning remains inexpensive.
@@ -966,13 +1415,162 @@ pub struct Binary {
    pub mimetype: Id<MimeTypeId>,
 }
 
+/// The maximal size of binary data accepted by default by `Parser<Binary>`, in bytes.
+/// See `Path::with_max_binary_size`.
+pub const DEFAULT_BINARY_MAX_SIZE: usize = 1024 * 1024;
+
+const BASE64_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Marks the bytes that are not base64 characters in `BASE64_VALUES`.
+const INVALID_BASE64: u8 = 0xff;
+
+lazy_static! {
+    // The value of each byte as a base64 character, or `INVALID_BASE64`.
+    static ref BASE64_VALUES: [u8; 256] = {
+        let mut values = [INVALID_BASE64; 256];
+        for (value, c) in BASE64_CHARS.iter().enumerate() {
+            values[*c as usize] = value as u8;
+        }
+        values
+    };
+}
+
+impl Binary {
+    /// Determine whether a string is a syntactically valid mimetype, i.e. `type/subtype`,
+    /// possibly followed by parameters.
+    pub fn is_valid_mimetype(mimetype: &str) -> bool {
+        let essence = mimetype.split(';').next().unwrap(); // `split` always yields at least once.
+        let mut parts = essence.trim().split('/');
+        let is_token = |part: &str| {
+            !part.is_empty() && part.chars().all(|c| {
+                (c as u32) < 128 && (c.is_alphanumeric() || "!#$&-^_.+".contains(c))
+            })
+        };
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(typ), Some(subtype), None) => is_token(typ) && is_token(subtype),
+            _ => false
+        }
+    }
+
+    /// Encode the data as base64.
+    pub fn to_base64(&self) -> String {
+        let mut result = String::with_capacity((self.data.len() + 2) / 3 * 4);
+        for chunk in self.data.chunks(3) {
+            let b0 = chunk[0] as usize;
+            let b1 = if chunk.len() > 1 { chunk[1] as usize } else { 0 };
+            let b2 = if chunk.len() > 2 { chunk[2] as usize } else { 0 };
+            result.push(BASE64_CHARS[b0 >> 2] as char);
+            result.push(BASE64_CHARS[((b0 & 0x03) << 4) | (b1 >> 4)] as char);
+            if chunk.len() > 1 {
+                result.push(BASE64_CHARS[((b1 & 0x0f) << 2) | (b2 >> 6)] as char);
+            } else {
+                result.push('=');
+            }
+            if chunk.len() > 2 {
+                result.push(BASE64_CHARS[b2 & 0x3f] as char);
+            } else {
+                result.push('=');
+            }
+        }
+        result
+    }
+
+    /// Decode base64 data. Padding is optional, but if present, it must be at most two `=`
+    /// that complete the data to a multiple of 4 characters. The bits left over after the
+    /// last byte must be zero, so that each sequence of bytes has a single encoding.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    ///
+    /// assert_eq!(Binary::from_base64("AAE="), Ok(vec![0, 1]));
+    /// assert_eq!(Binary::from_base64("AAE"), Ok(vec![0, 1]));
+    /// assert!(Binary::from_base64("AAF=").is_err());
+    /// assert!(Binary::from_base64("AA===").is_err());
+    /// assert!(Binary::from_base64("AAECA=").is_err());
+    /// assert!(Binary::from_base64("AA.C").is_err());
+    /// ```
+    pub fn from_base64(source: &str) -> Result<Vec<u8>, ()> {
+        let trimmed = source.trim_right_matches('=');
+        let padding = source.len() - trimmed.len();
+        if padding > 2 || (padding > 0 && source.len() % 4 != 0) {
+            return Err(());
+        }
+        let source = trimmed.as_bytes();
+        if source.len() % 4 == 1 {
+            return Err(());
+        }
+        let mut result = Vec::with_capacity(source.len() * 3 / 4);
+        let mut acc : u32 = 0;
+        let mut bits = 0;
+        for byte in source {
+            let value = BASE64_VALUES[*byte as usize];
+            if value == INVALID_BASE64 {
+                return Err(());
+            }
+            acc = (acc << 6) | value as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                result.push((acc >> bits) as u8);
+                acc &= (1 << bits) - 1;
+            }
+        }
+        if acc != 0 {
+            // The trailing bits are not part of the data.
+            return Err(());
+        }
+        Ok(result)
+    }
+}
+
 impl Parser<Binary> for Binary {
     fn description() -> String {
         "Binary".to_owned()
     }
     fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
-        let data = try!(path.push("data", |path| Vec::<u8>::take(path, source, "data")));
-        let mimetype = try!(path.push("mimetype", |path| Id::take(path, source, "mimetype")));
+        let mimetype : Id<MimeTypeId> = try!(path.push("mimetype", |path| Id::take(path, source, "mimetype")));
+        if !Binary::is_valid_mimetype(&mimetype.to_string()) {
+            return Err(path.push("mimetype", |path| ParseError::type_error("Binary", &path, "mimetype type/subtype")));
+        }
+        let max = path.max_binary_size();
+        let encoding = match source.find("encoding") {
+            None => None,
+            Some(&JSON::String(ref encoding)) => Some(encoding.clone()),
+            Some(_) => return Err(path.push("encoding", |path| ParseError::type_error("Binary", &path, "string")))
+        };
+        let data = match source.as_object_mut().and_then(|obj| obj.remove("data")) {
+            None => return Err(ParseError::missing_field("data", &path)),
+            Some(data) => data
+        };
+        let data = match (encoding, data) {
+            (None, JSON::Array(array)) => {
+                if array.len() > max {
+                    return Err(path.push("data", |path| ParseError::too_large(array.len(), max, &path)))
+                }
+                let mut array = JSON::Array(array);
+                try!(path.push("data", |path| Vec::<u8>::parse(path, &mut array)))
+            }
+            (Some(ref encoding), JSON::String(ref data)) if encoding == "base64" => {
+                // Check the size before decoding, to avoid allocating large buffers. Each
+                // group of 4 characters encodes 3 bytes, and a trailing group of 2 or 3
+                // characters encodes 1 or 2 bytes.
+                let len = data.trim_right_matches('=').len();
+                let size = len / 4 * 3 + (len % 4) * 3 / 4;
+                if size > max {
+                    return Err(path.push("data", |path| ParseError::too_large(size, max, &path)))
+                }
+                match Binary::from_base64(data) {
+                    Ok(data) => data,
+                    Err(()) => return Err(path.push("data", |path| ParseError::type_error("Binary", &path, "base64 string")))
+                }
+            }
+            (Some(ref encoding), _) if encoding != "base64" => {
+                return Err(path.push("encoding", |path| ParseError::unknown_constant(encoding, &path)))
+            }
+            _ => {
+                return Err(path.push("data", |path| ParseError::type_error("Binary", &path, "base64 string with encoding \"base64\" or array of bytes")))
+            }
+        };
         Ok(Binary {
             data: Arc::new(data),
             mimetype: mimetype
@@ -982,45 +1580,179 @@ impl Parser<Binary> for Binary {
 
 impl ToJSON for Binary {
     fn to_json(&self) -> JSON {
-        let mut source = vec![
-            ("data", JSON::Array(self.data.iter().map(|x| JSON::U64(*x as u64)).collect())),
+        vec![
+            ("data", JSON::String(self.to_base64())),
+            ("encoding", JSON::String("base64".to_owned())),
             ("mimetype", JSON::String(self.mimetype.to_string()))
-        ];
-        let map = source.drain(..)
-            .map(|(key, value)| (key.to_owned(), value))
-            .collect();
-        JSON::Object(map)
+        ].to_json()
     }
 }
 
-/// Representation of an actual value that can be sent to/received
-/// from a service.
+impl Serialize for Binary {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl Deserialize for Binary {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let mut source = try!(JSON::deserialize(deserializer));
+        Binary::parse(Path::new(), &mut source).map_err(|err| D::Error::custom(format!("{:?}", err)))
+    }
+}
+
+/// A reference to content provided by an adapter, e.g. a camera snapshot or a video stream.
 ///
-/// # JSON
+/// Unlike `Binary`, the value does not contain the content itself, which may be large or
+/// even unbounded. The content is opened on demand with `API::open_stream`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamRef {
+    /// An id for the content, chosen by the adapter.
+    pub id: Id<StreamId>,
+
+    /// The mime type of the content.
+    pub mimetype: Id<MimeTypeId>,
+}
+
+impl Parser<StreamRef> for StreamRef {
+    fn description() -> String {
+        "StreamRef".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let id = try!(path.push("id", |path| Id::take(path, source, "id")));
+        let mimetype = try!(path.push("mimetype", |path| Id::take(path, source, "mimetype")));
+        Ok(StreamRef {
+            id: id,
+            mimetype: mimetype
+        })
+    }
+}
+
+impl ToJSON for StreamRef {
+    fn to_json(&self) -> JSON {
+        vec![
+            ("id", self.id.to_json()),
+            ("mimetype", JSON::String(self.mimetype.to_string())),
+        ].to_json()
+    }
+}
+
+/// A secret, e.g. a password or a PIN code.
 ///
-/// Values of this state are represented by an object `{ key: value }`, where key is one of
-/// `Unit`, `OnOff`, `OpenClosed`, ... The `value` for `Unit` is ignored.
+/// The content of a secret is never displayed by `Debug`, nor included in JSON, so that
+/// secrets do not leak through logs, history or snapshots. Only the adapter receiving a
+/// secret should read it, with `Secret::expose`.
 ///
-/// # Other forms of (de)serialization
+/// # JSON
 ///
-/// Values of this state are represented by an object `{ key: value }`, where key is one of
-/// `Unit`, `OnOff`, `OpenClosed`, ... The `value` for `Unit` is ignored.
+/// Parsed from a string. Serialized as the string `"<redacted>"`, which is rejected when
+/// parsing or deserializing, so that a redacted secret is never mistaken for the actual
+/// secret. Consequently, values containing secrets do not survive a round-trip through
+/// JSON.
 ///
 /// ```
-/// extern crate serde;
-/// extern crate serde_json;
-/// extern crate foxbox_taxonomy;
-///
-/// # fn main() {
-/// use foxbox_taxonomy::values::Value::*;
-/// use foxbox_taxonomy::values::OnOff::*;
-/// use foxbox_taxonomy::values::OpenClosed::*;
-///
-/// let unit = serde_json::to_string(&Unit).unwrap();
-/// assert_eq!(unit, "{\"Unit\":[]}");
-///
-/// let unit : foxbox_taxonomy::values::Value = serde_json::from_str("{\"Unit\":[]}").unwrap();
-/// assert_eq!(unit, Unit);
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = Secret::from_str("\"hunter2\"").unwrap();
+/// assert_eq!(parsed.expose(), "hunter2");
+/// assert_eq!(format!("{:?}", parsed), "Secret(<redacted>)");
+/// assert_eq!(parsed.to_json().as_string().unwrap(), "<redacted>");
+/// assert!(Secret::from_str("\"<redacted>\"").is_err());
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Arc<String>);
+
+const SECRET_REDACTED : &'static str = "<redacted>";
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Secret(Arc::new(secret))
+    }
+
+    /// A placeholder secret, used to keep track of the presence of a secret without its
+    /// content, e.g. in logs.
+    pub fn redacted() -> Self {
+        Secret::new(SECRET_REDACTED.to_owned())
+    }
+
+    /// Read the content of the secret.
+    ///
+    /// This should only be called by the adapter that needs the actual secret, e.g. to
+    /// authenticate with a device. Never log the result.
+    pub fn expose(&self) -> &str {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "Secret({})", SECRET_REDACTED)
+    }
+}
+
+impl Parser<Secret> for Secret {
+    fn description() -> String {
+        "Secret".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some(str) if str != SECRET_REDACTED => Ok(Secret::new(str.to_owned())),
+            _ => Err(ParseError::type_error("Secret", &path, "string other than \"<redacted>\""))
+        }
+    }
+}
+
+impl ToJSON for Secret {
+    fn to_json(&self) -> JSON {
+        JSON::String(SECRET_REDACTED.to_owned())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        SECRET_REDACTED.serialize(serializer)
+    }
+}
+
+impl Deserialize for Secret {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let str = try!(String::deserialize(deserializer));
+        if str == SECRET_REDACTED {
+            return Err(D::Error::custom("Cannot deserialize a redacted Secret"));
+        }
+        Ok(Secret::new(str))
+    }
+}
+
+/// Representation of an actual value that can be sent to/received
+/// from a service.
+///
+/// # JSON
+///
+/// Values of this state are represented by an object `{ key: value }`, where key is one of
+/// `Unit`, `OnOff`, `OpenClosed`, ... The `value` for `Unit` is ignored.
+///
+/// # Other forms of (de)serialization
+///
+/// Values of this state are represented by an object `{ key: value }`, where key is one of
+/// `Unit`, `OnOff`, `OpenClosed`, ... The `value` for `Unit` is ignored.
+///
+/// ```
+/// extern crate serde;
+/// extern crate serde_json;
+/// extern crate foxbox_taxonomy;
+///
+/// # fn main() {
+/// use foxbox_taxonomy::values::Value::*;
+/// use foxbox_taxonomy::values::OnOff::*;
+/// use foxbox_taxonomy::values::OpenClosed::*;
+///
+/// let unit = serde_json::to_string(&Unit).unwrap();
+/// assert_eq!(unit, "{\"Unit\":[]}");
+///
+/// let unit : foxbox_taxonomy::values::Value = serde_json::from_str("{\"Unit\":[]}").unwrap();
+/// assert_eq!(unit, Unit);
 ///
 /// let on = serde_json::to_string(&OnOff(On)).unwrap();
 /// assert_eq!(on, "{\"OnOff\":\"On\"}");
@@ -1221,6 +1953,35 @@ pub enum Value {
     /// ```
     TimeStamp(TimeStamp),
 
+    /// A time of day, in the timezone of the home.
+    ///
+    /// # JSON
+    ///
+    /// Represented by `{TimeOfDay: string}`, where `string` is formatted as `"HH:MM:SS"`.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = Value::from_str("{\"TimeOfDay\": \"07:00\"}").unwrap();
+    /// assert_eq!(parsed, Value::TimeOfDay(TimeOfDay::from_hms(7, 0, 0).unwrap()));
+    /// ```
+    TimeOfDay(TimeOfDay),
+
+    /// A day of the week, in the timezone of the home.
+    ///
+    /// # JSON
+    ///
+    /// Represented by `{DayOfWeek: string}`, where `string` is `"Monday"`, ..., `"Sunday"`.
+    DayOfWeek(DayOfWeek),
+
+    /// A calendar date, in the timezone of the home.
+    ///
+    /// # JSON
+    ///
+    /// Represented by `{Date: string}`, where `string` is formatted as `"YYYY-MM-DD"`.
+    Date(Date),
+
     /// A duration, also used to represent a time of day.
     ///
     /// # JSON
@@ -1367,6 +2128,25 @@ pub enum Value {
     /// ```
     String(Arc<String>),
 
+    /// A secret, e.g. a password.
+    ///
+    /// # JSON
+    ///
+    /// Parsed from `{Secret: string}`. Serialized as `{Secret: "<redacted>"}`, see `Secret`.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// let parsed = Value::from_str("{\"Secret\": \"hunter2\"}").unwrap();
+    /// assert_eq!(parsed.get_type(), Type::Secret);
+    ///
+    /// let serialized: JSON = parsed.to_json();
+    /// let val = serialized.find_path(&["Secret"]).unwrap().as_string().unwrap();
+    /// assert_eq!(val, "<redacted>");
+    /// ```
+    Secret(Secret),
+
     // FIXME: Add more as we identify needs
 
     ThinkerbellRule(ThinkerbellRule),
@@ -1418,9 +2198,8 @@ pub enum Value {
     ///
     /// # JSON
     ///
-    /// Represented by `{Binary: {data: array, mimetype: string}}`.
-    ///
-    /// **This representation is likely to change in the future.**
+    /// Represented by `{Binary: {data: string, encoding: "base64", mimetype: string}}`.
+    /// See `Binary` for more details.
     ///
     /// ```
     /// extern crate foxbox_taxonomy;
@@ -1432,7 +2211,7 @@ pub enum Value {
     /// # fn main() {
     ///
     /// let source = "{
-    ///   \"Binary\": { \"data\": [0, 1, 2], \"mimetype\": \"binary/raw\" }
+    ///   \"Binary\": { \"data\": \"AAEC\", \"encoding\": \"base64\", \"mimetype\": \"binary/raw\" }
     /// }";
     /// let parsed = Value::from_str(source).unwrap();
     /// if let Value::Binary(ref obj) = parsed {
@@ -1449,6 +2228,38 @@ pub enum Value {
     /// # }
     /// ```
     Binary(Binary),
+
+    /// A reference to content provided by an adapter, e.g. a camera snapshot.
+    ///
+    /// # JSON
+    ///
+    /// Represented by `{Stream: {id: string, mimetype: string}}`.
+    ///
+    /// ```
+    /// extern crate foxbox_taxonomy;
+    ///
+    /// use foxbox_taxonomy::values::*;
+    /// use foxbox_taxonomy::parse::*;
+    ///
+    /// # fn main() {
+    ///
+    /// let source = "{
+    ///   \"Stream\": { \"id\": \"snapshot 1\", \"mimetype\": \"image/jpeg\" }
+    /// }";
+    /// let parsed = Value::from_str(source).unwrap();
+    /// if let Value::Stream(ref obj) = parsed {
+    ///   assert_eq!(obj.id.to_string(), "snapshot 1".to_owned());
+    ///   assert_eq!(obj.mimetype.to_string(), "image/jpeg".to_owned());
+    /// } else {
+    ///   panic!();
+    /// }
+    ///
+    /// let serialized: JSON = parsed.to_json();
+    /// let val = serialized.find_path(&["Stream", "id"]).unwrap().as_string().unwrap();
+    /// assert_eq!(val, "snapshot 1");
+    /// # }
+    /// ```
+    Stream(StreamRef),
 }
 
 
@@ -1480,6 +2291,18 @@ lazy_static! {
             let value = try!(path.push("TimeStamp", |path| self::TimeStamp::parse(path, v)));
             Ok(TimeStamp(value))
         }));
+        map.insert("TimeOfDay", Box::new(|path, v| {
+            let value = try!(path.push("TimeOfDay", |path| self::TimeOfDay::parse(path, v)));
+            Ok(TimeOfDay(value))
+        }));
+        map.insert("DayOfWeek", Box::new(|path, v| {
+            let value = try!(path.push("DayOfWeek", |path| self::DayOfWeek::parse(path, v)));
+            Ok(DayOfWeek(value))
+        }));
+        map.insert("Date", Box::new(|path, v| {
+            let value = try!(path.push("Date", |path| self::Date::parse(path, v)));
+            Ok(Date(value))
+        }));
         map.insert("Temperature", Box::new(|path, v| {
             let value = try!(path.push("Temperature", |path| self::Temperature::parse(path, v)));
             Ok(Temperature(value))
@@ -1500,6 +2323,10 @@ lazy_static! {
             let value = try!(path.push("String", |path| Arc::<StdString>::parse(path, v)));
             Ok(String(value))
         }));
+        map.insert("Secret", Box::new(|path, v| {
+            let value = try!(path.push("Secret", |path| self::Secret::parse(path, v)));
+            Ok(Secret(value))
+        }));
         map.insert("Json", Box::new(|path, v| {
             let value = try!(path.push("Json", |path| Arc::<self::Json>::parse(path, v)));
             Ok(Json(value))
@@ -1516,6 +2343,10 @@ lazy_static! {
             let value = try!(path.push("Binary", |path| self::Binary::parse(path, v)));
             Ok(Binary(value))
         }));
+        map.insert("Stream", Box::new(|path, v| {
+            let value = try!(path.push("Stream", |path| self::StreamRef::parse(path, v)));
+            Ok(Stream(value))
+        }));
         map
     };
     static ref VALUE_KEYS: String = {
@@ -1555,10 +2386,15 @@ impl ToJSON for Value {
             DoorLocked(ref val) => ("DoorLocked", val.to_json()),
             Duration(ref val) => ("Duration", val.to_json()),
             TimeStamp(ref val) => ("TimeStamp", val.to_json()),
+            TimeOfDay(ref val) => ("TimeOfDay", val.to_json()),
+            DayOfWeek(ref val) => ("DayOfWeek", val.to_json()),
+            Date(ref val) => ("Date", val.to_json()),
             Color(ref val) => ("Color", val.to_json()),
             String(ref val) => ("String", val.to_json()),
+            Secret(ref val) => ("Secret", val.to_json()),
             Json(ref val) => ("Json", val.to_json()),
             Binary(ref val) => ("Binary", val.to_json()),
+            Stream(ref val) => ("Stream", val.to_json()),
             Temperature(ref val) => ("Temperature", val.to_json()),
             ThinkerbellRule(ref val) => ("ThinkerbellRule", val.to_json()),
             WebPushNotify(ref val) => ("WebPushNotify", val.to_json()),
@@ -1579,12 +2415,17 @@ impl Value {
             Value::OpenClosed(_) => Type::OpenClosed,
             Value::DoorLocked(_) => Type::DoorLocked,
             Value::String(_) => Type::String,
+            Value::Secret(_) => Type::Secret,
             Value::Duration(_) => Type::Duration,
             Value::TimeStamp(_) => Type::TimeStamp,
+            Value::TimeOfDay(_) => Type::TimeOfDay,
+            Value::DayOfWeek(_) => Type::DayOfWeek,
+            Value::Date(_) => Type::Date,
             Value::Temperature(_) => Type::Temperature,
             Value::Color(_) => Type::Color,
             Value::Json(_) => Type::Json,
             Value::Binary(_) => Type::Binary,
+            Value::Stream(_) => Type::Stream,
             Value::ExtBool(_) => Type::ExtBool,
             Value::ExtNumeric(_) => Type::ExtNumeric,
             Value::ThinkerbellRule(_) => Type::ThinkerbellRule,
@@ -1633,6 +2474,15 @@ impl PartialOrd for Value {
             (&TimeStamp(ref a), &TimeStamp(ref b)) => a.partial_cmp(b),
             (&TimeStamp(_), _) => None,
 
+            (&TimeOfDay(ref a), &TimeOfDay(ref b)) => a.partial_cmp(b),
+            (&TimeOfDay(_), _) => None,
+
+            (&DayOfWeek(ref a), &DayOfWeek(ref b)) => a.partial_cmp(b),
+            (&DayOfWeek(_), _) => None,
+
+            (&Date(ref a), &Date(ref b)) => a.partial_cmp(b),
+            (&Date(_), _) => None,
+
             (&Temperature(ref a), &Temperature(ref b)) => a.partial_cmp(b),
             (&Temperature(_), _) => None,
 
@@ -1648,6 +2498,10 @@ impl PartialOrd for Value {
             (&String(ref a), &String(ref b)) => a.partial_cmp(b),
             (&String(_), _) => None,
 
+            // Secrets are not ordered, to avoid leaking their content through ranges.
+            (&Secret(ref a), &Secret(ref b)) if a == b => Some(Equal),
+            (&Secret(_), _) => None,
+
             (&Json(ref a), &Json(ref b)) => a.partial_cmp(b),
             (&Json(_), _) => None,
 
@@ -1660,6 +2514,9 @@ impl PartialOrd for Value {
             (&Binary(self::Binary {mimetype: ref a_mimetype, data: ref a_data}),
              &Binary(self::Binary {mimetype: ref b_mimetype, data: ref b_data})) if a_mimetype == b_mimetype => a_data.partial_cmp(b_data),
             (&Binary(_), _) => None,
+
+            (&Stream(ref a), &Stream(ref b)) if a == b => Some(Equal),
+            (&Stream(_), _) => None,
         }
     }
 }
@@ -1888,6 +2745,11 @@ impl Range {
             Geq(ref min) => value >= min,
             BetweenEq { ref min, ref max } => min <= value && value <= max,
             OutOfStrict { ref min, ref max } => value < min || max < value,
+            Eq(Value::Color(ref val)) => match *value {
+                // Colors may use distinct representations.
+                Value::Color(ref color) => color.approx_eq(val),
+                _ => false
+            },
             Eq(ref val) => value == val,
         }
     }
@@ -1921,7 +2783,8 @@ impl Range {
 ///
 /// # JSON
 ///
-/// Represented by a (floating-point) number of seconds.
+/// Represented by a (floating-point) number of seconds. The parser also accepts ISO-8601
+/// duration strings, such as `"PT5M"` or `"P1DT12H"`, but not years or months.
 ///
 /// ```
 /// extern crate foxbox_taxonomy;
@@ -1942,6 +2805,16 @@ impl Range {
 /// let serialized: JSON = parsed.to_json();
 /// assert_eq!(serialized.as_f64().unwrap(), 60.01);
 ///
+/// let parsed = Duration::from_str("\"PT5M\"").unwrap();
+/// let duration : ChronoDuration = parsed.into();
+/// assert_eq!(duration.num_seconds(), 300);
+///
+/// let parsed = Duration::from_str("\"P1DT0.5S\"").unwrap();
+/// let duration : ChronoDuration = parsed.into();
+/// assert_eq!(duration.num_milliseconds(), 86400500);
+///
+/// assert!(Duration::from_str("\"P1M\"").is_err());
+///
 /// # }
 /// ```
 #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
@@ -1952,6 +2825,12 @@ impl Parser<Duration> for Duration {
         "Duration".to_owned()
     }
     fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if let JSON::String(ref str) = *source {
+            return match Duration::from_iso8601(str) {
+                Some(duration) => Ok(duration),
+                None => Err(ParseError::type_error("Duration", &path, "ISO-8601 duration string"))
+            }
+        }
         let val = try!(f64::parse(path, source));
         Ok(Duration(ChronoDuration::milliseconds((val * 1000.) as i64)))
     }
@@ -2035,8 +2914,487 @@ impl Deserialize for Duration {
             {
                 self.visit_i64(v as i64)
             }
+            fn visit_str<E>(&mut self, v: &str) -> Result<Self::Value, E>
+                where E: Error,
+            {
+                Duration::from_iso8601(v).ok_or_else(|| E::custom("Invalid ISO-8601 duration"))
+            }
         }
         deserializer.deserialize_f64(DurationVisitor)
             .or_else(|_| deserializer.deserialize_i64(DurationVisitor))
     }
 }
+
+impl Duration {
+    /// Parse an ISO-8601 duration, such as `"PT5M"` or `"P1DT12H"`.
+    ///
+    /// Years and months are rejected, as their duration depends on the calendar. Each
+    /// component may appear at most once, from the largest to the smallest.
+    ///
+    /// ```
+    /// use foxbox_taxonomy::values::*;
+    ///
+    /// assert!(Duration::from_iso8601("P1W2DT3H4M5.5S").is_some());
+    /// assert!(Duration::from_iso8601("PT5S3H").is_none());
+    /// assert!(Duration::from_iso8601("P1D1D").is_none());
+    /// assert!(Duration::from_iso8601("P1DT").is_none());
+    /// ```
+    pub fn from_iso8601(source: &str) -> Option<Self> {
+        let mut chars = source.chars();
+        if chars.next() != Some('P') {
+            return None;
+        }
+        let mut millis : f64 = 0.;
+        let mut in_time = false;
+        let mut number = String::new();
+        // The rank of the latest component, from 0 (weeks) to 4 (seconds).
+        let mut last_rank = None;
+        for c in chars {
+            if c.is_digit(10) || c == '.' || c == ',' {
+                number.push(if c == ',' { '.' } else { c });
+                continue;
+            }
+            if c == 'T' {
+                if in_time || !number.is_empty() {
+                    return None;
+                }
+                in_time = true;
+                continue;
+            }
+            let value = match f64::from_str(&number) {
+                Ok(value) => value,
+                Err(_) => return None
+            };
+            number.clear();
+            let (unit, rank) = match (in_time, c) {
+                (false, 'W') => (7. * 24. * 3600., 0),
+                (false, 'D') => (24. * 3600., 1),
+                (true, 'H') => (3600., 2),
+                (true, 'M') => (60., 3),
+                (true, 'S') => (1., 4),
+                _ => return None
+            };
+            if let Some(last) = last_rank {
+                if rank <= last {
+                    return None;
+                }
+            }
+            last_rank = Some(rank);
+            millis += value * unit * 1000.;
+        }
+        if last_rank.is_none() || !number.is_empty() {
+            return None;
+        }
+        if in_time && last_rank < Some(2) {
+            // "T" must be followed by at least one of hours, minutes or seconds.
+            return None;
+        }
+        Some(Duration(ChronoDuration::milliseconds(millis as i64)))
+    }
+}
+
+/// A time of day, e.g. 07:00, without a timezone. Times of day are interpreted in the
+/// timezone of the home, see `HomeTimeZone`.
+///
+/// # JSON
+///
+/// Represented by a string `"HH:MM"` or `"HH:MM:SS"`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = TimeOfDay::from_str("\"07:30\"").unwrap();
+/// assert_eq!(parsed, TimeOfDay::from_hms(7, 30, 0).unwrap());
+/// assert_eq!(parsed.to_json().as_string().unwrap(), "07:30:00");
+///
+/// assert!(TimeOfDay::from_str("\"25:00\"").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimeOfDay(NaiveTime);
+
+impl TimeOfDay {
+    /// Create a time of day from hours, minutes and seconds. Return `None` if this is not
+    /// a valid time of day.
+    pub fn from_hms(hour: u32, min: u32, sec: u32) -> Option<Self> {
+        NaiveTime::from_hms_opt(hour, min, sec).map(TimeOfDay)
+    }
+
+    pub fn as_naive_time(&self) -> &NaiveTime {
+        &self.0
+    }
+
+    fn from_string(source: &str) -> Option<Self> {
+        NaiveTime::parse_from_str(source, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(source, "%H:%M"))
+            .ok()
+            .map(TimeOfDay)
+    }
+
+    fn to_string(&self) -> String {
+        self.0.format("%H:%M:%S").to_string()
+    }
+}
+
+impl Parser<TimeOfDay> for TimeOfDay {
+    fn description() -> String {
+        "TimeOfDay".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if let JSON::String(ref str) = *source {
+            if let Some(result) = TimeOfDay::from_string(str) {
+                return Ok(result);
+            }
+        }
+        Err(ParseError::type_error("TimeOfDay", &path, "time string HH:MM or HH:MM:SS"))
+    }
+}
+
+impl ToJSON for TimeOfDay {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.to_string())
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl Deserialize for TimeOfDay {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            TimeOfDay::from_string(source).ok_or(())
+        }))
+    }
+}
+
+/// A day of the week.
+///
+/// # JSON
+///
+/// Represented by one of the strings `"Monday"`, `"Tuesday"`, ..., `"Sunday"`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = DayOfWeek::from_str("\"Saturday\"").unwrap();
+/// assert_eq!(parsed, DayOfWeek::Saturday);
+/// assert!(parsed.is_weekend());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DayOfWeek {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl DayOfWeek {
+    /// `true` for Saturday and Sunday.
+    pub fn is_weekend(&self) -> bool {
+        *self == DayOfWeek::Saturday || *self == DayOfWeek::Sunday
+    }
+
+    fn as_str(&self) -> &'static str {
+        use self::DayOfWeek::*;
+        match *self {
+            Monday => "Monday",
+            Tuesday => "Tuesday",
+            Wednesday => "Wednesday",
+            Thursday => "Thursday",
+            Friday => "Friday",
+            Saturday => "Saturday",
+            Sunday => "Sunday",
+        }
+    }
+
+    fn from_string(source: &str) -> Option<Self> {
+        use self::DayOfWeek::*;
+        match source {
+            "Monday" => Some(Monday),
+            "Tuesday" => Some(Tuesday),
+            "Wednesday" => Some(Wednesday),
+            "Thursday" => Some(Thursday),
+            "Friday" => Some(Friday),
+            "Saturday" => Some(Saturday),
+            "Sunday" => Some(Sunday),
+            _ => None
+        }
+    }
+}
+
+impl From<Weekday> for DayOfWeek {
+    fn from(source: Weekday) -> Self {
+        match source {
+            Weekday::Mon => DayOfWeek::Monday,
+            Weekday::Tue => DayOfWeek::Tuesday,
+            Weekday::Wed => DayOfWeek::Wednesday,
+            Weekday::Thu => DayOfWeek::Thursday,
+            Weekday::Fri => DayOfWeek::Friday,
+            Weekday::Sat => DayOfWeek::Saturday,
+            Weekday::Sun => DayOfWeek::Sunday,
+        }
+    }
+}
+
+impl Parser<DayOfWeek> for DayOfWeek {
+    fn description() -> String {
+        "DayOfWeek".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        match source.as_string() {
+            Some(str) => match DayOfWeek::from_string(str) {
+                Some(day) => Ok(day),
+                None => Err(ParseError::unknown_constant(str, &path))
+            },
+            None => Err(ParseError::type_error("DayOfWeek", &path, "string"))
+        }
+    }
+}
+
+impl ToJSON for DayOfWeek {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.as_str().to_owned())
+    }
+}
+
+impl Serialize for DayOfWeek {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.as_str().serialize(serializer)
+    }
+}
+
+impl Deserialize for DayOfWeek {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            DayOfWeek::from_string(source).ok_or(())
+        }))
+    }
+}
+
+/// A calendar date, without a timezone. Dates are interpreted in the timezone of the home,
+/// see `HomeTimeZone`.
+///
+/// # JSON
+///
+/// Represented by a string `"YYYY-MM-DD"`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let parsed = Date::from_str("\"2016-03-27\"").unwrap();
+/// assert_eq!(parsed, Date::from_ymd(2016, 3, 27).unwrap());
+/// assert_eq!(parsed.day_of_week(), DayOfWeek::Sunday);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date(NaiveDate);
+
+impl Date {
+    /// Create a date from year, month and day. Return `None` if this is not a valid date.
+    pub fn from_ymd(year: i32, month: u32, day: u32) -> Option<Self> {
+        NaiveDate::from_ymd_opt(year, month, day).map(Date)
+    }
+
+    pub fn as_naive_date(&self) -> &NaiveDate {
+        &self.0
+    }
+
+    pub fn day_of_week(&self) -> DayOfWeek {
+        DayOfWeek::from(self.0.weekday())
+    }
+
+    fn from_string(source: &str) -> Option<Self> {
+        NaiveDate::parse_from_str(source, "%Y-%m-%d").ok().map(Date)
+    }
+
+    fn to_string(&self) -> String {
+        self.0.format("%Y-%m-%d").to_string()
+    }
+}
+
+impl Parser<Date> for Date {
+    fn description() -> String {
+        "Date".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        if let JSON::String(ref str) = *source {
+            if let Some(result) = Date::from_string(str) {
+                return Ok(result);
+            }
+        }
+        Err(ParseError::type_error("Date", &path, "date string YYYY-MM-DD"))
+    }
+}
+
+impl ToJSON for Date {
+    fn to_json(&self) -> JSON {
+        JSON::String(self.to_string())
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error> where S: Serializer {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl Deserialize for Date {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        deserializer.deserialize_string(TrivialEnumVisitor::new(|source| {
+            Date::from_string(source).ok_or(())
+        }))
+    }
+}
+
+/// The timezone of the home, used to convert between `TimeStamp` and local `Date`,
+/// `TimeOfDay` and `DayOfWeek`.
+///
+/// # JSON
+///
+/// Represented by the string `"system"`, to use the timezone of the system, including its
+/// daylight-saving rules, or by a fixed offset from UTC `"+HH:MM"` or `"-HH:MM"`.
+///
+/// ```
+/// use foxbox_taxonomy::values::*;
+/// use foxbox_taxonomy::parse::*;
+///
+/// let tz = HomeTimeZone::from_str("\"+02:00\"").unwrap();
+/// assert_eq!(tz, HomeTimeZone::Offset(7200));
+///
+/// let timestamp = TimeStamp::from_str("\"2016-03-27T22:30:00+00:00\"").unwrap();
+/// assert_eq!(tz.date(&timestamp), Date::from_ymd(2016, 3, 28).unwrap());
+/// assert_eq!(tz.day_of_week(&timestamp), DayOfWeek::Monday);
+/// assert_eq!(tz.time_of_day(&timestamp), TimeOfDay::from_hms(0, 30, 0).unwrap());
+///
+/// let back = tz.timestamp(&tz.date(&timestamp), &tz.time_of_day(&timestamp)).unwrap();
+/// assert_eq!(back, timestamp);
+///
+/// // Offsets of 24h or more are invalid.
+/// assert_eq!(HomeTimeZone::offset(86400), None);
+/// let invalid = HomeTimeZone::Offset(86400);
+/// assert_eq!(invalid.date(&timestamp), Date::from_ymd(2016, 3, 27).unwrap());
+/// assert_eq!(invalid.timestamp(&tz.date(&timestamp), &tz.time_of_day(&timestamp)), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomeTimeZone {
+    /// Use the timezone of the system, including daylight-saving transitions. This is the
+    /// default.
+    System,
+
+    /// A fixed offset from UTC, in seconds east of UTC. Must be strictly less than 24h in
+    /// absolute value, see `HomeTimeZone::offset`. Conversions treat larger offsets as UTC,
+    /// except `timestamp`, which returns `None`.
+    Offset(i32),
+}
+
+impl Default for HomeTimeZone {
+    fn default() -> Self {
+        HomeTimeZone::System
+    }
+}
+
+impl HomeTimeZone {
+    /// A fixed offset from UTC, in seconds east of UTC, or `None` if the offset is 24h or
+    /// more in absolute value.
+    pub fn offset(seconds: i32) -> Option<Self> {
+        let timezone = HomeTimeZone::Offset(seconds);
+        if timezone.is_valid() {
+            Some(timezone)
+        } else {
+            None
+        }
+    }
+
+    /// Determine whether the offset, if any, is strictly less than 24h in absolute value.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            HomeTimeZone::System => true,
+            HomeTimeZone::Offset(offset) => offset > -86400 && offset < 86400
+        }
+    }
+
+    fn naive_local(&self, timestamp: &TimeStamp) -> NaiveDateTime {
+        match *self {
+            HomeTimeZone::System => timestamp.0.with_timezone(&Local).naive_local(),
+            HomeTimeZone::Offset(offset) => match FixedOffset::east_opt(offset) {
+                Some(offset) => timestamp.0.with_timezone(&offset).naive_local(),
+                // Invalid offsets are rejected when the timezone is set, but the enum is public.
+                None => timestamp.0.naive_utc()
+            }
+        }
+    }
+
+    /// The local date at a given instant.
+    pub fn date(&self, timestamp: &TimeStamp) -> Date {
+        Date(self.naive_local(timestamp).date())
+    }
+
+    /// The local time of day at a given instant.
+    pub fn time_of_day(&self, timestamp: &TimeStamp) -> TimeOfDay {
+        TimeOfDay(self.naive_local(timestamp).time())
+    }
+
+    /// The local day of the week at a given instant.
+    pub fn day_of_week(&self, timestamp: &TimeStamp) -> DayOfWeek {
+        DayOfWeek::from(self.naive_local(timestamp).weekday())
+    }
+
+    /// The instant corresponding to a local date and time of day.
+    ///
+    /// During daylight-saving transitions, a local time may happen twice, in which case this
+    /// returns the earliest instant, or not at all, in which case this returns `None`.
+    pub fn timestamp(&self, date: &Date, time: &TimeOfDay) -> Option<TimeStamp> {
+        let naive = NaiveDateTime::new(date.0, time.0);
+        match *self {
+            HomeTimeZone::System => Local.from_local_datetime(&naive).earliest().map(TimeStamp::from),
+            HomeTimeZone::Offset(offset) => FixedOffset::east_opt(offset)
+                .and_then(|offset| offset.from_local_datetime(&naive).earliest())
+                .map(TimeStamp::from)
+        }
+    }
+}
+
+impl Parser<HomeTimeZone> for HomeTimeZone {
+    fn description() -> String {
+        "HomeTimeZone".to_owned()
+    }
+    fn parse(path: Path, source: &mut JSON) -> Result<Self, ParseError> {
+        let error = || ParseError::type_error("HomeTimeZone", &path, "\"system\" or offset string +HH:MM");
+        let str = match source.as_string() {
+            None => return Err(error()),
+            Some(str) => str
+        };
+        if str == "system" {
+            return Ok(HomeTimeZone::System);
+        }
+        let sign = match str.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(error())
+        };
+        match NaiveTime::parse_from_str(&str[1..], "%H:%M") {
+            Ok(time) => Ok(HomeTimeZone::Offset(sign * time.num_seconds_from_midnight() as i32)),
+            Err(_) => Err(error())
+        }
+    }
+}
+
+impl ToJSON for HomeTimeZone {
+    fn to_json(&self) -> JSON {
+        match *self {
+            HomeTimeZone::System => JSON::String("system".to_owned()),
+            HomeTimeZone::Offset(offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                let offset = offset.abs();
+                JSON::String(format!("{}{:02}:{:02}", sign, offset / 3600, offset % 3600 / 60))
+            }
+        }
+    }
+}
@@ -3,17 +3,28 @@ extern crate transformable_channels;
 #[macro_use]
 extern crate assert_matches;
 
+use foxbox_taxonomy::audit::*;
+use foxbox_taxonomy::conditions::*;
 use foxbox_taxonomy::manager::*;
 use foxbox_taxonomy::fake_adapter::*;
-use foxbox_taxonomy::api::{ API, Error, InternalError, TargetMap, Targetted, User, WatchEvent as Event };
+use foxbox_taxonomy::locations::*;
+use foxbox_taxonomy::parse::{ Parser, ToJSON };
+use foxbox_taxonomy::policy::*;
+use foxbox_taxonomy::profiles::*;
+use foxbox_taxonomy::rules::*;
+use foxbox_taxonomy::scenes::*;
+use foxbox_taxonomy::scheduler::*;
+use foxbox_taxonomy::api::{ API, Error, InternalError, Origin, JobId, Principal, SceneId, TargetMap, Targetted, WatchEvent as Event };
 use foxbox_taxonomy::selector::*;
 use foxbox_taxonomy::services::*;
+use foxbox_taxonomy::streams::*;
 use foxbox_taxonomy::values::*;
 
 use transformable_channels::mpsc::*;
 
 use std::collections::{ HashMap, HashSet };
-use std::sync::Arc;
+use std::io::Read;
+use std::sync::{ Arc, RwLock };
 use std::thread;
 
 // Trivial utility function to convert the old TargetMap format to the newer one, to avoid
@@ -22,6 +33,18 @@ fn target_map<K, T>(mut source: Vec<(Vec<K>, T)>) -> TargetMap<K, T> where K: Cl
     source.drain(..).map(|(v, t)| Targetted::new(v, t)).collect()
 }
 
+// Create an empty directory, unique to this run of the test, to store files.
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    for attempt in 0..100 {
+        let path = std::env::temp_dir().join(format!("foxbox_taxonomy_{}_{}_{}_{}", name, now.as_secs(), now.subsec_nanos(), attempt));
+        if std::fs::create_dir(&path).is_ok() {
+            return path;
+        }
+    }
+    panic!("Could not create a temporary directory for {}", name);
+}
+
 #[test]
 fn test_add_remove_adapter() {
     for clear in vec![false, true] {
@@ -99,9 +122,12 @@ fn test_add_remove_services() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -111,9 +137,13 @@ fn test_add_remove_services() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 updated: None,
+                write_only: false,
+                lease: None,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -123,9 +153,12 @@ fn test_add_remove_services() {
             service: service_id_3.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -135,9 +168,13 @@ fn test_add_remove_services() {
             service: service_id_3.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 updated: None,
+                write_only: false,
+                lease: None,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -157,6 +194,14 @@ fn test_add_remove_services() {
             adapter: id_1.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -166,9 +211,12 @@ fn test_add_remove_services() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -178,9 +226,13 @@ fn test_add_remove_services() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 updated: None,
+                write_only: false,
+                lease: None,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -190,6 +242,14 @@ fn test_add_remove_services() {
             adapter: id_2.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -411,9 +471,12 @@ fn test_add_remove_tags() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -423,9 +486,13 @@ fn test_add_remove_tags() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 updated: None,
+                write_only: false,
+                lease: None,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -435,6 +502,14 @@ fn test_add_remove_tags() {
             adapter: id_1.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -444,9 +519,12 @@ fn test_add_remove_tags() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -456,9 +534,13 @@ fn test_add_remove_tags() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 updated: None,
+                write_only: false,
+                lease: None,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -468,6 +550,14 @@ fn test_add_remove_tags() {
             adapter: id_2.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -493,34 +583,34 @@ fn test_add_remove_tags() {
         println!("* Removing tags from non-existent services and channels doesn't hurt and returns 0.");
         assert_eq!(manager
             .remove_service_tags(
-                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
         assert_eq!(manager
             .remove_getter_tags(
-                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
         assert_eq!(manager
             .remove_setter_tags(
-                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
 
         println!("* Adding tags to non-existent services and channels doesn't hurt and returns 0.");
         assert_eq!(manager
             .add_service_tags(
-                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
         assert_eq!(manager
             .add_getter_tags(
-                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
         assert_eq!(manager
             .add_setter_tags(
-                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             0);
 
@@ -536,34 +626,34 @@ fn test_add_remove_tags() {
         manager.add_setter(setter_2.clone()).unwrap();
         assert_eq!(manager
             .remove_service_tags(
-                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .remove_getter_tags(
-                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .remove_setter_tags(
-                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
 
         println!("* We can add tags tags to services and channels, this returns 1.");
         assert_eq!(manager
             .add_service_tags(
-                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .add_getter_tags(
-                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .add_setter_tags(
-                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()]
+                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_2.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
 
@@ -668,17 +758,17 @@ fn test_add_remove_tags() {
         println!("* We can remove tags, both existent and non-existent.");
         assert_eq!(manager
             .remove_service_tags(
-                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_1.clone(), tag_3.clone()]
+                vec![ServiceSelector::new().with_id(service_id_2.clone())], vec![tag_1.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .remove_getter_tags(
-                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_1.clone(), tag_3.clone()]
+                vec![GetterSelector::new().with_id(getter_id_2.clone())], vec![tag_1.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
         assert_eq!(manager
             .remove_setter_tags(
-                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_1.clone(), tag_3.clone()]
+                vec![SetterSelector::new().with_id(setter_id_2.clone())], vec![tag_1.clone(), tag_3.clone()], Principal::anonymous()
             ),
             1);
 
@@ -773,9 +863,12 @@ fn test_fetch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -785,9 +878,12 @@ fn test_fetch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -797,9 +893,12 @@ fn test_fetch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -809,9 +908,12 @@ fn test_fetch() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -821,6 +923,14 @@ fn test_fetch() {
             adapter: id_1.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -830,6 +940,14 @@ fn test_fetch() {
             adapter: id_2.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -838,21 +956,21 @@ fn test_fetch() {
         let adapter_2 = FakeAdapter::new(&id_2);
         let tweak_1 = adapter_1.get_tweak();
         println!("* Without adapters, fetching values from a selector that has no channels returns an empty vector.");
-        assert_eq!(manager.fetch_values(vec![GetterSelector::new()], User::None).len(), 0);
+        assert_eq!(manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous()).len(), 0);
 
         println!("* With adapters, fetching values from a selector that has no channels returns an empty vector.");
         manager.add_adapter(Arc::new(adapter_1)).unwrap();
         manager.add_adapter(Arc::new(adapter_2)).unwrap();
         manager.add_service(service_1.clone()).unwrap();
         manager.add_service(service_2.clone()).unwrap();
-        assert_eq!(manager.fetch_values(vec![GetterSelector::new()], User::None).len(), 0);
+        assert_eq!(manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous()).len(), 0);
 
         println!("* Fetching empty values from a selector that has channels returns a vector of empty values.");
         manager.add_getter(getter_1_1.clone()).unwrap();
         manager.add_getter(getter_1_2.clone()).unwrap();
         manager.add_getter(getter_1_3.clone()).unwrap();
         manager.add_getter(getter_2.clone()).unwrap();
-        let data = manager.fetch_values(vec![GetterSelector::new()], User::None);
+        let data = manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous());
         assert_eq!(data.len(), 4);
 
         for result in data.values() {
@@ -866,7 +984,7 @@ fn test_fetch() {
         println!("* Fetching values returns the right values.");
         tweak_1(Tweak::InjectGetterValue(getter_id_1_1.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
         tweak_1(Tweak::InjectGetterValue(getter_id_1_2.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
-        let data = manager.fetch_values(vec![GetterSelector::new()], User::None);
+        let data = manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous());
         assert_eq!(data.len(), 4);
         match data.get(&getter_id_1_1) {
             Some(&Ok(Some(Value::OnOff(OnOff::On)))) => {},
@@ -887,7 +1005,7 @@ fn test_fetch() {
 
         println!("* Fetching values returns the right errors.");
         tweak_1(Tweak::InjectGetterValue(getter_id_1_1.clone(), Err(Error::InternalError(InternalError::NoSuchGetter(getter_id_1_1.clone())))));
-        let data = manager.fetch_values(vec![GetterSelector::new()], User::None);
+        let data = manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous());
         assert_eq!(data.len(), 4);
         match data.get(&getter_id_1_1) {
             Some(&Err(Error::InternalError(InternalError::NoSuchGetter(ref id)))) if *id == getter_id_1_1 => {},
@@ -908,7 +1026,7 @@ fn test_fetch() {
 
         println!("* Fetching a value that causes an internal type error returns that error.");
         tweak_1(Tweak::InjectGetterValue(getter_id_1_1.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
-        let data = manager.fetch_values(vec![GetterSelector::new()], User::None);
+        let data = manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous());
         assert_eq!(data.len(), 4);
         match data.get(&getter_id_1_1) {
             Some(&Err(Error::TypeError(TypeError {
@@ -970,10 +1088,14 @@ fn test_send() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 kind: ChannelKind::LightOn,
                 updated: None,
+                write_only: false,
+                lease: None,
             },
         };
 
@@ -982,10 +1104,14 @@ fn test_send() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 kind: ChannelKind::LightOn,
                 updated: None,
+                write_only: false,
+                lease: None,
             },
         };
 
@@ -994,10 +1120,14 @@ fn test_send() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 kind: ChannelKind::LightOn,
                 updated: None,
+                write_only: false,
+                lease: None,
             },
         };
 
@@ -1006,10 +1136,14 @@ fn test_send() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Setter {
                 kind: ChannelKind::LightOn,
                 updated: None,
+                write_only: false,
+                lease: None,
             },
         };
 
@@ -1018,6 +1152,14 @@ fn test_send() {
             adapter: id_1.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -1027,6 +1169,14 @@ fn test_send() {
             adapter: id_2.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -1038,7 +1188,7 @@ fn test_send() {
         let rx_adapter_2 = adapter_2.take_rx();
 
         println!("* Without adapters, sending values to a selector that has no channels returns an empty vector.");
-        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), User::None);
+        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), Principal::anonymous());
 
         assert_eq!(data.len(), 0);
 
@@ -1047,7 +1197,7 @@ fn test_send() {
         manager.add_adapter(Arc::new(adapter_2)).unwrap();
         manager.add_service(service_1.clone()).unwrap();
         manager.add_service(service_2.clone()).unwrap();
-        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), User::None);
+        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), Principal::anonymous());
         assert_eq!(data.len(), 0);
 
         println!("* Sending well-typed values to channels succeeds if the adapter succeeds.");
@@ -1056,7 +1206,7 @@ fn test_send() {
         manager.add_setter(setter_1_3.clone()).unwrap();
         manager.add_setter(setter_2.clone()).unwrap();
 
-        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), User::None);
+        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), Principal::anonymous());
         assert_eq!(data.len(), 4);
         for result in data.values() {
             if let Ok(()) = *result {
@@ -1095,7 +1245,7 @@ fn test_send() {
             (vec![
                 SetterSelector::new().with_id(setter_id_1_3.clone()).clone()
             ], Value::OnOff(OnOff::On))
-        ]), User::None);
+        ]), Principal::anonymous());
         assert_eq!(data.len(), 4);
         for id in vec![&setter_id_1_1, &setter_id_1_2, &setter_id_2] {
             match data.get(id) {
@@ -1124,7 +1274,7 @@ fn test_send() {
         println!("* Sending values that cause channel errors will propagate the errors.");
         tweak_1(Tweak::InjectSetterError(setter_id_1_1.clone(), Some(Error::InternalError(InternalError::InvalidInitialService))));
 
-        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), User::None);
+        let data = manager.send_values(target_map(vec![(vec![SetterSelector::new()], Value::OnOff(OnOff::On))]), Principal::anonymous());
         assert_eq!(data.len(), 4);
         for id in vec![&setter_id_2, &setter_id_1_2, &setter_id_2] {
             match data.get(id) {
@@ -1198,9 +1348,12 @@ fn test_watch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -1210,9 +1363,12 @@ fn test_watch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -1222,9 +1378,12 @@ fn test_watch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -1234,9 +1393,12 @@ fn test_watch() {
             service: service_id_1.clone(),
             adapter: id_1.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -1246,9 +1408,12 @@ fn test_watch() {
             service: service_id_2.clone(),
             adapter: id_2.clone(),
             last_seen: None,
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
             tags: HashSet::new(),
             mechanism: Getter {
                 updated: None,
+                delivery: Delivery::State,
                 kind: ChannelKind::LightOn,
             },
         };
@@ -1258,6 +1423,14 @@ fn test_watch() {
             adapter: id_1.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -1267,6 +1440,14 @@ fn test_watch() {
             adapter: id_2.clone(),
             tags: HashSet::new(),
             properties: HashMap::new(),
+            user_properties: HashMap::new(),
+            tag_origins: HashMap::new(),
+            location: None,
+            location_ancestors: vec![],
+            parent: None,
+            related: HashSet::new(),
+            profiles: HashSet::new(),
+            actions: HashMap::new(),
             getters: HashMap::new(),
             setters: HashMap::new(),
         };
@@ -1363,7 +1544,7 @@ fn test_watch() {
         assert_eq!(manager.add_getter_tags(vec![
             GetterSelector::new().with_id(getter_id_1_3.clone()),
             GetterSelector::new().with_id(getter_id_2.clone()),
-        ], vec![tag_1.clone()]), 2);
+        ], vec![tag_1.clone()], Principal::anonymous()), 2);
 
         let (tx_watch_2, rx_watch_2) = channel();
         guards.push(manager.watch_values(target_map(vec![(
@@ -1447,7 +1628,7 @@ fn test_watch() {
         assert_eq!(manager.add_getter_tags(vec![
             GetterSelector::new().with_id(getter_id_1_1.clone()),
             GetterSelector::new().with_id(getter_id_2.clone()),
-        ], vec![tag_1.clone()]), 2);
+        ], vec![tag_1.clone()], Principal::anonymous()), 2);
         match rx_watch_2.recv().unwrap() {
             Event::GetterAdded(ref id) if *id == getter_id_1_1 => { }
             other => panic!("Unexpected event {:?}", other)
@@ -1459,7 +1640,7 @@ fn test_watch() {
 
         assert_eq!(manager.remove_getter_tags(vec![
             GetterSelector::new().with_id(getter_id_1_1.clone()),
-        ], vec![tag_1.clone()]), 1);
+        ], vec![tag_1.clone()], Principal::anonymous()), 1);
         match rx_watch_2.recv().unwrap() {
             Event::GetterRemoved(ref id) if *id == getter_id_1_1 => { }
             other => panic!("Unexpected event {:?}", other)
@@ -1483,3 +1664,2872 @@ fn test_watch() {
 
     println!("");
 }
+
+#[test]
+fn test_explain() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let tag_1 = Id::<TagId>::new("tag 1");
+    let tag_2 = Id::<TagId>::new("tag 2");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let service_1 = Service {
+        id: service_id_1.clone(),
+        adapter: id_1.clone(),
+        tags: HashSet::new(),
+        properties: HashMap::new(),
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        location: None,
+        location_ancestors: vec![],
+        parent: None,
+        related: HashSet::new(),
+        profiles: HashSet::new(),
+        actions: HashMap::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_service_tags(vec![ServiceSelector::new()], vec![tag_1.clone()], Principal::anonymous());
+
+    println!("* An explanation only reports the criteria specified by the selector.");
+    let explanations = manager.explain_services(ServiceSelector::new().with_id(service_id_1.clone()));
+    assert_eq!(explanations.len(), 1);
+    assert_eq!(explanations[0].id, service_id_1);
+    assert_eq!(explanations[0].matched, vec![Criterion::Id]);
+    assert!(explanations[0].failed.is_empty());
+    assert!(explanations[0].matches());
+
+    println!("* An explanation reports both the criteria that matched and those that failed.");
+    let explanations = manager.explain_services(ServiceSelector::new()
+        .with_tags(vec![tag_1.clone()])
+        .with_getters(vec![GetterSelector::new().with_kind(ChannelKind::Ready)]));
+    assert_eq!(explanations.len(), 1);
+    assert_eq!(explanations[0].matched, vec![Criterion::Tags]);
+    assert_eq!(explanations[0].failed, vec![Criterion::Getters]);
+    assert!(!explanations[0].matches());
+
+    println!("* Getter explanations take into account the tags of the service.");
+    let explanations = manager.explain_getters(GetterSelector::new()
+        .with_kind(ChannelKind::LightOn)
+        .with_service_tags(vec![tag_2.clone()]));
+    assert_eq!(explanations.len(), 1);
+    assert_eq!(explanations[0].id, getter_id_1);
+    assert_eq!(explanations[0].matched, vec![Criterion::Kind]);
+    assert_eq!(explanations[0].failed, vec![Criterion::ServiceTags]);
+
+    let explanations = manager.explain_getters(GetterSelector::new()
+        .with_service_tags(vec![tag_1.clone()]));
+    assert_eq!(explanations[0].matched, vec![Criterion::ServiceTags]);
+    assert!(explanations[0].matches());
+
+    println!("* Explaining setters works even when there are no setters.");
+    assert!(manager.explain_setters(SetterSelector::new().with_parent(service_id_1.clone())).is_empty());
+
+    println!("* Actions can be explained, too.");
+    let action_id_1 = Id::<Action>::new("action id 1");
+    manager.add_action(Channel {
+        id: action_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Action {
+            kind: ChannelKind::TakeSnapshot,
+            argument: Type::Unit,
+            result: Type::Unit,
+            updated: None,
+        },
+    }).unwrap();
+    let explanations = manager.explain_actions(ActionSelector::new()
+        .with_parent(service_id_1.clone())
+        .with_kind(ChannelKind::Ready));
+    assert_eq!(explanations.len(), 1);
+    assert_eq!(explanations[0].id, action_id_1);
+    assert_eq!(explanations[0].matched, vec![Criterion::Parent]);
+    assert_eq!(explanations[0].failed, vec![Criterion::Kind]);
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_add_service_with_channels() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let id_2 = Id::<AdapterId>::new("adapter id 2");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let service_1 = Service {
+        id: service_id_1.clone(),
+        adapter: id_1.clone(),
+        tags: HashSet::new(),
+        properties: HashMap::new(),
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        location: None,
+        location_ancestors: vec![],
+        parent: None,
+        related: HashSet::new(),
+        profiles: HashSet::new(),
+        actions: HashMap::new(),
+        getters: vec![(getter_id_1.clone(), getter_1.clone())].iter().cloned().collect(),
+        setters: vec![(setter_id_1.clone(), setter_1.clone())].iter().cloned().collect(),
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_2))).unwrap();
+
+    println!("* Adding a service whose channels belong to another service should fail.");
+    let service_2_with_bad_channels = Service {
+        id: service_id_2.clone(),
+        ..service_1.clone()
+    };
+    match manager.add_service_with_channels(service_2_with_bad_channels) {
+        Err(Error::InternalError(InternalError::ConflictingService(ref err_1, ref err_2)))
+            if *err_1 == service_id_2 && *err_2 == service_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Adding a service whose channels belong to another adapter should fail.");
+    let service_1_with_bad_adapter = Service {
+        adapter: id_2.clone(),
+        ..service_1.clone()
+    };
+    match manager.add_service_with_channels(service_1_with_bad_adapter) {
+        Err(Error::InternalError(InternalError::ConflictingAdapter(ref err_1, ref err_2)))
+            if *err_1 == id_2 && *err_2 == id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 0);
+
+    println!("* We can observe all channels of a service being added at once.");
+    let (tx_watch, rx_watch) = channel();
+    let guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new()],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+
+    manager.add_service_with_channels(service_1.clone()).unwrap();
+    match rx_watch.recv().unwrap() {
+        Event::GetterAdded(ref id) if *id == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].getters.len(), 1);
+    assert_eq!(services[0].setters.len(), 1);
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new()]).len(), 1);
+    assert_eq!(manager.get_setter_channels(vec![SetterSelector::new()]).len(), 1);
+
+    println!("* Adding a service whose channels are already registered should roll back entirely.");
+    let service_2_with_duplicate_channels = Service {
+        id: service_id_2.clone(),
+        getters: vec![(getter_id_1.clone(), Channel {
+            service: service_id_2.clone(),
+            ..getter_1.clone()
+        })].iter().cloned().collect(),
+        setters: HashMap::new(),
+        ..service_1.clone()
+    };
+    match manager.add_service_with_channels(service_2_with_duplicate_channels) {
+        Err(Error::InternalError(InternalError::DuplicateGetter(ref id))) if *id == getter_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 1);
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_id(service_id_2.clone())]).len(), 0);
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new()]).len(), 1);
+
+    println!("* Make sure that we haven't forgotten to eat a message.");
+    thread::sleep(std::time::Duration::new(1, 0));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+
+    drop(guard);
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_update() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+    let tag_1 = Id::<TagId>::new("tag 1");
+    let tag_user = Id::<TagId>::new("tag user");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let service_1 = Service {
+        id: service_id_1.clone(),
+        adapter: id_1.clone(),
+        tags: HashSet::new(),
+        properties: HashMap::new(),
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        location: None,
+        location_ancestors: vec![],
+        parent: None,
+        related: HashSet::new(),
+        profiles: HashSet::new(),
+        actions: HashMap::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+    };
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+
+    let (tx_watch, rx_watch) = channel();
+    let guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new().with_kind(ChannelKind::LightOn)],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+    match rx_watch.recv().unwrap() {
+        Event::GetterAdded(ref id) if *id == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Updating the tags of a getter doesn't drop its watchers.");
+    let mut getter_1_tagged = getter_1.clone();
+    getter_1_tagged.tags.insert(tag_1.clone());
+    manager.update_getter(getter_1_tagged.clone()).unwrap();
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new().with_tags(vec![tag_1.clone()])]).len(), 1);
+
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    match rx_watch.recv().unwrap() {
+        Event::EnterRange { ref from, .. } if *from == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Updating the properties and tags of a service doesn't drop watchers.");
+    let mut service_1_updated = service_1.clone();
+    service_1_updated.properties.insert("model".to_owned(), "2".to_owned());
+    service_1_updated.tags.insert(tag_1.clone());
+    manager.update_service(service_1_updated).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_tags(vec![tag_1.clone()])]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].properties.get("model"), Some(&"2".to_owned()));
+    assert_eq!(services[0].getters.len(), 1);
+
+    println!("* Updating the kind of a getter informs watchers that don't match anymore.");
+    manager.update_getter(Channel {
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::Ready,
+        },
+        ..getter_1_tagged.clone()
+    }).unwrap();
+    match rx_watch.recv().unwrap() {
+        Event::GetterRemoved(ref id) if *id == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* ... and watchers that match again.");
+    manager.update_getter(getter_1_tagged.clone()).unwrap();
+    match rx_watch.recv().unwrap() {
+        Event::GetterAdded(ref id) if *id == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Updating setters works.");
+    let mut setter_1_tagged = setter_1.clone();
+    setter_1_tagged.tags.insert(tag_1.clone());
+    manager.update_setter(setter_1_tagged).unwrap();
+    assert_eq!(manager.get_setter_channels(vec![SetterSelector::new().with_tags(vec![tag_1.clone()])]).len(), 1);
+
+    println!("* Updates don't overwrite the tags set by the user.");
+    manager.add_service_tags(vec![ServiceSelector::new()], vec![tag_user.clone()], Principal::anonymous());
+    manager.add_getter_tags(vec![GetterSelector::new()], vec![tag_user.clone()], Principal::anonymous());
+    manager.add_setter_tags(vec![SetterSelector::new()], vec![tag_user.clone()], Principal::anonymous());
+    manager.update_service(service_1.clone()).unwrap();
+    manager.update_getter(getter_1_tagged.clone()).unwrap();
+    manager.update_setter(setter_1.clone()).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags, vec![tag_user.clone()].iter().cloned().collect());
+    assert_eq!(services[0].tag_origins.get(&tag_user), Some(&TagOrigin::User));
+    let getters = manager.get_getter_channels(vec![GetterSelector::new()]);
+    assert_eq!(getters[0].tags, vec![tag_1.clone(), tag_user.clone()].iter().cloned().collect());
+    let setters = manager.get_setter_channels(vec![SetterSelector::new()]);
+    assert_eq!(setters[0].tags, vec![tag_user.clone()].iter().cloned().collect());
+
+    println!("* Updates can't move channels to another service or update unknown channels.");
+    match manager.update_setter(Channel {
+        service: Id::new("service id 2"),
+        ..setter_1.clone()
+    }) {
+        Err(Error::InternalError(InternalError::ConflictingService(ref err_1, _))) if *err_1 == service_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match manager.update_getter(Channel {
+        id: Id::new("getter id 2"),
+        ..getter_1.clone()
+    }) {
+        Err(Error::InternalError(InternalError::NoSuchGetter(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Make sure that we haven't forgotten to eat a message.");
+    thread::sleep(std::time::Duration::new(1, 0));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+
+    drop(guard);
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_user_properties() {
+    println!("");
+    let path = temp_dir("test_user_properties").join("user_properties.json");
+    let manager = AdapterManager::new();
+    manager.open_user_properties(&path).unwrap();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let service_1 = Service {
+        id: service_id_1.clone(),
+        adapter: id_1.clone(),
+        tags: HashSet::new(),
+        properties: vec![("model".to_owned(), "hue-bulb-00:17:88:01".to_owned())].iter().cloned().collect(),
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        location: None,
+        location_ancestors: vec![],
+        parent: None,
+        related: HashSet::new(),
+        profiles: HashSet::new(),
+        actions: HashMap::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+
+    let name = ("name".to_owned(), "Reading lamp".to_owned());
+    let room = ("room".to_owned(), "living room".to_owned());
+
+    println!("* We can set user properties on services and channels.");
+    assert_eq!(manager.set_service_user_properties(vec![ServiceSelector::new()],
+        vec![name.clone(), room.clone()].iter().cloned().collect()), 1);
+    assert_eq!(manager.set_getter_user_properties(vec![GetterSelector::new()],
+        vec![room.clone()].iter().cloned().collect()), 1);
+
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].user_properties.get("name"), Some(&name.1));
+    assert_eq!(services[0].properties.get("name"), None);
+    assert_eq!(services[0].getters.get(&getter_id_1).unwrap().user_properties.get("room"), Some(&room.1));
+
+    println!("* We can select by user properties.");
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_user_properties(vec![name.clone()])]).len(), 1);
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()
+        .with_user_properties(vec![("name".to_owned(), "Kitchen lamp".to_owned())])]).len(), 0);
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new().with_user_properties(vec![room.clone()])]).len(), 1);
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new().with_user_properties(vec![name.clone()])]).len(), 0);
+
+    println!("* Updates by the adapter do not overwrite user properties.");
+    manager.update_getter(getter_1.clone()).unwrap();
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new().with_user_properties(vec![room.clone()])]).len(), 1);
+
+    println!("* User properties survive re-registration.");
+    manager.remove_service(&service_id_1).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 0);
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_user_properties(vec![name.clone()])]).len(), 1);
+    assert_eq!(manager.get_getter_channels(vec![GetterSelector::new().with_user_properties(vec![room.clone()])]).len(), 1);
+
+    println!("* We can remove user properties.");
+    assert_eq!(manager.remove_service_user_properties(vec![ServiceSelector::new()], vec!["name".to_owned()]), 1);
+    assert_eq!(manager.remove_getter_user_properties(vec![GetterSelector::new()], vec!["room".to_owned()]), 1);
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].user_properties.get("name"), None);
+    assert_eq!(services[0].user_properties.get("room"), Some(&room.1));
+    assert!(services[0].getters.get(&getter_id_1).unwrap().user_properties.is_empty());
+    manager.stop();
+
+    println!("* User properties survive a restart.");
+    let manager = AdapterManager::new();
+    manager.open_user_properties(&path).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].user_properties.get("name"), None);
+    assert_eq!(services[0].user_properties.get("room"), Some(&room.1));
+    assert!(services[0].getters.get(&getter_id_1).unwrap().user_properties.is_empty());
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_tag_catalogue() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let tag_livingroom = Id::<TagId>::new("livingroom");
+    let tag_living_room = Id::<TagId>::new("living-room");
+    let tag_lamp = Id::<TagId>::new("lamp");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: vec![tag_livingroom.clone()].iter().cloned().collect(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_service(Service::empty(service_id_2.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_service_tags(vec![ServiceSelector::new()], vec![tag_livingroom.clone()], Principal::anonymous());
+    manager.add_service_tags(vec![ServiceSelector::new().with_id(service_id_1.clone())], vec![tag_living_room.clone(), tag_lamp.clone()], Principal::anonymous());
+
+    println!("* We can list tags along with their usage.");
+    let tags : HashMap<_, _> = manager.get_tags().drain(..).map(|info| (info.id.clone(), info)).collect();
+    assert_eq!(tags.len(), 3);
+    let info = tags.get(&tag_livingroom).unwrap();
+    assert_eq!((info.services, info.getters, info.setters), (2, 1, 0));
+    let info = tags.get(&tag_lamp).unwrap();
+    assert_eq!((info.services, info.getters, info.setters), (1, 0, 0));
+
+    println!("* Tags with metadata are listed even when they are not used.");
+    let metadata = TagMetadata {
+        name: Some("Living room".to_owned()),
+        icon: None,
+    };
+    manager.set_tag_metadata(tag_livingroom.clone(), metadata.clone());
+    manager.set_tag_metadata(Id::new("unused"), TagMetadata::default());
+    assert_eq!(manager.get_tags().len(), 4);
+    assert!(manager.remove_tag_metadata(&Id::new("unused")));
+    assert!(!manager.remove_tag_metadata(&Id::new("unused")));
+    assert_eq!(manager.get_tags().len(), 3);
+
+    println!("* Renaming a tag merges it with existing tags and moves its metadata.");
+    let (tx_watch, rx_watch) = channel();
+    let guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new().with_tags(vec![tag_living_room.clone()])],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+
+    assert_eq!(manager.rename_tag(&tag_livingroom, &tag_living_room, Principal::anonymous()), 3);
+    match rx_watch.recv().unwrap() {
+        Event::GetterAdded(ref id) if *id == getter_id_1 => {}
+        other => panic!("Unexpected event {:?}", other)
+    }
+    let tags : HashMap<_, _> = manager.get_tags().drain(..).map(|info| (info.id.clone(), info)).collect();
+    assert_eq!(tags.len(), 2);
+    assert!(tags.get(&tag_livingroom).is_none());
+    let info = tags.get(&tag_living_room).unwrap();
+    assert_eq!((info.services, info.getters, info.setters), (2, 1, 0));
+    assert_eq!(info.metadata, metadata);
+
+    println!("* Renaming a tag keeps its origin, even after re-registration by the adapter.");
+    let getters = manager.get_getter_channels(vec![GetterSelector::new()]);
+    assert_eq!(getters[0].tag_origins.get(&tag_living_room), Some(&TagOrigin::Adapter));
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_2.clone())]);
+    assert_eq!(services[0].tag_origins.get(&tag_living_room), Some(&TagOrigin::User));
+    manager.update_getter(getter_1.clone()).unwrap();
+    let getters = manager.get_getter_channels(vec![GetterSelector::new()]);
+    assert_eq!(getters[0].tags, vec![tag_living_room.clone()].iter().cloned().collect());
+    assert_eq!(getters[0].tag_origins.get(&tag_living_room), Some(&TagOrigin::Adapter));
+
+    println!("* Renaming a tag that doesn't exist does nothing.");
+    assert_eq!(manager.rename_tag(&tag_livingroom, &tag_lamp, Principal::anonymous()), 0);
+
+    println!("* Make sure that we haven't forgotten to eat a message.");
+    thread::sleep(std::time::Duration::new(1, 0));
+    assert_matches!(rx_watch.try_recv(), Err(_));
+
+    drop(guard);
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_locations() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let home = Id::<LocationId>::new("main house");
+    let ground_floor = Id::<LocationId>::new("ground floor");
+    let kitchen = Id::<LocationId>::new("kitchen");
+    let attic = Id::<LocationId>::new("attic");
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_service(Service::empty(service_id_2.clone(), id_1.clone())).unwrap();
+
+    println!("* We can build a location tree.");
+    manager.add_location(Location::new(home.clone(), None)).unwrap();
+    manager.add_location(Location::new(ground_floor.clone(), Some(home.clone()))).unwrap();
+    manager.add_location(Location::new(kitchen.clone(), Some(ground_floor.clone()))).unwrap();
+    manager.add_location(Location::new(attic.clone(), Some(home.clone()))).unwrap();
+    assert_eq!(manager.get_locations().len(), 4);
+    match manager.add_location(Location::new(Id::new("pantry"), Some(Id::new("no such location")))) {
+        Err(Error::InternalError(InternalError::NoSuchLocation(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We can place services in locations, but only in existing locations.");
+    assert_eq!(manager.set_service_location(vec![ServiceSelector::new().with_id(service_id_1.clone())], Some(kitchen.clone())).unwrap(), 1);
+    assert_eq!(manager.set_service_location(vec![ServiceSelector::new().with_id(service_id_2.clone())], Some(attic.clone())).unwrap(), 1);
+    match manager.set_service_location(vec![ServiceSelector::new()], Some(Id::new("no such location"))) {
+        Err(Error::InternalError(InternalError::NoSuchLocation(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].location, Some(kitchen.clone()));
+
+    println!("* Selecting a location also selects its descendants.");
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(home.clone())]).len(), 2);
+    let services = manager.get_services(vec![ServiceSelector::new().with_location(ground_floor.clone())]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].id, service_id_1);
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(kitchen.clone())]).len(), 1);
+
+    println!("* Services know the ancestors of their location, so they match selectors on their own.");
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].location_ancestors, vec![ground_floor.clone(), home.clone()]);
+    assert!(services[0].matches(&ServiceSelector::new().with_location(home.clone())));
+    assert!(services[0].matches(&ServiceSelector::new().with_location(kitchen.clone())));
+    assert!(!services[0].matches(&ServiceSelector::new().with_location(attic.clone())));
+
+    println!("* Locations survive re-registration.");
+    manager.remove_service(&service_id_1).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(ground_floor.clone())]).len(), 1);
+
+    println!("* Removing a location moves its services to the parent location.");
+    manager.remove_location(&kitchen).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].location, Some(ground_floor.clone()));
+
+    println!("* We can export and re-import the location tree.");
+    let exported = manager.get_locations();
+    manager.import_locations(exported.clone()).unwrap();
+    assert_eq!(manager.get_locations().len(), 3);
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(home.clone())]).len(), 2);
+
+    println!("* Importing a tree removes services from the locations that are not part of it.");
+    manager.import_locations(vec![Location::new(home.clone(), None), Location::new(attic.clone(), Some(home.clone()))]).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].location, None);
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(home.clone())]).len(), 1);
+    manager.import_locations(exported).unwrap();
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_location(ground_floor.clone())]).len(), 0);
+
+    println!("* ... including upon re-registration.");
+    manager.remove_service(&service_id_1).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].location, None);
+
+    println!("* Importing an inconsistent tree fails and leaves the tree unchanged.");
+    match manager.import_locations(vec![Location::new(attic.clone(), Some(attic.clone()))]) {
+        Err(Error::InternalError(InternalError::LocationCycle(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(manager.get_locations().len(), 3);
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_tag_origins() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let tag_vendor = Id::<TagId>::new("vendor");
+    let tag_light = Id::<TagId>::new("light");
+    let tag_kitchen = Id::<TagId>::new("kitchen");
+    let tag_automated = Id::<TagId>::new("automated");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: vec![tag_light.clone()].iter().cloned().collect(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+
+    let service_1 = Service {
+        id: service_id_1.clone(),
+        adapter: id_1.clone(),
+        tags: vec![tag_vendor.clone(), tag_light.clone()].iter().cloned().collect(),
+        properties: HashMap::new(),
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        location: None,
+        location_ancestors: vec![],
+        parent: None,
+        related: HashSet::new(),
+        profiles: HashSet::new(),
+        actions: HashMap::new(),
+        getters: HashMap::new(),
+        setters: HashMap::new(),
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+
+    println!("* Tags provided by the adapter are marked as such.");
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tag_origins.get(&tag_vendor), Some(&TagOrigin::Adapter));
+    assert_eq!(services[0].tags_with_origin(TagOrigin::Adapter).len(), 2);
+
+    println!("* Tags set through the API are marked as set by the user or by applications.");
+    assert_eq!(manager.add_service_tags(vec![ServiceSelector::new()], vec![tag_kitchen.clone()], Principal::anonymous()), 1);
+    assert_eq!(manager.add_service_tags_with_origin(vec![ServiceSelector::new()],
+        vec![tag_automated.clone()], TagOrigin::Application, Principal::anonymous()), 1);
+    assert_eq!(manager.add_getter_tags(vec![GetterSelector::new()], vec![tag_kitchen.clone()], Principal::anonymous()), 1);
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags.len(), 4);
+    assert_eq!(services[0].tags_with_origin(TagOrigin::User), vec![tag_kitchen.clone()].iter().cloned().collect());
+    assert_eq!(services[0].tags_with_origin(TagOrigin::Application), vec![tag_automated.clone()].iter().cloned().collect());
+    let getter = services[0].getters.get(&getter_id_1).unwrap();
+    assert_eq!(getter.tag_origins.get(&tag_kitchen), Some(&TagOrigin::User));
+
+    println!("* The user may remove tags set by the adapter.");
+    assert_eq!(manager.remove_service_tags(vec![ServiceSelector::new()], vec![tag_vendor.clone()], Principal::anonymous()), 1);
+
+    println!("* Tags survive updates and re-registration by the adapter.");
+    manager.update_service(service_1.clone()).unwrap();
+    manager.update_getter(getter_1.clone()).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags, vec![tag_light.clone(), tag_kitchen.clone(), tag_automated.clone()].iter().cloned().collect());
+    assert_eq!(services[0].getters.get(&getter_id_1).unwrap().tags.len(), 2);
+
+    manager.remove_service(&service_id_1).unwrap();
+    manager.add_service(service_1.clone()).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags, vec![tag_light.clone(), tag_kitchen.clone(), tag_automated.clone()].iter().cloned().collect());
+    assert_eq!(services[0].tag_origins.get(&tag_kitchen), Some(&TagOrigin::User));
+    assert_eq!(services[0].getters.get(&getter_id_1).unwrap().tags.len(), 2);
+
+    println!("* We can remove all the tags set by a given origin.");
+    assert_eq!(manager.remove_service_tags_by_origin(vec![ServiceSelector::new()], TagOrigin::Application, Principal::anonymous()), 1);
+    assert_eq!(manager.remove_getter_tags_by_origin(vec![GetterSelector::new()], TagOrigin::User, Principal::anonymous()), 1);
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags, vec![tag_light.clone(), tag_kitchen.clone()].iter().cloned().collect());
+    assert_eq!(services[0].getters.get(&getter_id_1).unwrap().tags, vec![tag_light.clone()].iter().cloned().collect());
+
+    println!("* Removed tags remain removed after re-registration.");
+    manager.update_service(service_1.clone()).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tags, vec![tag_light.clone(), tag_kitchen.clone()].iter().cloned().collect());
+
+    println!("* Tags set again by the user are restored.");
+    manager.add_service_tags(vec![ServiceSelector::new()], vec![tag_vendor.clone()], Principal::anonymous());
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services[0].tag_origins.get(&tag_vendor), Some(&TagOrigin::User));
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_service_hierarchy() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let id_2 = Id::<AdapterId>::new("adapter id 2");
+    let hub_id = Id::<ServiceId>::new("hub");
+    let bulb_id_1 = Id::<ServiceId>::new("bulb 1");
+    let bulb_id_2 = Id::<ServiceId>::new("bulb 2");
+    let other_id = Id::<ServiceId>::new("other");
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_2))).unwrap();
+
+    let hub = Service::empty(hub_id.clone(), id_1.clone());
+    let bulb_1 = Service {
+        parent: Some(hub_id.clone()),
+        ..Service::empty(bulb_id_1.clone(), id_1.clone())
+    };
+    let bulb_2 = Service {
+        parent: Some(hub_id.clone()),
+        related: vec![bulb_id_1.clone()].iter().cloned().collect(),
+        ..Service::empty(bulb_id_2.clone(), id_1.clone())
+    };
+    let other = Service {
+        related: vec![bulb_id_1.clone()].iter().cloned().collect(),
+        ..Service::empty(other_id.clone(), id_2.clone())
+    };
+
+    println!("* We can't add a service whose parent doesn't exist.");
+    match manager.add_service(bulb_1.clone()) {
+        Err(Error::InternalError(InternalError::NoSuchService(ref id))) if *id == hub_id => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    manager.add_service(hub.clone()).unwrap();
+
+    println!("* We can't add a service related to a service that doesn't exist, or to itself.");
+    match manager.add_service(bulb_2.clone()) {
+        Err(Error::InternalError(InternalError::NoSuchService(ref id))) if *id == bulb_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match manager.add_service(Service {
+        related: vec![bulb_id_1.clone()].iter().cloned().collect(),
+        ..bulb_1.clone()
+    }) {
+        Err(Error::InternalError(InternalError::RelatedToSelf(ref id))) if *id == bulb_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    manager.add_service(bulb_1.clone()).unwrap();
+    manager.add_service(bulb_2.clone()).unwrap();
+    manager.add_service(other.clone()).unwrap();
+
+    println!("* Updates may relate services to services that exist, but not to themselves.");
+    manager.update_service(Service {
+        related: vec![bulb_id_2.clone()].iter().cloned().collect(),
+        ..bulb_1.clone()
+    }).unwrap();
+    match manager.update_service(Service {
+        related: vec![bulb_id_2.clone(), Id::new("no such service")].iter().cloned().collect(),
+        ..bulb_1.clone()
+    }) {
+        Err(Error::InternalError(InternalError::NoSuchService(_))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match manager.update_service(Service {
+        related: vec![bulb_id_2.clone()].iter().cloned().collect(),
+        ..bulb_2.clone()
+    }) {
+        Err(Error::InternalError(InternalError::RelatedToSelf(ref id))) if *id == bulb_id_2 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(bulb_id_1.clone())]);
+    assert_eq!(services[0].related, vec![bulb_id_2.clone()].iter().cloned().collect());
+
+    println!("* We can't add a service whose parent belongs to another adapter.");
+    match manager.add_service(Service {
+        parent: Some(hub_id.clone()),
+        ..Service::empty(Id::new("bulb 3"), id_2.clone())
+    }) {
+        Err(Error::InternalError(InternalError::ConflictingAdapter(_, _))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We can select the children of a service.");
+    let children = manager.get_services(vec![ServiceSelector::new().with_parent(hub_id.clone())]);
+    assert_eq!(children.len(), 2);
+    for child in &children {
+        assert_eq!(child.parent, Some(hub_id.clone()));
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_parent(bulb_id_1.clone())]).len(), 0);
+
+    println!("* Removing a service removes its children and links to them.");
+    manager.remove_service(&hub_id).unwrap();
+    let services = manager.get_services(vec![ServiceSelector::new()]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].id, other_id);
+    assert!(services[0].related.is_empty());
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_profiles() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let light = Id::<ProfileId>::new("Light");
+    let switch = Id::<ProfileId>::new("Switch");
+
+    let getter_1 = Channel {
+        id: Id::<Getter>::new("getter id 1"),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_1 = Channel {
+        id: Id::<Setter>::new("setter id 1"),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_2 = Channel {
+        id: Id::<Setter>::new("setter id 2"),
+        service: service_id_2.clone(),
+        ..setter_1.clone()
+    };
+
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_service(Service::empty(service_id_2.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    manager.add_setter(setter_2.clone()).unwrap();
+
+    println!("* Built-in profiles are available.");
+    assert!(manager.get_profiles().iter().any(|profile| profile.id == light));
+
+    println!("* Services expose the profiles they satisfy.");
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].profiles, vec![light.clone()].iter().cloned().collect());
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_2.clone())]);
+    assert!(services[0].profiles.is_empty());
+
+    println!("* We can select services by profile.");
+    let services = manager.get_services(vec![ServiceSelector::new().with_profile(light.clone())]);
+    assert_eq!(services.len(), 1);
+    assert_eq!(services[0].id, service_id_1);
+
+    println!("* We can add and remove profiles.");
+    manager.add_profile(Profile::new(switch.clone(), vec![], vec![ChannelKind::LightOn])).unwrap();
+    match manager.add_profile(Profile::new(switch.clone(), vec![], vec![])) {
+        Err(Error::InternalError(InternalError::DuplicateProfile(ref id))) if *id == switch => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_profile(switch.clone())]).len(), 2);
+    assert!(manager.remove_profile(&switch).unwrap());
+    assert!(!manager.remove_profile(&switch).unwrap());
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_profile(switch.clone())]).len(), 0);
+
+    println!("* Built-in profiles cannot be removed.");
+    match manager.remove_profile(&light) {
+        Err(Error::InternalError(InternalError::BuiltinProfile(ref id))) if *id == light => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_profile(light.clone())]).len(), 1);
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_actions() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let action_id_1 = Id::<Action>::new("action id 1");
+    let action_id_2 = Id::<Action>::new("action id 2");
+
+    let action_1 = Channel {
+        id: action_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Action {
+            kind: ChannelKind::TakeSnapshot,
+            argument: Type::OnOff,
+            result: Type::OpenClosed,
+            updated: None,
+        },
+    };
+    let action_2 = Channel {
+        id: action_id_2.clone(),
+        mechanism: Action {
+            kind: ChannelKind::Ready,
+            argument: Type::Unit,
+            result: Type::Unit,
+            updated: None,
+        },
+        ..action_1.clone()
+    };
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+
+    println!("* We can register actions, but only once.");
+    manager.add_action(action_1.clone()).unwrap();
+    manager.add_action(action_2.clone()).unwrap();
+    match manager.add_action(action_1.clone()) {
+        Err(Error::InternalError(InternalError::DuplicateAction(ref id))) if *id == action_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We can select actions.");
+    assert_eq!(manager.get_action_channels(vec![ActionSelector::new()]).len(), 2);
+    let channels = manager.get_action_channels(vec![ActionSelector::new().with_kind(ChannelKind::TakeSnapshot)]);
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].id, action_id_1);
+    let services = manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]);
+    assert_eq!(services[0].actions.len(), 2);
+
+    println!("* Invoking an action with the right argument returns its result.");
+    tweak_1(Tweak::InjectActionResult(action_id_1.clone(), Some(Ok(Value::OpenClosed(OpenClosed::Open)))));
+    let data = manager.invoke(target_map(vec![(vec![ActionSelector::new().with_id(action_id_1.clone())], Value::OnOff(OnOff::On))]), Principal::anonymous());
+    assert_eq!(data.len(), 1);
+    match data.get(&action_id_1) {
+        Some(&Ok(Value::OpenClosed(OpenClosed::Open))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Invoking an action with the wrong argument is rejected.");
+    let data = manager.invoke(target_map(vec![(vec![ActionSelector::new()], Value::Unit)]), Principal::anonymous());
+    assert_eq!(data.len(), 2);
+    match data.get(&action_id_1) {
+        Some(&Err(Error::TypeError(TypeError { got: Type::Unit, expected: Type::OnOff }))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match data.get(&action_id_2) {
+        Some(&Ok(Value::Unit)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Results of the wrong type are rejected.");
+    tweak_1(Tweak::InjectActionResult(action_id_1.clone(), Some(Ok(Value::Unit))));
+    let data = manager.invoke(target_map(vec![(vec![ActionSelector::new().with_id(action_id_1.clone())], Value::OnOff(OnOff::On))]), Principal::anonymous());
+    match data.get(&action_id_1) {
+        Some(&Err(Error::TypeError(TypeError { got: Type::Unit, expected: Type::OpenClosed }))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We can remove actions.");
+    manager.remove_action(&action_id_1).unwrap();
+    assert_eq!(manager.get_action_channels(vec![ActionSelector::new()]).len(), 1);
+    match manager.remove_action(&action_id_1) {
+        Err(Error::InternalError(InternalError::NoSuchAction(ref id))) if *id == action_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    let data = manager.invoke(target_map(vec![(vec![ActionSelector::new().with_id(action_id_1.clone())], Value::OnOff(OnOff::On))]), Principal::anonymous());
+    assert_eq!(data.len(), 0);
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_events() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_state = Id::<Getter>::new("getter id state");
+    let getter_id_event = Id::<Getter>::new("getter id event");
+
+    let getter_state = Channel {
+        id: getter_id_state.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let getter_event = Channel {
+        id: getter_id_event.clone(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::Event,
+            kind: ChannelKind::LightOn,
+        },
+        ..getter_state.clone()
+    };
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_state.clone()).unwrap();
+    manager.add_getter(getter_event.clone()).unwrap();
+
+    println!("* We can select getters by delivery.");
+    let channels = manager.get_getter_channels(vec![GetterSelector::new().with_delivery(Delivery::Event)]);
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].id, getter_id_event);
+
+    println!("* Events cannot be fetched, even if the adapter has a value.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_state.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_event.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    let data = manager.fetch_values(vec![GetterSelector::new()], Principal::anonymous());
+    assert_eq!(data.len(), 2);
+    match data.get(&getter_id_state) {
+        Some(&Ok(Some(Value::OnOff(OnOff::On)))) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match data.get(&getter_id_event) {
+        Some(&Err(Error::GetterDoesNotSupportPolling(ref id))) if *id == getter_id_event => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Each event in the range is delivered, with its timestamp.");
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new().with_id(getter_id_event.clone())],
+        Exactly::Exactly(Range::Eq(Value::OnOff(OnOff::On)))
+    )]), Box::new(tx_watch));
+
+    tweak_1(Tweak::InjectGetterValue(getter_id_event.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_event.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_event.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_event.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    for _ in 0..3 {
+        match rx_watch.recv().unwrap() {
+            Event::Event { ref from, value: Value::OnOff(OnOff::On), .. } if *from == getter_id_event => {},
+            other => panic!("Unexpected event {:?}", other)
+        }
+    }
+    assert_matches!(rx_watch.try_recv(), Err(_));
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_streams() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_snapshot = Id::<Getter>::new("getter id snapshot");
+    let getter_id_light = Id::<Getter>::new("getter id light");
+    let stream_id = Id::<StreamId>::new("stream id");
+    let mimetype = Id::<MimeTypeId>::new("image/jpeg");
+
+    let getter_snapshot = Channel {
+        id: getter_id_snapshot.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::Snapshot,
+        },
+    };
+    let getter_light = Channel {
+        id: getter_id_light.clone(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+        ..getter_snapshot.clone()
+    };
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_snapshot.clone()).unwrap();
+    manager.add_getter(getter_light.clone()).unwrap();
+
+    println!("* Without a value, there is no snapshot.");
+    match manager.fetch_snapshot(&getter_id_snapshot, Principal::anonymous()) {
+        Err(Error::InternalError(InternalError::NoSnapshot(ref id))) if *id == getter_id_snapshot => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Values of type Stream are references to their content.");
+    let stream_ref = StreamRef {
+        id: stream_id.clone(),
+        mimetype: mimetype.clone()
+    };
+    tweak_1(Tweak::InjectGetterValue(getter_id_snapshot.clone(), Ok(Some(Value::Stream(stream_ref.clone())))));
+    let data = manager.fetch_values(vec![GetterSelector::new().with_id(getter_id_snapshot.clone())], Principal::anonymous());
+    match data.get(&getter_id_snapshot) {
+        Some(&Ok(Some(Value::Stream(ref got)))) if *got == stream_ref => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We cannot open a stream that the adapter doesn't know.");
+    match manager.open_stream(&getter_id_snapshot, &stream_id, Principal::anonymous()) {
+        Err(Error::InternalError(InternalError::NoSuchStream(ref id))) if *id == stream_id => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* We can open the latest snapshot.");
+    tweak_1(Tweak::InjectStream(stream_id.clone(), Some(Binary {
+        data: Arc::new(vec![0, 1, 2]),
+        mimetype: mimetype.clone()
+    })));
+    let stream = manager.fetch_snapshot(&getter_id_snapshot, Principal::anonymous()).unwrap();
+    assert_eq!(stream.mimetype, mimetype);
+    assert_eq!(stream.read_to_end().unwrap(), vec![0, 1, 2]);
+
+    let stream = manager.open_stream(&getter_id_snapshot, &stream_id, Principal::anonymous()).unwrap();
+    assert_eq!(stream.read_to_end().unwrap(), vec![0, 1, 2]);
+
+    println!("* We cannot open streams on getters that do not produce streams.");
+    match manager.open_stream(&getter_id_light, &stream_id, Principal::anonymous()) {
+        Err(Error::TypeError(TypeError { expected: Type::Stream, got: Type::OnOff })) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    manager.stop();
+    println!("");
+}
+
+#[test]
+fn test_home_timezone() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+
+    println!("* By default, the home uses the timezone of the system.");
+    assert_eq!(manager.get_home_timezone(), HomeTimeZone::System);
+
+    println!("* We can change the timezone of the home.");
+    manager.set_home_timezone(HomeTimeZone::Offset(-5 * 3600)).unwrap();
+    assert_eq!(manager.get_home_timezone(), HomeTimeZone::Offset(-5 * 3600));
+
+    println!("* We cannot set the home to an offset of 24h or more.");
+    match manager.set_home_timezone(HomeTimeZone::Offset(86400)) {
+        Err(Error::InvalidTimeZone(86400)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    match manager.set_home_timezone(HomeTimeZone::Offset(-86400)) {
+        Err(Error::InvalidTimeZone(-86400)) => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert_eq!(HomeTimeZone::offset(86400), None);
+    assert_eq!(HomeTimeZone::offset(86399), Some(HomeTimeZone::Offset(86399)));
+    assert_eq!(manager.get_home_timezone(), HomeTimeZone::Offset(-5 * 3600));
+
+    println!("* The timezone of the home determines the local date and time of day.");
+    let timezone = manager.get_home_timezone();
+    let timestamp = TimeStamp::from_s(1451617200); // 2016-01-01T03:00:00Z
+    assert_eq!(timezone.date(&timestamp), Date::from_ymd(2015, 12, 31).unwrap());
+    assert_eq!(timezone.day_of_week(&timestamp), DayOfWeek::Thursday);
+    assert_eq!(timezone.time_of_day(&timestamp), TimeOfDay::from_hms(22, 0, 0).unwrap());
+
+    println!("* We can fetch local time values from getters.");
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::CurrentLocalTimeOfDay,
+        },
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+
+    let time_of_day = timezone.time_of_day(&timestamp);
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::TimeOfDay(time_of_day.clone())))));
+    let data = manager.fetch_values(vec![GetterSelector::new().with_kind(ChannelKind::CurrentLocalTimeOfDay)], Principal::anonymous());
+    assert_eq!(data.len(), 1);
+    match data.get(&getter_id_1) {
+        Some(&Ok(Some(Value::TimeOfDay(ref got)))) if *got == time_of_day => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Times of day can be used in ranges.");
+    let range = Range::BetweenEq {
+        min: Value::TimeOfDay(TimeOfDay::from_hms(21, 0, 0).unwrap()),
+        max: Value::TimeOfDay(TimeOfDay::from_hms(23, 0, 0).unwrap()),
+    };
+    assert!(range.contains(&Value::TimeOfDay(time_of_day)));
+}
+
+#[test]
+fn test_secrets() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+
+    println!("* Secrets are redacted in Debug and JSON.");
+    let secret = Secret::new("hunter2".to_owned());
+    let value = Value::Secret(secret.clone());
+    assert!(!format!("{:?}", value).contains("hunter2"));
+    assert!(!format!("{:?}", value.to_json()).contains("hunter2"));
+    assert_eq!(secret.expose(), "hunter2");
+
+    println!("* Passwords are secrets.");
+    assert_eq!(ChannelKind::Password.get_type(), Type::Secret);
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::Password,
+        },
+    };
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::Password,
+        },
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(value.clone()))));
+
+    println!("* The adapter receives the actual secret.");
+    let result = manager.send_values(target_map(vec![(vec![SetterSelector::new().with_id(setter_id_1.clone())], value.clone())]), Principal::anonymous());
+    assert_eq!(result.len(), 1);
+    assert_matches!(result.get(&setter_id_1), Some(&Ok(())));
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, Value::Secret(ref got)) if *id == setter_id_1 => assert_eq!(got.expose(), "hunter2"),
+        other => panic!("Unexpected effect {:?}", other)
+    }
+
+    println!("* If the setter is not write-only, we can fetch and watch the secret.");
+    let data = manager.fetch_values(vec![GetterSelector::new().with_id(getter_id_1.clone())], Principal::anonymous());
+    assert_matches!(data.get(&getter_id_1), Some(&Ok(Some(Value::Secret(_)))));
+    let (tx_early, rx_early) = channel();
+    let _early_guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new().with_id(getter_id_1.clone())],
+        Exactly::Always
+    )]), Box::new(tx_early));
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(value.clone()))));
+    match rx_early.try_recv().unwrap() {
+        Event::EnterRange { ref from, .. } if *from == getter_id_1 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert_eq!(setter_1.to_json().find("write_only").and_then(|json| json.as_boolean()), Some(false));
+
+    println!("* If the setter is write-only, we cannot fetch the secret anymore.");
+    manager.update_setter(Channel {
+        mechanism: Setter {
+            updated: None,
+            write_only: true,
+            lease: None,
+            kind: ChannelKind::Password,
+        },
+        ..setter_1.clone()
+    }).unwrap();
+    let data = manager.fetch_values(vec![GetterSelector::new().with_id(getter_id_1.clone())], Principal::anonymous());
+    match data.get(&getter_id_1) {
+        Some(&Err(Error::WriteOnly(ref id))) if *id == getter_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_1.clone())]);
+    assert_eq!(channels[0].to_json().find("write_only").and_then(|json| json.as_boolean()), Some(true));
+
+    println!("* Ongoing watches stop once the setter becomes write-only.");
+    match rx_early.try_recv().unwrap() {
+        Event::InitializationError { ref channel, error: Error::WriteOnly(ref id) } if *channel == getter_id_1 && *id == getter_id_1 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(value.clone()))));
+    assert!(rx_early.try_recv().is_err());
+
+    println!("* If the setter is write-only, we cannot watch the secret either.");
+    let (tx_watch, rx_watch) = channel();
+    let _guard = manager.watch_values(target_map(vec![(
+        vec![GetterSelector::new().with_id(getter_id_1.clone())],
+        Exactly::Always
+    )]), Box::new(tx_watch));
+    match rx_watch.try_recv().unwrap() {
+        Event::InitializationError { ref channel, error: Error::WriteOnly(ref id) } if *channel == getter_id_1 && *id == getter_id_1 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(value.clone()))));
+    assert!(rx_watch.try_recv().is_err());
+}
+
+#[test]
+fn test_policy() {
+    println!("");
+    let manager = Arc::new(AdapterManager::new());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_lock = Id::<ServiceId>::new("service id lock");
+    let service_id_light = Id::<ServiceId>::new("service id light");
+    let getter_id_lock = Id::<Getter>::new("getter id lock");
+    let getter_id_light = Id::<Getter>::new("getter id light");
+    let setter_id_lock = Id::<Setter>::new("setter id lock");
+    let setter_id_light = Id::<Setter>::new("setter id light");
+    let kids = Id::<RoleId>::new("kids");
+
+    let getter_lock = Channel {
+        id: getter_id_lock.clone(),
+        service: service_id_lock.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::DoorLocked,
+        },
+    };
+    let getter_light = Channel {
+        id: getter_id_light.clone(),
+        service: service_id_light.clone(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+        ..getter_lock.clone()
+    };
+    let setter_lock = Channel {
+        id: setter_id_lock.clone(),
+        service: service_id_lock.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::DoorLocked,
+        },
+    };
+    let setter_light = Channel {
+        id: setter_id_light.clone(),
+        service: service_id_light.clone(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+        ..setter_lock.clone()
+    };
+
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_lock.clone(), id_1.clone())).unwrap();
+    manager.add_service(Service::empty(service_id_light.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_lock.clone()).unwrap();
+    manager.add_getter(getter_light.clone()).unwrap();
+    manager.add_setter(setter_lock.clone()).unwrap();
+    manager.add_setter(setter_light.clone()).unwrap();
+    tweak_1(Tweak::InjectGetterValue(getter_id_lock.clone(), Ok(Some(Value::DoorLocked(DoorLocked::Locked)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_light.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+
+    println!("* Kids may read, write and watch everything, except unlocking doors.");
+    let policy = Arc::new(RwLock::new(Policy::new()));
+    {
+        let mut policy = policy.write().unwrap();
+        policy.add_rule(Rule::allow(Subject::Role(kids.clone()),
+            vec![Permission::Read, Permission::Write, Permission::Watch],
+            vec![ServiceSelector::new()]));
+        policy.add_rule(Rule::deny(Subject::Role(kids.clone()),
+            vec![Permission::Write],
+            vec![ServiceSelector::new().with_setters(vec![SetterSelector::new().with_kind(ChannelKind::DoorLocked)])]));
+        policy.grant_role(1, kids.clone());
+    }
+    let kid = AccessControl::new(manager.clone(), policy.clone(), Principal::user(1));
+    let stranger = AccessControl::new(manager.clone(), policy.clone(), Principal::user(2));
+
+    println!("* Users only see the services on which they have permissions.");
+    assert_eq!(manager.get_services(vec![ServiceSelector::new()]).len(), 2);
+    assert_eq!(kid.get_services(vec![ServiceSelector::new()]).len(), 2);
+    assert_eq!(stranger.get_services(vec![ServiceSelector::new()]).len(), 0);
+    assert_eq!(stranger.get_getter_channels(vec![GetterSelector::new()]).len(), 0);
+
+    println!("* Kids can fetch values, strangers cannot.");
+    let data = kid.fetch_values(vec![GetterSelector::new()], Principal::user(1));
+    assert_eq!(data.len(), 2);
+    assert_matches!(data.get(&getter_id_lock), Some(&Ok(Some(Value::DoorLocked(DoorLocked::Locked)))));
+    assert_matches!(data.get(&getter_id_light), Some(&Ok(Some(Value::OnOff(OnOff::On)))));
+
+    let data = stranger.fetch_values(vec![GetterSelector::new()], Principal::user(2));
+    assert_eq!(data.len(), 2);
+    for result in data.values() {
+        assert_matches!(*result, Err(Error::PermissionDenied(Permission::Read)));
+    }
+
+    println!("* Kids can switch lights, but cannot unlock doors.");
+    let data = kid.send_values(target_map(vec![
+        (vec![SetterSelector::new().with_kind(ChannelKind::DoorLocked)], Value::DoorLocked(DoorLocked::Unlocked)),
+        (vec![SetterSelector::new().with_kind(ChannelKind::LightOn)], Value::OnOff(OnOff::Off)),
+    ]), Principal::user(1));
+    assert_eq!(data.len(), 2);
+    assert_matches!(data.get(&setter_id_lock), Some(&Err(Error::PermissionDenied(Permission::Write))));
+    assert_matches!(data.get(&setter_id_light), Some(&Ok(())));
+
+    println!("* Kids only receive the values of getters that they may watch, denials are reported.");
+    policy.write().unwrap().add_rule(Rule::deny(Subject::Role(kids.clone()),
+        vec![Permission::Watch],
+        vec![ServiceSelector::new().with_id(service_id_lock.clone())]));
+    let (tx_watch, rx_watch) = channel();
+    let _guard = kid.watch_values(target_map(vec![(vec![GetterSelector::new()], Exactly::Always)]), Box::new(tx_watch));
+    match rx_watch.try_recv().unwrap() {
+        Event::InitializationError { ref channel, error: Error::PermissionDenied(Permission::Watch) } if *channel == getter_id_lock => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert!(rx_watch.try_recv().is_err());
+    tweak_1(Tweak::InjectGetterValue(getter_id_lock.clone(), Ok(Some(Value::DoorLocked(DoorLocked::Unlocked)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_light.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    match rx_watch.try_recv().unwrap() {
+        Event::EnterRange { ref from, .. } if *from == getter_id_light => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert!(rx_watch.try_recv().is_err());
+
+    println!("* Getters that start matching later are checked when they send values.");
+    let getter_id_lock_2 = Id::<Getter>::new("getter id lock 2");
+    manager.add_getter(Channel {
+        id: getter_id_lock_2.clone(),
+        ..getter_lock.clone()
+    }).unwrap();
+    match rx_watch.try_recv().unwrap() {
+        Event::GetterAdded(ref id) if *id == getter_id_lock_2 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    for _ in 0..2 {
+        tweak_1(Tweak::InjectGetterValue(getter_id_lock_2.clone(), Ok(Some(Value::DoorLocked(DoorLocked::Locked)))));
+    }
+    match rx_watch.try_recv().unwrap() {
+        Event::InitializationError { ref channel, error: Error::PermissionDenied(Permission::Watch) } if *channel == getter_id_lock_2 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert!(rx_watch.try_recv().is_err());
+    manager.remove_getter(&getter_id_lock_2).unwrap();
+    match rx_watch.try_recv().unwrap() {
+        Event::GetterRemoved(ref id) if *id == getter_id_lock_2 => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+
+    println!("* Revoking a permission applies to ongoing watches.");
+    policy.write().unwrap().add_rule(Rule::deny(Subject::Role(kids.clone()),
+        vec![Permission::Watch],
+        vec![ServiceSelector::new().with_id(service_id_light.clone())]));
+    for _ in 0..2 {
+        tweak_1(Tweak::InjectGetterValue(getter_id_light.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    }
+    match rx_watch.try_recv().unwrap() {
+        Event::InitializationError { ref channel, error: Error::PermissionDenied(Permission::Watch) } if *channel == getter_id_light => {},
+        other => panic!("Unexpected event {:?}", other)
+    }
+    assert!(rx_watch.try_recv().is_err());
+
+    println!("* Impersonating another user doesn't grant more permissions.");
+    let data = stranger.send_values(target_map(vec![
+        (vec![SetterSelector::new().with_kind(ChannelKind::LightOn)], Value::OnOff(OnOff::Off)),
+    ]), Principal::user(1));
+    assert_matches!(data.get(&setter_id_light), Some(&Err(Error::PermissionDenied(Permission::Write))));
+
+    println!("* Kids cannot tag services or change the configuration.");
+    assert_eq!(kid.add_service_tags(vec![ServiceSelector::new()], vec![Id::new("tag")], Principal::anonymous()), 0);
+    assert_matches!(kid.add_location(Location::new(Id::new("kitchen"), None)), Err(Error::PermissionDenied(Permission::Configure)));
+
+    println!("* Granting permissions to a user takes effect immediately.");
+    policy.write().unwrap().add_rule(Rule::allow(Subject::User(2), vec![Permission::Tag, Permission::Configure], vec![ServiceSelector::new()]));
+    assert_eq!(stranger.get_services(vec![ServiceSelector::new()]).len(), 2);
+    assert_eq!(stranger.add_service_tags(vec![ServiceSelector::new()], vec![Id::new("tag")], Principal::anonymous()), 2);
+    stranger.add_location(Location::new(Id::new("kitchen"), None)).unwrap();
+
+    println!("* The tag catalogue only counts what users may see.");
+    let tags = kid.get_tags();
+    assert_eq!(tags.len(), 1);
+    assert_eq!(tags[0].services, 2);
+
+    println!("* Revoking a role takes effect immediately.");
+    assert!(policy.write().unwrap().revoke_role(1, &kids));
+    assert_eq!(kid.get_services(vec![ServiceSelector::new()]).len(), 0);
+    assert_eq!(manager.get_tags().len(), 1);
+    assert_eq!(kid.get_tags().len(), 0);
+
+    println!("* Principals may carry their own roles.");
+    let guest = AccessControl::new(manager.clone(), policy.clone(), Principal::anonymous().with_role(kids.clone()));
+    assert_eq!(guest.get_services(vec![ServiceSelector::new()]).len(), 2);
+
+    println!("* Expired principals are granted nothing.");
+    let expired = Principal::anonymous().with_role(kids.clone()).with_expiry(TimeStamp::from_s(0));
+    let guest = AccessControl::new(manager.clone(), policy.clone(), expired.clone());
+    assert_eq!(guest.get_services(vec![ServiceSelector::new()]).len(), 0);
+    let data = guest.fetch_values(vec![GetterSelector::new().with_id(getter_id_light.clone())], expired);
+    assert_matches!(data.get(&getter_id_light), Some(&Err(Error::PermissionDenied(Permission::Read))));
+}
+
+#[test]
+fn test_audit() {
+    println!("");
+    let audit = Arc::new(AuditLog::new());
+    let manager = AdapterManager::with_audit_log(audit.clone());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let service_id_2 = Id::<ServiceId>::new("service id 2");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+    let setter_id_2 = Id::<Setter>::new("setter id 2");
+    let tag_1 = Id::<TagId>::new("tag 1");
+
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: true,
+            lease: None,
+            kind: ChannelKind::Password,
+        },
+    };
+    let setter_2 = Channel {
+        id: setter_id_2.clone(),
+        service: service_id_2.clone(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+        ..setter_1.clone()
+    };
+
+    println!("* Registrations are recorded.");
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_service(Service::empty(service_id_2.clone(), id_1.clone())).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    manager.add_setter(setter_2.clone()).unwrap();
+    assert!(manager.add_setter(setter_2.clone()).is_err());
+
+    let entries = audit.entries();
+    assert_eq!(entries.len(), 6);
+    assert_eq!(entries[0].operation, Operation::AddAdapter);
+    assert_eq!(entries[0].results[0].target, AuditTarget::Adapter(id_1.clone()));
+    assert_eq!(entries[0].principal, Principal::anonymous());
+    assert_eq!(entries[4].operation, Operation::AddSetter);
+    assert!(entries[4].results[0].is_ok());
+    assert_eq!(entries[5].operation, Operation::AddSetter);
+    assert!(!entries[5].results[0].is_ok());
+
+    println!("* Tag edits are recorded with the principal and the channels they target.");
+    manager.add_setter_tags(vec![SetterSelector::new().with_kind(ChannelKind::LightOn)], vec![tag_1.clone()], Principal::user(3));
+    let entries = audit.entries();
+    assert_eq!(entries.len(), 7);
+    assert_eq!(entries[6].operation, Operation::AddTags(vec![tag_1.clone()]));
+    assert_eq!(entries[6].principal.user, Some(3));
+    assert_eq!(entries[6].results.len(), 1);
+    assert_eq!(entries[6].results[0].target, AuditTarget::Setter(setter_id_2.clone()));
+
+    println!("* Sending values is recorded with the principal, values and results.");
+    let secret = Value::Secret(Secret::new("hunter2".to_owned()));
+    manager.send_values(target_map(vec![
+        (vec![SetterSelector::new().with_id(setter_id_1.clone())], secret.clone()),
+        (vec![SetterSelector::new().with_id(setter_id_2.clone())], Value::OnOff(OnOff::On)),
+    ]), Principal::user(1));
+    manager.send_values(target_map(vec![
+        (vec![SetterSelector::new().with_id(setter_id_2.clone())], Value::OnOff(OnOff::Off)),
+    ]), Principal::user(2));
+
+    let entries = audit.entries();
+    assert_eq!(entries.len(), 9);
+    assert_eq!(entries[7].operation, Operation::SendValues);
+    assert_eq!(entries[7].principal.user, Some(1));
+    assert_eq!(entries[7].results.len(), 2);
+    for result in &entries[7].results {
+        assert!(result.is_ok());
+        match (&result.target, &result.value) {
+            (&AuditTarget::Setter(ref id), &None) if *id == setter_id_1 =>
+                assert!(result.redacted),
+            (&AuditTarget::Setter(ref id), &Some(Value::OnOff(OnOff::On))) if *id == setter_id_2 =>
+                assert!(!result.redacted),
+            other => panic!("Unexpected result {:?}", other)
+        }
+    }
+
+    println!("* Secrets never make it to the log.");
+    assert!(!format!("{:?}", entries).contains("hunter2"));
+
+    println!("* We can query by user.");
+    let found = manager.get_audit_entries(AuditQuery::new().with_user(2));
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].principal.user, Some(2));
+
+    println!("* We can query by selector.");
+    let found = manager.get_audit_entries(AuditQuery::new()
+        .with_setters(vec![SetterSelector::new().with_id(setter_id_1.clone())]));
+    assert_eq!(found.len(), 2); // add_setter, send_values
+    let found = manager.get_audit_entries(AuditQuery::new()
+        .with_services(vec![ServiceSelector::new().with_id(service_id_2.clone())]));
+    assert_eq!(found.len(), 6); // add_service, add_setter (x2), add_setter_tags, send_values (x2)
+    let found = manager.get_audit_entries(AuditQuery::new()
+        .with_user(1)
+        .with_setters(vec![SetterSelector::new().with_id(setter_id_2.clone())]));
+    assert_eq!(found.len(), 1);
+
+    println!("* We can query by time range.");
+    let found = manager.get_audit_entries(AuditQuery::new()
+        .with_since(TimeStamp::from_s(0))
+        .with_until(TimeStamp::from_s(4102444800)));
+    assert_eq!(found.len(), 9);
+    let found = manager.get_audit_entries(AuditQuery::new()
+        .with_since(TimeStamp::from_s(4102444800)));
+    assert_eq!(found.len(), 0);
+
+    println!("* Renaming a tag is recorded with the channels that carried it.");
+    let tag_2 = Id::<TagId>::new("tag 2");
+    assert_eq!(manager.rename_tag(&tag_1, &tag_2, Principal::user(3)), 1);
+    let entries = audit.entries();
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[9].operation, Operation::RenameTag { from: tag_1.clone(), to: tag_2.clone() });
+    assert_eq!(entries[9].principal.user, Some(3));
+    assert_eq!(entries[9].results.len(), 1);
+    assert_eq!(entries[9].results[0].target, AuditTarget::Setter(setter_id_2.clone()));
+
+    println!("* Without an audit log, nothing is recorded.");
+    let manager = AdapterManager::new();
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    assert_eq!(manager.get_audit_entries(AuditQuery::new()).len(), 0);
+
+    println!("* Entries are persisted to and reloaded from a file.");
+    let dir = temp_dir("test_audit");
+    let path = dir.join("audit.log");
+    {
+        let audit = Arc::new(AuditLog::open(&path).unwrap());
+        let manager = AdapterManager::with_audit_log(audit.clone());
+        manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+        manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+        manager.add_setter(setter_1.clone()).unwrap();
+        manager.send_values(target_map(vec![
+            (vec![SetterSelector::new().with_id(setter_id_1.clone())], secret.clone()),
+        ]), Principal::user(1));
+        assert_eq!(audit.entries().len(), 4);
+    }
+    {
+        let audit = AuditLog::open(&path).unwrap();
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].operation, Operation::SendValues);
+        assert!(entries[3].results[0].redacted);
+        audit.record(Principal::anonymous(), Operation::RemoveAdapter, vec![]);
+    }
+    let audit = AuditLog::open(&path).unwrap();
+    assert_eq!(audit.entries().len(), 5);
+    let mut content = String::new();
+    std::fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+    assert!(!content.contains("hunter2"));
+
+    println!("* Only the most recent entries are kept, and the file is rotated.");
+    let path = dir.join("capped.log");
+    {
+        let audit = AuditLog::open_with_capacity(&path, 3).unwrap();
+        for _ in 0..4 {
+            audit.record(Principal::anonymous(), Operation::AddAdapter, vec![]);
+        }
+        audit.record(Principal::anonymous(), Operation::RemoveAdapter, vec![]);
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].operation, Operation::RemoveAdapter);
+    }
+    assert!(dir.join("capped.log.1").exists());
+    let audit = AuditLog::open_with_capacity(&path, 3).unwrap();
+    let entries = audit.entries();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[2].operation, Operation::RemoveAdapter);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_leases() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+    let setter_id_2 = Id::<Setter>::new("setter id 2");
+    let one_hour = Duration::from_iso8601("PT1H").unwrap();
+
+    let rule = Principal::user(1).with_origin(Origin::Rule("evening".to_owned()));
+    let human = Principal::user(1).with_origin(Origin::Rest);
+
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_2 = Channel {
+        id: setter_id_2.clone(),
+        ..setter_1.clone()
+    };
+    manager.add_adapter(Arc::new(FakeAdapter::new(&id_1))).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    manager.add_setter(setter_2.clone()).unwrap();
+
+    let send = |value: OnOff, user: &Principal| {
+        manager.send_values(target_map(vec![(vec![SetterSelector::new().with_id(setter_id_1.clone())], Value::OnOff(value))]), user.clone())
+    };
+
+    println!("* Without a lease, anybody may send values.");
+    assert_matches!(send(OnOff::On, &rule).get(&setter_id_1), Some(&Ok(())));
+    assert_matches!(send(OnOff::Off, &human).get(&setter_id_1), Some(&Ok(())));
+
+    println!("* Anonymous principals may not acquire leases.");
+    let anonymous = Principal::anonymous().with_origin(Origin::Rule("evening".to_owned()));
+    assert_matches!(manager.acquire_lease(vec![SetterSelector::new().with_id(setter_id_1.clone())], one_hour.clone(), 0, anonymous.clone()),
+        Err(Error::PermissionDenied(Permission::Write)));
+
+    println!("* Once a lease is acquired, only its holder may send values.");
+    let lease = manager.acquire_lease(vec![SetterSelector::new().with_id(setter_id_1.clone())], one_hour.clone(), 0, rule.clone()).unwrap();
+    assert!(lease.is_held_by(&rule));
+    assert!(!lease.is_held_by(&human));
+    assert!(!lease.is_held_by(&anonymous));
+    assert_matches!(send(OnOff::On, &rule).get(&setter_id_1), Some(&Ok(())));
+    assert_matches!(send(OnOff::On, &anonymous).get(&setter_id_1), Some(&Err(Error::HeldBy(_))));
+    match send(OnOff::Off, &human).get(&setter_id_1) {
+        Some(&Err(Error::HeldBy(ref got))) if *got == lease.info() => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* Setters not covered by the lease are not affected.");
+    let result = manager.send_values(target_map(vec![(vec![SetterSelector::new().with_id(setter_id_2.clone())], Value::OnOff(OnOff::On))]), human.clone());
+    assert_matches!(result.get(&setter_id_2), Some(&Ok(())));
+
+    println!("* The lease is visible in the channel and its JSON, without its holder or id.");
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_1.clone())]);
+    assert_eq!(channels[0].mechanism.lease, Some(lease.info()));
+    assert!(channels[0].to_json().find("lease").is_some());
+    let json = channels[0].to_json();
+    assert!(json.find_path(&["lease", "priority"]).is_some());
+    assert!(json.find_path(&["lease", "holder"]).is_none());
+    assert!(json.find_path(&["lease", "id"]).is_none());
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_2.clone())]);
+    assert_eq!(channels[0].mechanism.lease, None);
+    assert!(channels[0].to_json().find("lease").is_none());
+
+    println!("* The lease survives an update of the setter by the adapter.");
+    manager.update_setter(setter_1.clone()).unwrap();
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_1.clone())]);
+    assert_eq!(channels[0].mechanism.lease, Some(lease.info()));
+
+    println!("* The lease survives the setter being removed and added again.");
+    manager.remove_setter(&setter_id_1).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_1.clone())]);
+    assert_eq!(channels[0].mechanism.lease, Some(lease.info()));
+
+    println!("* The lease survives the service being removed and added again with its channels.");
+    manager.remove_service(&service_id_1).unwrap();
+    let mut service_1 = Service::empty(service_id_1.clone(), id_1.clone());
+    service_1.setters.insert(setter_id_1.clone(), setter_1.clone());
+    service_1.setters.insert(setter_id_2.clone(), setter_2.clone());
+    manager.add_service_with_channels(service_1).unwrap();
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_1.clone())]);
+    assert_eq!(channels[0].mechanism.lease, Some(lease.info()));
+    assert_matches!(send(OnOff::On, &human).get(&setter_id_1), Some(&Err(Error::HeldBy(_))));
+
+    println!("* Acquiring a lease with the same priority fails, and changes nothing.");
+    let selectors = vec![SetterSelector::new().with_kind(ChannelKind::LightOn)];
+    match manager.acquire_lease(selectors.clone(), one_hour.clone(), 0, human.clone()) {
+        Err(Error::HeldBy(ref got)) if *got == lease.info() => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    let channels = manager.get_setter_channels(vec![SetterSelector::new().with_id(setter_id_2.clone())]);
+    assert_eq!(channels[0].mechanism.lease, None);
+
+    println!("* Acquiring a lease with a higher priority preempts the existing lease.");
+    let lease_2 = manager.acquire_lease(selectors.clone(), one_hour.clone(), 1, human.clone()).unwrap();
+    assert!(lease_2.id != lease.id);
+    assert_matches!(send(OnOff::On, &human).get(&setter_id_1), Some(&Ok(())));
+    match send(OnOff::Off, &rule).get(&setter_id_1) {
+        Some(&Err(Error::HeldBy(ref got))) if *got == lease_2.info() => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+
+    println!("* The holder may acquire a new lease on the same setters, replacing its lease.");
+    let lease_3 = manager.acquire_lease(selectors.clone(), one_hour.clone(), 1, human.clone()).unwrap();
+    let channels = manager.get_setter_channels(selectors.clone());
+    assert_eq!(channels.len(), 2);
+    for channel in channels {
+        assert_eq!(channel.mechanism.lease, Some(lease_3.info()));
+    }
+
+    println!("* Only the holder may release a lease.");
+    assert_eq!(manager.release_lease(&lease_3.id, rule.clone()), 0);
+    assert_matches!(send(OnOff::On, &rule).get(&setter_id_1), Some(&Err(Error::HeldBy(_))));
+    assert_eq!(manager.release_lease(&lease_3.id, human.clone()), 2);
+    assert_matches!(send(OnOff::On, &rule).get(&setter_id_1), Some(&Ok(())));
+    let channels = manager.get_setter_channels(selectors.clone());
+    for channel in channels {
+        assert_eq!(channel.mechanism.lease, None);
+    }
+
+    println!("* Expired leases are ignored.");
+    let expired = manager.acquire_lease(selectors.clone(), Duration::from_iso8601("PT0S").unwrap(), 0, human.clone()).unwrap();
+    assert!(!expired.is_active());
+    for channel in manager.get_setter_channels(selectors.clone()) {
+        assert_eq!(channel.mechanism.lease, None);
+    }
+    for service in manager.get_services(vec![ServiceSelector::new().with_id(service_id_1.clone())]) {
+        for channel in service.setters.values() {
+            assert_eq!(channel.mechanism.lease, None);
+        }
+    }
+    assert_matches!(send(OnOff::Off, &rule).get(&setter_id_1), Some(&Ok(())));
+    manager.acquire_lease(selectors.clone(), one_hour.clone(), 0, rule.clone()).unwrap();
+}
+
+#[test]
+fn test_send_values_if() {
+    println!("");
+    let audit = Arc::new(AuditLog::new());
+    let manager = AdapterManager::with_audit_log(audit.clone());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let setter_id_1 = Id::<Setter>::new("setter id 1");
+    let setter_id_2 = Id::<Setter>::new("setter id 2");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_1 = Channel {
+        id: setter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_2 = Channel {
+        id: setter_id_2.clone(),
+        ..setter_1.clone()
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_setter(setter_1.clone()).unwrap();
+    manager.add_setter(setter_2.clone()).unwrap();
+
+    let if_on = Precondition::new(vec![GetterSelector::new().with_id(getter_id_1.clone())], Range::Eq(Value::OnOff(OnOff::On)));
+    let turn_off_if = |precondition: &Precondition| {
+        manager.send_values_if(vec![
+            Targetted::new(vec![SetterSelector::new().with_id(setter_id_1.clone())],
+                Conditional::new(Value::OnOff(OnOff::Off)).with_precondition(precondition.clone()))
+        ], Principal::anonymous())
+    };
+
+    println!("* Parsing conditional sends from JSON.");
+    let parsed = TargetMap::<SetterSelector, Conditional>::from_str(r#"{
+        "select": {"id": "setter id 1"},
+        "value": {"OnOff": "Off"},
+        "preconditions": [{
+            "select": {"id": "getter id 1"},
+            "range": {"Eq": {"OnOff": "On"}},
+            "max_age": "PT1M"
+        }]
+    }"#).unwrap();
+    assert_eq!(parsed.len(), 1);
+    assert_eq!(parsed[0].payload.preconditions.len(), 1);
+    assert_eq!(parsed[0].payload.preconditions[0].max_age, Duration::from_iso8601("PT1M"));
+    let parsed = TargetMap::<SetterSelector, Conditional>::from_str(r#"{
+        "select": {"id": "setter id 1"},
+        "value": {"OnOff": "Off"}
+    }"#).unwrap();
+    assert_eq!(parsed[0].payload.preconditions.len(), 0);
+
+    println!("* If the precondition holds, the value is sent.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    assert_matches!(turn_off_if(&if_on).get(&setter_id_1), Some(&Ok(())));
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, Value::OnOff(OnOff::Off)) if *id == setter_id_1 => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+
+    println!("* If the precondition doesn't hold, the value is not sent.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    match turn_off_if(&if_on).get(&setter_id_1) {
+        Some(&Err(Error::PreconditionFailed(PreconditionFailure::OutOfRange(ref id, Value::OnOff(OnOff::Off)))))
+            if *id == getter_id_1 => {},
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Setters whose precondition doesn't hold are recorded in the audit log.");
+    let entries = audit.entries();
+    let last = entries.last().unwrap();
+    assert_eq!(last.operation, Operation::SendValues);
+    assert_eq!(last.results.len(), 1);
+    assert_eq!(last.results[0].target, AuditTarget::Setter(setter_id_1.clone()));
+    assert_matches!(last.results[0].error, Some(Error::PreconditionFailed(PreconditionFailure::OutOfRange(_, _))));
+
+    println!("* Preconditions on getters that don't exist or don't have a value fail.");
+    let if_nothing = Precondition::new(vec![GetterSelector::new().with_id(Id::new("no such getter"))], Range::Eq(Value::OnOff(OnOff::On)));
+    assert_matches!(turn_off_if(&if_nothing).get(&setter_id_1),
+        Some(&Err(Error::PreconditionFailed(PreconditionFailure::NoSuchGetter))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(None)));
+    assert_matches!(turn_off_if(&if_on).get(&setter_id_1),
+        Some(&Err(Error::PreconditionFailed(PreconditionFailure::NoValue(_, None)))));
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Errors fetching the value of a getter are reported.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Err(Error::InternalError(InternalError::NoSuchGetter(getter_id_1.clone())))));
+    match turn_off_if(&if_on).get(&setter_id_1) {
+        Some(&Err(Error::PreconditionFailed(PreconditionFailure::NoValue(ref id, Some(ref err))))) if *id == getter_id_1 => {
+            assert_matches!(**err, Error::InternalError(InternalError::NoSuchGetter(_)));
+        }
+        other => panic!("Unexpected result {:?}", other)
+    }
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* With a max age, preconditions are evaluated against values fetched recently.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    manager.fetch_values(vec![GetterSelector::new().with_id(getter_id_1.clone())], Principal::anonymous());
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    let if_on_recently = if_on.clone().with_max_age(Duration::from_iso8601("PT1H").unwrap());
+    assert_matches!(turn_off_if(&if_on_recently).get(&setter_id_1),
+        Some(&Err(Error::PreconditionFailed(PreconditionFailure::OutOfRange(_, _)))));
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Without a max age, preconditions are evaluated against fresh values.");
+    assert_matches!(turn_off_if(&if_on).get(&setter_id_1), Some(&Ok(())));
+    assert!(rx_adapter_1.try_recv().is_ok());
+
+    println!("* Once fetched, the fresh value is cached.");
+    assert_matches!(turn_off_if(&if_on_recently).get(&setter_id_1), Some(&Ok(())));
+    assert!(rx_adapter_1.try_recv().is_ok());
+
+    println!("* Adapter errors are reported as such.");
+    tweak_1(Tweak::InjectSetterError(setter_id_1.clone(), Some(Error::InternalError(InternalError::NoSuchSetter(setter_id_1.clone())))));
+    assert_matches!(turn_off_if(&if_on).get(&setter_id_1), Some(&Err(Error::InternalError(_))));
+    tweak_1(Tweak::InjectSetterError(setter_id_1.clone(), None));
+
+    println!("* Each setter is sent its value depending on its own preconditions.");
+    let if_off = Precondition::new(vec![GetterSelector::new().with_id(getter_id_1.clone())], Range::Eq(Value::OnOff(OnOff::Off)));
+    let result = manager.send_values_if(vec![
+        Targetted::new(vec![SetterSelector::new().with_id(setter_id_1.clone())],
+            Conditional::new(Value::OnOff(OnOff::Off)).with_precondition(if_on.clone())),
+        Targetted::new(vec![SetterSelector::new().with_id(setter_id_2.clone())],
+            Conditional::new(Value::OnOff(OnOff::Off)).with_precondition(if_off.clone())),
+    ], Principal::anonymous());
+    assert_eq!(result.len(), 2);
+    assert_matches!(result.get(&setter_id_1), Some(&Ok(())));
+    assert_matches!(result.get(&setter_id_2), Some(&Err(Error::PreconditionFailed(_))));
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, _) if *id == setter_id_1 => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Values of removed getters are forgotten.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    manager.fetch_values(vec![GetterSelector::new().with_id(getter_id_1.clone())], Principal::anonymous());
+    manager.remove_getter(&getter_id_1).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    tweak_1(Tweak::InjectGetterValue(getter_id_1.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    let values = manager.fetch_cached_values(vec![GetterSelector::new().with_id(getter_id_1.clone())],
+        Duration::from_iso8601("PT1H").unwrap(), Principal::anonymous());
+    assert_matches!(values.get(&getter_id_1), Some(&Ok(Some(Value::OnOff(OnOff::Off)))));
+
+    println!("* The cache evicts the oldest values once full, and never keeps secrets.");
+    let cache = ValueCache::with_capacity(2);
+    let max_age = Duration::from_iso8601("PT1H").unwrap();
+    let getter = |name: &str| Id::<Getter>::new(name);
+    cache.store(getter("a"), Value::OnOff(OnOff::On));
+    cache.store(getter("b"), Value::OnOff(OnOff::On));
+    cache.store(getter("a"), Value::OnOff(OnOff::Off));
+    assert_eq!(cache.len(), 2);
+    cache.store(getter("c"), Value::OnOff(OnOff::On));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&getter("b"), &max_age), None);
+    assert_eq!(cache.get(&getter("a"), &max_age), Some(Value::OnOff(OnOff::Off)));
+    cache.store(getter("a"), Value::Secret(Secret::new("hunter2".to_owned())));
+    assert_eq!(cache.get(&getter("a"), &max_age), None);
+    cache.retain(|id| *id != getter("c"));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_dry_run() {
+    println!("");
+    let manager = AdapterManager::new();
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_1 = Id::<Getter>::new("getter id 1");
+    let setter_id_light = Id::<Setter>::new("setter id light");
+    let setter_id_lock = Id::<Setter>::new("setter id lock");
+    let setter_id_other = Id::<Setter>::new("setter id other");
+    let downstairs = Id::<TagId>::new("downstairs");
+    let kitchen = Id::<TagId>::new("kitchen");
+
+    let getter_1 = Channel {
+        id: getter_id_1.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_light = Channel {
+        id: setter_id_light.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_lock = Channel {
+        id: setter_id_lock.clone(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::DoorLocked,
+        },
+        ..setter_light.clone()
+    };
+    let setter_other = Channel {
+        id: setter_id_other.clone(),
+        ..setter_light.clone()
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_1.clone()).unwrap();
+    manager.add_setter(setter_light.clone()).unwrap();
+    manager.add_setter(setter_lock.clone()).unwrap();
+    manager.add_setter(setter_other.clone()).unwrap();
+    manager.add_setter_tags(vec![
+        SetterSelector::new().with_id(setter_id_light.clone()),
+        SetterSelector::new().with_id(setter_id_lock.clone()),
+    ], vec![downstairs.clone()], Principal::anonymous());
+
+    println!("* A dry run of send_values predicts the targets and type errors.");
+    let turn_off_downstairs = target_map(vec![(vec![SetterSelector::new().with_tags(vec![downstairs.clone()])], Value::OnOff(OnOff::Off))]);
+    let result = manager.send_values_dry_run(turn_off_downstairs.clone(), Principal::anonymous());
+    assert_eq!(result.len(), 2);
+    assert_matches!(result.get(&setter_id_light), Some(&Ok(())));
+    assert_matches!(result.get(&setter_id_lock), Some(&Err(Error::TypeError(_))));
+
+    println!("* A dry run of send_values doesn't contact the adapter.");
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* A dry run of send_values predicts leases held by others.");
+    manager.acquire_lease(vec![SetterSelector::new().with_id(setter_id_light.clone())],
+        Duration::from_iso8601("PT1H").unwrap(), 0, Principal::user(1)).unwrap();
+    let result = manager.send_values_dry_run(turn_off_downstairs.clone(), Principal::user(2));
+    assert_matches!(result.get(&setter_id_light), Some(&Err(Error::HeldBy(_))));
+    let result = manager.send_values_dry_run(turn_off_downstairs.clone(), Principal::user(1));
+    assert_matches!(result.get(&setter_id_light), Some(&Ok(())));
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* A dry run of tag edits predicts the targets and the tags that would change.");
+    let selectors = vec![SetterSelector::new().with_kind(ChannelKind::LightOn)];
+    let result = manager.add_setter_tags_dry_run(selectors.clone(), vec![downstairs.clone(), kitchen.clone()]);
+    assert_eq!(result.len(), 2);
+    assert_eq!(result.get(&setter_id_light), Some(&vec![kitchen.clone()]));
+    assert_eq!(result.get(&setter_id_other), Some(&vec![downstairs.clone(), kitchen.clone()]));
+
+    let result = manager.remove_setter_tags_dry_run(selectors.clone(), vec![downstairs.clone(), kitchen.clone()]);
+    assert_eq!(result.len(), 2);
+    assert_eq!(result.get(&setter_id_light), Some(&vec![downstairs.clone()]));
+    assert_eq!(result.get(&setter_id_other), Some(&vec![]));
+
+    let result = manager.add_getter_tags_dry_run(vec![GetterSelector::new()], vec![kitchen.clone()]);
+    assert_eq!(result.get(&getter_id_1), Some(&vec![kitchen.clone()]));
+    let result = manager.remove_getter_tags_dry_run(vec![GetterSelector::new()], vec![kitchen.clone()]);
+    assert_eq!(result.get(&getter_id_1), Some(&vec![]));
+
+    let result = manager.add_service_tags_dry_run(vec![ServiceSelector::new()], vec![kitchen.clone()]);
+    assert_eq!(result.get(&service_id_1), Some(&vec![kitchen.clone()]));
+    let result = manager.remove_service_tags_dry_run(vec![ServiceSelector::new()], vec![kitchen.clone()]);
+    assert_eq!(result.get(&service_id_1), Some(&vec![]));
+
+    println!("* A dry run of tag edits doesn't change anything.");
+    for channel in manager.get_setter_channels(vec![SetterSelector::new()]) {
+        assert!(!channel.tags.contains(&kitchen));
+        assert_eq!(channel.tags.contains(&downstairs), channel.id != setter_id_other);
+    }
+    assert!(!manager.get_getter_channels(vec![GetterSelector::new()])[0].tags.contains(&kitchen));
+    assert!(!manager.get_services(vec![ServiceSelector::new()])[0].tags.contains(&kitchen));
+}
+
+#[test]
+fn test_scenes() {
+    println!("");
+    let manager = Arc::new(AdapterManager::new());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_light = Id::<Getter>::new("getter id light");
+    let setter_id_light = Id::<Setter>::new("setter id light");
+    let setter_id_lock = Id::<Setter>::new("setter id lock");
+    let movie_night = Id::<SceneId>::new("movie night");
+
+    let getter_light = Channel {
+        id: getter_id_light.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_light = Channel {
+        id: setter_id_light.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let setter_lock = Channel {
+        id: setter_id_lock.clone(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::DoorLocked,
+        },
+        ..setter_light.clone()
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_light.clone()).unwrap();
+    manager.add_setter(setter_light.clone()).unwrap();
+    manager.add_setter(setter_lock.clone()).unwrap();
+
+    let scenes = Scenes::new(manager.clone(), SceneStore::new());
+
+    println!("* Capturing a scene fetches the values of the paired getters.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_light.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    let scene = scenes.capture(movie_night.clone(), vec![SetterSelector::new()], None, Principal::anonymous()).unwrap();
+    assert_eq!(scene.id, movie_night);
+    assert_eq!(scene.steps.len(), 1);
+
+    println!("* Setters without a paired getter are left out of the scene.");
+    assert_eq!(scene.steps[0].values.len(), 1);
+    assert_eq!(scene.steps[0].values.get(&setter_id_light), Some(&Value::OnOff(OnOff::Off)));
+    assert_eq!(scenes.get(&movie_night), Some(scene.clone()));
+    assert_eq!(scenes.get_scenes().len(), 1);
+
+    println!("* Capturing a scene with a max age uses the values fetched recently.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_light.clone(), Ok(Some(Value::OnOff(OnOff::On)))));
+    let scene = scenes.capture(movie_night.clone(), vec![SetterSelector::new()], Duration::from_iso8601("PT1H"), Principal::anonymous()).unwrap();
+    assert_eq!(scene.steps[0].values.get(&setter_id_light), Some(&Value::OnOff(OnOff::Off)));
+
+    let apply = |id: &Id<SceneId>| {
+        let (tx, rx) = channel();
+        scenes.apply(id, Principal::anonymous(), Box::new(tx)).map(|()| rx)
+    };
+
+    println!("* Applying a scene sends the captured values.");
+    let rx_steps = apply(&movie_night).unwrap();
+    let step = rx_steps.try_recv().unwrap();
+    assert_eq!(step.step, 0);
+    assert_eq!(step.results.len(), 1);
+    assert_matches!(step.results.get(&setter_id_light), Some(&Ok(())));
+    assert!(rx_steps.recv().is_err());
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, Value::OnOff(OnOff::Off)) if *id == setter_id_light => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Applying an unknown scene fails.");
+    assert_matches!(apply(&Id::new("no such scene")),
+        Err(Error::InternalError(InternalError::NoSuchScene(_))));
+
+    println!("* Scenes containing secrets are rejected.");
+    let mut secrets = HashMap::new();
+    secrets.insert(setter_id_light.clone(), Value::Secret(Secret::new("hunter2".to_owned())));
+    assert_matches!(scenes.put(Scene::new(Id::new("secret")).with_step(secrets, None)), Err(Error::InvalidValue(_)));
+    assert_eq!(scenes.get(&Id::new("secret")), None);
+
+    println!("* Steps are applied in order, delayed steps in the background.");
+    let mut lights = HashMap::new();
+    lights.insert(setter_id_light.clone(), Value::OnOff(OnOff::Off));
+    let mut locks = HashMap::new();
+    locks.insert(setter_id_lock.clone(), Value::DoorLocked(DoorLocked::Locked));
+    let bedtime = Scene::new(Id::new("bedtime"))
+        .with_step(locks, None)
+        .with_step(lights, Duration::from_iso8601("PT0.2S"));
+    scenes.put(bedtime.clone()).unwrap();
+    let before = std::time::Instant::now();
+    let rx_steps = apply(&bedtime.id).unwrap();
+    let step = rx_steps.try_recv().unwrap();
+    assert_eq!(step.step, 0);
+    assert_eq!(step.results.len(), 1);
+    assert_matches!(step.results.get(&setter_id_lock), Some(&Ok(())));
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, _) if *id == setter_id_lock => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+    let step = rx_steps.recv().unwrap();
+    assert!(before.elapsed() >= std::time::Duration::from_millis(200));
+    assert_eq!(step.step, 1);
+    assert_eq!(step.results.len(), 1);
+    assert_matches!(step.results.get(&setter_id_light), Some(&Ok(())));
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, _) if *id == setter_id_light => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+    assert!(rx_steps.recv().is_err());
+    assert!(!scenes.cancel(&bedtime.id));
+
+    println!("* Setters that have disappeared are reported.");
+    manager.remove_setter(&setter_id_lock).unwrap();
+    let rx_steps = apply(&bedtime.id).unwrap();
+    let step = rx_steps.try_recv().unwrap();
+    assert_matches!(step.results.get(&setter_id_lock), Some(&Err(Error::InternalError(InternalError::NoSuchSetter(_)))));
+    let step = rx_steps.recv().unwrap();
+    assert_matches!(step.results.get(&setter_id_light), Some(&Ok(())));
+    assert_matches!(rx_adapter_1.try_recv().unwrap(), Effect::ValueSent(_, _));
+
+    println!("* Removing a scene cancels its delayed steps.");
+    let later = Scene::new(Id::new("later"))
+        .with_step(scene.steps[0].values.clone(), Duration::from_iso8601("PT1H"));
+    scenes.put(later.clone()).unwrap();
+    let rx_steps = apply(&later.id).unwrap();
+    assert!(rx_steps.try_recv().is_err());
+    assert_eq!(scenes.remove(&later.id).unwrap(), true);
+    assert!(rx_steps.recv().is_err());
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Scenes can be removed.");
+    assert_eq!(scenes.remove(&bedtime.id).unwrap(), true);
+    assert_eq!(scenes.remove(&bedtime.id).unwrap(), false);
+    assert_eq!(scenes.get(&bedtime.id), None);
+
+    println!("* Scenes persist across reopening the store.");
+    let dir = temp_dir("test_scenes");
+    let path = dir.join("scenes.json");
+    {
+        let store = SceneStore::open(&path).unwrap();
+        store.put(scene.clone()).unwrap();
+        store.put(bedtime.clone()).unwrap();
+        store.remove(&bedtime.id).unwrap();
+    }
+    let store = SceneStore::open(&path).unwrap();
+    assert_eq!(store.get_scenes(), vec![scene.clone()]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_scheduler() {
+    println!("");
+    let manager = Arc::new(AdapterManager::new());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let setter_id_porch = Id::<Setter>::new("setter id porch");
+    let porch_lights = Id::<JobId>::new("porch lights");
+    let wake_up = Id::<JobId>::new("wake up");
+    let date = |source: &str| TimeStamp::from_str(&format!("\"{}\"", source)).unwrap();
+
+    let setter_porch = Channel {
+        id: setter_id_porch.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_setter(setter_porch.clone()).unwrap();
+    manager.set_home_timezone(HomeTimeZone::Offset(0)).unwrap();
+
+    let turn_on_porch = target_map(vec![(vec![SetterSelector::new().with_id(setter_id_porch.clone())], Value::OnOff(OnOff::On))]);
+
+    println!("* Cron expressions are parsed and evaluated in the timezone of the home.");
+    let utc = HomeTimeZone::Offset(0);
+    let every_evening = CronSchedule::new("0 19 * * *").unwrap();
+    assert_eq!(every_evening.next_after(&date("2016-03-27T12:00:00+00:00"), &utc), Some(date("2016-03-27T19:00:00+00:00")));
+    assert_eq!(every_evening.next_after(&date("2016-03-27T19:00:00+00:00"), &utc), Some(date("2016-03-28T19:00:00+00:00")));
+    assert_eq!(every_evening.next_after(&date("2016-03-27T12:00:00+00:00"), &HomeTimeZone::Offset(2 * 3600)),
+        Some(date("2016-03-27T17:00:00+00:00")));
+    let office_hours = CronSchedule::new("*/15 9-17 * * 1-5").unwrap();
+    // 2016-03-26 is a Saturday.
+    assert_eq!(office_hours.next_after(&date("2016-03-26T12:00:00+00:00"), &utc), Some(date("2016-03-28T09:00:00+00:00")));
+    assert_eq!(office_hours.next_after(&date("2016-03-28T09:00:00+00:00"), &utc), Some(date("2016-03-28T09:15:00+00:00")));
+    assert_eq!(office_hours.next_after(&date("2016-03-28T17:45:00+00:00"), &utc), Some(date("2016-03-29T09:00:00+00:00")));
+    // If both the day of the month and the day of the week are restricted, either may match.
+    let first_or_sunday = CronSchedule::new("0 0 1 * 0").unwrap();
+    assert_eq!(first_or_sunday.next_after(&date("2016-03-28T00:00:00+00:00"), &utc), Some(date("2016-04-01T00:00:00+00:00")));
+    assert_eq!(first_or_sunday.next_after(&date("2016-04-01T00:00:00+00:00"), &utc), Some(date("2016-04-03T00:00:00+00:00")));
+    assert_eq!(CronSchedule::new("0 0 * * 7").unwrap().next_after(&date("2016-03-28T00:00:00+00:00"), &utc),
+        Some(date("2016-04-03T00:00:00+00:00")));
+    // A day of the month starting with `*` doesn't count as restricted.
+    assert_eq!(CronSchedule::new("0 0 */2 * 0").unwrap().next_after(&date("2016-03-28T00:00:00+00:00"), &utc),
+        Some(date("2016-04-03T00:00:00+00:00")));
+    assert_eq!(CronSchedule::new("0 0 31 2 *").unwrap().next_after(&date("2016-03-28T00:00:00+00:00"), &utc), None);
+    for invalid in &["", "0 19 * * * *", "60 * * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "5-1 * * * *", "*/0 * * * *", "a * * * *"] {
+        assert!(CronSchedule::new(invalid).is_none(), "{} should be invalid", invalid);
+    }
+
+    let policy = Arc::new(RwLock::new(Policy::new()));
+    policy.write().unwrap().add_rule(Rule::allow(Subject::User(1), vec![Permission::Write], vec![ServiceSelector::new()]));
+    let clock = Arc::new(FakeClock::new(date("2016-03-27T18:59:00+00:00")));
+    let scheduler = Scheduler::new(manager.clone(), policy.clone(), clock.clone());
+
+    println!("* Adding a job computes its next run.");
+    let job = scheduler.add_job(Job::new(porch_lights.clone(), Schedule::Cron(every_evening.clone()), turn_on_porch.clone(), Principal::user(1))).unwrap();
+    assert_eq!(job.next_run, Some(date("2016-03-27T19:00:00+00:00")));
+    assert!(job.last_run.is_none());
+    assert_eq!(scheduler.get_jobs().len(), 1);
+
+    println!("* Jobs sending secrets are rejected.");
+    let secret = target_map(vec![(vec![SetterSelector::new().with_id(setter_id_porch.clone())], Value::Secret(Secret::new("hunter2".to_owned())))]);
+    assert_matches!(scheduler.add_job(Job::new(wake_up.clone(), Schedule::Cron(every_evening.clone()), secret, Principal::user(1))),
+        Err(Error::InvalidValue(_)));
+    assert!(scheduler.get_job(&wake_up).is_none());
+
+    println!("* Jobs don't run before they are due.");
+    assert_eq!(scheduler.tick().unwrap(), vec![]);
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Jobs run once they are due, and report their results.");
+    clock.advance(Duration::from_iso8601("PT1M").unwrap());
+    assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, Value::OnOff(OnOff::On)) if *id == setter_id_porch => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+    let job = scheduler.get_job(&porch_lights).unwrap();
+    let last_run = job.last_run.clone().unwrap();
+    assert_eq!(last_run.date, date("2016-03-27T19:00:00+00:00"));
+    assert_matches!(last_run.results.get(&setter_id_porch), Some(&Ok(())));
+    assert_eq!(job.next_run, Some(date("2016-03-28T19:00:00+00:00")));
+
+    println!("* Jobs don't run twice for the same date.");
+    assert_eq!(scheduler.tick().unwrap(), vec![]);
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Jobs that missed several runs run only once.");
+    clock.set(date("2016-03-30T20:00:00+00:00"));
+    assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+    assert!(rx_adapter_1.try_recv().is_ok());
+    assert!(rx_adapter_1.try_recv().is_err());
+    assert_eq!(scheduler.get_job(&porch_lights).unwrap().next_run, Some(date("2016-03-31T19:00:00+00:00")));
+
+    println!("* One-shot jobs run once.");
+    scheduler.add_job(Job::new(wake_up.clone(), Schedule::Once(date("2016-03-31T07:00:00+00:00")), turn_on_porch.clone(), Principal::user(1))).unwrap();
+    clock.set(date("2016-03-31T07:00:00+00:00"));
+    assert_eq!(scheduler.tick().unwrap(), vec![wake_up.clone()]);
+    assert!(rx_adapter_1.try_recv().is_ok());
+    let job = scheduler.get_job(&wake_up).unwrap();
+    assert!(job.last_run.is_some());
+    assert_eq!(job.next_run, None);
+    clock.advance(Duration::from_iso8601("P1D").unwrap());
+    assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+    assert!(rx_adapter_1.try_recv().is_ok());
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Setters that have disappeared are left out of the last run.");
+    manager.remove_setter(&setter_id_porch).unwrap();
+    clock.advance(Duration::from_iso8601("P1D").unwrap());
+    assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+    let last_run = scheduler.get_job(&porch_lights).unwrap().last_run.unwrap();
+    assert!(last_run.results.is_empty());
+    assert!(rx_adapter_1.try_recv().is_err());
+    manager.add_setter(setter_porch.clone()).unwrap();
+
+    println!("* Jobs only send values that their principal may send.");
+    let stranger = Id::<JobId>::new("stranger");
+    scheduler.add_job(Job::new(stranger.clone(), Schedule::Once(date("2016-04-02T08:00:00+00:00")), turn_on_porch.clone(), Principal::user(2))).unwrap();
+    clock.set(date("2016-04-02T08:00:00+00:00"));
+    assert_eq!(scheduler.tick().unwrap(), vec![stranger.clone()]);
+    assert!(rx_adapter_1.try_recv().is_err());
+    let last_run = scheduler.get_job(&stranger).unwrap().last_run.unwrap();
+    assert_matches!(last_run.results.get(&setter_id_porch), Some(&Err(Error::PermissionDenied(Permission::Write))));
+    assert_eq!(scheduler.remove_job(&stranger).unwrap(), true);
+
+    println!("* Jobs whose principal has expired are skipped.");
+    let expired = Id::<JobId>::new("expired");
+    let principal = Principal::user(1).with_expiry(TimeStamp::from_s(0));
+    scheduler.add_job(Job::new(expired.clone(), Schedule::Once(date("2016-04-02T09:00:00+00:00")), turn_on_porch.clone(), principal)).unwrap();
+    clock.set(date("2016-04-02T09:00:00+00:00"));
+    assert_eq!(scheduler.tick().unwrap(), vec![]);
+    assert!(rx_adapter_1.try_recv().is_err());
+    let job = scheduler.get_job(&expired).unwrap();
+    assert!(job.last_run.is_none());
+    assert_eq!(job.next_run, None);
+    assert_eq!(scheduler.remove_job(&expired).unwrap(), true);
+
+    println!("* Jobs can be removed.");
+    assert_eq!(scheduler.remove_job(&wake_up).unwrap(), true);
+    assert_eq!(scheduler.remove_job(&wake_up).unwrap(), false);
+    assert!(scheduler.get_job(&wake_up).is_none());
+
+    println!("* Jobs persist across reopening the scheduler.");
+    let dir = temp_dir("test_scheduler");
+    let path = dir.join("jobs.json");
+    {
+        let scheduler = Scheduler::open(manager.clone(), policy.clone(), clock.clone(), &path).unwrap();
+        scheduler.add_job(Job::new(porch_lights.clone(), Schedule::Cron(every_evening.clone()), turn_on_porch.clone(), Principal::user(1))).unwrap();
+        clock.set(date("2016-04-10T19:00:00+00:00"));
+        assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+        assert!(rx_adapter_1.try_recv().is_ok());
+    }
+    let scheduler = Scheduler::open(manager.clone(), policy.clone(), clock.clone(), &path).unwrap();
+    let job = scheduler.get_job(&porch_lights).unwrap();
+    assert_eq!(job.schedule, Schedule::Cron(every_evening.clone()));
+    assert_eq!(job.principal, Principal::user(1));
+    assert_eq!(job.next_run, Some(date("2016-04-11T19:00:00+00:00")));
+    assert_matches!(job.last_run.unwrap().results.get(&setter_id_porch), Some(&Ok(())));
+    assert_eq!(job.values.len(), 1);
+
+    println!("* Persisted values have the same format as in the API, i.e. {select, value}.");
+    let mut content = String::new();
+    std::fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+    assert!(content.contains("\"value\":{\"OnOff\":\"On\"}"));
+    assert!(!content.contains("payload"));
+
+    println!("* Persisted jobs keep running.");
+    clock.advance(Duration::from_iso8601("P1D").unwrap());
+    assert_eq!(scheduler.tick().unwrap(), vec![porch_lights.clone()]);
+    assert!(rx_adapter_1.try_recv().is_ok());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_rules() {
+    println!("");
+    let manager = Arc::new(AdapterManager::new());
+    let id_1 = Id::<AdapterId>::new("adapter id 1");
+    let service_id_1 = Id::<ServiceId>::new("service id 1");
+    let getter_id_door = Id::<Getter>::new("getter id door");
+    let getter_id_daylight = Id::<Getter>::new("getter id daylight");
+    let setter_id_light = Id::<Setter>::new("setter id light");
+
+    let getter_door = Channel {
+        id: getter_id_door.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::OpenClosed,
+        },
+    };
+    let getter_daylight = Channel {
+        id: getter_id_daylight.clone(),
+        mechanism: Getter {
+            updated: None,
+            delivery: Delivery::State,
+            kind: ChannelKind::LightOn,
+        },
+        ..getter_door.clone()
+    };
+    let setter_light = Channel {
+        id: setter_id_light.clone(),
+        service: service_id_1.clone(),
+        adapter: id_1.clone(),
+        last_seen: None,
+        user_properties: HashMap::new(),
+        tag_origins: HashMap::new(),
+        tags: HashSet::new(),
+        mechanism: Setter {
+            updated: None,
+            write_only: false,
+            lease: None,
+            kind: ChannelKind::LightOn,
+        },
+    };
+    let adapter_1 = FakeAdapter::new(&id_1);
+    let tweak_1 = adapter_1.get_tweak();
+    let rx_adapter_1 = adapter_1.take_rx();
+    manager.add_adapter(Arc::new(adapter_1)).unwrap();
+    manager.add_service(Service::empty(service_id_1.clone(), id_1.clone())).unwrap();
+    manager.add_getter(getter_door.clone()).unwrap();
+    manager.add_getter(getter_daylight.clone()).unwrap();
+    manager.add_setter(setter_light.clone()).unwrap();
+
+    let policy = Arc::new(RwLock::new(Policy::new()));
+    policy.write().unwrap().add_rule(Rule::allow(Subject::User(1),
+        vec![Permission::Read, Permission::Write, Permission::Watch],
+        vec![ServiceSelector::new()]));
+
+    println!("* Starting the engine registers its root service.");
+    let engine = ThinkerBell::init(&manager, policy.clone()).unwrap();
+    assert_eq!(manager.get_setter_channels(vec![SetterSelector::new().with_kind(ChannelKind::AddThinkerbellRule)]).len(), 1);
+
+    let source = r#"{
+        "conditions": [{
+            "select": {"id": "getter id door"},
+            "range": {"Eq": {"OpenClosed": "Open"}}
+        }, {
+            "select": {"id": "getter id daylight"},
+            "range": {"Eq": {"OnOff": "Off"}}
+        }],
+        "actions": [{
+            "select": {"id": "setter id light"},
+            "value": {"OnOff": "On"}
+        }]
+    }"#;
+    let add_rule_as = |name: &str, source: &str, principal: Principal| {
+        manager.send_values(target_map(vec![(vec![SetterSelector::new().with_id(engine.get_add_rule_setter())],
+            Value::ThinkerbellRule(ThinkerbellRule {
+                name: name.to_owned(),
+                source: source.to_owned(),
+            }))]), principal)
+    };
+    let add_rule = |name: &str, source: &str| add_rule_as(name, source, Principal::user(1));
+    let set_rule = |kind: ChannelKind, value: Value| {
+        manager.send_values(target_map(vec![(vec![SetterSelector::new().with_kind(kind)], value)]), Principal::user(1))
+    };
+
+    println!("* Invalid rules are rejected.");
+    let result = add_rule("lights", r#"{"conditions": [], "actions": []}"#);
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Err(Error::InvalidValue(_))));
+    assert!(engine.get_rule_service("lights").is_none());
+
+    println!("* Rules whose principal may not watch the conditions or send the actions are rejected.");
+    let result = add_rule_as("lights", source, Principal::user(2));
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Err(Error::PermissionDenied(Permission::Watch))));
+    assert!(engine.get_rule_service("lights").is_none());
+    policy.write().unwrap().add_rule(Rule::allow(Subject::User(2), vec![Permission::Watch], vec![ServiceSelector::new()]));
+    let result = add_rule_as("lights", source, Principal::user(2));
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Err(Error::PermissionDenied(Permission::Write))));
+    assert!(engine.get_rule_service("lights").is_none());
+
+    println!("* Adding a rule registers its service.");
+    let result = add_rule("lights", source);
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Ok(())));
+    let service_id = engine.get_rule_service("lights").unwrap();
+    let service = manager.get_services(vec![ServiceSelector::new().with_id(service_id.clone())]);
+    assert_eq!(service.len(), 1);
+    assert_eq!(service[0].getters.len(), 2);
+    assert_eq!(service[0].setters.len(), 2);
+    let fetch = |kind: ChannelKind| {
+        manager.fetch_values(vec![GetterSelector::new().with_kind(kind)], Principal::anonymous())
+            .into_iter().map(|(_, result)| result.unwrap().unwrap()).next()
+    };
+    assert_eq!(fetch(ChannelKind::ThinkerbellRuleSource), Some(Value::String(Arc::new(source.to_owned()))));
+    assert_eq!(fetch(ChannelKind::ThinkerbellRuleOn), Some(Value::OnOff(OnOff::On)));
+
+    println!("* Rules with the same name are rejected.");
+    let result = add_rule("lights", source);
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Err(Error::InternalError(InternalError::DuplicateService(_)))));
+
+    println!("* A rule doesn't trigger until all its conditions are met.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* A rule triggers once all its conditions are met.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_daylight.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    engine.sync();
+    match rx_adapter_1.try_recv().unwrap() {
+        Effect::ValueSent(ref id, Value::OnOff(OnOff::On)) if *id == setter_id_light => {},
+        other => panic!("Unexpected effect {:?}", other)
+    }
+
+    println!("* A rule triggers again only after its conditions have stopped being met.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Closed)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert_matches!(rx_adapter_1.try_recv().unwrap(), Effect::ValueSent(_, _));
+
+    println!("* A disabled rule doesn't trigger.");
+    let result = set_rule(ChannelKind::ThinkerbellRuleOn, Value::OnOff(OnOff::Off));
+    assert_eq!(result.len(), 1);
+    assert_matches!(result.values().next(), Some(&Ok(())));
+    assert_eq!(fetch(ChannelKind::ThinkerbellRuleOn), Some(Value::OnOff(OnOff::Off)));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Closed)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* A rule enabled again triggers.");
+    set_rule(ChannelKind::ThinkerbellRuleOn, Value::OnOff(OnOff::On));
+    assert_eq!(fetch(ChannelKind::ThinkerbellRuleOn), Some(Value::OnOff(OnOff::On)));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_daylight.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    engine.sync();
+    assert_matches!(rx_adapter_1.try_recv().unwrap(), Effect::ValueSent(_, _));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* Removing a rule removes its service and stops it.");
+    let result = set_rule(ChannelKind::RemoveThinkerbellRule, Value::Unit);
+    assert_matches!(result.values().next(), Some(&Ok(())));
+    assert!(engine.get_rule_service("lights").is_none());
+    assert_eq!(manager.get_services(vec![ServiceSelector::new().with_id(service_id.clone())]).len(), 0);
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Closed)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+
+    println!("* A rule may be added again after it has been removed.");
+    let result = add_rule("lights", source);
+    assert_matches!(result.get(&engine.get_add_rule_setter()), Some(&Ok(())));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    tweak_1(Tweak::InjectGetterValue(getter_id_daylight.clone(), Ok(Some(Value::OnOff(OnOff::Off)))));
+    engine.sync();
+    assert_matches!(rx_adapter_1.try_recv().unwrap(), Effect::ValueSent(_, _));
+
+    println!("* A rule doesn't send values once its principal has lost the permission to write.");
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Closed)))));
+    engine.sync();
+    policy.write().unwrap().add_rule(Rule::deny(Subject::User(1), vec![Permission::Write], vec![ServiceSelector::new()]));
+    tweak_1(Tweak::InjectGetterValue(getter_id_door.clone(), Ok(Some(Value::OpenClosed(OpenClosed::Open)))));
+    engine.sync();
+    assert!(rx_adapter_1.try_recv().is_err());
+}